@@ -0,0 +1,261 @@
+// This file implements the `--batch` mode, which answers every line of a JSONL file of prompts
+// the requests run concurrently on one tokio runtime and the results are appended to a JSONL file
+use crate::client::{record_usage_async, send_with_fallback_async, TokenUsage};
+use crate::config::{GlobalConfig, Input};
+use crate::utils::{init_tokio_runtime, sha256sum};
+
+use anyhow::{bail, Context, Result};
+use futures_util::{stream, StreamExt};
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs::{read_to_string, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Instant;
+
+// the number of requests in flight when `--concurrency` is not given
+pub const DEFAULT_CONCURRENCY: usize = 4;
+
+// struct representing a line of the batch file
+#[derive(Debug, Clone, Deserialize)]
+struct BatchItem {
+    text: String,
+    #[serde(default)]
+    files: Vec<String>,
+    role: Option<String>,
+    model: Option<String>,
+}
+
+// struct representing a line of the output file
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+struct BatchResult {
+    line: usize, // the line number in the batch file, starts at 1
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    hash: String, // the hash of the line, so a result is not taken for another prompt once the file is edited
+    #[serde(skip_serializing_if = "Option::is_none")]
+    model: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    output: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    input_tokens: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    output_tokens: Option<usize>,
+    latency_ms: u128,
+}
+
+// this function runs the batch file and appends the results to the output file
+// lines that already succeeded in the output file, with the same content, are skipped,
+// so a failed run can be resumed
+pub fn run(
+    config: &GlobalConfig,
+    path: &str,
+    output: Option<String>,
+    concurrency: Option<usize>,
+) -> Result<()> {
+    let path = Path::new(path);
+    let output = output
+        .map(PathBuf::from)
+        .unwrap_or_else(|| default_output_path(path));
+    let concurrency = concurrency.unwrap_or(DEFAULT_CONCURRENCY);
+    if concurrency == 0 {
+        bail!("Invalid concurrency 0");
+    }
+
+    let content = read_to_string(path)
+        .with_context(|| format!("Failed to load batch file at {}", path.display()))?;
+    let done = load_succeeded_lines(&output)?;
+
+    let mut items = vec![];
+    for (index, line) in content.lines().enumerate() {
+        let line_number = index + 1;
+        let hash = sha256sum(line);
+        if line.trim().is_empty() || done.contains(&(line_number, hash.clone())) {
+            continue;
+        }
+        // an invalid line is reported in the output file like any other failure
+        let item = serde_json::from_str::<BatchItem>(line)
+            .with_context(|| format!("Invalid batch line {line_number}"));
+        items.push((line_number, hash, item));
+    }
+
+    let skipped = content.lines().filter(|v| !v.trim().is_empty()).count() - items.len();
+    eprintln!(
+        "Running {} lines ({} already succeeded), writing to {}",
+        items.len(),
+        skipped,
+        output.display()
+    );
+
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&output)
+        .with_context(|| format!("Failed to open {}", output.display()))?;
+
+    let (mut succeeded, mut failed) = (0, 0);
+    init_tokio_runtime()?.block_on(async {
+        let mut results = stream::iter(items)
+            .map(|(line, hash, item)| async move {
+                match item {
+                    Ok(item) => run_item(config, line, hash, item).await,
+                    Err(err) => BatchResult {
+                        line,
+                        hash,
+                        error: Some(format!("{err:#}")),
+                        ..Default::default()
+                    },
+                }
+            })
+            .buffer_unordered(concurrency);
+        while let Some(result) = results.next().await {
+            match &result.error {
+                Some(err) => {
+                    failed += 1;
+                    eprintln!("Line {} failed: {err}", result.line);
+                }
+                None => succeeded += 1,
+            }
+            // each result is written as soon as it arrives, so an interrupted run loses nothing
+            let line = serde_json::to_string(&result)?;
+            writeln!(file, "{line}")?;
+            file.flush()?;
+        }
+        anyhow::Ok(())
+    })?;
+
+    eprintln!("Done: {succeeded} succeeded, {failed} failed");
+    Ok(())
+}
+
+// this function answers one line of the batch file, its errors are kept in the result
+async fn run_item(
+    config: &GlobalConfig,
+    line: usize,
+    hash: String,
+    item: BatchItem,
+) -> BatchResult {
+    let start = Instant::now();
+    let mut result = BatchResult {
+        line,
+        hash,
+        ..Default::default()
+    };
+    if let Err(err) = send_item(config, item, &mut result).await {
+        result.error = Some(format!("{err:#}"));
+    }
+    result.latency_ms = start.elapsed().as_millis();
+    result
+}
+
+// this function sends one line of the batch file with its own role and model
+async fn send_item(config: &GlobalConfig, item: BatchItem, result: &mut BatchResult) -> Result<()> {
+    // each line works on its own copy of the config, so it never changes the shared state
+    let mut item_config = config.read().clone();
+    item_config.session = None;
+    if let Some(role) = &item.role {
+        item_config.set_role(role)?;
+    }
+    if let Some(model) = &item.model {
+        item_config.set_model(model)?;
+    }
+
+    let input = Input::new(&item.text, item.files)?;
+    let item_config = Arc::new(RwLock::new(item_config));
+    // the models of the fallback chain are tried in turn, as in the other modes
+    let output = send_with_fallback_async(&item_config, &input, |client| {
+        let item_config = item_config.clone();
+        let input = &input;
+        async move {
+            let data = item_config.read().prepare_send_data(input, false)?;
+            let messages = data.messages.clone();
+            let http_client = client.build_client()?;
+            let output = client
+                .send_message_with_retry(&http_client, data)
+                .await
+                .with_context(|| "Failed to get answer")?;
+            record_usage_async(item_config, messages, output.clone()).await?;
+            Ok(output)
+        }
+    })
+    .await?;
+
+    let config = item_config.read();
+    let model = config.answer_model();
+    result.model = Some(model.id());
+    // the counts reported by the api are preferred to the local estimate
+    let usage = match output.usage {
+        Some(usage) => usage,
+        None => TokenUsage {
+            input_tokens: model.total_tokens(&config.build_messages(&input)?),
+            output_tokens: model.count_tokens(&output.text),
+        },
+    };
+    result.input_tokens = Some(usage.input_tokens);
+    result.output_tokens = Some(usage.output_tokens);
+    result.output = Some(output.text);
+    Ok(())
+}
+
+// this function reads the line numbers and hashes of the lines that already succeeded from a previous output file
+fn load_succeeded_lines(path: &Path) -> Result<HashSet<(usize, String)>> {
+    let mut lines = HashSet::new();
+    if !path.exists() {
+        return Ok(lines);
+    }
+    let content = read_to_string(path)
+        .with_context(|| format!("Failed to load batch output at {}", path.display()))?;
+    for line in content.lines() {
+        if let Ok(result) = serde_json::from_str::<BatchResult>(line) {
+            if result.error.is_none() {
+                lines.insert((result.line, result.hash));
+            }
+        }
+    }
+    Ok(lines)
+}
+
+// this function derives the output path from the batch file, e.g. prompts.jsonl -> prompts.out.jsonl
+fn default_output_path(path: &Path) -> PathBuf {
+    let stem = path
+        .file_stem()
+        .map(|v| v.to_string_lossy().to_string())
+        .unwrap_or_default();
+    path.with_file_name(format!("{stem}.out.jsonl"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_output_path() {
+        assert_eq!(
+            default_output_path(Path::new("data/prompts.jsonl")),
+            PathBuf::from("data/prompts.out.jsonl")
+        );
+    }
+
+    #[test]
+    fn test_load_succeeded_lines() {
+        let path = std::env::temp_dir().join(format!("aichat-batch-{}.jsonl", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"{"line":1,"hash":"a","output":"ok","latency_ms":10}
+{"line":2,"hash":"b","error":"timeout","latency_ms":10}
+{"line":2,"hash":"b","output":"ok","latency_ms":10}
+{"line":3,"hash":"c","error":"timeout","latency_ms":10}
+"#,
+        )
+        .unwrap();
+        let lines = load_succeeded_lines(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            lines,
+            HashSet::from([(1, "a".to_string()), (2, "b".to_string())])
+        );
+    }
+}
@@ -1,8 +1,12 @@
-use clap::Parser;
+use crate::client::GenerationParams;
+use crate::render::OutputFormat;
+
+use clap::{ArgGroup, Parser};
 // This file uses clap crate for parsing and handling command-line arguments
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
+#[command(group(ArgGroup::new("report").args(["history", "usage"]).multiple(true)))]
 pub struct Cli {
     // Choose a LLM model
     #[clap(short, long)]
@@ -28,21 +32,102 @@ pub struct Cli {
     // Use light theme
     #[clap(long)]
     pub light_theme: bool,
+    // Turn the text into a shell command, run once confirmed
+    #[clap(short = 'e', long)]
+    pub execute: bool,
+    // Print only the code of the answer, without its markdown fences
+    #[clap(short = 'c', long)]
+    pub code: bool,
+    // Write each code block of the answer to a file of the directory, implies --code
+    #[clap(long, value_name = "DIR")]
+    pub code_out: Option<String>,
+    // Print the answer as json, or stream it as json lines with jsonl
+    #[clap(long, value_enum, value_name = "FORMAT")]
+    pub output: Option<OutputFormat>,
+    // Make the answer a json matching the json schema of the file
+    #[clap(long, value_name = "FILE")]
+    pub schema: Option<String>,
     // Run in dry run mode
     #[clap(long)]
     pub dry_run: bool,
+    // Sample from the tokens of the top probability mass, between 0 and 1
+    #[clap(long, value_name = "VALUE")]
+    pub top_p: Option<f64>,
+    // Limit the number of tokens of the answer
+    #[clap(long, value_name = "NUM")]
+    pub max_output_tokens: Option<usize>,
+    // Stop the answer at the sequence, can be given several times
+    #[clap(long, value_name = "TEXT")]
+    pub stop: Vec<String>,
+    // Sample with the seed, for repeatable answers
+    #[clap(long, value_name = "NUM")]
+    pub seed: Option<u64>,
+    // Penalize the tokens already present in the text
+    #[clap(long, value_name = "VALUE")]
+    pub presence_penalty: Option<f64>,
+    // Penalize the tokens by how often they appear in the text
+    #[clap(long, value_name = "VALUE")]
+    pub frequency_penalty: Option<f64>,
     // Print related information
     #[clap(long)]
     pub info: bool,
     // List all available models
     #[clap(long)]
     pub list_models: bool,
+    // Fetch the models listed by the APIs of the clients and cache them
+    #[clap(long)]
+    pub refresh_models: bool,
+    // Index the files and directories given as text under a name, for --rag
+    #[clap(long, value_name = "NAME")]
+    pub index: Option<String>,
+    // Build a keyword index with --index, searched without an embedding model
+    #[clap(long, requires = "index")]
+    pub bm25: bool,
+    // Send the chunks of an index closest to each question along with it
+    #[clap(long, value_name = "NAME")]
+    pub rag: Option<String>,
     // List all available roles
     #[clap(long)]
     pub list_roles: bool,
     // List all available sessions
     #[clap(long)]
     pub list_sessions: bool,
+    // Search the saved sessions and messages for the words of the query
+    #[clap(long, value_name = "QUERY")]
+    pub search: Option<String>,
+    // Continue the session of the best search result in the REPL
+    #[clap(long, requires = "search")]
+    pub open: bool,
+    // List the messages saved in messages.jsonl, or show the one with the given number
+    #[clap(long, value_name = "NUM")]
+    pub history: Option<Option<usize>>,
+    // Only list the messages answered by a model whose id contains the text
+    #[clap(long, value_name = "MODEL", requires = "history")]
+    pub history_model: Option<String>,
+    // Only list the messages sent with the role
+    #[clap(long, value_name = "ROLE", requires = "history")]
+    pub history_role: Option<String>,
+    // Report the tokens and the spend of each model and day, from the usage ledger
+    #[clap(long)]
+    pub usage: bool,
+    // Only list the messages, or count the usage, on or after the date (YYYY-MM-DD)
+    #[clap(long, value_name = "DATE", requires = "report")]
+    pub since: Option<String>,
+    // Only list the messages, or count the usage, on or before the date (YYYY-MM-DD)
+    #[clap(long, value_name = "DATE", requires = "report")]
+    pub until: Option<String>,
+    // Answer every line of a JSONL file of prompts
+    #[clap(long, value_name = "FILE")]
+    pub batch: Option<String>,
+    // Where to write the batch results, <FILE>.out.jsonl by default
+    #[clap(long, value_name = "FILE", requires = "batch")]
+    pub batch_output: Option<String>,
+    // The number of batch requests sent at the same time
+    #[clap(long, value_name = "NUM", requires = "batch")]
+    pub concurrency: Option<usize>,
+    // Serve an OpenAI-compatible API on the address, 127.0.0.1:8000 by default
+    #[clap(long, value_name = "ADDRESS")]
+    pub serve: Option<Option<String>>,
     // Input text
     text: Vec<String>,
 }
@@ -64,4 +149,22 @@ impl Cli {
         }
         Some(text) // Else return string wrapped in 'Some'
     }
+
+    // this method returns the input text as separate paths, for --index
+    pub fn paths(&self) -> Vec<String> {
+        self.text.clone()
+    }
+
+    // this method gathers the generation parameters given as options
+    pub fn params(&self) -> GenerationParams {
+        GenerationParams {
+            top_p: self.top_p,
+            max_output_tokens: self.max_output_tokens,
+            stop: self.stop.clone(),
+            seed: self.seed,
+            presence_penalty: self.presence_penalty,
+            frequency_penalty: self.frequency_penalty,
+            provider_options: Default::default(),
+        }
+    }
 }
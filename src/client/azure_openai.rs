@@ -1,9 +1,9 @@
-use super::openai::{openai_build_body, OPENAI_TOKENS_COUNT_FACTORS};
+use super::openai::{openai_build_body, openai_build_embeddings_body, OPENAI_TOKENS_COUNT_FACTORS};
 use super::{AzureOpenAIClient, ExtraConfig, Model, ModelConfig, PromptType, SendData};
 
 use crate::utils::PromptKind;
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use async_trait::async_trait;
 use reqwest::{Client as ReqwestClient, RequestBuilder};
 use serde::Deserialize;
@@ -53,6 +53,8 @@ impl AzureOpenAIClient {
                     .set_max_tokens(v.max_tokens)
                     .set_capabilities(v.capabilities)
                     .set_tokens_count_factors(OPENAI_TOKENS_COUNT_FACTORS)
+                    .set_prices(v.input_price, v.output_price)
+                    .set_tokenizer(v.tokenizer.clone())
             })
             .collect()
     }
@@ -63,7 +65,7 @@ impl AzureOpenAIClient {
         let api_base = self.get_api_base()?;
         let api_key = self.get_api_key()?;
 
-        let body = openai_build_body(data, self.model.name.clone());
+        let body = openai_build_body(data, &self.model);
 
         let url = format!(
             "{}/openai/deployments/{}/chat/completions?api-version=2023-05-15",
@@ -78,4 +80,26 @@ impl AzureOpenAIClient {
 
         Ok(builder)
     }
+
+    // the models of azure are the names of deployments, which the api does not list with the chat key
+    fn models_request_builder(&self, _client: &ReqwestClient) -> Result<RequestBuilder> {
+        bail!("the deployments of Azure OpenAI are set in `models`")
+    }
+
+    // this function constructs a request builder turning texts into vectors with the deployment of the model
+    fn embeddings_request_builder(
+        &self,
+        client: &ReqwestClient,
+        texts: &[String],
+    ) -> Result<RequestBuilder> {
+        let api_base = self.get_api_base()?;
+        let api_key = self.get_api_key()?;
+        let body = openai_build_embeddings_body(texts, &self.model.name);
+        let url = format!(
+            "{}/openai/deployments/{}/embeddings?api-version=2023-05-15",
+            &api_base, self.model.name
+        );
+        debug!("AzureOpenAI Embeddings Request: {url}");
+        Ok(client.post(url).header("api-key", api_key).json(&body))
+    }
 }
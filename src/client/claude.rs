@@ -0,0 +1,513 @@
+use super::{
+    check_status, message::*, patch_schema_message, status_error, ClaudeClient, Client,
+    ExtraConfig, Model, PromptType, SendData, SendOutput, TokenUsage, TokensCountFactors,
+};
+
+use crate::{render::ReplyHandler, utils::PromptKind};
+
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
+use futures_util::StreamExt;
+use reqwest::{Client as ReqwestClient, RequestBuilder};
+use reqwest_eventsource::{Error as EventSourceError, Event, RequestBuilderExt};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::env;
+
+// the base api url
+const API_BASE: &str = "https://api.anthropic.com/v1";
+
+// the api version sent with every request
+const API_VERSION: &str = "2023-06-01";
+
+// the messages api requires max_tokens, so we use this when nothing else is given
+const DEFAULT_MAX_TOKENS: usize = 4096;
+
+// array holding all the model names, token count, type of model, and the input/output prices in USD per 1M tokens
+const MODELS: [(&str, usize, &str, f64, f64); 5] = [
+    ("claude-3-opus-20240229", 200000, "text,vision", 15.0, 75.0),
+    ("claude-3-sonnet-20240229", 200000, "text,vision", 3.0, 15.0),
+    ("claude-3-haiku-20240307", 200000, "text,vision", 0.25, 1.25),
+    ("claude-2.1", 200000, "text", 8.0, 24.0),
+    ("claude-instant-1.2", 100000, "text", 0.8, 2.4),
+];
+
+const TOKENS_COUNT_FACTORS: TokensCountFactors = (5, 2);
+
+// struct representing the configuration for the claude client
+#[derive(Debug, Clone, Deserialize, Default)]
+pub struct ClaudeConfig {
+    pub name: Option<String>,
+    pub api_key: Option<String>,
+    pub extra: Option<ExtraConfig>,
+}
+
+// implementation of client trait for claude client
+#[async_trait]
+impl Client for ClaudeClient {
+    client_common_fns!();
+
+    // this function sends a message using the provided ReqwestClient and SendData
+    async fn send_message_inner(
+        &self,
+        client: &ReqwestClient,
+        data: SendData,
+    ) -> Result<SendOutput> {
+        let builder = self.request_builder(client, data)?;
+        send_message(builder).await
+    }
+
+    // this function does the same thing as above one but does in a streaming way
+    async fn send_message_streaming_inner(
+        &self,
+        client: &ReqwestClient,
+        handler: &mut ReplyHandler,
+        data: SendData,
+    ) -> Result<()> {
+        let builder = self.request_builder(client, data)?;
+        send_message_streaming(builder, handler).await
+    }
+}
+
+impl ClaudeClient {
+    // macro for generating function named get_api_key to retrieve the api key from the configs
+    config_get_fn!(api_key, get_api_key);
+
+    // constant defining an array of prompts used for configuration input
+    pub const PROMPTS: [PromptType<'static>; 1] =
+        [("api_key", "API Key:", true, PromptKind::String)];
+
+    // initializes models based on the variable MODELS and the configuration
+    pub fn list_models(local_config: &ClaudeConfig) -> Vec<Model> {
+        let client_name = Self::name(local_config);
+        MODELS
+            .into_iter()
+            .map(
+                |(name, max_tokens, capabilities, input_price, output_price)| {
+                    Model::new(client_name, name)
+                        .set_capabilities(capabilities.into())
+                        .set_max_tokens(Some(max_tokens))
+                        .set_tokens_count_factors(TOKENS_COUNT_FACTORS)
+                        .set_prices(Some(input_price), Some(output_price))
+                },
+            )
+            .collect()
+    }
+
+    // this function constructs a request builder for sending requests to the messages api
+    fn request_builder(&self, client: &ReqwestClient, data: SendData) -> Result<RequestBuilder> {
+        let api_key = self.get_api_key()?;
+
+        let body = build_body(data, self.model.name.clone())?;
+
+        let env_prefix = Self::name(&self.config).to_uppercase();
+        // the base url can be overridden by an environment variable, e.g. to point at a mock server
+        let api_base = env::var(format!("{env_prefix}_API_BASE"))
+            .ok()
+            .unwrap_or_else(|| API_BASE.to_string());
+
+        let url = format!("{api_base}/messages");
+
+        debug!("Claude Request: {url} {body}");
+
+        let builder = client
+            .post(url)
+            .header("x-api-key", api_key)
+            .header("anthropic-version", API_VERSION)
+            .json(&body);
+
+        Ok(builder)
+    }
+}
+
+// this function sends the request and extracts the text and the usage from the response
+async fn send_message(builder: RequestBuilder) -> Result<SendOutput> {
+    let res = check_status(builder.send().await?, check_error).await?;
+    let data: Value = res.json().await?;
+    // the response content is a list of blocks, we join all the text blocks
+    let output = data["content"]
+        .as_array()
+        .map(|list| {
+            list.iter()
+                .filter_map(|v| v["text"].as_str())
+                .collect::<Vec<&str>>()
+                .join("")
+        })
+        .ok_or_else(|| anyhow!("Invalid response data: {data}"))?;
+    Ok(SendOutput {
+        text: output,
+        tool_calls: vec![],
+        usage: TokenUsage::from_json(&data["usage"], "input_tokens", "output_tokens"),
+        finish_reason: data["stop_reason"].as_str().map(|v| v.to_string()),
+    })
+}
+
+// this function processes the server-sent events of a streaming response
+async fn send_message_streaming(builder: RequestBuilder, handler: &mut ReplyHandler) -> Result<()> {
+    let mut es = builder.eventsource()?;
+    // the input tokens are given when the message starts, the output tokens when it ends
+    let mut usage = TokenUsage::default();
+    while let Some(event) = es.next().await {
+        match event {
+            Ok(Event::Open) => {}
+            Ok(Event::Message(message)) => {
+                let data: Value = serde_json::from_str(&message.data)?;
+                match data["type"].as_str() {
+                    Some("message_start") => {
+                        if let Some(v) = data["message"]["usage"]["input_tokens"].as_u64() {
+                            usage.input_tokens = v as usize;
+                        }
+                    }
+                    Some("message_delta") => {
+                        if let Some(reason) = data["delta"]["stop_reason"].as_str() {
+                            handler.finish_reason(reason)?;
+                        }
+                        if let Some(v) = data["usage"]["output_tokens"].as_u64() {
+                            usage.output_tokens = v as usize;
+                            handler.usage(usage)?;
+                        }
+                    }
+                    Some("content_block_delta") => {
+                        if let Some(text) = data["delta"]["text"].as_str() {
+                            handler.text(text)?;
+                        }
+                    }
+                    Some("message_stop") => {
+                        break;
+                    }
+                    // errors can also be emitted in the middle of the stream, e.g. when overloaded
+                    Some("error") => {
+                        check_error(&data)?;
+                    }
+                    _ => {}
+                }
+            }
+            Err(err) => {
+                match err {
+                    EventSourceError::InvalidStatusCode(_, res) => {
+                        return Err(status_error(res, check_error).await);
+                    }
+                    EventSourceError::StreamEnded => {}
+                    _ => {
+                        return Err(err.into());
+                    }
+                }
+                es.close();
+            }
+        }
+    }
+    Ok(())
+}
+
+// this function extracts the error type and message from an error response
+fn check_error(data: &Value) -> Result<()> {
+    if let Some(message) = data["error"]["message"].as_str() {
+        match data["error"]["type"].as_str() {
+            Some(typ) => bail!("{typ}: {message}"),
+            None => bail!("{message}"),
+        }
+    } else {
+        bail!("Error {}", data);
+    }
+}
+
+// this function constructs the request body to be sent to the messages api
+fn build_body(data: SendData, model: String) -> Result<Value> {
+    let SendData {
+        mut messages,
+        temperature,
+        params,
+        stream,
+        schema,
+        ..
+    } = data;
+
+    // the messages api has no json mode, the schema is asked for in the prompt
+    if let Some(schema) = &schema {
+        patch_schema_message(&mut messages, schema);
+    }
+
+    // the messages api takes the system prompt as a top-level field, as text
+    let system = match messages.first() {
+        Some(message) if message.role.is_system() => match messages.remove(0).content {
+            MessageContent::Text(text) => Some(text),
+            MessageContent::Array(list) => {
+                let texts: Vec<String> = list
+                    .into_iter()
+                    .filter_map(|item| match item {
+                        MessageContentPart::Text { text } => Some(text),
+                        _ => None,
+                    })
+                    .collect();
+                Some(texts.join("\n\n")).filter(|v| !v.is_empty())
+            }
+        },
+        _ => None,
+    };
+
+    let mut network_image_urls = vec![];
+    let messages: Vec<Value> = messages
+        .into_iter()
+        .map(|message| {
+            let role = match message.role {
+                MessageRole::Assistant => "assistant",
+                _ => "user",
+            };
+            match message.content {
+                MessageContent::Text(text) => json!({
+                    "role": role,
+                    "content": text,
+                }),
+                MessageContent::Array(list) => {
+                    let content: Vec<Value> = list
+                        .into_iter()
+                        .map(|item| match item {
+                            MessageContentPart::Text { text } => {
+                                json!({ "type": "text", "text": text })
+                            }
+                            // only inline base64 images are accepted by the api
+                            MessageContentPart::ImageUrl {
+                                image_url: ImageUrl { url },
+                            } => {
+                                if let Some((mime_type, data)) = url
+                                    .strip_prefix("data:")
+                                    .and_then(|v| v.split_once(";base64,"))
+                                {
+                                    json!({
+                                        "type": "image",
+                                        "source": {
+                                            "type": "base64",
+                                            "media_type": mime_type,
+                                            "data": data,
+                                        }
+                                    })
+                                } else {
+                                    network_image_urls.push(url.clone());
+                                    json!({ "url": url })
+                                }
+                            }
+                            // tools are not declared to this client, so past calls are kept as text
+                            MessageContentPart::ToolCall { tool_call } => json!({
+                                "type": "text",
+                                "text": format!("Call tool '{}' with {}", tool_call.name, tool_call.arguments),
+                            }),
+                            MessageContentPart::ToolResult { tool_result } => json!({
+                                "type": "text",
+                                "text": tool_result.content,
+                            }),
+                        })
+                        .collect();
+                    json!({ "role": role, "content": content })
+                }
+            }
+        })
+        .fold(vec![], merge_message);
+
+    if !network_image_urls.is_empty() {
+        bail!(
+            "The model does not support network images: {:?}",
+            network_image_urls
+        );
+    }
+
+    let mut body = json!({
+        "model": model,
+        "messages": messages,
+        "max_tokens": DEFAULT_MAX_TOKENS,
+    });
+
+    if let Some(system) = system {
+        body["system"] = system.into();
+    }
+    if let Some(temperature) = temperature {
+        body["temperature"] = temperature.into();
+    }
+    // the max_tokens set replaces the default one, seeds and penalties are not supported
+    params.insert_into(
+        &mut body,
+        ["top_p", "max_tokens", "stop_sequences", "", "", ""],
+    );
+    if stream {
+        body["stream"] = true.into();
+    }
+
+    Ok(body)
+}
+
+// this function appends the message to the ones before, the api rejects consecutive turns of the same role,
+// like the results of tools after a question, so they are merged into one turn
+fn merge_message(mut messages: Vec<Value>, message: Value) -> Vec<Value> {
+    match messages.last_mut() {
+        Some(last) if last["role"] == message["role"] => {
+            let mut content = content_blocks(last["content"].take());
+            content.extend(content_blocks(message["content"].clone()));
+            last["content"] = content.into();
+        }
+        _ => messages.push(message),
+    }
+    messages
+}
+
+// this function returns the content of a message as a list of blocks
+fn content_blocks(content: Value) -> Vec<Value> {
+    match content {
+        Value::Array(list) => list,
+        Value::String(text) => vec![json!({ "type": "text", "text": text })],
+        _ => vec![],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{mock_server, GenerationParams};
+    use crate::utils::create_abort_signal;
+
+    use crossbeam::channel::unbounded;
+
+    #[test]
+    fn test_build_body() {
+        let data = SendData {
+            messages: vec![
+                Message {
+                    role: MessageRole::System,
+                    content: MessageContent::Text("be brief".into()),
+                },
+                Message {
+                    role: MessageRole::User,
+                    content: MessageContent::Array(vec![
+                        MessageContentPart::Text {
+                            text: "what is this?".into(),
+                        },
+                        MessageContentPart::ImageUrl {
+                            image_url: ImageUrl {
+                                url: "data:image/png;base64,AAAA".into(),
+                            },
+                        },
+                    ]),
+                },
+            ],
+            temperature: None,
+            params: GenerationParams {
+                max_output_tokens: Some(1024),
+                stop: vec!["END".into()],
+                seed: Some(1),
+                ..Default::default()
+            },
+            stream: true,
+            tools: vec![],
+            schema: None,
+        };
+        let body = build_body(data, "claude-3-haiku-20240307".into()).unwrap();
+        assert_eq!(body["max_tokens"], 1024);
+        assert_eq!(body["stop_sequences"], json!(["END"]));
+        assert_eq!(body.get("seed"), None);
+        assert_eq!(body["system"], "be brief");
+        assert_eq!(body["messages"].as_array().unwrap().len(), 1);
+        assert_eq!(body["messages"][0]["content"][1]["type"], "image");
+        assert_eq!(
+            body["messages"][0]["content"][1]["source"]["media_type"],
+            "image/png"
+        );
+        assert_eq!(body["stream"], true);
+    }
+
+    #[test]
+    fn test_build_body_merge_messages() {
+        let text = |role, text: &str| Message {
+            role,
+            content: MessageContent::Text(text.into()),
+        };
+        let data = SendData {
+            messages: vec![
+                Message {
+                    role: MessageRole::System,
+                    content: MessageContent::Array(vec![
+                        MessageContentPart::Text {
+                            text: "be brief".into(),
+                        },
+                        MessageContentPart::Text {
+                            text: "answer in french".into(),
+                        },
+                    ]),
+                },
+                text(MessageRole::User, "what time is it?"),
+                text(MessageRole::Assistant, "let me check"),
+                Message {
+                    role: MessageRole::Tool,
+                    content: MessageContent::Array(vec![MessageContentPart::ToolResult {
+                        tool_result: ToolResult {
+                            id: "call_1".into(),
+                            name: "time".into(),
+                            content: "10:00".into(),
+                        },
+                    }]),
+                },
+                text(MessageRole::User, "and the date?"),
+            ],
+            temperature: None,
+            params: Default::default(),
+            stream: false,
+            tools: vec![],
+            schema: None,
+        };
+        let body = build_body(data, "claude-3-haiku-20240307".into()).unwrap();
+        assert_eq!(body["system"], "be brief\n\nanswer in french");
+        let messages = body["messages"].as_array().unwrap();
+        assert_eq!(messages.len(), 3);
+        assert_eq!(messages[2]["role"], "user");
+        assert_eq!(
+            messages[2]["content"],
+            json!([
+                { "type": "text", "text": "10:00" },
+                { "type": "text", "text": "and the date?" },
+            ])
+        );
+    }
+
+    #[test]
+    fn test_send_message_streaming() {
+        let events = [
+            r#"{"type":"message_start","message":{"id":"msg_1","usage":{"input_tokens":12,"output_tokens":1}}}"#,
+            r#"{"type":"content_block_delta","index":0,"delta":{"type":"text_delta","text":"Hello"}}"#,
+            r#"{"type":"content_block_delta","index":0,"delta":{"type":"text_delta","text":" world"}}"#,
+            r#"{"type":"message_delta","delta":{"stop_reason":"end_turn"},"usage":{"output_tokens":2}}"#,
+            r#"{"type":"message_stop"}"#,
+        ];
+        let body: String = events.iter().map(|v| format!("data: {v}\n\n")).collect();
+        let url = mock_server(&[("200 OK", "text/event-stream", &body)]);
+        let (tx, _rx) = unbounded();
+        let mut handler = ReplyHandler::new(tx, create_abort_signal());
+        crate::utils::init_tokio_runtime()
+            .unwrap()
+            .block_on(async {
+                let builder = ReqwestClient::new().post(url);
+                send_message_streaming(builder, &mut handler).await
+            })
+            .unwrap();
+        assert_eq!(handler.get_buffer(), "Hello world");
+        assert_eq!(
+            handler.get_usage(),
+            Some(TokenUsage {
+                input_tokens: 12,
+                output_tokens: 2
+            })
+        );
+    }
+
+    #[test]
+    fn test_send_message_streaming_error() {
+        let body = r#"{"type":"error","error":{"type":"authentication_error","message":"invalid x-api-key"}}"#;
+        let url = mock_server(&[("401 Unauthorized", "application/json", body)]);
+        let (tx, _rx) = unbounded();
+        let mut handler = ReplyHandler::new(tx, create_abort_signal());
+        let err = crate::utils::init_tokio_runtime()
+            .unwrap()
+            .block_on(async {
+                let builder = ReqwestClient::new().post(url);
+                send_message_streaming(builder, &mut handler).await
+            })
+            .unwrap_err();
+        assert_eq!(err.to_string(), "authentication_error: invalid x-api-key");
+    }
+}
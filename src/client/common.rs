@@ -1,9 +1,12 @@
 // This file contains all the common utility functions to be used inside other files
 // for managing client configurations, sending messages, and handling configurations
-use super::{openai::OpenAIConfig, ClientConfig, Message, MessageContent, Model};
+use super::{
+    openai::OpenAIConfig, ClientConfig, DiscoveredModel, GenerationParams, Message, MessageContent,
+    Model, ToolCall,
+};
 
 use crate::{
-    config::{GlobalConfig, Input},
+    config::{compress_session, GlobalConfig, Input, Tool},
     render::ReplyHandler,
     utils::{
         init_tokio_runtime, prompt_input_integer, prompt_input_string, tokenize, AbortSignal,
@@ -11,12 +14,14 @@ use crate::{
     },
 };
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use async_trait::async_trait;
-use reqwest::{Client as ReqwestClient, ClientBuilder, Proxy, RequestBuilder};
-use serde::Deserialize;
+use futures_util::FutureExt;
+use reqwest::{Client as ReqwestClient, ClientBuilder, Proxy, RequestBuilder, Response};
+use reqwest_eventsource::Error as EventSourceError;
+use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
-use std::{env, future::Future, time::Duration};
+use std::{env, fmt, future::Future, time::Duration};
 use tokio::time::sleep;
 
 // a macro for registering client configurations
@@ -62,7 +67,7 @@ macro_rules! register_client {
                 // constructor for client, based on the global configurations
                 pub fn init(global_config: &$crate::config::GlobalConfig) -> Option<Box<dyn Client>> {
                     let model = global_config.read().model.clone();
-                    // iterates over client configs in the global configs 
+                    // iterates over client configs in the global configs
                     // and tries to find a matching client based on the model's name
                     let config = global_config.read().clients.iter().find_map(|client_config| {
                         if let ClientConfig::$config(c) = client_config {
@@ -72,21 +77,21 @@ macro_rules! register_client {
                         }
                         None
                     })?;
-                    
+
                     Some(Box::new(Self {
                         global_config: global_config.clone(),
                         config,
                         model,
                     }))
                 }
-                
+
                 pub fn name(config: &$config) -> &str {
                     config.name.as_deref().unwrap_or(Self::NAME)
                 }
             }
-            
+
         )+
-        
+
         // initializes a client instance based on the global configurations
         pub fn init_client(config: &$crate::config::GlobalConfig) -> anyhow::Result<Box<dyn Client>> {
             None
@@ -116,7 +121,7 @@ macro_rules! register_client {
         pub fn list_client_types() -> Vec<&'static str> {
             vec![$($client::NAME,)+]
         }
-        
+
         // utility functions for creating client configurations
         pub fn create_client_config(client: &str) -> anyhow::Result<serde_json::Value> {
             $(
@@ -126,19 +131,36 @@ macro_rules! register_client {
             )+
             anyhow::bail!("Unknown client {}", client)
         }
-        
-        // utility functions for listing available models
+
+        // utility functions for listing available models, followed by the ones discovered by `--refresh-models`
         pub fn list_models(config: &$crate::config::Config) -> Vec<$crate::client::Model> {
+            let cache = $crate::client::ModelsCache::get();
             config
                 .clients
                 .iter()
                 .flat_map(|v| match v {
-                    $(ClientConfig::$config(c) => $client::list_models(c),)+
+                    $(ClientConfig::$config(c) => cache.merge_into($client::name(c), $client::list_models(c)),)+
                     ClientConfig::Unknown => vec![],
                 })
                 .collect()
         }
 
+        // this function creates a client for every client config, whatever the current model
+        pub fn init_all_clients(config: &$crate::config::GlobalConfig) -> Vec<Box<dyn Client>> {
+            let clients = config.read().clients.clone();
+            clients
+                .into_iter()
+                .filter_map(|v| match v {
+                    $(ClientConfig::$config(c) => Some(Box::new($client {
+                        global_config: config.clone(),
+                        model: $crate::client::Model::new($client::name(&c), ""),
+                        config: c,
+                    }) as Box<dyn Client>),)+
+                    ClientConfig::Unknown => None,
+                })
+                .collect()
+        }
+
     };
 }
 
@@ -190,13 +212,13 @@ macro_rules! openai_compatible_client {
                 &self,
                 client: &reqwest::Client,
                 data: $crate::client::SendData,
-            ) -> anyhow::Result<String> {
+            ) -> anyhow::Result<$crate::client::SendOutput> {
                 // making a request builder
                 let builder = self.request_builder(client, data)?;
                 // calling 'openai_send_message' from the openai module, using the request builder, and await
                 $crate::client::openai::openai_send_message(builder).await
             }
-            
+
             // this is an asynchronous method is responsible for sending a message in a streaming fashion
             async fn send_message_streaming_inner(
                 &self,
@@ -209,6 +231,25 @@ macro_rules! openai_compatible_client {
                 // calling 'openai_send_message_streaming' from the openai module, using the request builder, and await
                 $crate::client::openai::openai_send_message_streaming(builder, handler).await
             }
+
+            // this is an asynchronous method listing the models of the `/models` endpoint
+            async fn fetch_models(
+                &self,
+                client: &reqwest::Client,
+            ) -> anyhow::Result<Vec<$crate::client::DiscoveredModel>> {
+                let builder = self.models_request_builder(client)?;
+                $crate::client::openai::openai_fetch_models(builder).await
+            }
+
+            // this is an asynchronous method turning texts into vectors with the `/embeddings` endpoint
+            async fn embeddings(
+                &self,
+                client: &reqwest::Client,
+                texts: &[String],
+            ) -> anyhow::Result<Vec<Vec<f32>>> {
+                let builder = self.embeddings_request_builder(client, texts)?;
+                $crate::client::openai::openai_embeddings(builder).await
+            }
         }
     };
 }
@@ -236,7 +277,7 @@ macro_rules! config_get_fn {
 
 // trait for defining common client functionality
 #[async_trait]
-pub trait Client {
+pub trait Client: Sync + Send {
     // We just declare these and use the above macro to define them later
     fn config(&self) -> (&GlobalConfig, &Option<ExtraConfig>);
 
@@ -246,6 +287,20 @@ pub trait Client {
 
     fn set_model(&mut self, model: Model);
 
+    // this function lists the models served by the api, for the clients whose api can list them
+    async fn fetch_models(&self, _client: &ReqwestClient) -> Result<Vec<DiscoveredModel>> {
+        bail!("listing the models is not supported")
+    }
+
+    // this function turns texts into vectors with the current model, for the clients whose api has embeddings
+    async fn embeddings(
+        &self,
+        _client: &ReqwestClient,
+        _texts: &[String],
+    ) -> Result<Vec<Vec<f32>>> {
+        bail!("embeddings are not supported")
+    }
+
     // This function builds and returns a Reqwest client, based on the client's configuration
     fn build_client(&self) -> Result<ReqwestClient> {
         let mut builder = ReqwestClient::builder();
@@ -264,23 +319,65 @@ pub trait Client {
         Ok(client)
     }
 
-    // this function sends a message asynchronously and returns the response as a string
-    fn send_message(&self, input: Input) -> Result<String> {
+    // this function returns the retry policy of the client, the default one if it is not configured
+    fn retry_config(&self) -> RetryConfig {
+        self.config()
+            .1
+            .as_ref()
+            .and_then(|v| v.retry.clone())
+            .unwrap_or_default()
+    }
+
+    // this function sends a message asynchronously and returns the response text and tool calls
+    fn send_message(&self, input: Input) -> Result<SendOutput> {
         // We use tokio, initialized lazily for using async/await
         init_tokio_runtime()?.block_on(async {
             let global_config = self.config().0;
             if global_config.read().dry_run {
                 let content = global_config.read().echo_messages(&input);
-                return Ok(content);
+                return Ok(content.into());
             }
             let client = self.build_client()?;
             let data = global_config.read().prepare_send_data(&input, false)?;
-            self.send_message_inner(&client, data)
+            self.send_message_with_retry(&client, data)
                 .await
                 .with_context(|| "Failed to get answer")
         })
     }
 
+    // this function sends the data, and sends it again on transient errors following the retry policy
+    async fn send_message_with_retry(
+        &self,
+        client: &ReqwestClient,
+        data: SendData,
+    ) -> Result<SendOutput> {
+        let retry = self.retry_config();
+        let mut attempt = 1;
+        loop {
+            let err = match self.send_message_inner(client, data.clone()).await {
+                Ok(output) => return Ok(output),
+                Err(err) => err,
+            };
+            match retry.delay(attempt, &err) {
+                Some(delay) => {
+                    attempt += 1;
+                    debug!(
+                        "Retry ({attempt}/{}) in {delay:?}: {err}",
+                        retry.max_attempts
+                    );
+                    // without a stream there is no spinner to show the notice, it goes to stderr
+                    eprintln!(
+                        "Retrying ({attempt}/{}) in {:.1}s: {err}",
+                        retry.max_attempts,
+                        delay.as_secs_f64()
+                    );
+                    sleep(delay).await;
+                }
+                None => return Err(err),
+            }
+        }
+    }
+
     // function for sending a message in stream mode
     fn send_message_streaming(&self, input: &Input, handler: &mut ReplyHandler) -> Result<()> {
         // listening for an abort signal, stops sending messages if it is received.
@@ -309,7 +406,7 @@ pub trait Client {
                     }
                     let client = self.build_client()?;
                     let data = global_config.read().prepare_send_data(&input, true)?;
-                    self.send_message_streaming_inner(&client, handler, data).await
+                    self.send_message_streaming_with_retry(&client, handler, data).await
                 } => {
                     handler.done()?;
                     ret.with_context(|| "Failed to get answer")
@@ -322,9 +419,47 @@ pub trait Client {
         })
     }
 
+    // this function sends the data in stream mode, and sends it again on transient errors following the retry
+    // policy, as long as nothing has been emitted since sending again would repeat it
+    async fn send_message_streaming_with_retry(
+        &self,
+        client: &ReqwestClient,
+        handler: &mut ReplyHandler,
+        data: SendData,
+    ) -> Result<()> {
+        let retry = self.retry_config();
+        let mut attempt = 1;
+        loop {
+            let err = match self
+                .send_message_streaming_inner(client, handler, data.clone())
+                .await
+            {
+                Ok(()) => return Ok(()),
+                Err(err) if handler.emitted() => return Err(err),
+                Err(err) => err,
+            };
+            match retry.delay(attempt, &err) {
+                Some(delay) => {
+                    attempt += 1;
+                    debug!(
+                        "Retry ({attempt}/{}) in {delay:?}: {err}",
+                        retry.max_attempts
+                    );
+                    handler.retry(attempt, retry.max_attempts)?;
+                    sleep(delay).await;
+                }
+                None => return Err(err),
+            }
+        }
+    }
+
     // functions responsible for sending messages using the Reqwest
     // takes in a data payload, and a reply handler as input and returns a result
-    async fn send_message_inner(&self, client: &ReqwestClient, data: SendData) -> Result<String>;
+    async fn send_message_inner(
+        &self,
+        client: &ReqwestClient,
+        data: SendData,
+    ) -> Result<SendOutput>;
 
     // functions responsible for sending messages using the Reqwest
     // takes in a data payload, and a reply handler as input and returns a result
@@ -346,19 +481,258 @@ impl Default for ClientConfig {
 // struct for storing extra configuration options, all the elements in this struct are optional
 #[derive(Debug, Clone, Deserialize, Default)]
 pub struct ExtraConfig {
-    pub proxy: Option<String>, // holds the proxy configuration
+    pub proxy: Option<String>,        // holds the proxy configuration
     pub connect_timeout: Option<u64>, // tells the connection timeout duration
+    pub retry: Option<RetryConfig>,   // how transient errors are retried
 }
 
-// struct represents the data to be sent over the client
+// struct for storing the retry policy of a client
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RetryConfig {
+    pub max_attempts: usize, // the total number of attempts, 1 disables retrying
+    pub base_delay: u64, // the delay in milliseconds before the first retry, doubled for each next one
+    pub max_delay: u64, // the longest delay in milliseconds, for the backoff and the `Retry-After` header
+    pub retry_after: bool, // whether to wait as long as the `Retry-After` header asks
+    pub status_codes: Vec<u16>, // the response statuses that are retried
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            base_delay: 1000,
+            max_delay: 60_000,
+            retry_after: true,
+            status_codes: vec![408, 429, 500, 502, 503, 504],
+        }
+    }
+}
+
+impl RetryConfig {
+    // this function returns how long to wait before the next attempt, or None if the error should not be retried,
+    // only the transport errors and the retryable statuses are
+    pub fn delay(&self, attempt: usize, err: &anyhow::Error) -> Option<Duration> {
+        if attempt >= self.max_attempts {
+            return None;
+        }
+        let max_delay = Duration::from_millis(self.max_delay);
+        let backoff =
+            Duration::from_millis(self.base_delay.saturating_mul(1 << (attempt - 1).min(16)))
+                .min(max_delay);
+        for cause in err.chain() {
+            if let Some(status_error) = cause.downcast_ref::<StatusError>() {
+                if !self.status_codes.contains(&status_error.status) {
+                    return None;
+                }
+                return match status_error.retry_after {
+                    Some(retry_after) if self.retry_after => Some(retry_after.min(max_delay)),
+                    _ => Some(backoff),
+                };
+            }
+            if let Some(err) = cause.downcast_ref::<reqwest::Error>() {
+                if is_transient(err) {
+                    return Some(backoff);
+                }
+            }
+            if let Some(EventSourceError::Transport(err)) = cause.downcast_ref::<EventSourceError>()
+            {
+                if is_transient(err) {
+                    return Some(backoff);
+                }
+            }
+        }
+        None
+    }
+}
+
+// this function checks whether a request failed because of the connection rather than the server's answer
+fn is_transient(err: &reqwest::Error) -> bool {
+    err.is_connect() || err.is_timeout() || err.is_request() || err.is_body()
+}
+
+// this function returns a stable code for the kind of an error, with the http status of an unsuccessful response
+pub fn error_code(err: &anyhow::Error) -> (&'static str, Option<u16>) {
+    let transport_code = |err: &reqwest::Error| {
+        if err.is_timeout() {
+            "timeout"
+        } else {
+            "connection_error"
+        }
+    };
+    for cause in err.chain() {
+        if let Some(status_error) = cause.downcast_ref::<StatusError>() {
+            let code = match status_error.status {
+                401 | 403 => "auth_error",
+                429 => "rate_limit",
+                408 | 504 => "timeout",
+                400 | 404 | 413 | 422 => "invalid_request",
+                500..=599 => "server_error",
+                _ => "http_error",
+            };
+            return (code, Some(status_error.status));
+        }
+        if let Some(err) = cause.downcast_ref::<reqwest::Error>() {
+            return (transport_code(err), None);
+        }
+        if let Some(EventSourceError::Transport(err)) = cause.downcast_ref::<EventSourceError>() {
+            return (transport_code(err), None);
+        }
+    }
+    ("error", None)
+}
+
+// error raised when the answer fails after a part of it has been printed, it is not sent again
 #[derive(Debug)]
+pub struct PartialOutputError;
+
+impl fmt::Display for PartialOutputError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "The answer was interrupted")
+    }
+}
+
+impl std::error::Error for PartialOutputError {}
+
+// error holding the status of an unsuccessful response, kept as the source of the error message
+#[derive(Debug)]
+pub struct StatusError {
+    pub status: u16,
+    pub retry_after: Option<Duration>,
+}
+
+impl StatusError {
+    // constructor that reads the status and the `Retry-After` header of a response
+    pub fn new(res: &Response) -> Self {
+        let retry_after = res
+            .headers()
+            .get("retry-after")
+            .and_then(|v| v.to_str().ok())
+            .and_then(parse_retry_after);
+        Self {
+            status: res.status().as_u16(),
+            retry_after,
+        }
+    }
+
+    // this function keeps the message of the error while recording the status as its source
+    pub fn wrap(self, err: anyhow::Error) -> anyhow::Error {
+        anyhow::Error::new(self).context(err.to_string())
+    }
+}
+
+// this function reads a `Retry-After` header, given in seconds or as an http date
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    let millis = (date.with_timezone(&chrono::Utc) - chrono::Utc::now()).num_milliseconds();
+    Some(Duration::from_millis(millis.max(0) as u64))
+}
+
+impl fmt::Display for StatusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "HTTP status {}", self.status)
+    }
+}
+
+impl std::error::Error for StatusError {}
+
+// this function returns the response if it is successful, otherwise the error found in its body
+pub async fn check_status(
+    res: Response,
+    check_error: fn(&Value) -> Result<()>,
+) -> Result<Response> {
+    if res.status().is_success() {
+        return Ok(res);
+    }
+    Err(status_error(res, check_error).await)
+}
+
+// this function builds the error of an unsuccessful response, using check_error to read its body
+// when the body holds no known error, the status and the raw body are used instead
+pub async fn status_error(res: Response, check_error: fn(&Value) -> Result<()>) -> anyhow::Error {
+    let status_error = StatusError::new(&res);
+    let status = res.status();
+    let text = res.text().await.unwrap_or_default();
+    let err = serde_json::from_str::<Value>(&text)
+        .ok()
+        .and_then(|data| check_error(&data).err())
+        .unwrap_or_else(|| anyhow!("{status}, {text}"));
+    status_error.wrap(err)
+}
+
+// struct represents the data to be sent over the client
+#[derive(Debug, Clone)]
 pub struct SendData {
     pub messages: Vec<Message>, // vector of messages, which holds the content of the messages
     pub temperature: Option<f64>, // this determines the creativity and randomness of generated responses
-    pub stream: bool, // indicates whether the message should be sent as streaming
+    pub params: GenerationParams, // the other generation parameters, top_p, stop sequences, seed...
+    pub stream: bool,             // indicates whether the message should be sent as streaming
+    pub tools: Vec<Tool>,         // tools the model is allowed to call
+    pub schema: Option<Value>,    // the json schema the answer must match
+}
+
+// struct represents the data received from the client
+#[derive(Debug, Clone, Default)]
+pub struct SendOutput {
+    pub text: String,                  // the text of the answer
+    pub tool_calls: Vec<ToolCall>,     // the tools the model wants to call
+    pub usage: Option<TokenUsage>,     // the tokens used, when the api reports them
+    pub finish_reason: Option<String>, // why the model stopped, like `stop` or `length`, when the api tells
+}
+
+// a plain text answer without tool calls
+impl From<String> for SendOutput {
+    fn from(text: String) -> Self {
+        Self {
+            text,
+            tool_calls: vec![],
+            usage: None,
+            finish_reason: None,
+        }
+    }
+}
+
+// struct represents the number of tokens used by a request
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct TokenUsage {
+    pub input_tokens: usize,  // the tokens of the prompt
+    pub output_tokens: usize, // the tokens of the answer
+}
+
+impl TokenUsage {
+    // this function reads the usage from two fields of a json object, or returns None when both are missing
+    pub fn from_json(data: &Value, input_key: &str, output_key: &str) -> Option<Self> {
+        let input_tokens = data[input_key].as_u64();
+        let output_tokens = data[output_key].as_u64();
+        if input_tokens.is_none() && output_tokens.is_none() {
+            return None;
+        }
+        Some(Self {
+            input_tokens: input_tokens.unwrap_or_default() as usize,
+            output_tokens: output_tokens.unwrap_or_default() as usize,
+        })
+    }
+
+    // this function adds up the usage of the requests of an answer, or returns None when one of them is unknown
+    pub fn sum(usages: &[Option<Self>]) -> Option<Self> {
+        if usages.is_empty() {
+            return None;
+        }
+        usages.iter().try_fold(Self::default(), |total, usage| {
+            let usage = (*usage)?;
+            Some(Self {
+                input_tokens: total.input_tokens + usage.input_tokens,
+                output_tokens: total.output_tokens + usage.output_tokens,
+            })
+        })
+    }
 }
 
-// Represents a tuple containing prompt related info 
+// Represents a tuple containing prompt related info
 // like prompt name, description, requirement status, and prompt kind
 pub type PromptType<'a> = (&'a str, &'a str, bool, PromptKind);
 
@@ -384,6 +758,86 @@ pub fn create_config(list: &[PromptType], client: &str) -> Result<Value> {
     Ok(clients)
 }
 
+// function to send the input with the current model, moving on to the next model of the fallback
+// chain as long as the sending fails and nothing of the answer has been printed.
+// Every request starts from the first model, the chain of the config and the session is left as it is
+pub fn send_with_fallback<F>(
+    config: &GlobalConfig,
+    input: &Input,
+    mut send: F,
+) -> Result<SendOutput>
+where
+    F: FnMut(&Input, &dyn Client) -> Result<SendOutput>,
+{
+    let ret = send_with_fallback_async(config, input, |client| {
+        let ret = send(input, client.as_ref()).and_then(|output| {
+            let messages = config.read().build_messages(input)?;
+            config.write().record_usage(&messages, &output)?;
+            Ok(output)
+        });
+        std::future::ready(ret)
+    });
+    // the sending is done when `send` is called, so the future is ready the first time it is polled
+    ret.now_or_never()
+        .unwrap_or_else(|| Err(anyhow!("The sending did not complete")))
+}
+
+// the core of `send_with_fallback`, shared with the batch which sends its lines concurrently:
+// `send` gets the client of each model of the chain in turn, and records the usage of its answer
+pub async fn send_with_fallback_async<F, Fut>(
+    config: &GlobalConfig,
+    input: &Input,
+    mut send: F,
+) -> Result<SendOutput>
+where
+    F: FnMut(Box<dyn Client>) -> Fut,
+    Fut: Future<Output = Result<SendOutput>>,
+{
+    let models = config.read().model_candidates();
+    let primary = models[0].clone();
+    for (i, model) in models.iter().enumerate() {
+        // the model answering is the current one for the time of the request, so the usage is its own
+        config.write().model = model.clone();
+        let client = super::init_client(config).and_then(|mut client| {
+            super::ensure_model_capabilities(client.as_mut(), input.required_capabilities())?;
+            compress_session(config, client.as_ref(), input)?;
+            Ok(client)
+        });
+        let ret = match client {
+            Ok(client) => send(client).await,
+            Err(err) => Err(err),
+        };
+        {
+            let mut config = config.write();
+            config.model = primary.clone();
+            config.answer_model = (i > 0).then(|| model.clone());
+        }
+        let err = match ret {
+            Ok(output) => return Ok(output),
+            Err(err) => err,
+        };
+        // another model would print a second answer after the part already printed
+        let partial = err.chain().any(|v| v.is::<PartialOutputError>());
+        match models.get(i + 1) {
+            Some(next) if !partial => {
+                eprintln!("Model '{}' failed: {err:#}", model.id());
+                eprintln!("Falling back to '{}'", next.id());
+            }
+            _ => return Err(err),
+        }
+    }
+    unreachable!("the chain has at least the current model")
+}
+
+// this function records the usage of an answer away from the async workers, as it writes to the usage file
+pub async fn record_usage_async(
+    config: GlobalConfig,
+    messages: Vec<Message>,
+    output: SendOutput,
+) -> Result<()> {
+    tokio::task::spawn_blocking(move || config.write().record_usage(&messages, &output)).await?
+}
+
 // function to send message as stream
 #[allow(unused)]
 pub async fn send_message_as_streaming<F, Fut>(
@@ -416,6 +870,19 @@ pub fn patch_system_message(messages: &mut Vec<Message>) {
     }
 }
 
+// this function asks for a json matching the schema at the end of the last user message,
+// for the apis that cannot constrain the answer to a schema
+pub fn patch_schema_message(messages: &mut [Message], schema: &Value) {
+    let instruction = format!(
+        "Answer with only a JSON value matching this JSON schema, without any explanation or code fence:\n{schema}"
+    );
+    if let Some(message) = messages.iter_mut().rev().find(|v| v.role.is_user()) {
+        message
+            .content
+            .merge_prompt(|v: &str| format!("{v}\n\n{instruction}"));
+    }
+}
+
 // function to set configuration value
 fn set_config_value(json: &mut Value, path: &str, kind: &PromptKind, value: &str) {
     let segs: Vec<&str> = path.split('.').collect();
@@ -475,3 +942,112 @@ fn set_proxy(builder: ClientBuilder, proxy: &Option<String>) -> Result<ClientBui
         builder.proxy(Proxy::all(&proxy).with_context(|| format!("Invalid proxy `{proxy}`"))?);
     Ok(builder)
 }
+
+// this function serves canned (status, content type, body) http responses from a local port,
+// one per request in order
+#[cfg(test)]
+pub fn mock_server(responses: &[(&str, &str, &str)]) -> String {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let responses: Vec<String> = responses
+        .iter()
+        .map(|(status, content_type, body)| {
+            format!(
+                "HTTP/1.1 {status}\r\ncontent-type: {content_type}\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}",
+                body.len()
+            )
+        })
+        .collect();
+    std::thread::spawn(move || {
+        for response in responses {
+            if let Ok((mut stream, _)) = listener.accept() {
+                let mut buf = [0; 4096];
+                let _ = stream.read(&mut buf);
+                let _ = stream.write_all(response.as_bytes());
+            }
+        }
+    });
+    format!("http://{addr}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // this function builds an error the way the clients do for an unsuccessful response
+    fn status_err(status: u16, retry_after: Option<u64>) -> anyhow::Error {
+        StatusError {
+            status,
+            retry_after: retry_after.map(Duration::from_secs),
+        }
+        .wrap(anyhow!("rate_limit_error: too many requests"))
+    }
+
+    #[test]
+    fn test_retry_delay() {
+        let retry = RetryConfig::default();
+        let err = status_err(429, None);
+        assert_eq!(err.to_string(), "rate_limit_error: too many requests");
+        assert_eq!(retry.delay(1, &err), Some(Duration::from_millis(1000)));
+        assert_eq!(retry.delay(2, &err), Some(Duration::from_millis(2000)));
+        assert_eq!(retry.delay(3, &err), None);
+        assert_eq!(
+            retry.delay(1, &status_err(503, Some(7))),
+            Some(Duration::from_secs(7))
+        );
+        assert_eq!(retry.delay(1, &status_err(400, None)), None);
+        assert_eq!(retry.delay(1, &anyhow!("Invalid response data")), None);
+        // the wait asked by the server is capped
+        assert_eq!(
+            retry.delay(1, &status_err(429, Some(3600))),
+            Some(Duration::from_millis(retry.max_delay))
+        );
+    }
+
+    #[test]
+    fn test_parse_retry_after() {
+        assert_eq!(parse_retry_after(" 120 "), Some(Duration::from_secs(120)));
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"),
+            Some(Duration::ZERO)
+        );
+        let date = (chrono::Utc::now() + chrono::Duration::seconds(30)).to_rfc2822();
+        let delay = parse_retry_after(&date).unwrap();
+        assert!(delay > Duration::from_secs(25) && delay <= Duration::from_secs(30));
+        assert_eq!(parse_retry_after("soon"), None);
+    }
+
+    #[test]
+    fn test_error_code() {
+        assert_eq!(
+            error_code(&status_err(401, None)),
+            ("auth_error", Some(401))
+        );
+        assert_eq!(
+            error_code(&status_err(429, Some(1))),
+            ("rate_limit", Some(429))
+        );
+        assert_eq!(
+            error_code(&status_err(502, None)),
+            ("server_error", Some(502))
+        );
+        assert_eq!(error_code(&anyhow!("Invalid model 'x'")), ("error", None));
+    }
+
+    #[test]
+    fn test_sum_usage() {
+        let usage = |input_tokens, output_tokens| TokenUsage {
+            input_tokens,
+            output_tokens,
+        };
+        assert_eq!(
+            TokenUsage::sum(&[Some(usage(10, 2)), Some(usage(15, 3))]),
+            Some(usage(25, 5))
+        );
+        assert_eq!(TokenUsage::sum(&[Some(usage(10, 2)), None]), None);
+        assert_eq!(TokenUsage::sum(&[]), None);
+    }
+}
@@ -0,0 +1,189 @@
+// This file implements the discovery of the models listed by the apis of the clients, which `--refresh-models`
+// caches in `models.yaml` and `list_models` merges with the built-in and configured models
+use super::{init_all_clients, Model, ModelCapabilities, TokensCountFactors};
+
+use crate::config::{Config, GlobalConfig};
+use crate::utils::{init_tokio_runtime, now};
+
+use anyhow::{Context, Result};
+use lazy_static::lazy_static;
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{read_to_string, write};
+use std::path::Path;
+use std::sync::Arc;
+
+// the factors of the discovered models of a client without built-in or configured ones
+const DEFAULT_TOKENS_COUNT_FACTORS: TokensCountFactors = (5, 2);
+
+lazy_static! {
+    // the cache read from disk once per run, replaced when the models are refreshed
+    static ref MODELS_CACHE: RwLock<Option<Arc<ModelsCache>>> = Default::default();
+}
+
+// struct representing a model listed by the api of a client
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct DiscoveredModel {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<usize>, // the context length, when the api exposes it
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub vision: bool, // whether the api says the model takes images
+}
+
+impl DiscoveredModel {
+    pub fn new(name: &str, max_tokens: Option<usize>, vision: bool) -> Self {
+        Self {
+            name: name.to_string(),
+            max_tokens,
+            vision,
+        }
+    }
+}
+
+// struct representing the cache of the discovered models, by client name
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct ModelsCache {
+    #[serde(default)]
+    pub refreshed_at: String,
+    #[serde(default)]
+    pub clients: BTreeMap<String, Vec<DiscoveredModel>>,
+}
+
+impl ModelsCache {
+    // this function returns the cache, which is only loaded the first time
+    pub fn get() -> Arc<Self> {
+        if let Some(cache) = MODELS_CACHE.read().as_ref() {
+            return cache.clone();
+        }
+        MODELS_CACHE
+            .write()
+            .get_or_insert_with(|| Arc::new(Self::load()))
+            .clone()
+    }
+
+    // this function loads the cache, an unreadable cache is ignored so that the configured models still work
+    fn load() -> Self {
+        let path = match Config::models_cache_file() {
+            Ok(v) => v,
+            Err(_) => return Self::default(),
+        };
+        if !path.exists() {
+            return Self::default();
+        }
+        match Self::load_from(&path) {
+            Ok(v) => v,
+            Err(err) => {
+                warn!("{err:#}");
+                Self::default()
+            }
+        }
+    }
+
+    fn load_from(path: &Path) -> Result<Self> {
+        let content = read_to_string(path)
+            .with_context(|| format!("Failed to load models cache at {}", path.display()))?;
+        serde_yaml::from_str(&content)
+            .with_context(|| format!("Invalid models cache at {}", path.display()))
+    }
+
+    // this function adds the discovered models of a client to its built-in and configured ones, which come first
+    // and keep their settings, only taking the context length or the vision the api exposes when they lack it
+    pub fn merge_into(&self, client_name: &str, mut models: Vec<Model>) -> Vec<Model> {
+        let Some(discovered) = self.clients.get(client_name) else {
+            return models;
+        };
+        let tokens_count_factors = models
+            .first()
+            .map(|v| v.tokens_count_factors)
+            .unwrap_or(DEFAULT_TOKENS_COUNT_FACTORS);
+        for item in discovered {
+            let capabilities = if item.vision {
+                ModelCapabilities::Text | ModelCapabilities::Vision
+            } else {
+                ModelCapabilities::Text
+            };
+            match models.iter_mut().find(|v| v.name == item.name) {
+                Some(model) => {
+                    if model.max_tokens.is_none() {
+                        model.max_tokens = item.max_tokens;
+                    }
+                    if item.vision {
+                        model.capabilities |= ModelCapabilities::Vision;
+                    }
+                }
+                None => models.push(
+                    Model::new(client_name, &item.name)
+                        .set_capabilities(capabilities)
+                        .set_max_tokens(item.max_tokens)
+                        .set_tokens_count_factors(tokens_count_factors),
+                ),
+            }
+        }
+        models
+    }
+}
+
+// this function asks every client for its models and caches them, a client failing keeps its cached models
+pub fn refresh_models(config: &GlobalConfig) -> Result<String> {
+    let path = Config::models_cache_file()?;
+    let mut cache = if path.exists() {
+        ModelsCache::load_from(&path)?
+    } else {
+        ModelsCache::default()
+    };
+    let clients = init_all_clients(config);
+    let mut lines = vec![];
+    init_tokio_runtime()?.block_on(async {
+        for client in &clients {
+            let name = client.model().client_name.clone();
+            let models = match client.build_client() {
+                Ok(http_client) => client.fetch_models(&http_client).await,
+                Err(err) => Err(err),
+            };
+            match models {
+                Ok(models) => {
+                    lines.push(format!("{name}: {} models", models.len()));
+                    cache.clients.insert(name, models);
+                }
+                Err(err) => lines.push(format!("{name}: skipped, {err:#}")),
+            }
+        }
+    });
+    cache.refreshed_at = now();
+    let content = serde_yaml::to_string(&cache)?;
+    write(&path, content)
+        .with_context(|| format!("Failed to save models cache at {}", path.display()))?;
+    *MODELS_CACHE.write() = Some(Arc::new(cache));
+    lines.push(format!("Saved to {}", path.display()));
+    Ok(lines.join("\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merge_into() {
+        let cache: ModelsCache = serde_yaml::from_str(
+            r#"
+clients:
+  ollama:
+    - { name: llama3, max_tokens: 8192 }
+    - { name: llava, max_tokens: 4096, vision: true }
+"#,
+        )
+        .unwrap();
+        let configured = vec![Model::new("ollama", "llama3").set_tokens_count_factors((3, 1))];
+        let models = cache.merge_into("ollama", configured);
+        assert_eq!(models.len(), 2);
+        assert_eq!(models[0].max_tokens, Some(8192));
+        assert_eq!(models[1].id(), "ollama:llava");
+        assert!(models[1].capabilities.contains(ModelCapabilities::Vision));
+        assert_eq!(models[1].tokens_count_factors, (3, 1));
+
+        let models = cache.merge_into("openai", vec![]);
+        assert!(models.is_empty());
+    }
+}
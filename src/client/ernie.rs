@@ -1,10 +1,15 @@
-use super::{patch_system_message, Client, ErnieClient, ExtraConfig, Model, PromptType, SendData};
+use super::{
+    check_status, patch_schema_message, patch_system_message, Client, ErnieClient, ExtraConfig,
+    Model, PromptType, SendData, SendOutput, TokenUsage,
+};
 
 use crate::{render::ReplyHandler, utils::PromptKind};
 
 use anyhow::{anyhow, bail, Context, Result};
 use async_trait::async_trait;
 use futures_util::StreamExt;
+use lazy_static::lazy_static;
+use parking_lot::RwLock;
 use reqwest::{Client as ReqwestClient, RequestBuilder};
 use reqwest_eventsource::{Error as EventSourceError, Event, RequestBuilderExt};
 use serde::Deserialize;
@@ -22,8 +27,10 @@ const MODELS: [(&str, &str); 4] = [
     ("ernie-bot-turbo", "/wenxinworkshop/chat/eb-instant"),
 ];
 
-// static mutable string used to store the access token
-static mut ACCESS_TOKEN: String = String::new(); // safe under linear operation
+// lazily initialized string used to store the access token
+lazy_static! {
+    static ref ACCESS_TOKEN: RwLock<String> = RwLock::new(String::new());
+}
 
 // struct for represents the configuration options for ErnieClient
 // all of the variables are optional in this struct
@@ -40,7 +47,11 @@ pub struct ErnieConfig {
 impl Client for ErnieClient {
     client_common_fns!();
 
-    async fn send_message_inner(&self, client: &ReqwestClient, data: SendData) -> Result<String> {
+    async fn send_message_inner(
+        &self,
+        client: &ReqwestClient,
+        data: SendData,
+    ) -> Result<SendOutput> {
         // function to ensure that an access token is available before making requests
         self.prepare_access_token().await?;
         // we call the 'request_builder' function to construct the request
@@ -91,9 +102,10 @@ impl ErnieClient {
         .ok_or_else(|| anyhow!("Miss Model '{}'", self.model.id()))?;
     
         // constructs the URL using the base URL, model endpoint, and access token 
-        let url = format!("{API_BASE}{chat_endpoint}?access_token={}", unsafe {
-            &ACCESS_TOKEN
-        });
+        let url = format!(
+            "{API_BASE}{chat_endpoint}?access_token={}",
+            ACCESS_TOKEN.read()
+        );
 
         debug!("Ernie Request: {url} {body}");
 
@@ -106,7 +118,7 @@ impl ErnieClient {
     // If the access token is empty, it fetches the api and secret key
     // from the configuration or environment variables
     async fn prepare_access_token(&self) -> Result<()> {
-        if unsafe { ACCESS_TOKEN.is_empty() } {
+        if ACCESS_TOKEN.read().is_empty() {
             // Note: cannot use config_get_fn!
             let env_prefix = Self::name(&self.config).to_uppercase();
             let api_key = self.config.api_key.clone();
@@ -122,17 +134,18 @@ impl ErnieClient {
             let token = fetch_access_token(&api_key, &secret_key)
                 .await
                 .with_context(|| "Failed to fetch access token")?;
-            unsafe { ACCESS_TOKEN = token };
+            *ACCESS_TOKEN.write() = token;
         }
         Ok(())
     }
 }
 
 // this function sends a message using RequestBuilder
-async fn send_message(builder: RequestBuilder) -> Result<String> {
+async fn send_message(builder: RequestBuilder) -> Result<SendOutput> {
     // the request is sent asynchronously and wait for response
     // response is parsed as json
-    let data: Value = builder.send().await?.json().await?;
+    let res = check_status(builder.send().await?, check_error).await?;
+    let data: Value = res.json().await?;
     check_error(&data)?;
 
     // here, we extract the result from the json data
@@ -140,8 +153,13 @@ async fn send_message(builder: RequestBuilder) -> Result<String> {
         .as_str()
         .ok_or_else(|| anyhow!("Unexpected response {data}"))?;
 
-    // returning the extracted output as string
-    Ok(output.to_string())
+    // returning the extracted output with the token usage
+    Ok(SendOutput {
+        text: output.to_string(),
+        tool_calls: vec![],
+        usage: TokenUsage::from_json(&data["usage"], "prompt_tokens", "completion_tokens"),
+        finish_reason: data["finish_reason"].as_str().map(|v| v.to_string()),
+    })
 }
 
 // this function sends a message in streaming mode using RequestBuilder and ReplyHandler
@@ -160,6 +178,15 @@ async fn send_message_streaming(builder: RequestBuilder, handler: &mut ReplyHand
                     // If successful, we send the extracted text to ReplyHandler
                     handler.text(text)?;
                 }
+                if let Some(reason) = data["finish_reason"].as_str().filter(|v| !v.is_empty()) {
+                    handler.finish_reason(reason)?;
+                }
+                // the usage of the last message covers the whole reply
+                if let Some(usage) =
+                    TokenUsage::from_json(&data["usage"], "prompt_tokens", "completion_tokens")
+                {
+                    handler.usage(usage)?;
+                }
             }
             // handling different types of errors, as invalid content type, stream ending, or general errors
             Err(err) => {
@@ -188,7 +215,7 @@ async fn send_message_streaming(builder: RequestBuilder, handler: &mut ReplyHand
                     EventSourceError::StreamEnded => {}
                     _ => {
                         // if any errors occur during the process, we returns an error wrapped in a Result
-                        bail!("{}", err);
+                        return Err(err.into());
                     }
                 }
                 // we close the builder eventsource before ending the funciton 
@@ -207,7 +234,7 @@ fn check_error(data: &Value) -> Result<()> {
         if let Some(code) = data["error_code"].as_number().and_then(|v| v.as_u64()) {
             if code == 110 {
                 // if an error is detected, it returns an error in a Result
-                unsafe { ACCESS_TOKEN = String::new() }
+                ACCESS_TOKEN.write().clear();
             }
             bail!("{err_msg}. err_code: {code}");
         } else {
@@ -224,10 +251,17 @@ fn build_body(data: SendData, _model: String) -> Value {
     let SendData {
         mut messages,
         temperature,
+        params,
         stream,
+        schema,
+        ..
     } = data;
 
     patch_system_message(&mut messages);
+    // ernie has no json mode, the schema is asked for in the prompt
+    if let Some(schema) = &schema {
+        patch_schema_message(&mut messages, schema);
+    }
 
     // we make the body as a json
     let mut body = json!({
@@ -238,6 +272,11 @@ fn build_body(data: SendData, _model: String) -> Value {
     if let Some(temperature) = temperature {
         body["temperature"] = (temperature / 2.0).into();
     }
+    // ernie takes no seed and a penalty_score of its own, which the provider options can give
+    params.insert_into(
+        &mut body,
+        ["top_p", "max_output_tokens", "stop", "", "", ""],
+    );
     if stream {
         body["stream"] = true.into();
     }
@@ -1,6 +1,8 @@
+use super::openai::parse_vector;
 use super::{
-    message::*, patch_system_message, Client, ExtraConfig, GeminiClient, Model, PromptType,
-    SendData, TokensCountFactors,
+    check_status, message::*, patch_system_message, status_error, Client, DiscoveredModel,
+    ExtraConfig, GeminiClient, Model, PromptType, SendData, SendOutput, TokenUsage,
+    TokensCountFactors,
 };
 
 use crate::{render::ReplyHandler, utils::PromptKind};
@@ -16,10 +18,11 @@ use serde_json::{json, Value};
 const API_BASE: &str = "https://generativelanguage.googleapis.com/v1beta/models/";
 
 // array of all the available models
-const MODELS: [(&str, usize, &str); 3] = [
+const MODELS: [(&str, usize, &str); 4] = [
     ("gemini-pro", 32768, "text"),
     ("gemini-pro-vision", 16384, "vision"),
     ("gemini-ultra", 32768, "text"),
+    ("text-embedding-004", 2048, "embed"),
 ];
 
 const TOKENS_COUNT_FACTORS: TokensCountFactors = (5, 2);
@@ -38,7 +41,11 @@ impl Client for GeminiClient {
     client_common_fns!();
 
     // this function sends a message using the provided ReqwestClient and SendData
-    async fn send_message_inner(&self, client: &ReqwestClient, data: SendData) -> Result<String> {
+    async fn send_message_inner(
+        &self,
+        client: &ReqwestClient,
+        data: SendData,
+    ) -> Result<SendOutput> {
         // making a builder with request_builder funciton 
         let builder = self.request_builder(client, data)?;
         send_message(builder).await
@@ -55,6 +62,30 @@ impl Client for GeminiClient {
         let builder = self.request_builder(client, data)?;
         send_message_streaming(builder, handler).await
     }
+
+    // this function lists the models of `models.list` that can generate content
+    async fn fetch_models(&self, client: &ReqwestClient) -> Result<Vec<DiscoveredModel>> {
+        let api_key = self.get_api_key()?;
+        let url = format!("{API_BASE}?key={api_key}&pageSize=1000");
+        let res = check_status(client.get(url).send().await?, check_error).await?;
+        let data: Value = res.json().await?;
+        parse_models(&data)
+    }
+
+    // this function turns texts into vectors with `batchEmbedContents`
+    async fn embeddings(&self, client: &ReqwestClient, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+        let api_key = self.get_api_key()?;
+        let model = &self.model.name;
+        let url = format!("{API_BASE}{model}:batchEmbedContents?key={api_key}");
+        let body = build_embeddings_body(texts, model);
+        debug!("Gemini Embeddings Request: {API_BASE}{model}:batchEmbedContents");
+        let res = check_status(client.post(url).json(&body).send().await?, check_error).await?;
+        let data: Value = res.json().await?;
+        data["embeddings"]
+            .as_array()
+            .and_then(|list| list.iter().map(|v| parse_vector(&v["values"])).collect())
+            .ok_or_else(|| anyhow!("Invalid response data: {data}"))
+    }
 }
 
 // This defines the GeminiClient struct and all its functions
@@ -106,22 +137,71 @@ impl GeminiClient {
     }
 }
 
+// this function reads the models of a `models.list` response, the api gives the context length but not
+// whether a model takes images
+fn parse_models(data: &Value) -> Result<Vec<DiscoveredModel>> {
+    let Some(list) = data["models"].as_array() else {
+        bail!("Invalid response data: {data}");
+    };
+    let models = list
+        .iter()
+        .filter(|v| {
+            v["supportedGenerationMethods"]
+                .as_array()
+                .is_some_and(|list| list.iter().any(|v| v == "generateContent"))
+        })
+        .filter_map(|v| {
+            let name = v["name"].as_str()?;
+            let name = name.strip_prefix("models/").unwrap_or(name);
+            let max_tokens = v["inputTokenLimit"].as_u64().map(|v| v as usize);
+            Some(DiscoveredModel::new(name, max_tokens, false))
+        })
+        .collect();
+    Ok(models)
+}
+
+// this function constructs the body of `batchEmbedContents`, one request per text
+fn build_embeddings_body(texts: &[String], model: &str) -> Value {
+    let requests: Vec<Value> = texts
+        .iter()
+        .map(|text| {
+            json!({
+                "model": format!("models/{model}"),
+                "content": { "parts": [{ "text": text }] },
+            })
+        })
+        .collect();
+    json!({ "requests": requests })
+}
+
 // function is used to construct an HTTP request for sending message
-async fn send_message(builder: RequestBuilder) -> Result<String> {
-    let res = builder.send().await?;
-    let status = res.status();
-    let data: Value = res.json().await?;
+async fn send_message(builder: RequestBuilder) -> Result<SendOutput> {
     // checking the http status code, if it's not 200, indicating an error, 
     // we parse the json response and check for any error messages
-    if status != 200 {
-        check_error(&data)?;
+    let res = check_status(builder.send().await?, check_error).await?;
+    let data: Value = res.json().await?;
+    // if response is successful, we extract the text and the function calls
+    // from the parts of the first candidate
+    let parts = data["candidates"][0]["content"]["parts"]
+        .as_array()
+        .ok_or_else(|| anyhow!("Invalid response data: {data}"))?;
+    let mut output = SendOutput::default();
+    for part in parts {
+        if let Some(text) = part["text"].as_str() {
+            output.text.push_str(text);
+        } else if let Some(tool_call) = extract_function_call(part) {
+            output.tool_calls.push(tool_call);
+        }
     }
-    // if response is successful, we extract the content of the 
-    // first candidate from the json and return it as a string
-    let output = data["candidates"][0]["content"]["parts"][0]["text"]
+    output.usage = TokenUsage::from_json(
+        &data["usageMetadata"],
+        "promptTokenCount",
+        "candidatesTokenCount",
+    );
+    output.finish_reason = data["candidates"][0]["finishReason"]
         .as_str()
-        .ok_or_else(|| anyhow!("Invalid response data: {data}"))?;
-    Ok(output.to_string())
+        .map(|v| v.to_string());
+    Ok(output)
 }
 
 // function is similar to send_message but for handling streaming mode
@@ -131,8 +211,7 @@ async fn send_message_streaming(builder: RequestBuilder, handler: &mut ReplyHand
     let res = builder.send().await?;
     // response status code is not 200, we parse the json response and checks for any error messages
     if res.status() != 200 {
-        let data: Value = res.json().await?;
-        check_error(&data)?;
+        return Err(status_error(res, check_error).await);
     }
     // if response is successful 
     else {
@@ -166,22 +245,36 @@ async fn send_message_streaming(builder: RequestBuilder, handler: &mut ReplyHand
                         }
                         balances.push(ch);
                     }
-                    '[' => {
-                        if start != 0 {
-                            balances.push(ch);
-                        }
+                    '[' if start != 0 => {
+                        balances.push(ch);
                     }
                     '}' => {
                         balances.pop();
                         if balances.is_empty() {
                             let value: String = buffer[start..=i].iter().collect();
                             let value: Value = serde_json::from_str(&value)?;
-                            if let Some(text) =
-                                value["candidates"][0]["content"]["parts"][0]["text"].as_str()
-                            {
-                                handler.text(text)?;
-                            } else {
-                                bail!("Invalid response data: {value}")
+                            let parts = value["candidates"][0]["content"]["parts"]
+                                .as_array()
+                                .ok_or_else(|| anyhow!("Invalid response data: {value}"))?;
+                            for part in parts {
+                                if let Some(text) = part["text"].as_str() {
+                                    handler.text(text)?;
+                                } else if let Some(tool_call) = extract_function_call(part) {
+                                    handler.tool_call(tool_call)?;
+                                } else {
+                                    bail!("Invalid response data: {value}")
+                                }
+                            }
+                            if let Some(reason) = value["candidates"][0]["finishReason"].as_str() {
+                                handler.finish_reason(reason)?;
+                            }
+                            // every chunk carries the counts so far
+                            if let Some(usage) = TokenUsage::from_json(
+                                &value["usageMetadata"],
+                                "promptTokenCount",
+                                "candidatesTokenCount",
+                            ) {
+                                handler.usage(usage)?;
                             }
                         }
                     }
@@ -198,6 +291,17 @@ async fn send_message_streaming(builder: RequestBuilder, handler: &mut ReplyHand
     Ok(())
 }
 
+// this function converts a `functionCall` part to a tool call
+// gemini does not give ids to the calls, so the function name is used instead
+fn extract_function_call(part: &Value) -> Option<ToolCall> {
+    let name = part["functionCall"]["name"].as_str()?;
+    Some(ToolCall {
+        id: name.to_string(),
+        name: name.to_string(),
+        arguments: part["functionCall"]["args"].clone(),
+    })
+}
+
 // this function attempts to extract error information from the json object by checking if it contains an error
 fn check_error(data: &Value) -> Result<()> {
     // the error field is present and contains both "status" and "message" fields, 
@@ -223,6 +327,9 @@ fn build_body(data: SendData, _model: String) -> Result<Value> {
     let SendData {
         mut messages,
         temperature,
+        params,
+        tools,
+        schema,
         ..
     } = data;
 
@@ -235,6 +342,7 @@ fn build_body(data: SendData, _model: String) -> Result<Value> {
         .map(|message| {
             let role = match message.role {
                 MessageRole::User => "user",
+                MessageRole::Tool => "function",
                 _ => "model",
             };
             match message.content {
@@ -256,6 +364,15 @@ fn build_body(data: SendData, _model: String) -> Result<Value> {
                                     json!({ "url": url })
                                 }
                             },
+                            MessageContentPart::ToolCall { tool_call } => json!({
+                                "functionCall": { "name": tool_call.name, "args": tool_call.arguments }
+                            }),
+                            MessageContentPart::ToolResult { tool_result } => json!({
+                                "functionResponse": {
+                                    "name": tool_result.name,
+                                    "response": { "name": tool_result.name, "content": tool_result.content },
+                                }
+                            }),
                         })
                         .collect();
                     json!({ "role": role, "parts": list })
@@ -277,11 +394,151 @@ fn build_body(data: SendData, _model: String) -> Result<Value> {
         "contents": contents,
     });
 
+    // the generation parameters and the provider options go in the generation config
+    let mut generation_config = json!({});
     if let Some(temperature) = temperature {
-        body["generationConfig"] = json!({
-            "temperature": temperature,
-        });
+        generation_config["temperature"] = temperature.into();
+    }
+    params.insert_into(
+        &mut generation_config,
+        [
+            "topP",
+            "maxOutputTokens",
+            "stopSequences",
+            "seed",
+            "presencePenalty",
+            "frequencyPenalty",
+        ],
+    );
+    // the answer is constrained to a json matching the schema
+    if let Some(schema) = schema {
+        generation_config["responseMimeType"] = "application/json".into();
+        generation_config["responseSchema"] = gemini_schema(&schema);
+    }
+    if generation_config.as_object().is_some_and(|v| !v.is_empty()) {
+        body["generationConfig"] = generation_config;
+    }
+
+    // if there are tools, we declare them as functions
+    if !tools.is_empty() {
+        let declarations: Vec<Value> = tools.iter().map(|v| v.declaration()).collect();
+        body["tools"] = json!([{ "functionDeclarations": declarations }]);
     }
 
     Ok(body)
 }
+
+// this function keeps the keywords of the schema that gemini knows, which rejects the others,
+// a list of types with `null` becomes a nullable type
+fn gemini_schema(schema: &Value) -> Value {
+    const KEYS: [&str; 11] = [
+        "type",
+        "format",
+        "description",
+        "nullable",
+        "enum",
+        "required",
+        "minItems",
+        "maxItems",
+        "minimum",
+        "maximum",
+        "propertyOrdering",
+    ];
+    let Some(schema) = schema.as_object() else {
+        return json!({});
+    };
+    let mut output = json!({});
+    for (key, value) in schema {
+        match key.as_str() {
+            "type" if value.is_array() => {
+                let types: Vec<&Value> = value.as_array().into_iter().flatten().collect();
+                if let Some(name) = types.iter().find(|v| v.as_str() != Some("null")) {
+                    output["type"] = (*name).clone();
+                }
+                if types.iter().any(|v| v.as_str() == Some("null")) {
+                    output["nullable"] = true.into();
+                }
+            }
+            "properties" => {
+                let properties: serde_json::Map<String, Value> = value
+                    .as_object()
+                    .into_iter()
+                    .flatten()
+                    .map(|(k, v)| (k.clone(), gemini_schema(v)))
+                    .collect();
+                output["properties"] = properties.into();
+            }
+            "items" => output["items"] = gemini_schema(value),
+            "anyOf" => {
+                output["anyOf"] = value
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .map(gemini_schema)
+                    .collect();
+            }
+            _ if KEYS.contains(&key.as_str()) => output[key] = value.clone(),
+            _ => {}
+        }
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_models() {
+        let data = json!({
+            "models": [
+                {
+                    "name": "models/gemini-1.5-flash",
+                    "inputTokenLimit": 1048576,
+                    "supportedGenerationMethods": ["generateContent", "countTokens"],
+                },
+                {
+                    "name": "models/text-embedding-004",
+                    "inputTokenLimit": 2048,
+                    "supportedGenerationMethods": ["embedContent"],
+                },
+            ]
+        });
+        assert_eq!(
+            parse_models(&data).unwrap(),
+            [DiscoveredModel::new(
+                "gemini-1.5-flash",
+                Some(1048576),
+                false
+            )]
+        );
+        assert!(parse_models(&json!({ "error": {} })).is_err());
+    }
+
+    #[test]
+    fn test_gemini_schema() {
+        let schema = json!({
+            "$schema": "https://json-schema.org/draft/2020-12/schema",
+            "type": "object",
+            "properties": {
+                "level": { "type": "string", "enum": ["info", "error"] },
+                "host": { "type": ["string", "null"] },
+                "tags": { "type": "array", "items": { "type": "string", "minLength": 1 } }
+            },
+            "required": ["level"],
+            "additionalProperties": false
+        });
+        assert_eq!(
+            gemini_schema(&schema),
+            json!({
+                "type": "object",
+                "properties": {
+                    "level": { "type": "string", "enum": ["info", "error"] },
+                    "host": { "type": "string", "nullable": true },
+                    "tags": { "type": "array", "items": { "type": "string" } }
+                },
+                "required": ["level"]
+            })
+        );
+    }
+}
@@ -1,4 +1,4 @@
-use super::openai::{openai_build_body, OPENAI_TOKENS_COUNT_FACTORS};
+use super::openai::{openai_build_body, openai_build_embeddings_body, OPENAI_TOKENS_COUNT_FACTORS};
 use super::{ExtraConfig, LocalAIClient, Model, ModelConfig, PromptType, SendData};
 
 use crate::utils::PromptKind;
@@ -15,6 +15,7 @@ pub struct LocalAIConfig {
     pub api_base: String,              // base URL for api endpoints
     pub api_key: Option<String>,       // api key used for authentication
     pub chat_endpoint: Option<String>, // optional endpoint for chat
+    #[serde(default)]
     pub models: Vec<ModelConfig>, // vector of structs representing different models supported by the local-ai
     pub extra: Option<ExtraConfig>, // Optional extra configurations
 }
@@ -55,6 +56,8 @@ impl LocalAIClient {
                     .set_capabilities(v.capabilities)
                     .set_max_tokens(v.max_tokens)
                     .set_tokens_count_factors(OPENAI_TOKENS_COUNT_FACTORS)
+                    .set_prices(v.input_price, v.output_price)
+                    .set_tokenizer(v.tokenizer.clone())
             })
             .collect()
     }
@@ -63,7 +66,7 @@ impl LocalAIClient {
     fn request_builder(&self, client: &ReqwestClient, data: SendData) -> Result<RequestBuilder> {
         let api_key = self.get_api_key().ok();
 
-        let body = openai_build_body(data, self.model.name.clone());
+        let body = openai_build_body(data, &self.model);
 
         let chat_endpoint = self
             .config
@@ -85,4 +88,29 @@ impl LocalAIClient {
         // finally, we return a RequestBuilder instance to be used for making the api calls
         Ok(builder)
     }
+
+    // this function constructs a request builder listing the models of local-AI
+    fn models_request_builder(&self, client: &ReqwestClient) -> Result<RequestBuilder> {
+        let mut builder = client.get(format!("{}/models", self.config.api_base));
+        if let Ok(api_key) = self.get_api_key() {
+            builder = builder.bearer_auth(api_key);
+        }
+        Ok(builder)
+    }
+
+    // this function constructs a request builder turning texts into vectors with the current model
+    fn embeddings_request_builder(
+        &self,
+        client: &ReqwestClient,
+        texts: &[String],
+    ) -> Result<RequestBuilder> {
+        let body = openai_build_embeddings_body(texts, &self.model.name);
+        let url = format!("{}/embeddings", self.config.api_base);
+        debug!("LocalAI Embeddings Request: {url}");
+        let mut builder = client.post(url).json(&body);
+        if let Ok(api_key) = self.get_api_key() {
+            builder = builder.bearer_auth(api_key);
+        }
+        Ok(builder)
+    }
 }
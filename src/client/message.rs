@@ -2,6 +2,7 @@
 use crate::config::Input;
 
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
 // This struct represents a message to be sent to user
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -18,6 +19,31 @@ impl Message {
             content: input.to_message_content(),
         }
     }
+
+    // this function returns the tool calls requested in an assistant message
+    pub fn tool_calls(&self) -> Vec<&ToolCall> {
+        match &self.content {
+            MessageContent::Array(list) => list
+                .iter()
+                .filter_map(|item| match item {
+                    MessageContentPart::ToolCall { tool_call } => Some(tool_call),
+                    _ => None,
+                })
+                .collect(),
+            MessageContent::Text(_) => vec![],
+        }
+    }
+
+    // this function returns the tool result carried by a tool message
+    pub fn tool_result(&self) -> Option<&ToolResult> {
+        match &self.content {
+            MessageContent::Array(list) => list.iter().find_map(|item| match item {
+                MessageContentPart::ToolResult { tool_result } => Some(tool_result),
+                _ => None,
+            }),
+            MessageContent::Text(_) => None,
+        }
+    }
 }
 
 //This is an enum for telling the roles inside a message
@@ -27,6 +53,7 @@ pub enum MessageRole {
     System,
     Assistant,
     User,
+    Tool,
 }
 
 // utility methods of MessageRole to check if the role is system, assistant, or user
@@ -46,6 +73,11 @@ impl MessageRole {
     pub fn is_assistant(&self) -> bool {
         matches!(self, MessageRole::Assistant)
     }
+
+    // returns True of the MessageRole is Tool
+    pub fn is_tool(&self) -> bool {
+        matches!(self, MessageRole::Tool)
+    }
 }
 
 // This is the Struct which represents the contents inside a message
@@ -71,6 +103,8 @@ impl MessageContent {
                         MessageContentPart::ImageUrl { image_url } => {
                             files.push(resolve_url_fn(&image_url.url))
                         }
+                        MessageContentPart::ToolCall { .. }
+                        | MessageContentPart::ToolResult { .. } => {}
                     }
                 }
                 if !concated_text.is_empty() {
@@ -81,6 +115,21 @@ impl MessageContent {
         }
     }
 
+    // this function joins the text parts of the content, ignoring images and tool parts
+    pub fn to_text(&self) -> String {
+        match self {
+            MessageContent::Text(text) => text.to_string(),
+            MessageContent::Array(list) => list
+                .iter()
+                .filter_map(|item| match item {
+                    MessageContentPart::Text { text } => Some(text.as_str()),
+                    _ => None,
+                })
+                .collect::<Vec<&str>>()
+                .join("\n\n"),
+        }
+    }
+
     // Function to merge the whole prompt into one
     pub fn merge_prompt(&mut self, replace_fn: impl Fn(&str) -> String) {
         match self {
@@ -99,12 +148,14 @@ impl MessageContent {
 }
 
 // Enum for a part of Message content
-// It can either be text or Image
+// It can either be text, Image, or a tool call/result
 #[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum MessageContentPart {
     Text { text: String },
     ImageUrl { image_url: ImageUrl },
+    ToolCall { tool_call: ToolCall },
+    ToolResult { tool_result: ToolResult },
 }
 
 // Struct to represent the url of an Image
@@ -113,6 +164,46 @@ pub struct ImageUrl {
     pub url: String,
 }
 
+// Struct to represent a tool call requested by the model
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct ToolCall {
+    pub id: String,
+    pub name: String,
+    pub arguments: Value,
+}
+
+impl ToolCall {
+    // constructor that parses the arguments, falling back to the raw string if they are not valid json
+    pub fn new(id: &str, name: &str, arguments: &str) -> Self {
+        let arguments = if arguments.trim().is_empty() {
+            Value::Object(Default::default())
+        } else {
+            serde_json::from_str(arguments).unwrap_or_else(|_| arguments.into())
+        };
+        Self {
+            id: id.to_string(),
+            name: name.to_string(),
+            arguments,
+        }
+    }
+
+    // this function returns the arguments as a json string, which is how openai expects them
+    pub fn arguments_string(&self) -> String {
+        match &self.arguments {
+            Value::String(v) => v.clone(),
+            v => v.to_string(),
+        }
+    }
+}
+
+// Struct to represent the output of a tool call
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct ToolResult {
+    pub id: String,
+    pub name: String,
+    pub content: String,
+}
+
 // This is a test to check if the message is beign made as we want
 #[cfg(test)]
 mod tests {
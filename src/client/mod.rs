@@ -3,16 +3,21 @@
 // are organized into separate modules for better organization and maintainability
 #[macro_use]
 mod common;
+mod discovery;
 mod message;
 mod model;
+mod params;
 
 pub use common::*;
+pub use discovery::*;
 pub use message::*;
 pub use model::*;
+pub use params::*;
 
 register_client!(
     (openai, "openai", OpenAIConfig, OpenAIClient),
     (gemini, "gemini", GeminiConfig, GeminiClient),
+    (claude, "claude", ClaudeConfig, ClaudeClient),
     (localai, "localai", LocalAIConfig, LocalAIClient),
     (ollama, "ollama", OllamaConfig, OllamaClient),
     (
@@ -21,6 +26,12 @@ register_client!(
         AzureOpenAIConfig,
         AzureOpenAIClient
     ),
+    (
+        openai_compatible,
+        "openai-compatible",
+        OpenAICompatibleConfig,
+        OpenAICompatibleClient
+    ),
     (ernie, "ernie", ErnieConfig, ErnieClient),
     (qianwen, "qianwen", QianwenConfig, QianwenClient),
 );
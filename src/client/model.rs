@@ -1,12 +1,16 @@
-use super::message::{Message, MessageContent};
+use super::message::{Message, MessageContent, MessageContentPart};
+use super::TokenUsage;
 
-use crate::utils::count_tokens;
+use crate::utils::{data_url_image_size, default_tokenizer, get_tokenizer, CL100K_BASE};
 
 use anyhow::{bail, Result};
 use serde::{Deserialize, Deserializer};
 
 pub type TokensCountFactors = (usize, usize); // (per-messages, bias)
 
+// the dimensions assumed for an image given by url, whose size is unknown
+const UNKNOWN_IMAGE_SIZE: (u32, u32) = (1024, 1024);
+
 // this struct represents a llm
 #[derive(Debug, Clone)]
 pub struct Model {
@@ -15,6 +19,9 @@ pub struct Model {
     pub max_tokens: Option<usize>, // maximum number of tokens allowed for text generation
     pub tokens_count_factors: TokensCountFactors, // factors affecting token count, such as tokens per message
     pub capabilities: ModelCapabilities,          // enum indicating the capabilities of the model
+    pub input_price: Option<f64>,                 // the price of 1M input tokens in USD
+    pub output_price: Option<f64>,                // the price of 1M output tokens in USD
+    pub tokenizer: Option<String>, // the tokenizer counting the tokens, the default of the model name when None
 }
 
 // defalult implementations for model
@@ -33,6 +40,9 @@ impl Model {
             max_tokens: None,
             tokens_count_factors: Default::default(),
             capabilities: ModelCapabilities::Text,
+            input_price: None,
+            output_price: None,
+            tokenizer: None,
         }
     }
 
@@ -94,15 +104,96 @@ impl Model {
         self
     }
 
+    // this function sets the prices of 1M input and output tokens in USD and returns self
+    pub fn set_prices(mut self, input_price: Option<f64>, output_price: Option<f64>) -> Self {
+        self.input_price = input_price;
+        self.output_price = output_price;
+        self
+    }
+
+    // this function sets the tokenizer, `cl100k_base`, `o200k_base` or the path of a tokenizer.json, and returns self
+    pub fn set_tokenizer(mut self, tokenizer: Option<String>) -> Self {
+        self.tokenizer = tokenizer;
+        self
+    }
+
+    // this function returns the name of the tokenizer of the model, the configured one or the default of its name
+    pub fn tokenizer_name(&self) -> &str {
+        self.tokenizer
+            .as_deref()
+            .unwrap_or_else(|| default_tokenizer(&self.name))
+    }
+
+    // this function tells that the tokens are counted with cl100k_base because the tokenizer of the model
+    // failed to load, and why
+    pub fn tokenizer_fallback(&self) -> Option<String> {
+        let name = self.tokenizer_name();
+        get_tokenizer(name)
+            .fallback()
+            .map(|err| format!("{CL100K_BASE} in place of {name} ({err})"))
+    }
+
+    // this function counts the tokens of a text with the tokenizer of the model
+    pub fn count_tokens(&self, text: &str) -> usize {
+        get_tokenizer(self.tokenizer_name()).count(text)
+    }
+
+    // this function estimates the tokens of an image as the provider of the model counts them,
+    // from the dimensions of a data url or a typical size for remote images
+    pub fn image_tokens(&self, url: &str) -> usize {
+        let (width, height) = data_url_image_size(url).unwrap_or(UNKNOWN_IMAGE_SIZE);
+        let (width, height) = (width.max(1) as f64, height.max(1) as f64);
+        if self.name.contains("gemini") {
+            // gemini counts every image as 258 tokens
+            return 258;
+        }
+        if self.name.contains("claude") {
+            // claude scales the long side down to 1568 pixels and counts a token per 750 pixels
+            let scale = (1568.0 / width.max(height)).min(1.0);
+            return ((width * scale) * (height * scale) / 750.0).ceil() as usize;
+        }
+        // openai fits the image in 2048x2048, scales its short side down to 768 and counts 170 tokens
+        // per 512px tile plus 85
+        let scale = (2048.0 / width.max(height)).min(1.0);
+        let (width, height) = (width * scale, height * scale);
+        let scale = (768.0 / width.min(height)).min(1.0);
+        let (width, height) = (width * scale, height * scale);
+        let tiles = (width / 512.0).ceil() * (height / 512.0).ceil();
+        170 * tiles as usize + 85
+    }
+
+    // this function calculates the cost of the tokens in USD, or None when the model has no prices
+    pub fn cost(&self, usage: TokenUsage) -> Option<f64> {
+        if self.input_price.is_none() && self.output_price.is_none() {
+            return None;
+        }
+        let input_cost = usage.input_tokens as f64 * self.input_price.unwrap_or_default();
+        let output_cost = usage.output_tokens as f64 * self.output_price.unwrap_or_default();
+        Some((input_cost + output_cost) / 1_000_000.0)
+    }
+
     // this function calculates the total number of tokens in the given message
     pub fn messages_tokens(&self, messages: &[Message]) -> usize {
         messages
             .iter()
-            .map(|v| {
-                match &v.content {
-                    MessageContent::Text(text) => count_tokens(text),
-                    MessageContent::Array(_) => 0, // TODO
-                }
+            .map(|v| match &v.content {
+                MessageContent::Text(text) => self.count_tokens(text),
+                MessageContent::Array(list) => list
+                    .iter()
+                    .map(|part| match part {
+                        MessageContentPart::Text { text } => self.count_tokens(text),
+                        MessageContentPart::ImageUrl { image_url } => {
+                            self.image_tokens(&image_url.url)
+                        }
+                        MessageContentPart::ToolCall { tool_call } => {
+                            self.count_tokens(&tool_call.name)
+                                + self.count_tokens(&tool_call.arguments_string())
+                        }
+                        MessageContentPart::ToolResult { tool_result } => {
+                            self.count_tokens(&tool_result.content)
+                        }
+                    })
+                    .sum(),
             })
             .sum()
     }
@@ -143,6 +234,10 @@ pub struct ModelConfig {
     #[serde(deserialize_with = "deserialize_capabilities")]
     #[serde(default = "default_capabilities")]
     pub capabilities: ModelCapabilities, // the capabilities of model
+    pub chat_endpoint: Option<String>, // overrides the client's chat endpoint for this model
+    pub input_price: Option<f64>,  // the price of 1M input tokens in USD
+    pub output_price: Option<f64>, // the price of 1M output tokens in USD
+    pub tokenizer: Option<String>, // cl100k_base, o200k_base or the path of a tokenizer.json
 }
 
 // bitflags enum representing the capabilities of a model
@@ -151,6 +246,9 @@ bitflags::bitflags! {
     pub struct ModelCapabilities: u32 {
         const Text = 0b00000001;
         const Vision = 0b00000010;
+        const Embed = 0b00000100; // the model turns texts into vectors, for the retrieval of `--rag`
+        const JsonSchema = 0b00001000; // the api constrains the answer to a json schema with `response_format`
+        const StreamUsage = 0b00010000; // the api reports the usage at the end of a stream asked with `stream_options`
     }
 }
 
@@ -165,6 +263,15 @@ impl From<&str> for ModelCapabilities {
         if value.contains("vision") {
             output |= ModelCapabilities::Vision;
         }
+        if value.contains("embed") {
+            output |= ModelCapabilities::Embed;
+        }
+        if value.contains("json_schema") {
+            output |= ModelCapabilities::JsonSchema;
+        }
+        if value.contains("stream_usage") {
+            output |= ModelCapabilities::StreamUsage;
+        }
         output
     }
 }
@@ -182,3 +289,40 @@ where
 fn default_capabilities() -> ModelCapabilities {
     ModelCapabilities::Text
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{ImageUrl, MessageRole};
+
+    #[test]
+    fn test_image_tokens() {
+        let url = "https://example.com/a.png";
+        assert_eq!(
+            Model::new("openai", "gpt-4-vision-preview").image_tokens(url),
+            765
+        );
+        assert_eq!(
+            Model::new("claude", "claude-3-haiku-20240307").image_tokens(url),
+            1399
+        );
+        assert_eq!(
+            Model::new("gemini", "gemini-pro-vision").image_tokens(url),
+            258
+        );
+
+        let message = Message {
+            role: MessageRole::User,
+            content: MessageContent::Array(vec![
+                MessageContentPart::Text {
+                    text: "hello world".into(),
+                },
+                MessageContentPart::ImageUrl {
+                    image_url: ImageUrl { url: url.into() },
+                },
+            ]),
+        };
+        let model = Model::new("openai", "gpt-4-vision-preview");
+        assert_eq!(model.messages_tokens(&[message]), 2 + 765);
+    }
+}
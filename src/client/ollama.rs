@@ -1,6 +1,7 @@
+use super::openai::parse_vector;
 use super::{
-    message::*, patch_system_message, Client, ExtraConfig, Model, ModelConfig, OllamaClient,
-    PromptType, SendData, TokensCountFactors,
+    check_status, message::*, patch_system_message, Client, DiscoveredModel, ExtraConfig, Model,
+    ModelConfig, OllamaClient, PromptType, SendData, SendOutput, TokenUsage, TokensCountFactors,
 };
 
 use crate::{render::ReplyHandler, utils::PromptKind};
@@ -22,7 +23,8 @@ pub struct OllamaConfig {
     pub api_base: String, // base url for the Ollama api
     pub api_key: Option<String>, // api key for Ollama 
     pub chat_endpoint: Option<String>, // endpoint for chat operations
-    pub models: Vec<ModelConfig>, // configurations for different models
+    #[serde(default)]
+    pub models: Vec<ModelConfig>, // configurations for different models, `--refresh-models` can discover them
     pub extra: Option<ExtraConfig>, // extra and optional configurations
 }
 
@@ -32,7 +34,11 @@ impl Client for OllamaClient {
     client_common_fns!();
 
     // this function sends a message using the provided Reqwest client and message
-    async fn send_message_inner(&self, client: &ReqwestClient, data: SendData) -> Result<String> {
+    async fn send_message_inner(
+        &self,
+        client: &ReqwestClient,
+        data: SendData,
+    ) -> Result<SendOutput> {
         let builder = self.request_builder(client, data)?;
         send_message(builder).await
     }
@@ -47,6 +53,43 @@ impl Client for OllamaClient {
         let builder = self.request_builder(client, data)?;
         send_message_streaming(builder, handler).await
     }
+
+    // this function lists the local models, then asks for the details of each one
+    async fn fetch_models(&self, client: &ReqwestClient) -> Result<Vec<DiscoveredModel>> {
+        let builder = self.authorize(client.get(format!("{}/api/tags", self.config.api_base)));
+        let res = check_status(builder.send().await?, |_| Ok(())).await?;
+        let data: Value = res.json().await?;
+        let Some(list) = data["models"].as_array() else {
+            bail!("Invalid response data: {data}");
+        };
+        let mut models = vec![];
+        for name in list.iter().filter_map(|v| v["name"].as_str()) {
+            let url = format!("{}/api/show", self.config.api_base);
+            let builder = self.authorize(client.post(url).json(&json!({ "name": name })));
+            // a model whose details fail is still listed, without its context length
+            let details = match builder.send().await {
+                Ok(res) if res.status().is_success() => res.json().await.unwrap_or_default(),
+                _ => Value::Null,
+            };
+            let (max_tokens, vision) = parse_model_details(&details);
+            models.push(DiscoveredModel::new(name, max_tokens, vision));
+        }
+        Ok(models)
+    }
+
+    // this function turns texts into vectors with the `/api/embed` endpoint
+    async fn embeddings(&self, client: &ReqwestClient, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+        let url = format!("{}/api/embed", self.config.api_base);
+        let body = json!({ "model": self.model.name, "input": texts });
+        debug!("Ollama Embeddings Request: {url}");
+        let builder = self.authorize(client.post(url).json(&body));
+        let res = check_status(builder.send().await?, |_| Ok(())).await?;
+        let data: Value = res.json().await?;
+        data["embeddings"]
+            .as_array()
+            .and_then(|list| list.iter().map(parse_vector).collect())
+            .ok_or_else(|| anyhow!("Invalid response data: {data}"))
+    }
 }
 
 impl OllamaClient {
@@ -81,15 +124,14 @@ impl OllamaClient {
                     .set_capabilities(v.capabilities)
                     .set_max_tokens(v.max_tokens)
                     .set_tokens_count_factors(TOKENS_COUNT_FACTORS)
+                    .set_prices(v.input_price, v.output_price)
+                    .set_tokenizer(v.tokenizer.clone())
             })
             .collect()
     }
 
     //  this function constructs a request builder for sending requests to the Ollama api
     fn request_builder(&self, client: &ReqwestClient, data: SendData) -> Result<RequestBuilder> {
-        // retrieving the API key from the client's configuration
-        let api_key = self.get_api_key().ok();
-
         // constructing the request body 
         let body = build_body(data, self.model.name.clone())?;
 
@@ -100,74 +142,118 @@ impl OllamaClient {
         // logging the constructed request url and body
         debug!("Ollama Request: {url} {body}");
 
-        // creates a POST request using the request builder
-        let mut builder = client.post(url).json(&body);
-        if let Some(api_key) = api_key {
-            builder = builder.header("Authorization", api_key)
-        }
+        // creates a POST request using the request builder, with the api key
+        Ok(self.authorize(client.post(url).json(&body)))
+    }
 
-        // returns RequestBuilder wrapped in a Result
-        Ok(builder)
+    // this function adds the api key to a request, when there is one
+    fn authorize(&self, builder: RequestBuilder) -> RequestBuilder {
+        match self.get_api_key() {
+            Ok(api_key) => builder.header("Authorization", api_key),
+            Err(_) => builder,
+        }
     }
 }
 
+// this function reads the context length and the image input of a model from its `/api/show` details,
+// the context length is under `<architecture>.context_length` and the image input is a `vision` capability
+// or a clip projector in older versions
+fn parse_model_details(details: &Value) -> (Option<usize>, bool) {
+    let max_tokens = details["model_info"].as_object().and_then(|info| {
+        info.iter()
+            .find(|(key, _)| key.ends_with(".context_length"))
+            .and_then(|(_, value)| value.as_u64())
+            .map(|v| v as usize)
+    });
+    let has = |list: &Value, name: &str| {
+        list.as_array()
+            .is_some_and(|list| list.iter().any(|v| v == name))
+    };
+    let vision =
+        has(&details["capabilities"], "vision") || has(&details["details"]["families"], "clip");
+    (max_tokens, vision)
+}
+
 // for sending to the client 
-async fn send_message(builder: RequestBuilder) -> Result<String> {
-    // sends the request using the send method of builder
-    let res = builder.send().await?;
-    // retrieving the HTTP status code
-    let status = res.status();
-    if status != 200 {
-        // reading the response body as text to extract the error message
-        let text = res.text().await?;
-        bail!("{status}, {text}");
-    }
+async fn send_message(builder: RequestBuilder) -> Result<SendOutput> {
+    // sends the request and checks the HTTP status code, the error message is the response body
+    let res = check_status(builder.send().await?, |_| Ok(())).await?;
     // parsing the response body as json into a Value object
     let data: Value = res.json().await?;
     // extracting the content of the message
     let output = data["message"]["content"]
     .as_str()
     .ok_or_else(|| anyhow!("Invalid response data: {data}"))?;
-Ok(output.to_string())
+    Ok(SendOutput {
+        text: output.to_string(),
+        tool_calls: extract_tool_calls(&data),
+        usage: TokenUsage::from_json(&data, "prompt_eval_count", "eval_count"),
+        finish_reason: data["done_reason"].as_str().map(|v| v.to_string()),
+    })
 }
 
 // similar to above function but is intended for streaming responses
 async fn send_message_streaming(builder: RequestBuilder, handler: &mut ReplyHandler) -> Result<()> {
-    // sends the request using the send method of builder
-    let res = builder.send().await?;
-    // retrieving the HTTP status code
-    let status = res.status();
-    if status != 200 {
-        // reading the response body as text to extract the error message
-        let text = res.text().await?;
-        bail!("{status}, {text}");
-    } else {
-        // initializing a byte stream from the response
-        let mut stream = res.bytes_stream();
-        // iterating over the stream, processing each chunk asynchronously
-        while let Some(chunk) = stream.next().await {
-            let chunk = chunk?;
-            // For each chunk, we deserialize the json data into a Value object
-            let data: Value = serde_json::from_slice(&chunk)?;
-            if data["done"].is_boolean() {
-                if let Some(text) = data["message"]["content"].as_str() {
-                    handler.text(text)?;
-                }
-            } else {
-                bail!("Invalid response data: {data}")
+    // sends the request and checks the HTTP status code, the error message is the response body
+    let res = check_status(builder.send().await?, |_| Ok(())).await?;
+    // initializing a byte stream from the response
+    let mut stream = res.bytes_stream();
+    // iterating over the stream, processing each chunk asynchronously
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk?;
+        // For each chunk, we deserialize the json data into a Value object
+        let data: Value = serde_json::from_slice(&chunk)?;
+        if data["done"].is_boolean() {
+            if let Some(text) = data["message"]["content"].as_str() {
+                handler.text(text)?;
+            }
+            for tool_call in extract_tool_calls(&data) {
+                handler.tool_call(tool_call)?;
+            }
+            if let Some(reason) = data["done_reason"].as_str() {
+                handler.finish_reason(reason)?;
             }
+            // the counts are only given in the last chunk
+            if let Some(usage) = TokenUsage::from_json(&data, "prompt_eval_count", "eval_count") {
+                handler.usage(usage)?;
+            }
+        } else {
+            bail!("Invalid response data: {data}")
         }
     }
     Ok(())
 }
 
+// this function extracts the tool calls of a response message
+// ollama does not give ids to the calls, so the function name is used instead
+fn extract_tool_calls(data: &Value) -> Vec<ToolCall> {
+    data["message"]["tool_calls"]
+        .as_array()
+        .map(|list| {
+            list.iter()
+                .filter_map(|v| {
+                    let name = v["function"]["name"].as_str()?;
+                    Some(ToolCall {
+                        id: name.to_string(),
+                        name: name.to_string(),
+                        arguments: v["function"]["arguments"].clone(),
+                    })
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
 // This function constructs the json body for the request based on the provided data and model
 fn build_body(data: SendData, model: String) -> Result<Value> {
     // destructuring the data object to extract messages, temperature, and stream information
     let SendData {
         mut messages,
         temperature,
+        params,
         stream,
+        tools,
+        schema,
     } = data;
 
     patch_system_message(&mut messages);
@@ -188,6 +274,7 @@ fn build_body(data: SendData, model: String) -> Result<Value> {
                 MessageContent::Array(list) => {
                     let mut content = vec![];
                     let mut images = vec![];
+                    let mut tool_calls = vec![];
                     for item in list {
                         match item {
                             MessageContentPart::Text { text } => {
@@ -205,10 +292,25 @@ fn build_body(data: SendData, model: String) -> Result<Value> {
                                     network_image_urls.push(url.clone());
                                 }
                             }
+                            MessageContentPart::ToolCall { tool_call } => {
+                                tool_calls.push(json!({
+                                    "function": {
+                                        "name": tool_call.name,
+                                        "arguments": tool_call.arguments,
+                                    }
+                                }));
+                            }
+                            MessageContentPart::ToolResult { tool_result } => {
+                                content.push(tool_result.content);
+                            }
                         }
                     }
                     let content = content.join("\n\n");
-                    json!({ "role": role, "content": content, "images": images })
+                    if tool_calls.is_empty() {
+                        json!({ "role": role, "content": content, "images": images })
+                    } else {
+                        json!({ "role": role, "content": content, "tool_calls": tool_calls })
+                    }
                 }
             }
         })
@@ -227,13 +329,56 @@ fn build_body(data: SendData, model: String) -> Result<Value> {
         "stream": stream,
     });
 
-    // If temperature value is provided, we add an options field to the body json object
+    // the temperature, the generation parameters and the provider options go in the options field
+    let mut options = json!({});
     if let Some(temperature) = temperature {
-        body["options"] = json!({
-            "temperature": temperature,
-        });
+        options["temperature"] = temperature.into();
+    }
+    params.insert_into(
+        &mut options,
+        [
+            "top_p",
+            "num_predict",
+            "stop",
+            "seed",
+            "presence_penalty",
+            "frequency_penalty",
+        ],
+    );
+    if options.as_object().is_some_and(|v| !v.is_empty()) {
+        body["options"] = options;
+    }
+
+    // the answer is constrained to a json matching the schema
+    if let Some(schema) = schema {
+        body["format"] = schema;
+    }
+
+    // if there are tools, we declare them as functions
+    if !tools.is_empty() {
+        body["tools"] = tools
+            .iter()
+            .map(|v| json!({ "type": "function", "function": v.declaration() }))
+            .collect();
     }
 
     // returning the constructed json wrapped in a Result
     Ok(body)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_model_details() {
+        let details = json!({
+            "details": { "family": "llama", "families": ["llama", "clip"] },
+            "model_info": { "general.architecture": "llama", "llama.context_length": 4096 },
+        });
+        assert_eq!(parse_model_details(&details), (Some(4096), true));
+        let details = json!({ "capabilities": ["completion"], "model_info": {} });
+        assert_eq!(parse_model_details(&details), (None, false));
+        assert_eq!(parse_model_details(&Value::Null), (None, false));
+    }
+}
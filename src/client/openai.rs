@@ -1,8 +1,12 @@
-use super::{ExtraConfig, Model, OpenAIClient, PromptType, SendData, TokensCountFactors};
+use super::{
+    check_status, patch_schema_message, status_error, DiscoveredModel, ExtraConfig, Message, Model,
+    ModelCapabilities, OpenAIClient, PromptType, SendData, SendOutput, TokenUsage,
+    TokensCountFactors, ToolCall,
+};
 
 use crate::{render::ReplyHandler, utils::PromptKind};
 
-use anyhow::{anyhow, bail, Result};
+use anyhow::{bail, Result};
 use async_trait::async_trait;
 use futures_util::StreamExt;
 use reqwest::{Client as ReqwestClient, RequestBuilder};
@@ -14,15 +18,17 @@ use std::env;
 // defining the base url
 const API_BASE: &str = "https://api.openai.com/v1";
 
-// Array holding all the model names, token count, type of model
-const MODELS: [(&str, usize, &str); 7] = [
-    ("gpt-3.5-turbo", 4096, "text"),
-    ("gpt-3.5-turbo-16k", 16385, "text"),
-    ("gpt-3.5-turbo-1106", 16385, "text"),
-    ("gpt-4", 8192, "text"),
-    ("gpt-4-32k", 32768, "text"),
-    ("gpt-4-1106-preview", 128000, "text"),
-    ("gpt-4-vision-preview", 128000, "text,vision"),
+// Array holding all the model names, token count, type of model, and the input/output prices in USD per 1M tokens
+const MODELS: [(&str, usize, &str, f64, f64); 9] = [
+    ("gpt-3.5-turbo", 4096, "text", 0.5, 1.5),
+    ("gpt-3.5-turbo-16k", 16385, "text", 3.0, 4.0),
+    ("gpt-3.5-turbo-1106", 16385, "text", 1.0, 2.0),
+    ("gpt-4", 8192, "text", 30.0, 60.0),
+    ("gpt-4-32k", 32768, "text", 60.0, 120.0),
+    ("gpt-4-1106-preview", 128000, "text", 10.0, 30.0),
+    ("gpt-4-vision-preview", 128000, "text,vision", 10.0, 30.0),
+    ("text-embedding-3-small", 8191, "embed", 0.02, 0.0),
+    ("text-embedding-3-large", 8191, "embed", 0.13, 0.0),
 ];
 
 // defining the token count factors
@@ -34,6 +40,8 @@ pub struct OpenAIConfig {
     pub name: Option<String>,
     pub api_key: Option<String>,
     pub organization_id: Option<String>,
+    #[serde(default)]
+    pub json_schema: bool, // whether the models constrain the answer to a json schema with `response_format`
     pub extra: Option<ExtraConfig>,
 }
 
@@ -53,13 +61,16 @@ impl OpenAIClient {
         let client_name = Self::name(local_config);
         MODELS
             .into_iter()
-            .map(|(name, max_tokens, capabilities)| {
-                // constructing with capabilities, maximum tokens, and token count factors
-                Model::new(client_name, name)
-                    .set_capabilities(capabilities.into())
-                    .set_max_tokens(Some(max_tokens))
-                    .set_tokens_count_factors(OPENAI_TOKENS_COUNT_FACTORS)
-            })
+            .map(
+                |(name, max_tokens, capabilities, input_price, output_price)| {
+                    // constructing with capabilities, maximum tokens, token count factors and prices
+                    Model::new(client_name, name)
+                        .set_capabilities(capabilities.into())
+                        .set_max_tokens(Some(max_tokens))
+                        .set_tokens_count_factors(OPENAI_TOKENS_COUNT_FACTORS)
+                        .set_prices(Some(input_price), Some(output_price))
+                },
+            )
             .collect()
     }
 
@@ -68,16 +79,17 @@ impl OpenAIClient {
         // retrieving the api key from the client configuration
         let api_key = self.get_api_key()?;
 
-        // building the request body
-        let body = openai_build_body(data, self.model.name.clone());
+        // building the request body, the api reports the usage of a stream,
+        // and the client may tell that all its models take a json schema
+        let mut model = self.model.clone();
+        model.capabilities |= ModelCapabilities::StreamUsage;
+        if self.config.json_schema {
+            model.capabilities |= ModelCapabilities::JsonSchema;
+        }
+        let body = openai_build_body(data, &model);
 
-        let env_prefix = Self::name(&self.config).to_uppercase();
         // constructing the url for the request based on base url, obtained from the environment variables or a default value
-        let api_base = env::var(format!("{env_prefix}_API_BASE"))
-            .ok()
-            .unwrap_or_else(|| API_BASE.to_string());
-
-        let url = format!("{api_base}/chat/completions");
+        let url = format!("{}/chat/completions", self.api_base());
 
         debug!("OpenAI Request: {url} {body}");
 
@@ -91,23 +103,170 @@ impl OpenAIClient {
         // returning the builder wrapped in Result
         Ok(builder)
     }
+
+    // this function constructs a request builder listing the models of the api
+    fn models_request_builder(&self, client: &ReqwestClient) -> Result<RequestBuilder> {
+        let api_key = self.get_api_key()?;
+        let url = format!("{}/models", self.api_base());
+        let mut builder = client.get(url).bearer_auth(api_key);
+        if let Some(organization_id) = &self.config.organization_id {
+            builder = builder.header("OpenAI-Organization", organization_id);
+        }
+        Ok(builder)
+    }
+
+    // this function constructs a request builder turning texts into vectors with the current model
+    fn embeddings_request_builder(
+        &self,
+        client: &ReqwestClient,
+        texts: &[String],
+    ) -> Result<RequestBuilder> {
+        let api_key = self.get_api_key()?;
+        let body = openai_build_embeddings_body(texts, &self.model.name);
+        let url = format!("{}/embeddings", self.api_base());
+        debug!("OpenAI Embeddings Request: {url}");
+        let mut builder = client.post(url).bearer_auth(api_key).json(&body);
+        if let Some(organization_id) = &self.config.organization_id {
+            builder = builder.header("OpenAI-Organization", organization_id);
+        }
+        Ok(builder)
+    }
+
+    // this function returns the base url, from the `<NAME>_API_BASE` environment variable or the default one
+    fn api_base(&self) -> String {
+        let env_prefix = Self::name(&self.config).to_uppercase();
+        env::var(format!("{env_prefix}_API_BASE"))
+            .ok()
+            .unwrap_or_else(|| API_BASE.to_string())
+    }
+}
+
+// the parts of the ids of the listed models that do not chat, like embeddings or speech
+const NON_CHAT_MODEL_PATTERNS: [&str; 10] = [
+    "embed",
+    "whisper",
+    "tts",
+    "dall-e",
+    "moderation",
+    "davinci",
+    "babbage",
+    "transcribe",
+    "realtime",
+    "audio",
+];
+
+// this function lists the chat models of a `/models` endpoint, with the context length and the image input
+// some servers add, like openrouter, vllm or groq
+pub async fn openai_fetch_models(builder: RequestBuilder) -> Result<Vec<DiscoveredModel>> {
+    let res = check_status(builder.send().await?, openai_check_error).await?;
+    let data: Value = res.json().await?;
+    let Some(list) = data["data"].as_array() else {
+        bail!("Invalid response data: {data}");
+    };
+    let models = list
+        .iter()
+        .filter_map(|v| {
+            let id = v["id"].as_str()?;
+            if NON_CHAT_MODEL_PATTERNS.iter().any(|p| id.contains(p)) {
+                return None;
+            }
+            let max_tokens = ["context_length", "context_window", "max_model_len"]
+                .iter()
+                .find_map(|key| v[key].as_u64())
+                .map(|v| v as usize);
+            let architecture = &v["architecture"];
+            let vision = architecture["input_modalities"]
+                .as_array()
+                .is_some_and(|list| list.iter().any(|v| v == "image"))
+                || architecture["modality"]
+                    .as_str()
+                    .and_then(|v| v.split_once("->"))
+                    .is_some_and(|(input, _)| input.contains("image"));
+            Some(DiscoveredModel::new(id, max_tokens, vision))
+        })
+        .collect();
+    Ok(models)
+}
+
+// this function constructs the request body of the `/embeddings` endpoint
+pub fn openai_build_embeddings_body(texts: &[String], model: &str) -> Value {
+    json!({
+        "model": model,
+        "input": texts,
+    })
+}
+
+// this function sends an embeddings request and returns the vectors in the order of the texts
+pub async fn openai_embeddings(builder: RequestBuilder) -> Result<Vec<Vec<f32>>> {
+    let res = check_status(builder.send().await?, openai_check_error).await?;
+    let data: Value = res.json().await?;
+    openai_parse_embeddings(&data)
+}
+
+// this function reads the vectors of an embeddings response, sorted by their index
+fn openai_parse_embeddings(data: &Value) -> Result<Vec<Vec<f32>>> {
+    let Some(list) = data["data"].as_array() else {
+        bail!("Invalid response data: {data}");
+    };
+    let mut items = vec![];
+    for item in list {
+        let Some(vector) = parse_vector(&item["embedding"]) else {
+            bail!("Invalid response data: {data}");
+        };
+        items.push((item["index"].as_u64().unwrap_or_default(), vector));
+    }
+    items.sort_by_key(|(index, _)| *index);
+    Ok(items.into_iter().map(|(_, vector)| vector).collect())
+}
+
+// this function reads a json array of numbers as a vector
+pub fn parse_vector(value: &Value) -> Option<Vec<f32>> {
+    value
+        .as_array()?
+        .iter()
+        .map(|v| v.as_f64().map(|v| v as f32))
+        .collect()
 }
 
 // this function sends the request and parses the json into a Value
-pub async fn openai_send_message(builder: RequestBuilder) -> Result<String> {
-    let data: Value = builder.send().await?.json().await?;
+pub async fn openai_send_message(builder: RequestBuilder) -> Result<SendOutput> {
+    let res = check_status(builder.send().await?, openai_check_error).await?;
+    let data: Value = res.json().await?;
     // checking if there's an error message in the response. If there is, return an error
-    if let Some(err_msg) = data["error"]["message"].as_str() {
-        bail!("{err_msg}");
-    }
+    openai_check_error(&data)?;
 
-    // extracting the message content from the response
-    let output = data["choices"][0]["message"]["content"]
-        .as_str()
-        .ok_or_else(|| anyhow!("Invalid response data: {data}"))?;
+    let message = &data["choices"][0]["message"];
+    let finish_reason = data["choices"][0]["finish_reason"].as_str();
 
-    // return it as a string
-    Ok(output.to_string())
+    // extracting the tool calls from the response
+    let tool_calls: Vec<ToolCall> = message["tool_calls"]
+        .as_array()
+        .map(|list| {
+            list.iter()
+                .map(|v| {
+                    ToolCall::new(
+                        v["id"].as_str().unwrap_or_default(),
+                        v["function"]["name"].as_str().unwrap_or_default(),
+                        v["function"]["arguments"].as_str().unwrap_or_default(),
+                    )
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    // extracting the message content from the response, which is null when only tools are called
+    let output = match message["content"].as_str() {
+        Some(v) => v,
+        None if !tool_calls.is_empty() => "",
+        None => bail!("Invalid response data: {data}"),
+    };
+
+    Ok(SendOutput {
+        text: output.to_string(),
+        tool_calls,
+        usage: TokenUsage::from_json(&data["usage"], "prompt_tokens", "completion_tokens"),
+        finish_reason: finish_reason.map(|v| v.to_string()),
+    })
 }
 
 // 
@@ -116,6 +275,8 @@ pub async fn openai_send_message_streaming(
     handler: &mut ReplyHandler,
 ) -> Result<()> {
     let mut es = builder.eventsource()?;
+    // the tool calls arrive in fragments, we gather (id, name, arguments) by their index
+    let mut tool_calls: Vec<(String, String, String)> = vec![];
     // it enters a loop to process events received from the event source
     while let Some(event) = es.next().await {
         match event {
@@ -129,23 +290,47 @@ pub async fn openai_send_message_streaming(
                 }
                 // serialize the message content
                 let data: Value = serde_json::from_str(&message.data)?;
-                if let Some(text) = data["choices"][0]["delta"]["content"].as_str() {
+                // the usage comes in a last chunk without choices
+                if let Some(usage) =
+                    TokenUsage::from_json(&data["usage"], "prompt_tokens", "completion_tokens")
+                {
+                    handler.usage(usage)?;
+                }
+                if let Some(reason) = data["choices"][0]["finish_reason"].as_str() {
+                    handler.finish_reason(reason)?;
+                }
+                let delta = &data["choices"][0]["delta"];
+                if let Some(text) = delta["content"].as_str() {
                     handler.text(text)?;
                 }
+                if let Some(list) = delta["tool_calls"].as_array() {
+                    for item in list {
+                        let index = item["index"].as_u64().unwrap_or_default() as usize;
+                        if tool_calls.len() <= index {
+                            tool_calls.resize(index + 1, Default::default());
+                        }
+                        let (id, name, arguments) = &mut tool_calls[index];
+                        if let Some(v) = item["id"].as_str() {
+                            id.push_str(v);
+                        }
+                        if let Some(v) = item["function"]["name"].as_str() {
+                            name.push_str(v);
+                        }
+                        if let Some(v) = item["function"]["arguments"].as_str() {
+                            arguments.push_str(v);
+                        }
+                    }
+                }
             }
             // if there is an error, we classify the error and exit using the bail macro
             Err(err) => {
                 match err {
                     EventSourceError::InvalidStatusCode(_, res) => {
-                        let data: Value = res.json().await?;
-                        if let Some(err_msg) = data["error"]["message"].as_str() {
-                            bail!("{err_msg}");
-                        }
-                        bail!("Request failed");
+                        return Err(status_error(res, openai_check_error).await);
                     }
                     EventSourceError::StreamEnded => {}
                     _ => {
-                        bail!("{}", err);
+                        return Err(err.into());
                     }
                 }
                 // closing the event source
@@ -154,27 +339,45 @@ pub async fn openai_send_message_streaming(
         }
     }
 
+    for (id, name, arguments) in tool_calls {
+        handler.tool_call(ToolCall::new(&id, &name, &arguments))?;
+    }
+
     // returing a Ok(())
     Ok(())
 }
 
 // this function constructs the request body for sending messages to OpenAI api
-pub fn openai_build_body(data: SendData, model: String) -> Value {
+pub fn openai_build_body(data: SendData, model: &Model) -> Value {
     // destructuring the data object to extract messages, temperature, and stream information
     let SendData {
-        messages,
+        mut messages,
         temperature,
+        params,
         stream,
+        tools,
+        schema,
     } = data;
 
+    // not every openai-compatible api knows `response_format`, the other models are asked for the schema in the prompt
+    let schema = match schema {
+        Some(schema) if !model.capabilities.contains(ModelCapabilities::JsonSchema) => {
+            patch_schema_message(&mut messages, &schema);
+            None
+        }
+        schema => schema,
+    };
+
+    let messages: Vec<Value> = messages.into_iter().map(openai_build_message).collect();
+
     // constructing the body
     let mut body = json!({
-        "model": model, // model to be used
+        "model": model.name, // model to be used
         "messages": messages, // vector of messages to be processed
     });
 
     // The default max_tokens of gpt-4-vision-preview is only 16, we need to make it larger
-    if model == "gpt-4-vision-preview" {
+    if model.name == "gpt-4-vision-preview" {
         body["max_tokens"] = json!(4096);
     }
 
@@ -182,10 +385,267 @@ pub fn openai_build_body(data: SendData, model: String) -> Value {
     if let Some(v) = temperature {
         body["temperature"] = v.into();
     }
-    // if stream is true, we add it to the body
+    // the answer is constrained to a json matching the schema
+    if let Some(schema) = schema {
+        body["response_format"] = json!({
+            "type": "json_schema",
+            "json_schema": { "name": "response", "schema": schema },
+        });
+    }
+    // the generation parameters that are set, a max_tokens replacing the one of the vision model
+    params.insert_into(
+        &mut body,
+        [
+            "top_p",
+            "max_tokens",
+            "stop",
+            "seed",
+            "presence_penalty",
+            "frequency_penalty",
+        ],
+    );
+    // if stream is true, we add it to the body, the usage is only sent at the end of a stream when it is asked for,
+    // which older or stricter openai-compatible apis reject
     if stream {
         body["stream"] = true.into();
+        if model.capabilities.contains(ModelCapabilities::StreamUsage) {
+            body["stream_options"] = json!({ "include_usage": true });
+        }
+    }
+    // if there are tools, we declare them as functions
+    if !tools.is_empty() {
+        body["tools"] = tools
+            .iter()
+            .map(|v| json!({ "type": "function", "function": v.declaration() }))
+            .collect();
     }
     // returning the body
     body
 }
+
+// this function extracts the error message of an openai response
+pub fn openai_check_error(data: &Value) -> Result<()> {
+    if let Some(err_msg) = data["error"]["message"].as_str() {
+        bail!("{err_msg}");
+    }
+    Ok(())
+}
+
+// this function converts a message to json, mapping tool calls and results to the openai format
+pub fn openai_build_message(message: Message) -> Value {
+    if let Some(tool_result) = message.tool_result() {
+        return json!({
+            "role": "tool",
+            "tool_call_id": tool_result.id,
+            "content": tool_result.content,
+        });
+    }
+    let tool_calls = message.tool_calls();
+    if tool_calls.is_empty() {
+        return json!(message);
+    }
+    let tool_calls: Vec<Value> = tool_calls
+        .into_iter()
+        .map(|v| {
+            json!({
+                "id": v.id,
+                "type": "function",
+                "function": {
+                    "name": v.name,
+                    "arguments": v.arguments_string(),
+                }
+            })
+        })
+        .collect();
+    let text = message.content.to_text();
+    json!({
+        "role": message.role,
+        "content": if text.is_empty() { Value::Null } else { text.into() },
+        "tool_calls": tool_calls,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{mock_server, MessageContent, MessageContentPart, MessageRole, ToolResult};
+    use crate::config::Input;
+
+    #[test]
+    fn test_build_body_with_tools() {
+        let mut input = Input::from_str("What time is it?");
+        input.add_tool_calls(
+            String::new(),
+            vec![ToolCall::new("call_1", "get_time", "{}")],
+            vec![ToolResult {
+                id: "call_1".into(),
+                name: "get_time".into(),
+                content: "12:00".into(),
+            }],
+        );
+        let mut messages = vec![Message::new(&input)];
+        messages.extend(input.tool_messages().iter().cloned());
+        let data = SendData {
+            messages,
+            temperature: None,
+            params: Default::default(),
+            stream: false,
+            tools: serde_yaml::from_str("[{ name: get_time, command: date }]").unwrap(),
+            schema: None,
+        };
+        let body = openai_build_body(data, &Model::new("openai", "gpt-4"));
+        assert_eq!(
+            body["messages"],
+            json!([
+                { "role": "user", "content": "What time is it?" },
+                {
+                    "role": "assistant",
+                    "content": null,
+                    "tool_calls": [{
+                        "id": "call_1",
+                        "type": "function",
+                        "function": { "name": "get_time", "arguments": "{}" }
+                    }]
+                },
+                { "role": "tool", "tool_call_id": "call_1", "content": "12:00" }
+            ])
+        );
+        assert_eq!(body["tools"][0]["function"]["name"], "get_time");
+    }
+
+    #[test]
+    fn test_build_body_stream() {
+        let data = SendData {
+            messages: vec![Message::new(&Input::from_str("hi"))],
+            temperature: None,
+            params: Default::default(),
+            stream: true,
+            tools: vec![],
+            schema: None,
+        };
+        let model = Model::new("openai", "gpt-4").set_capabilities("text,stream_usage".into());
+        let body = openai_build_body(data, &model);
+        assert_eq!(body["stream"], true);
+        assert_eq!(body["stream_options"], json!({ "include_usage": true }));
+    }
+
+    #[test]
+    fn test_build_body_stream_without_usage() {
+        for model in [
+            Model::new("azure-openai", "gpt-35-turbo"),
+            Model::new("openai-compatible", "llama3"),
+        ] {
+            let data = SendData {
+                messages: vec![Message::new(&Input::from_str("hi"))],
+                temperature: None,
+                params: Default::default(),
+                stream: true,
+                tools: vec![],
+                schema: None,
+            };
+            let body = openai_build_body(data, &model);
+            assert_eq!(body["stream"], true);
+            assert_eq!(body.get("stream_options"), None);
+        }
+    }
+
+    #[test]
+    fn test_build_body_schema_in_prompt() {
+        let data = SendData {
+            messages: vec![Message::new(&Input::from_str("hi"))],
+            temperature: None,
+            params: Default::default(),
+            stream: false,
+            tools: vec![],
+            schema: Some(json!({ "type": "object" })),
+        };
+        let body = openai_build_body(data, &Model::new("local", "llama3"));
+        assert_eq!(body.get("response_format"), None);
+        let content = body["messages"][0]["content"].as_str().unwrap();
+        assert!(content.starts_with("hi\n") && content.ends_with(r#"{"type":"object"}"#));
+    }
+
+    #[test]
+    fn test_build_body_with_params() {
+        let data = SendData {
+            messages: vec![Message::new(&Input::from_str("hi"))],
+            temperature: Some(0.2),
+            params: serde_yaml::from_str(
+                "top_p: 0.9\nmax_output_tokens: 256\nstop: ['###']\nseed: 7\nfrequency_penalty: 0.5\nprovider_options: { logit_bias: {} }",
+            )
+            .unwrap(),
+            stream: false,
+            tools: vec![],
+            schema: Some(json!({ "type": "object" })),
+        };
+        let model = Model::new("openai", "gpt-4-vision-preview")
+            .set_capabilities("text,vision,json_schema".into());
+        let body = openai_build_body(data, &model);
+        assert_eq!(body["temperature"], 0.2);
+        assert_eq!(body["top_p"], 0.9);
+        assert_eq!(body["max_tokens"], 256);
+        assert_eq!(body["stop"], json!(["###"]));
+        assert_eq!(body["seed"], 7);
+        assert_eq!(body.get("presence_penalty"), None);
+        assert_eq!(body["frequency_penalty"], 0.5);
+        assert_eq!(body["logit_bias"], json!({}));
+        assert_eq!(body.get("stream_options"), None);
+        assert_eq!(body["response_format"]["type"], "json_schema");
+        assert_eq!(
+            body["response_format"]["json_schema"]["schema"],
+            json!({ "type": "object" })
+        );
+    }
+
+    #[test]
+    fn test_fetch_models() {
+        let body = r#"{"data":[
+            {"id":"gpt-4o"},
+            {"id":"text-embedding-3-small"},
+            {"id":"anthropic/claude-3-haiku","context_length":200000,"architecture":{"modality":"text+image->text"}},
+            {"id":"llama-3-70b","max_model_len":8192}
+        ]}"#;
+        let url = mock_server(&[("200 OK", "application/json", body)]);
+        let models = crate::utils::init_tokio_runtime()
+            .unwrap()
+            .block_on(openai_fetch_models(ReqwestClient::new().get(url)))
+            .unwrap();
+        assert_eq!(
+            models,
+            [
+                DiscoveredModel::new("gpt-4o", None, false),
+                DiscoveredModel::new("anthropic/claude-3-haiku", Some(200000), true),
+                DiscoveredModel::new("llama-3-70b", Some(8192), false),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_embeddings() {
+        let texts = vec!["a".to_string(), "b".to_string()];
+        assert_eq!(
+            openai_build_embeddings_body(&texts, "text-embedding-3-small"),
+            json!({ "model": "text-embedding-3-small", "input": ["a", "b"] })
+        );
+        // the vectors may come in another order than the texts
+        let body = r#"{"data":[{"index":1,"embedding":[0.5,1]},{"index":0,"embedding":[1,0]}]}"#;
+        let url = mock_server(&[("200 OK", "application/json", body)]);
+        let vectors = crate::utils::init_tokio_runtime()
+            .unwrap()
+            .block_on(openai_embeddings(ReqwestClient::new().post(url)))
+            .unwrap();
+        assert_eq!(vectors, [vec![1.0, 0.0], vec![0.5, 1.0]]);
+    }
+
+    #[test]
+    fn test_build_message_text_parts() {
+        let message = Message {
+            role: MessageRole::User,
+            content: MessageContent::Array(vec![MessageContentPart::Text { text: "hi".into() }]),
+        };
+        assert_eq!(
+            openai_build_message(message),
+            json!({ "role": "user", "content": [{ "type": "text", "text": "hi" }] })
+        );
+    }
+}
@@ -0,0 +1,241 @@
+use super::openai::{openai_build_body, openai_build_embeddings_body, OPENAI_TOKENS_COUNT_FACTORS};
+use super::{ExtraConfig, Model, ModelConfig, OpenAICompatibleClient, PromptType, SendData};
+
+use crate::utils::PromptKind;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use reqwest::{Client as ReqwestClient, RequestBuilder};
+use serde::Deserialize;
+use std::collections::HashMap;
+
+// the chat endpoint used when neither the client nor the model overrides it
+const DEFAULT_CHAT_ENDPOINT: &str = "/chat/completions";
+
+// struct representing the configuration for a generic openai-compatible api
+#[derive(Debug, Clone, Deserialize)]
+pub struct OpenAICompatibleConfig {
+    pub name: Option<String>,
+    pub api_base: String,        // base URL for api endpoints
+    pub api_key: Option<String>, // api key used for authentication
+    #[serde(default)]
+    pub auth: AuthScheme, // how the api key is sent to the server
+    pub auth_header: Option<String>, // header name used when auth is `header`
+    pub chat_endpoint: Option<String>, // optional endpoint for chat
+    #[serde(default)]
+    pub headers: HashMap<String, String>, // extra headers sent with every request
+    #[serde(default)]
+    pub query: HashMap<String, String>, // extra query parameters, e.g. api-version
+    #[serde(default)]
+    pub models: Vec<ModelConfig>, // models served by the api, `--refresh-models` can discover the others
+    pub extra: Option<ExtraConfig>, // Optional extra configurations
+}
+
+// enum representing the ways an api key can be attached to a request
+#[derive(Debug, Clone, Copy, Deserialize, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthScheme {
+    // `Authorization: Bearer <api_key>`
+    #[default]
+    Bearer,
+    // `<auth_header>: <api_key>`
+    Header,
+    // the api key is not sent at all
+    None,
+}
+
+// macro invocation generates an implementation of the Client trait for OpenAICompatibleClient
+openai_compatible_client!(OpenAICompatibleClient);
+
+impl OpenAICompatibleClient {
+    // this macro invocation generates a function named get_api_key for retrieving the api key from the config
+    config_get_fn!(api_key, get_api_key);
+
+    // constant array defines prompts for collecting user input
+    pub const PROMPTS: [PromptType<'static>; 4] = [
+        ("api_base", "API Base:", true, PromptKind::String),
+        ("api_key", "API Key:", false, PromptKind::String),
+        ("models[].name", "Model Name:", true, PromptKind::String),
+        (
+            "models[].max_tokens",
+            "Max Tokens:",
+            false,
+            PromptKind::Integer,
+        ),
+    ];
+
+    // this function generates a list of Model instances based on the provided configurations
+    pub fn list_models(local_config: &OpenAICompatibleConfig) -> Vec<Model> {
+        let client_name = Self::name(local_config);
+
+        local_config
+            .models
+            .iter()
+            .map(|v| {
+                Model::new(client_name, &v.name)
+                    .set_capabilities(v.capabilities)
+                    .set_max_tokens(v.max_tokens)
+                    .set_tokens_count_factors(OPENAI_TOKENS_COUNT_FACTORS)
+                    .set_prices(v.input_price, v.output_price)
+                    .set_tokenizer(v.tokenizer.clone())
+            })
+            .collect()
+    }
+
+    // this function constructs a request builder from the configured endpoint, auth, headers and query
+    fn request_builder(&self, client: &ReqwestClient, data: SendData) -> Result<RequestBuilder> {
+        let body = openai_build_body(data, &self.model);
+
+        // a model level endpoint takes precedence over the client level one
+        let chat_endpoint = self
+            .config
+            .models
+            .iter()
+            .find(|v| v.name == self.model.name)
+            .and_then(|v| v.chat_endpoint.as_deref())
+            .or(self.config.chat_endpoint.as_deref())
+            .unwrap_or(DEFAULT_CHAT_ENDPOINT);
+
+        let url = format!("{}{chat_endpoint}", self.config.api_base);
+
+        debug!("OpenAICompatible Request: {url} {body}");
+
+        self.authorize(client.post(url).json(&body))
+    }
+
+    // this function constructs a request builder listing the models of the api
+    fn models_request_builder(&self, client: &ReqwestClient) -> Result<RequestBuilder> {
+        let url = format!("{}/models", self.config.api_base);
+        self.authorize(client.get(url))
+    }
+
+    // this function constructs a request builder turning texts into vectors with the current model
+    fn embeddings_request_builder(
+        &self,
+        client: &ReqwestClient,
+        texts: &[String],
+    ) -> Result<RequestBuilder> {
+        let body = openai_build_embeddings_body(texts, &self.model.name);
+        let url = format!("{}/embeddings", self.config.api_base);
+        debug!("OpenAICompatible Embeddings Request: {url}");
+        self.authorize(client.post(url).json(&body))
+    }
+
+    // this function adds the configured query, headers and api key to a request
+    fn authorize(&self, mut builder: RequestBuilder) -> Result<RequestBuilder> {
+        if !self.config.query.is_empty() {
+            builder = builder.query(&self.config.query);
+        }
+
+        for (key, value) in &self.config.headers {
+            builder = builder.header(key, value);
+        }
+
+        match self.config.auth {
+            AuthScheme::Bearer => {
+                if let Ok(api_key) = self.get_api_key() {
+                    builder = builder.bearer_auth(api_key);
+                }
+            }
+            AuthScheme::Header => {
+                let api_key = self.get_api_key()?;
+                let header = self.config.auth_header.as_deref().unwrap_or("api-key");
+                builder = builder.header(header, api_key);
+            }
+            AuthScheme::None => {}
+        }
+
+        Ok(builder)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{Message, MessageContent, MessageRole};
+    use crate::config::Config;
+
+    use parking_lot::RwLock;
+    use std::sync::Arc;
+
+    // this function creates a client from a yaml snippet and a model name
+    fn create_client(yaml: &str, model: &str) -> OpenAICompatibleClient {
+        let config: OpenAICompatibleConfig = serde_yaml::from_str(yaml).unwrap();
+        let model = Model::new(OpenAICompatibleClient::name(&config), model);
+        OpenAICompatibleClient {
+            global_config: Arc::new(RwLock::new(Config::default())),
+            config,
+            model,
+        }
+    }
+
+    fn send_data() -> SendData {
+        SendData {
+            messages: vec![Message {
+                role: MessageRole::User,
+                content: MessageContent::Text("hello".into()),
+            }],
+            temperature: None,
+            params: Default::default(),
+            stream: false,
+            tools: vec![],
+            schema: None,
+        }
+    }
+
+    #[test]
+    fn test_request_builder() {
+        let client = create_client(
+            r#"
+api_base: http://localhost:8000/v1
+api_key: secret
+auth: header
+auth_header: x-api-key
+headers:
+  x-team: infra
+query:
+  api-version: "2024-02-01"
+models:
+  - name: llama
+  - name: mixtral
+    chat_endpoint: /mixtral/chat
+"#,
+            "mixtral",
+        );
+        let request = client
+            .request_builder(&ReqwestClient::new(), send_data())
+            .unwrap()
+            .build()
+            .unwrap();
+        assert_eq!(
+            request.url().as_str(),
+            "http://localhost:8000/v1/mixtral/chat?api-version=2024-02-01"
+        );
+        assert_eq!(request.headers()["x-api-key"], "secret");
+        assert_eq!(request.headers()["x-team"], "infra");
+        assert!(request.headers().get("authorization").is_none());
+    }
+
+    #[test]
+    fn test_request_builder_default() {
+        let client = create_client(
+            r#"
+api_base: http://localhost:8000/v1
+api_key: secret
+models:
+  - name: llama
+"#,
+            "llama",
+        );
+        let request = client
+            .request_builder(&ReqwestClient::new(), send_data())
+            .unwrap()
+            .build()
+            .unwrap();
+        assert_eq!(
+            request.url().as_str(),
+            "http://localhost:8000/v1/chat/completions"
+        );
+        assert_eq!(request.headers()["authorization"], "Bearer secret");
+    }
+}
@@ -0,0 +1,205 @@
+// This file defines the generation parameters sent along with the temperature, which the config, a role,
+// a session, the command line and `.set` can give
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+// the names of the parameters given to `.set`
+pub const PARAM_KEYS: [&str; 7] = [
+    "top_p",
+    "max_output_tokens",
+    "stop",
+    "seed",
+    "presence_penalty",
+    "frequency_penalty",
+    "provider_options",
+];
+
+// the names a provider gives to top_p, max_output_tokens, stop, seed, presence_penalty and frequency_penalty,
+// an empty name for a parameter it does not support
+pub type ParamNames<'a> = [&'a str; 6];
+
+// struct representing the generation parameters, a parameter that is not set is not sent
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct GenerationParams {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_output_tokens: Option<usize>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub stop: Vec<String>, // the sequences that end the answer
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub presence_penalty: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f64>,
+    // options passed as they are, in the `options` of ollama, the `generationConfig` of gemini,
+    // the `parameters` of qianwen and the body of the others
+    #[serde(default, skip_serializing_if = "Map::is_empty")]
+    pub provider_options: Map<String, Value>,
+}
+
+impl GenerationParams {
+    // this function returns the parameters with the ones set in `other` replacing them
+    pub fn merge(&self, other: &Self) -> Self {
+        let mut provider_options = self.provider_options.clone();
+        provider_options.extend(other.provider_options.clone());
+        Self {
+            top_p: other.top_p.or(self.top_p),
+            max_output_tokens: other.max_output_tokens.or(self.max_output_tokens),
+            stop: if other.stop.is_empty() {
+                self.stop.clone()
+            } else {
+                other.stop.clone()
+            },
+            seed: other.seed.or(self.seed),
+            presence_penalty: other.presence_penalty.or(self.presence_penalty),
+            frequency_penalty: other.frequency_penalty.or(self.frequency_penalty),
+            provider_options,
+        }
+    }
+
+    // this function sets a parameter from the text given to `.set`, `null` unsets it,
+    // the stop sequences are separated by commas and the provider options are a json object
+    pub fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        let unset = value == "null";
+        fn parse<T: std::str::FromStr>(value: &str, unset: bool) -> Result<Option<T>> {
+            if unset {
+                return Ok(None);
+            }
+            let value = value.parse().ok().with_context(|| "Invalid value")?;
+            Ok(Some(value))
+        }
+        match key {
+            "top_p" => self.top_p = parse(value, unset)?,
+            "max_output_tokens" => self.max_output_tokens = parse(value, unset)?,
+            "stop" => {
+                self.stop = if unset {
+                    vec![]
+                } else {
+                    value.split(',').map(|v| v.to_string()).collect()
+                }
+            }
+            "seed" => self.seed = parse(value, unset)?,
+            "presence_penalty" => self.presence_penalty = parse(value, unset)?,
+            "frequency_penalty" => self.frequency_penalty = parse(value, unset)?,
+            "provider_options" => {
+                self.provider_options = if unset {
+                    Map::new()
+                } else {
+                    serde_json::from_str(value)
+                        .with_context(|| "Invalid value, expected a json object")?
+                }
+            }
+            _ => bail!("Unknown key `{key}`"),
+        }
+        Ok(())
+    }
+
+    // this function returns the value of every parameter for `.info`, `-` when it is not set
+    pub fn items(&self) -> Vec<(&'static str, String)> {
+        let show = |v: Option<String>| v.unwrap_or_else(|| "-".into());
+        vec![
+            ("top_p", show(self.top_p.map(|v| v.to_string()))),
+            (
+                "max_output_tokens",
+                show(self.max_output_tokens.map(|v| v.to_string())),
+            ),
+            (
+                "stop",
+                show((!self.stop.is_empty()).then(|| format!("{:?}", self.stop))),
+            ),
+            ("seed", show(self.seed.map(|v| v.to_string()))),
+            (
+                "presence_penalty",
+                show(self.presence_penalty.map(|v| v.to_string())),
+            ),
+            (
+                "frequency_penalty",
+                show(self.frequency_penalty.map(|v| v.to_string())),
+            ),
+            (
+                "provider_options",
+                show(
+                    (!self.provider_options.is_empty())
+                        .then(|| Value::Object(self.provider_options.clone()).to_string()),
+                ),
+            ),
+        ]
+    }
+
+    // this function adds the parameters that are set to a json object under the names of the provider,
+    // followed by the provider options
+    pub fn insert_into(&self, target: &mut Value, names: ParamNames) {
+        let [top_p, max_output_tokens, stop, seed, presence_penalty, frequency_penalty] = names;
+        let mut insert = |name: &str, value: Option<Value>| {
+            if let (false, Some(value)) = (name.is_empty(), value) {
+                target[name] = value;
+            }
+        };
+        insert(top_p, self.top_p.map(Into::into));
+        insert(max_output_tokens, self.max_output_tokens.map(Into::into));
+        insert(
+            stop,
+            (!self.stop.is_empty()).then(|| self.stop.clone().into()),
+        );
+        insert(seed, self.seed.map(Into::into));
+        insert(presence_penalty, self.presence_penalty.map(Into::into));
+        insert(frequency_penalty, self.frequency_penalty.map(Into::into));
+        for (key, value) in &self.provider_options {
+            target[key] = value.clone();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_merge_and_set() {
+        let mut config = GenerationParams::default();
+        config.set("max_output_tokens", "512").unwrap();
+        config.set("stop", "###,END").unwrap();
+        config
+            .set("provider_options", r#"{"num_ctx":4096}"#)
+            .unwrap();
+        assert_eq!(config.stop, ["###", "END"]);
+        assert!(config.set("seed", "-1").is_err());
+        assert!(config.set("provider_options", "[1]").is_err());
+        assert!(config.set("top_k", "40").is_err());
+
+        let role: GenerationParams =
+            serde_yaml::from_str("seed: 42\nprovider_options: { mirostat: 1 }").unwrap();
+        let params = config.merge(&role);
+        assert_eq!(params.seed, Some(42));
+        assert_eq!(params.max_output_tokens, Some(512));
+        assert_eq!(
+            Value::Object(params.provider_options.clone()),
+            json!({ "num_ctx": 4096, "mirostat": 1 })
+        );
+
+        let mut params = params;
+        params.set("max_output_tokens", "null").unwrap();
+        assert_eq!(params.max_output_tokens, None);
+    }
+
+    #[test]
+    fn test_insert_into() {
+        let params = GenerationParams {
+            top_p: Some(0.9),
+            max_output_tokens: Some(100),
+            stop: vec!["END".into()],
+            seed: Some(7),
+            ..Default::default()
+        };
+        let mut body = json!({});
+        params.insert_into(&mut body, ["topP", "maxOutputTokens", "", "seed", "", ""]);
+        assert_eq!(
+            body,
+            json!({ "topP": 0.9, "maxOutputTokens": 100, "seed": 7 })
+        );
+    }
+}
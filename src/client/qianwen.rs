@@ -1,4 +1,7 @@
-use super::{message::*, Client, ExtraConfig, Model, PromptType, QianwenClient, SendData};
+use super::{
+    check_status, message::*, patch_schema_message, status_error, Client, ExtraConfig, Model,
+    PromptType, QianwenClient, SendData, SendOutput, TokenUsage,
+};
 
 use crate::{
     render::ReplyHandler,
@@ -54,7 +57,7 @@ impl Client for QianwenClient {
         &self,
         client: &ReqwestClient,
         mut data: SendData,
-    ) -> Result<String> {
+    ) -> Result<SendOutput> {
         // retrieving the api key
         let api_key = self.get_api_key()?;
         // patching the messages with the model name and api key
@@ -147,8 +150,9 @@ impl QianwenClient {
 
 // this function handles sending a message with a single response
 // it sends the request,
-async fn send_message(builder: RequestBuilder, is_vl: bool) -> Result<String> {
-    let data: Value = builder.send().await?.json().await?;
+async fn send_message(builder: RequestBuilder, is_vl: bool) -> Result<SendOutput> {
+    let res = check_status(builder.send().await?, check_error).await?;
+    let data: Value = res.json().await?;
     check_error(&data)?;
 
     // processes the response json, extracts the message content as output
@@ -159,9 +163,15 @@ async fn send_message(builder: RequestBuilder, is_vl: bool) -> Result<String> {
     };
 
     let output = output.ok_or_else(|| anyhow!("Unexpected response {data}"))?;
-
-    // returning the output as string
-    Ok(output.to_string())
+    let finish_reason = finish_reason(&data, is_vl);
+
+    // returning the output with the token usage
+    Ok(SendOutput {
+        text: output.to_string(),
+        tool_calls: vec![],
+        usage: TokenUsage::from_json(&data["usage"], "input_tokens", "output_tokens"),
+        finish_reason: finish_reason.map(|v| v.to_string()),
+    })
 }
 
 // this function handles sending a message with streaming responses
@@ -193,13 +203,25 @@ async fn send_message_streaming(
                 } else if let Some(text) = data["output"]["text"].as_str() {
                     handler.text(text)?;
                 }
+                if let Some(reason) = finish_reason(&data, is_vl) {
+                    handler.finish_reason(reason)?;
+                }
+                // every message carries the counts so far
+                if let Some(usage) =
+                    TokenUsage::from_json(&data["usage"], "input_tokens", "output_tokens")
+                {
+                    handler.usage(usage)?;
+                }
             }
             // checking for errors
             Err(err) => {
                 match err {
+                    EventSourceError::InvalidStatusCode(_, res) => {
+                        return Err(status_error(res, check_error).await);
+                    }
                     EventSourceError::StreamEnded => {}
                     _ => {
-                        bail!("{}", err);
+                        return Err(err.into());
                     }
                 }
                 // closing es before closing
@@ -211,6 +233,16 @@ async fn send_message_streaming(
     Ok(())
 }
 
+// this function returns why the model stopped, the api gives `null` as a string until the end of a stream
+fn finish_reason(data: &Value, is_vl: bool) -> Option<&str> {
+    let reason = if is_vl {
+        data["output"]["choices"][0]["finish_reason"].as_str()
+    } else {
+        data["output"]["finish_reason"].as_str()
+    };
+    reason.filter(|v| *v != "null")
+}
+
 // This function is a utility function for seeing the error
 fn check_error(data: &Value) -> Result<()> {
     if let (Some(code), Some(message)) = (data["code"].as_str(), data["message"].as_str()) {
@@ -224,14 +256,22 @@ fn check_error(data: &Value) -> Result<()> {
 fn build_body(data: SendData, model: String, is_vl: bool) -> Result<(Value, bool)> {
     // Retriving the data from the data variable
     let SendData {
-        messages,
+        mut messages,
         temperature,
+        params,
         stream,
+        schema,
+        ..
     } = data;
 
+    // qianwen has no json mode, the schema is asked for in the prompt
+    if let Some(schema) = &schema {
+        patch_schema_message(&mut messages, schema);
+    }
+
     let mut has_upload = false;
     // constructing different inputs and parameters object, depending on is_vl
-    let (input, parameters) = if is_vl {
+    let (input, mut parameters) = if is_vl {
         // iterating over each message for constructing json objects representing the message content
         let messages: Vec<Value> = messages
             .into_iter()
@@ -252,6 +292,13 @@ fn build_body(data: SendData, model: String, is_vl: bool) -> Result<(Value, bool
                                 }
                                 json!({"image": url})
                             }
+                            // tools are not declared to this client, so past calls are kept as text
+                            MessageContentPart::ToolCall { tool_call } => json!({
+                                "text": format!("Call tool '{}' with {}", tool_call.name, tool_call.arguments)
+                            }),
+                            MessageContentPart::ToolResult { tool_result } => {
+                                json!({"text": tool_result.content})
+                            }
                         })
                         .collect(),
                 };
@@ -285,6 +332,17 @@ fn build_body(data: SendData, model: String, is_vl: bool) -> Result<(Value, bool
         }
         (input, parameters)
     };
+    params.insert_into(
+        &mut parameters,
+        [
+            "top_p",
+            "max_tokens",
+            "stop",
+            "seed",
+            "presence_penalty",
+            "",
+        ],
+    );
 
     // constructing the overall request json containing the model, input, and parameters
     let body = json!({
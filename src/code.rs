@@ -0,0 +1,112 @@
+// This file implements the `--code` mode, where the model answers with code only, whose fenced blocks are
+// printed without their fences, or written to files with `--code-out`
+use crate::client::send_with_fallback;
+use crate::config::{GlobalConfig, Input, Role};
+use crate::render::{extract_code_blocks, CodeBlock, MarkdownRender, RenderOptions};
+
+use anyhow::{bail, Context, Result};
+use std::fs::{create_dir_all, write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+// this function answers the input with code only, and prints the code or writes each block to a file of the dir
+pub fn run(config: &GlobalConfig, input: Input, out_dir: Option<&Path>) -> Result<()> {
+    let code_config = config.read().with_builtin_role(code_role());
+    let start = Instant::now();
+    let output = send_with_fallback(&code_config, &input, |input, client| {
+        client.send_message(input.clone())
+    })?;
+    if config.read().dry_run {
+        println!("{}", output.text);
+        return Ok(());
+    }
+    code_config
+        .write()
+        .save_message(input, &output.text, output.usage, start.elapsed())?;
+    let mut blocks = extract_code_blocks(&output.text);
+    // the answer is the code itself when it has no fence
+    if blocks.is_empty() && !output.text.trim().is_empty() {
+        blocks.push(CodeBlock {
+            language: String::new(),
+            code: format!("{}\n", output.text.trim()),
+        });
+    }
+    if blocks.is_empty() {
+        bail!("No code in the answer");
+    }
+    match out_dir {
+        Some(dir) => {
+            for path in write_code_blocks(dir, &blocks)? {
+                println!("{}", path.display());
+            }
+        }
+        None => {
+            let code: Vec<&str> = blocks.iter().map(|v| v.code.as_str()).collect();
+            print!("{}", code.join("\n"));
+        }
+    }
+    Ok(())
+}
+
+// this function writes the blocks to `code-<n>.<ext>` files of the dir, the extension comes from the language
+// of the fence, `txt` when it has none or is unknown. The files of the same name are replaced
+fn write_code_blocks(dir: &Path, blocks: &[CodeBlock]) -> Result<Vec<PathBuf>> {
+    create_dir_all(dir).with_context(|| format!("Failed to create dir '{}'", dir.display()))?;
+    let render = MarkdownRender::init(RenderOptions::default())?;
+    let mut paths = vec![];
+    for (i, block) in blocks.iter().enumerate() {
+        let extension = match block.language.is_empty() {
+            true => None,
+            false => render.code_extension(&block.language),
+        };
+        let extension = extension.unwrap_or_else(|| "txt".into());
+        let path = dir.join(format!("code-{}.{extension}", i + 1));
+        write(&path, &block.code)
+            .with_context(|| format!("Failed to write '{}'", path.display()))?;
+        paths.push(path);
+    }
+    Ok(paths)
+}
+
+// this function returns the role making the model answer with code only
+fn code_role() -> Role {
+    let prompt = r#"Provide only code as output without any description or explanation.
+Put the code in markdown code blocks tagged with their language, like ```python, one block per file.
+If there is a lack of details, provide the most logical solution.
+You are not allowed to ask for more details."#;
+    Role {
+        name: "%code%".into(),
+        prompt: prompt.into(),
+        model: String::new(),
+        temperature: None,
+        params: Default::default(),
+        schema: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_code_blocks() {
+        let dir = std::env::temp_dir().join(format!("aichat-code-{}", std::process::id()));
+        let blocks = [
+            CodeBlock {
+                language: "rust".into(),
+                code: "fn main() {}\n".into(),
+            },
+            CodeBlock {
+                language: String::new(),
+                code: "notes\n".into(),
+            },
+        ];
+        let paths = write_code_blocks(&dir, &blocks).unwrap();
+        assert_eq!(paths, [dir.join("code-1.rs"), dir.join("code-2.txt")]);
+        assert_eq!(
+            std::fs::read_to_string(&paths[0]).unwrap(),
+            "fn main() {}\n"
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
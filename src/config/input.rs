@@ -1,4 +1,7 @@
-use crate::client::{ImageUrl, MessageContent, MessageContentPart, ModelCapabilities};
+use crate::client::{
+    ImageUrl, Message, MessageContent, MessageContentPart, MessageRole, ModelCapabilities,
+    ToolCall, ToolResult,
+};
 use crate::utils::sha256sum;
 
 use anyhow::{bail, Context, Result};
@@ -25,8 +28,15 @@ lazy_static! {
 #[derive(Debug, Clone)]
 pub struct Input {
     text: String,
+    prompt: String, // the text as typed, without the content of the attached text files
+    files: Vec<String>, // the paths or urls of the attached files
     medias: Vec<String>,
     data_urls: HashMap<String, String>,
+    tool_messages: Vec<Message>,
+    // the answer that does not match the schema and the request to repair it
+    repair_messages: Vec<Message>,
+    context: Option<String>, // the passages retrieved for the text, sent before it
+    sources: Vec<String>,    // where the retrieved passages come from, listed under the answer
 }
 
 impl Input {
@@ -34,14 +44,21 @@ impl Input {
     pub fn from_str(text: &str) -> Self {
         Self {
             text: text.to_string(),
+            prompt: text.to_string(),
+            files: Default::default(),
             medias: Default::default(),
             data_urls: Default::default(),
+            tool_messages: Default::default(),
+            repair_messages: Default::default(),
+            context: None,
+            sources: Default::default(),
         }
     }
 
     // another constructor that creates an Input instance from a string and files, using file path
     pub fn new(text: &str, files: Vec<String>) -> Result<Self> {
         let mut texts = vec![text.to_string()];
+        let mut paths = vec![];
         let mut medias = vec![];
         let mut data_urls = HashMap::new();
         for file_item in files.into_iter() {
@@ -49,21 +66,18 @@ impl Input {
                 Some(file_path) => {
                     let file_path = fs::canonicalize(file_path)
                         .with_context(|| format!("Unable to use file '{file_item}"))?;
+                    paths.push(file_path.display().to_string());
                     if is_image_ext(&file_path) {
                         let data_url = read_media_to_data_url(&file_path)?;
                         data_urls.insert(sha256sum(&data_url), file_path.display().to_string());
                         medias.push(data_url)
                     } else {
-                        let mut text = String::new();
-                        let mut file = File::open(&file_path)
-                            .with_context(|| format!("Unable to open file '{file_item}'"))?;
-                        file.read_to_string(&mut text)
-                            .with_context(|| format!("Unable to read file '{file_item}'"))?;
-                        texts.push(text);
+                        texts.push(read_text_file(&file_path)?);
                     }
                 }
                 None => {
                     if is_image_ext(Path::new(&file_item)) {
+                        paths.push(file_item.clone());
                         medias.push(file_item)
                     } else {
                         bail!("Unable to use file '{file_item}");
@@ -74,16 +88,124 @@ impl Input {
 
         Ok(Self {
             text: texts.join("\n"),
+            prompt: text.to_string(),
+            files: paths,
             medias,
             data_urls,
+            tool_messages: Default::default(),
+            repair_messages: Default::default(),
+            context: None,
+            sources: Default::default(),
         })
     }
 
+    // returns the text of the input, including the content of the attached text files
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    // returns the text as typed, without the content of the attached text files
+    pub fn prompt(&self) -> &str {
+        &self.prompt
+    }
+
+    // returns the input as it is typed in the REPL, with `.file` for the attached files
+    pub fn command(&self) -> String {
+        if self.files.is_empty() {
+            return self.prompt.clone();
+        }
+        let files = shell_words::join(&self.files);
+        if self.prompt.is_empty() {
+            format!(".file {files}")
+        } else {
+            format!(".file {files} -- {}", self.prompt)
+        }
+    }
+
+    // returns the paths or urls of the attached files
+    pub fn files(&self) -> &[String] {
+        &self.files
+    }
+
     // returns a clone of the data urls stored in the input
     pub fn data_urls(&self) -> HashMap<String, String> {
         self.data_urls.clone()
     }
 
+    // returns the tool calls and results exchanged so far while answering this input
+    pub fn tool_messages(&self) -> &[Message] {
+        &self.tool_messages
+    }
+
+    // forgets the tool calls exchanged so far, before the input is sent again
+    pub fn clear_tool_messages(&mut self) {
+        self.tool_messages.clear();
+    }
+
+    // returns the wrong answer and the request to repair it, sent after the tool calls
+    pub fn repair_messages(&self) -> &[Message] {
+        &self.repair_messages
+    }
+
+    // records an answer that must be repaired, so the next request carries it and what is wrong with it
+    pub fn add_repair(&mut self, answer: String, request: String) {
+        self.repair_messages = vec![
+            Message {
+                role: MessageRole::Assistant,
+                content: MessageContent::Text(answer),
+            },
+            Message {
+                role: MessageRole::User,
+                content: MessageContent::Text(request),
+            },
+        ];
+    }
+
+    // forgets the repaired answer, which is not kept in the session
+    pub fn clear_repair_messages(&mut self) {
+        self.repair_messages.clear();
+    }
+
+    // returns the passages retrieved for the text
+    pub fn context(&self) -> Option<&str> {
+        self.context.as_deref()
+    }
+
+    // returns where the retrieved passages come from
+    pub fn sources(&self) -> &[String] {
+        &self.sources
+    }
+
+    // sets the passages retrieved for the text and where they come from
+    pub fn set_context(&mut self, context: String, sources: Vec<String>) {
+        self.context = Some(context);
+        self.sources = sources;
+    }
+
+    // records a round of tool calls, so the next request carries the calls and their results
+    pub fn add_tool_calls(&mut self, text: String, calls: Vec<ToolCall>, results: Vec<ToolResult>) {
+        let mut list = vec![];
+        if !text.is_empty() {
+            list.push(MessageContentPart::Text { text });
+        }
+        list.extend(
+            calls
+                .into_iter()
+                .map(|tool_call| MessageContentPart::ToolCall { tool_call }),
+        );
+        self.tool_messages.push(Message {
+            role: MessageRole::Assistant,
+            content: MessageContent::Array(list),
+        });
+        self.tool_messages
+            .extend(results.into_iter().map(|tool_result| Message {
+                role: MessageRole::Tool,
+                content: MessageContent::Array(vec![MessageContentPart::ToolResult {
+                    tool_result,
+                }]),
+            }));
+    }
+
     // renders the input into a format of text and media files
     pub fn render(&self) -> String {
         if self.medias.is_empty() {
@@ -172,8 +294,18 @@ fn resolve_path(file: &str) -> Option<PathBuf> {
     Some(path)
 }
 
+// this function reads the text of a file, attached to a message or indexed
+pub fn read_text_file(path: &Path) -> Result<String> {
+    let mut text = String::new();
+    let mut file =
+        File::open(path).with_context(|| format!("Unable to open file '{}'", path.display()))?;
+    file.read_to_string(&mut text)
+        .with_context(|| format!("Unable to read file '{}'", path.display()))?;
+    Ok(text)
+}
+
 // checks if its extension matches the image file extensions defined in IMAGE_EXTS
-fn is_image_ext(path: &Path) -> bool {
+pub fn is_image_ext(path: &Path) -> bool {
     // extracting the extension using path.extension()
     path.extension()
         .map(|v| {
@@ -0,0 +1,227 @@
+// This file implements the `messages.jsonl` log, one json record per answered message,
+// and the `--history` mode which lists, filters and shows its records
+use super::Config;
+
+use crate::client::GenerationParams;
+use crate::render::MarkdownRender;
+
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, NaiveDate};
+use serde::{Deserialize, Serialize};
+use std::fs::read_to_string;
+use std::path::Path;
+
+// the number of characters of the input shown in the list of records
+const PREVIEW_WIDTH: usize = 60;
+
+// enum representing the format of the messages file
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MessagesFormat {
+    #[default]
+    Markdown, // free-form blocks appended to messages.md
+    Jsonl, // one record per line in messages.jsonl
+}
+
+impl MessagesFormat {
+    // this function returns the name of the format
+    pub fn stringify(&self) -> &str {
+        match self {
+            MessagesFormat::Markdown => "markdown",
+            MessagesFormat::Jsonl => "jsonl",
+        }
+    }
+}
+
+// struct representing a line of the messages log
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct MessageRecord {
+    pub timestamp: String,
+    pub model: String, // the model that answered, which differs from the configured one after a fallback
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub role: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f64>,
+    #[serde(flatten)]
+    pub params: GenerationParams, // the seed and the other parameters, to repeat the request
+    pub input: String, // the text as typed, the content of the files is not repeated
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub files: Vec<String>,
+    pub output: String,
+    pub input_tokens: usize,
+    pub output_tokens: usize,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub estimated: bool, // whether the tokens were counted locally because the api did not report them
+    pub latency_ms: u128,
+}
+
+// struct representing the filters of `--history`, a record must match all of them
+#[derive(Debug, Clone, Default)]
+pub struct MessageFilter {
+    pub model: Option<String>, // part of the model id
+    pub role: Option<String>,
+    pub since: Option<NaiveDate>, // the first day included
+    pub until: Option<NaiveDate>, // the last day included
+}
+
+impl MessageFilter {
+    // this function builds a filter from the `--history` options, the dates being given as YYYY-MM-DD
+    pub fn new(
+        model: Option<String>,
+        role: Option<String>,
+        since: Option<&str>,
+        until: Option<&str>,
+    ) -> Result<Self> {
+        Ok(Self {
+            model,
+            role,
+            since: since.map(parse_date).transpose()?,
+            until: until.map(parse_date).transpose()?,
+        })
+    }
+
+    // this function checks whether a record matches the filter
+    fn matches(&self, record: &MessageRecord) -> bool {
+        if let Some(model) = &self.model {
+            if !record.model.contains(model.as_str()) {
+                return false;
+            }
+        }
+        if self.role.is_some() && record.role != self.role {
+            return false;
+        }
+        in_date_range(&record.timestamp, self.since, self.until)
+    }
+}
+
+// this function parses a date given as YYYY-MM-DD
+pub fn parse_date(value: &str) -> Result<NaiveDate> {
+    NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .with_context(|| format!("Invalid date '{value}', expected YYYY-MM-DD"))
+}
+
+// this function checks whether a rfc3339 timestamp falls between two days, both included
+pub fn in_date_range(timestamp: &str, since: Option<NaiveDate>, until: Option<NaiveDate>) -> bool {
+    if since.is_none() && until.is_none() {
+        return true;
+    }
+    let Ok(date) = DateTime::parse_from_rfc3339(timestamp).map(|v| v.date_naive()) else {
+        return false;
+    };
+    !(since.map(|v| date < v).unwrap_or_default() || until.map(|v| date > v).unwrap_or_default())
+}
+
+// this function loads the records of the messages log, numbered from 1 in the order they were written
+pub fn load_message_records(path: &Path) -> Result<Vec<(usize, MessageRecord)>> {
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+    let content = read_to_string(path)
+        .with_context(|| format!("Failed to load messages at {}", path.display()))?;
+    let mut records = vec![];
+    for (index, line) in content.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record = serde_json::from_str(line)
+            .with_context(|| format!("Invalid message record at line {}", index + 1))?;
+        records.push((index + 1, record));
+    }
+    Ok(records)
+}
+
+// this function lists the records matching the filter, one line each
+pub fn list_message_records(filter: &MessageFilter) -> Result<String> {
+    let path = Config::messages_log_file()?;
+    let lines: Vec<String> = load_message_records(&path)?
+        .into_iter()
+        .filter(|(_, record)| filter.matches(record))
+        .map(|(id, record)| {
+            let role = record
+                .role
+                .as_ref()
+                .map(|v| format!(" ({v})"))
+                .unwrap_or_default();
+            let mut preview: String = record
+                .input
+                .split_whitespace()
+                .collect::<Vec<_>>()
+                .join(" ");
+            if let Some((index, _)) = preview.char_indices().nth(PREVIEW_WIDTH) {
+                preview.truncate(index);
+                preview.push_str("...");
+            }
+            format!(
+                "{id:>4}  {}  {}{role}  {}+{} tokens  {}ms  {preview}",
+                record.timestamp,
+                record.model,
+                record.input_tokens,
+                record.output_tokens,
+                record.latency_ms,
+            )
+        })
+        .collect();
+    if lines.is_empty() {
+        return Ok(format!("No matching messages in {}", path.display()));
+    }
+    Ok(lines.join("\n"))
+}
+
+// this function shows the full input and output of the record with the given number
+pub fn show_message_record(id: usize, render: Option<&mut MarkdownRender>) -> Result<String> {
+    let path = Config::messages_log_file()?;
+    let Some((_, record)) = load_message_records(&path)?
+        .into_iter()
+        .find(|(v, _)| *v == id)
+    else {
+        bail!("No message {id} in {}", path.display());
+    };
+    let mut header = vec![format!("# {id} [{}] {}", record.timestamp, record.model)];
+    if let Some(role) = &record.role {
+        header.push(format!("role: {role}"));
+    }
+    if let Some(temperature) = record.temperature {
+        header.push(format!("temperature: {temperature}"));
+    }
+    if !record.files.is_empty() {
+        header.push(format!("files: {}", record.files.join(" ")));
+    }
+    header.push(format!(
+        "tokens: {} + {}, latency: {}ms",
+        record.input_tokens, record.output_tokens, record.latency_ms
+    ));
+    let output = match render {
+        Some(render) => render.render(&record.output),
+        None => record.output.clone(),
+    };
+    Ok(format!(
+        "{}\n\n{}\n--------\n{}",
+        header.join("\n"),
+        record.input.trim(),
+        output.trim()
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filter() {
+        let record: MessageRecord = serde_json::from_str(
+            r#"{"timestamp":"2024-03-01T10:00:00+08:00","model":"openai:gpt-4","role":"coder","input":"hi","output":"hello","input_tokens":8,"output_tokens":2,"latency_ms":420}"#,
+        )
+        .unwrap();
+        let filter = |model: Option<&str>, role: Option<&str>, since, until| {
+            MessageFilter::new(model.map(Into::into), role.map(Into::into), since, until).unwrap()
+        };
+        assert!(filter(None, None, None, None).matches(&record));
+        assert!(filter(Some("gpt-4"), Some("coder"), None, None).matches(&record));
+        assert!(!filter(Some("claude"), None, None, None).matches(&record));
+        assert!(!filter(None, Some("shell"), None, None).matches(&record));
+        assert!(filter(None, None, Some("2024-03-01"), Some("2024-03-01")).matches(&record));
+        assert!(!filter(None, None, Some("2024-03-02"), None).matches(&record));
+        assert!(!filter(None, None, None, Some("2024-02-29")).matches(&record));
+        assert!(MessageFilter::new(None, None, Some("03/01/2024"), None).is_err());
+    }
+}
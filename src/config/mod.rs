@@ -1,16 +1,33 @@
 mod input;
+mod message_log;
+mod rag;
 mod role;
+mod schema;
+mod search;
 mod session;
+mod tool;
+mod usage;
 
 pub use self::input::Input;
-use self::role::Role;
-use self::session::{Session, TEMP_SESSION_NAME};
+pub use self::message_log::{list_message_records, show_message_record, MessageFilter};
+use self::message_log::{MessageRecord, MessagesFormat};
+use self::rag::list_rags;
+pub use self::rag::{augment_input, render_sources, Rag};
+pub use self::role::Role;
+pub use self::schema::{read_schema, send_with_schema};
+pub use self::search::search_messages;
+pub use self::session::compress_session;
+use self::session::{ContextStrategy, Session, TEMP_SESSION_NAME};
+pub use self::tool::{send_with_tools, Tool};
+pub use self::usage::usage_report;
+use self::usage::{append_usage_record, format_cost, UsageRecord, UsageTotals};
 
 use crate::client::{
-    create_client_config, list_client_types, list_models, ClientConfig, ExtraConfig, Message,
-    Model, OpenAIClient, SendData,
+    create_client_config, list_client_types, list_models, ClientConfig, ExtraConfig,
+    GenerationParams, Message, Model, OpenAIClient, SendData, SendOutput, TokenUsage, PARAM_KEYS,
 };
-use crate::render::{MarkdownRender, RenderOptions};
+use crate::render::{MarkdownRender, OutputFormat, RenderOptions};
+use crate::shell::SHELL_DENYLIST;
 use crate::utils::{get_env_name, light_theme_from_colorfgbg, now, prompt_op_err, render_prompt};
 
 use anyhow::{anyhow, bail, Context, Result};
@@ -26,6 +43,7 @@ use std::{
     path::{Path, PathBuf},
     process::exit,
     sync::Arc,
+    time::Duration,
 };
 use syntect::highlighting::ThemeSet;
 
@@ -35,24 +53,39 @@ const LIGHT_THEME: &[u8] = include_bytes!("../../assets/monokai-extended-light.t
 
 const CONFIG_FILE_NAME: &str = "config.yaml";
 const ROLES_FILE_NAME: &str = "roles.yaml";
+const TOOLS_FILE_NAME: &str = "tools.yaml";
 const MESSAGES_FILE_NAME: &str = "messages.md";
+const MESSAGES_LOG_FILE_NAME: &str = "messages.jsonl";
+const USAGE_FILE_NAME: &str = "usage.jsonl";
+const MODELS_CACHE_FILE_NAME: &str = "models.yaml";
 const SESSIONS_DIR_NAME: &str = "sessions";
+const RAGS_DIR_NAME: &str = "rags";
+const HISTORY_FILE_NAME: &str = "history.txt";
+const HISTORY_DIR_NAME: &str = "history";
 
 const CLIENTS_FIELD: &str = "clients";
 
 #[derive(Debug, Clone, Deserialize)]
 #[serde(default)]
 pub struct Config {
-    /// LLM model
-    #[serde(rename(serialize = "model", deserialize = "model"))]
+    /// LLM model, or a list of models tried in order when one fails
+    #[serde(
+        rename(serialize = "model", deserialize = "model"),
+        deserialize_with = "deserialize_model_ids"
+    )]
     pub model_id: Option<String>,
     /// GPT temperature, between 0 and 2
     #[serde(rename(serialize = "temperature", deserialize = "temperature"))]
     pub default_temperature: Option<f64>,
+    /// Other generation parameters, top_p, max_output_tokens, stop, seed, penalties and provider options
+    #[serde(flatten)]
+    pub default_params: GenerationParams,
     /// Dry-run flag
     pub dry_run: bool,
     /// Whether to save the message
     pub save: bool,
+    /// Format of the saved messages (markdown, jsonl)
+    pub messages_format: MessagesFormat,
     /// Whether to disable highlight
     pub highlight: bool,
     /// Whether to use a light theme
@@ -67,15 +100,32 @@ pub struct Config {
     pub keybindings: Keybindings,
     /// Set a default role or session (role:<name>, session:<name>)
     pub prelude: String,
+    /// Whether to send the tools defined in tools.yaml to the model
+    pub function_calling: bool,
+    /// What to do when a session exceeds the context window (none, truncate, summarize)
+    pub context_strategy: ContextStrategy,
+    /// Maximum number of entries kept in the REPL history, 0 disables it
+    pub history_size: usize,
+    /// Whether each session has its own REPL history
+    pub session_history: bool,
     /// REPL left prompt
     pub left_prompt: String,
     /// REPL right prompt
     pub right_prompt: String,
+    /// Model turning texts into vectors for `--index` and `--rag`, the first model that can embed by default
+    pub embedding_model: Option<String>,
+    /// Number of indexed chunks sent along with a question
+    pub rag_top_k: usize,
+    /// Regex patterns of the commands that `--execute` runs only once typed again
+    pub shell_denylist: Vec<String>,
     /// Setup clients
     pub clients: Vec<ClientConfig>,
     /// Predefined roles
     #[serde(skip)]
     pub roles: Vec<Role>,
+    /// Locally defined tools
+    #[serde(skip)]
+    pub tools: Vec<Tool>,
     /// Current selected role
     #[serde(skip)]
     pub role: Option<Role>,
@@ -85,9 +135,37 @@ pub struct Config {
     #[serde(skip)]
     pub model: Model,
     #[serde(skip)]
+    pub fallback_models: Vec<Model>,
+    /// The model of the fallback chain that answered the last input
+    #[serde(skip)]
+    pub answer_model: Option<Model>,
+    #[serde(skip)]
     pub last_message: Option<(Input, String)>,
     #[serde(skip)]
     pub temperature: Option<f64>,
+    #[serde(skip)]
+    pub params: GenerationParams,
+    /// Index whose chunks are retrieved for each question
+    #[serde(skip)]
+    pub rag: Option<Arc<Rag>>,
+    /// Whether the questions are turned into shell commands
+    #[serde(skip)]
+    pub shell: bool,
+    /// Whether only the code of the answers is printed
+    #[serde(skip)]
+    pub code: bool,
+    /// Directory where the code blocks of the answers are written instead
+    #[serde(skip)]
+    pub code_out: Option<PathBuf>,
+    /// Format in which the command mode prints the answer
+    #[serde(skip)]
+    pub output_format: OutputFormat,
+    /// Json schema of `--schema`, which the answers must match
+    #[serde(skip)]
+    pub schema: Option<serde_json::Value>,
+    /// Tokens and cost of the requests sent since the start
+    #[serde(skip)]
+    pub usage: UsageTotals,
 }
 
 // here, we define the implementation of the Default trait for Config
@@ -96,7 +174,9 @@ impl Default for Config {
         Self {
             model_id: None,
             default_temperature: None,
+            default_params: Default::default(),
             save: true,
+            messages_format: Default::default(),
             highlight: true,
             dry_run: false,
             light_theme: false,
@@ -105,16 +185,34 @@ impl Default for Config {
             auto_copy: false,
             keybindings: Default::default(),
             prelude: String::new(),
-            left_prompt: "{color.green}{?session {session}{?role /}}{role}{color.cyan}{?session )}{!session >}{color.reset} ".to_string(),
-            right_prompt: "{color.purple}{?session {?consume_tokens {consume_tokens}({consume_percent}%)}{!consume_tokens {consume_tokens}}}{color.reset}"
+            function_calling: false,
+            context_strategy: Default::default(),
+            history_size: 1000,
+            session_history: true,
+            left_prompt: "{color.green}{?session {session}{?role /}}{role}{?shell {?role /}{!role {?session /}}{color.yellow}shell}{color.cyan}{?session )}{!session >}{color.reset} ".to_string(),
+            right_prompt: "{color.purple}{?session {?compressed ~}{?consume_tokens {consume_tokens}({consume_percent}%)}{!consume_tokens {consume_tokens}}}{color.reset}"
                 .to_string(),
+            embedding_model: None,
+            rag_top_k: 4,
+            shell_denylist: SHELL_DENYLIST.iter().map(|v| v.to_string()).collect(),
             clients: vec![ClientConfig::default()],
             roles: vec![],
+            tools: vec![],
             role: None,
             session: None,
             model: Default::default(),
+            fallback_models: vec![],
+            answer_model: None,
             temperature: None,
+            params: Default::default(),
+            rag: None,
+            shell: false,
+            code: false,
+            code_out: None,
+            output_format: Default::default(),
+            schema: None,
             last_message: None,
+            usage: Default::default(),
         }
     }
 }
@@ -127,6 +225,40 @@ pub type GlobalConfig = Arc<RwLock<Config>>;
 impl Config {
     // this function is responsible for initializing the application's configuration
     pub fn init(is_interactive: bool) -> Result<Self> {
+        let mut config = Self::load(is_interactive)?;
+
+        if let Some(wrap) = config.wrap.clone() {
+            config.set_wrap(&wrap)?;
+        }
+
+        // setting the temperature to the default temperture in the configuration
+        config.temperature = config.default_temperature;
+        config.params = config.default_params.clone();
+
+        config.load_roles()?;
+        config.load_tools()?;
+
+        // setting upt the configurations of the model by calling some setter functions
+        config.setup_model()?;
+        config.setup_highlight();
+        config.setup_light_theme()?;
+
+        setup_logger()?;
+
+        // returning the configurations wrapped in a Result
+        Ok(config)
+    }
+
+    // this function initializes the configuration without choosing a model, for `--refresh-models`
+    // which must work before any model is known
+    pub fn init_clients() -> Result<Self> {
+        let config = Self::load(false)?;
+        setup_logger()?;
+        Ok(config)
+    }
+
+    // this function reads the configuration file, offering to create it in interactive mode
+    fn load(is_interactive: bool) -> Result<Self> {
         // getting the config_path using the config_file function from the the configuration
         let config_path = Self::config_file()?;
 
@@ -149,27 +281,11 @@ impl Config {
             config.compat_old_config(&config_path)?;
         }
 
-        if let Some(wrap) = config.wrap.clone() {
-            config.set_wrap(&wrap)?;
-        }
-
-        // setting the temperature to the default temperture in the configuration
-        config.temperature = config.default_temperature;
-
-        config.load_roles()?;
-
-        // setting upt the configurations of the model by calling some setter functions
-        config.setup_model()?;
-        config.setup_highlight();
-        config.setup_light_theme()?;
-
-        setup_logger()?;
-
-        // returning the configurations wrapped in a Result
         Ok(config)
     }
 
     // function to be called at the start of the application
+    #[allow(clippy::collapsible_match)]
     pub fn onstart(&mut self) -> Result<()> {
         // checking the prelude configuration
         let prelude = self.prelude.clone();
@@ -237,8 +353,16 @@ impl Config {
         Ok(path)
     }
 
-    // this function is responsible for saving a message to a file or a session
-    pub fn save_message(&mut self, input: Input, output: &str) -> Result<()> {
+    // this function is responsible for saving a message to a file or a session,
+    // the latency is the time it took to answer, which is kept by the jsonl format
+    // the usage is the one reported by the api for the requests of the answer, the tokens are counted locally without it
+    pub fn save_message(
+        &mut self,
+        input: Input,
+        output: &str,
+        usage: Option<TokenUsage>,
+        latency: Duration,
+    ) -> Result<()> {
         // firstly, we update the last_message field with the input and output provided
         self.last_message = Some((input.clone(), output.to_string()));
 
@@ -249,9 +373,11 @@ impl Config {
         }
 
         // If a session is active
+        let answer_model = self.answer_model().id();
         if let Some(session) = self.session.as_mut() {
-            //  we add the message to the session and return
+            //  we add the message to the session with the model that answered it, and return
             session.add_message(&input, output)?;
+            session.set_last_model(answer_model);
             return Ok(());
         }
 
@@ -259,20 +385,51 @@ impl Config {
         if !self.save {
             return Ok(());
         }
-        // else we write it in the file
-        let mut file = self.open_message_file()?;
-        if output.is_empty() || !self.save {
+        if output.is_empty() {
             return Ok(());
         }
+        if self.messages_format == MessagesFormat::Jsonl {
+            let (usage, estimated) = match usage {
+                Some(usage) => (usage, false),
+                None => {
+                    let usage = TokenUsage {
+                        input_tokens: self.model.total_tokens(&self.build_messages(&input)?),
+                        output_tokens: self.model.count_tokens(output),
+                    };
+                    (usage, true)
+                }
+            };
+            let record = MessageRecord {
+                timestamp: now(),
+                model: self.answer_model().id(),
+                role: self.role.as_ref().map(|v| v.name.clone()),
+                temperature: self.temperature,
+                params: self.params.clone(),
+                input: input.prompt().to_string(),
+                files: input.files().to_vec(),
+                output: output.to_string(),
+                input_tokens: usage.input_tokens,
+                output_tokens: usage.output_tokens,
+                estimated,
+                latency_ms: latency.as_millis(),
+            };
+            let mut file = self.open_message_file(&Self::messages_log_file()?)?;
+            let line = serde_json::to_string(&record)?;
+            return writeln!(file, "{line}").with_context(|| "Failed to save message");
+        }
+        // else we write it in the file
+        let mut file = self.open_message_file(&Self::messages_file()?)?;
         let timestamp = now();
         let input_markdown = input.render();
+        // the model that answered is recorded, it differs from the configured one after a fallback
+        let model_id = self.answer_model().id();
         let output = match self.role.as_ref() {
             None => {
-                format!("# CHAT:[{timestamp}]\n{input_markdown}\n--------\n{output}\n--------\n\n",)
+                format!("# CHAT:[{timestamp}] {model_id}\n{input_markdown}\n--------\n{output}\n--------\n\n",)
             }
             Some(v) => {
                 format!(
-                    "# CHAT:[{timestamp}] ({})\n{input_markdown}\n--------\n{output}\n--------\n\n",
+                    "# CHAT:[{timestamp}] {model_id} ({})\n{input_markdown}\n--------\n{output}\n--------\n\n",
                     v.name,
                 )
             }
@@ -281,6 +438,39 @@ impl Config {
             .with_context(|| "Failed to save message")
     }
 
+    // this function accounts the tokens and the cost of a request in the totals of the run and of the session,
+    // and appends it to the usage ledger, the tokens are counted locally when the api did not report them
+    pub fn record_usage(&mut self, messages: &[Message], output: &SendOutput) -> Result<()> {
+        if self.dry_run {
+            return Ok(());
+        }
+        let (usage, estimated) = match output.usage {
+            Some(usage) => (usage, false),
+            None => {
+                let usage = TokenUsage {
+                    input_tokens: self.model.total_tokens(messages),
+                    output_tokens: self.model.count_tokens(&output.text),
+                };
+                (usage, true)
+            }
+        };
+        let cost = self.model.cost(usage);
+        self.usage.add(usage, cost);
+        if let Some(session) = self.session.as_mut() {
+            session.add_usage(usage, cost);
+        }
+        let record = UsageRecord {
+            timestamp: now(),
+            model: self.model.id(),
+            session: self.session.as_ref().map(|v| v.name().to_string()),
+            input_tokens: usage.input_tokens,
+            output_tokens: usage.output_tokens,
+            cost,
+            estimated,
+        };
+        append_usage_record(&Self::usage_file()?, &record)
+    }
+
     // this function returns the path to the configuration file (config.yaml)
     pub fn config_file() -> Result<PathBuf> {
         Self::local_path(CONFIG_FILE_NAME)
@@ -295,11 +485,35 @@ impl Config {
         )
     }
 
+    // this function returns the path to the tools file (tools.yaml)
+    pub fn tools_file() -> Result<PathBuf> {
+        let env_name = get_env_name("tools_file");
+        env::var(env_name).map_or_else(
+            |_| Self::local_path(TOOLS_FILE_NAME),
+            |value| Ok(PathBuf::from(value)),
+        )
+    }
+
     // this function returns the path to the messages file (messages.md)
     pub fn messages_file() -> Result<PathBuf> {
         Self::local_path(MESSAGES_FILE_NAME)
     }
 
+    // this function returns the path to the messages log (messages.jsonl), written with `messages_format: jsonl`
+    pub fn messages_log_file() -> Result<PathBuf> {
+        Self::local_path(MESSAGES_LOG_FILE_NAME)
+    }
+
+    // this function returns the path to the usage ledger (usage.jsonl)
+    pub fn usage_file() -> Result<PathBuf> {
+        Self::local_path(USAGE_FILE_NAME)
+    }
+
+    // this function returns the path of the cache of the models discovered by `--refresh-models`
+    pub fn models_cache_file() -> Result<PathBuf> {
+        Self::local_path(MODELS_CACHE_FILE_NAME)
+    }
+
     // this function returns the path to the directory where session files are stored (sessions)
     pub fn sessions_dir() -> Result<PathBuf> {
         Self::local_path(SESSIONS_DIR_NAME)
@@ -308,17 +522,51 @@ impl Config {
     // This function constructs the path to a session file based on the session name
     pub fn session_file(name: &str) -> Result<PathBuf> {
         let mut path = Self::sessions_dir()?;
-        path.push(&format!("{name}.yaml"));
+        path.push(format!("{name}.yaml"));
+        Ok(path)
+    }
+
+    // this function returns the path to the directory of the indexes
+    pub fn rags_dir() -> Result<PathBuf> {
+        Self::local_path(RAGS_DIR_NAME)
+    }
+
+    // this function returns the path to the file of an index
+    pub fn rag_file(name: &str) -> Result<PathBuf> {
+        let mut path = Self::rags_dir()?;
+        path.push(format!("{name}.bin"));
         Ok(path)
     }
 
+    // this function returns the path to the REPL history file, of the current session when each session has its own
+    // history, or None when the history is disabled
+    pub fn history_file(&self) -> Result<Option<PathBuf>> {
+        if self.history_size == 0 {
+            return Ok(None);
+        }
+        let path = match self.session.as_ref() {
+            Some(session) if self.session_history => {
+                let mut path = Self::local_path(HISTORY_DIR_NAME)?;
+                path.push(format!("{}.txt", session.name()));
+                path
+            }
+            _ => Self::local_path(HISTORY_FILE_NAME)?,
+        };
+        Ok(Some(path))
+    }
+
     // this function lets us, set the role for the current configuration based on the provided name
     pub fn set_role(&mut self, name: &str) -> Result<()> {
         let role = self.retrieve_role(name)?;
+        // the model of the role, with its fallbacks, replaces the current one like the model of a session
+        if !role.model.is_empty() {
+            self.set_model(&role.model)?;
+        }
         if let Some(session) = self.session.as_mut() {
             session.update_role(Some(role.clone()))?;
         }
         self.temperature = role.temperature;
+        self.params = self.default_params.merge(&role.params);
         self.role = Some(role);
         Ok(())
     }
@@ -329,10 +577,34 @@ impl Config {
             session.update_role(None)?;
         }
         self.temperature = self.default_temperature;
+        self.params = self.default_params.clone();
         self.role = None;
         Ok(())
     }
 
+    // this function loads an index, whose chunks are then retrieved for each question
+    pub fn set_rag(&mut self, name: &str) -> Result<()> {
+        self.rag = Some(Arc::new(Rag::load(name)?));
+        Ok(())
+    }
+
+    // this function stops retrieving the chunks of the index
+    pub fn clear_rag(&mut self) {
+        self.rag = None;
+    }
+
+    // this function returns a copy of the config sending only the input with a built-in role,
+    // outside of the session and without retrieval or tools
+    pub fn with_builtin_role(&self, role: Role) -> GlobalConfig {
+        let mut config = self.clone();
+        config.session = None;
+        config.rag = None;
+        config.function_calling = false;
+        config.schema = None;
+        config.role = Some(role);
+        Arc::new(RwLock::new(config))
+    }
+
     // this function returns the current state of the configuration
     pub fn get_state(&self) -> State {
         if let Some(session) = &self.session {
@@ -357,6 +629,18 @@ impl Config {
         self.temperature
     }
 
+    // this function returns the json schema the answers must match, the one of `--schema` or of the role
+    pub fn get_schema(&self) -> Option<serde_json::Value> {
+        if self.schema.is_some() {
+            return self.schema.clone();
+        }
+        let role = self
+            .role
+            .as_ref()
+            .or_else(|| self.session.as_ref().and_then(|v| v.role.as_ref()));
+        role.and_then(|v| v.schema.clone())
+    }
+
     // this function lets us set the temperature for the configuration
     pub fn set_temperature(&mut self, value: Option<f64>) -> Result<()> {
         self.temperature = value;
@@ -366,21 +650,40 @@ impl Config {
         Ok(())
     }
 
+    // this function sets a generation parameter from its text, for the session too
+    pub fn set_param(&mut self, key: &str, value: &str) -> Result<()> {
+        self.params.set(key, value)?;
+        if let Some(session) = self.session.as_mut() {
+            session.set_param(key, value)?;
+        }
+        Ok(())
+    }
+
+    // this function applies the generation parameters given on the command line over the current ones
+    pub fn override_params(&mut self, params: &GenerationParams) {
+        self.params = self.params.merge(params);
+    }
+
     // this function echoes the messages based on the current configuration state
     pub fn echo_messages(&self, input: &Input) -> String {
-        if let Some(session) = self.session.as_ref() {
+        let messages = if let Some(session) = self.session.as_ref() {
             session.echo_messages(input)
         } else if let Some(role) = self.role.as_ref() {
             role.echo_messages(input)
         } else {
             input.render()
+        };
+        // the retrieved passages are sent before the messages of the input
+        match input.context() {
+            Some(context) => format!("{context}\n\n{messages}"),
+            None => messages,
         }
     }
 
     // this function is for build messages based on the current configuration state
     pub fn build_messages(&self, input: &Input) -> Result<Vec<Message>> {
         // If a session is active, we build messages from the session
-        let messages = if let Some(session) = self.session.as_ref() {
+        let mut messages = if let Some(session) = self.session.as_ref() {
             session.build_emssages(input)
         } else if let Some(role) = self.role.as_ref() {
             role.build_messages(input)
@@ -388,6 +691,18 @@ impl Config {
             let message = Message::new(input);
             vec![message]
         };
+        // the passages retrieved for the input come before its text, in the last user message
+        if let Some(context) = input.context() {
+            if let Some(message) = messages.iter_mut().rev().find(|v| v.role.is_user()) {
+                message
+                    .content
+                    .merge_prompt(|v: &str| format!("{context}\n\n{v}"));
+            }
+        }
+        // the tool calls made while answering this input follow the user message
+        messages.extend(input.tool_messages().iter().cloned());
+        // followed by the answer to repair when it does not match the schema
+        messages.extend(input.repair_messages().iter().cloned());
         Ok(messages)
     }
 
@@ -407,21 +722,51 @@ impl Config {
     }
 
     // this function is for setting the model for the configuration based on the provided value
+    // the value can list several comma-separated models, the ones after the first are fallbacks
     pub fn set_model(&mut self, value: &str) -> Result<()> {
         // retrieving a list of available models
         let models = list_models(self);
-        let model = Model::find(&models, value);
-        // attempting to find the model by matching
-        match model {
-            None => bail!("Invalid model '{}'", value),
-            Some(model) => {
-                if let Some(session) = self.session.as_mut() {
-                    session.set_model(model.clone())?;
-                }
-                self.model = model;
-                Ok(())
+        let mut chain = vec![];
+        for id in value.split(',').map(|v| v.trim()).filter(|v| !v.is_empty()) {
+            // attempting to find the model by matching
+            match Model::find(&models, id) {
+                None => bail!("Invalid model '{}'", id),
+                Some(model) => chain.push(model),
             }
         }
+        if chain.is_empty() {
+            bail!("Invalid model '{}'", value);
+        }
+        self.model = chain.remove(0);
+        self.fallback_models = chain;
+        self.sync_session_model()
+    }
+
+    // this function returns the model that answered the last input, the current one unless it fell back
+    pub fn answer_model(&self) -> &Model {
+        self.answer_model.as_ref().unwrap_or(&self.model)
+    }
+
+    // this function returns the current model followed by its fallbacks, in the order they are tried
+    pub fn model_candidates(&self) -> Vec<Model> {
+        let mut models = vec![self.model.clone()];
+        models.extend(self.fallback_models.iter().cloned());
+        models
+    }
+
+    // this function returns the current model followed by its fallbacks, separated by commas
+    pub fn model_chain(&self) -> String {
+        let ids: Vec<String> = self.model_candidates().iter().map(|v| v.id()).collect();
+        ids.join(",")
+    }
+
+    // this function records the current model chain in the session
+    fn sync_session_model(&mut self) -> Result<()> {
+        let model_chain = self.model_chain();
+        if let Some(session) = self.session.as_mut() {
+            session.set_model(&model_chain, self.model.clone())?;
+        }
+        Ok(())
     }
 
     // this function generates system information for the configuration
@@ -436,17 +781,28 @@ impl Config {
             .wrap
             .clone()
             .map_or_else(|| String::from("no"), |v| v.to_string());
+        let messages_file = match self.messages_format {
+            MessagesFormat::Markdown => Self::messages_file()?,
+            MessagesFormat::Jsonl => Self::messages_log_file()?,
+        };
         let prelude = if self.prelude.is_empty() {
             String::from("-")
         } else {
             self.prelude.clone()
         };
         // this constructs a formatted string containing the configuration information
-        let items = vec![
-            ("model", self.model.id()),
+        let mut items = vec![
+            ("model", self.model_chain()),
+            (
+                "tokenizer",
+                self.model
+                    .tokenizer_fallback()
+                    .unwrap_or_else(|| self.model.tokenizer_name().into()),
+            ),
             ("temperature", temperature),
             ("dry_run", self.dry_run.to_string()),
             ("save", self.save.to_string()),
+            ("messages_format", self.messages_format.stringify().into()),
             ("highlight", self.highlight.to_string()),
             ("light_theme", self.light_theme.to_string()),
             ("wrap", wrap),
@@ -454,11 +810,42 @@ impl Config {
             ("auto_copy", self.auto_copy.to_string()),
             ("keybindings", self.keybindings.stringify().into()),
             ("prelude", prelude),
+            ("function_calling", self.function_calling.to_string()),
+            ("context_strategy", self.context_strategy.stringify().into()),
+            (
+                "embedding_model",
+                self.embedding_model.clone().unwrap_or_else(|| "-".into()),
+            ),
+            (
+                "rag",
+                self.rag
+                    .as_ref()
+                    .map(|v| v.info())
+                    .unwrap_or_else(|| "-".into()),
+            ),
+            ("rag_top_k", self.rag_top_k.to_string()),
+            ("history_size", self.history_size.to_string()),
+            ("session_history", self.session_history.to_string()),
             ("config_file", display_path(&Self::config_file()?)),
             ("roles_file", display_path(&Self::roles_file()?)),
-            ("messages_file", display_path(&Self::messages_file()?)),
+            ("tools_file", display_path(&Self::tools_file()?)),
+            ("messages_file", display_path(&messages_file)),
             ("sessions_dir", display_path(&Self::sessions_dir()?)),
+            ("rags_dir", display_path(&Self::rags_dir()?)),
+            ("usage_file", display_path(&Self::usage_file()?)),
+            (
+                "models_cache_file",
+                display_path(&Self::models_cache_file()?),
+            ),
+            (
+                "history_file",
+                self.history_file()?
+                    .map(|v| display_path(&v))
+                    .unwrap_or_else(|| "-".into()),
+            ),
         ];
+        // the generation parameters follow the temperature
+        items.splice(2..2, self.params.items());
         let output = items
             .iter()
             .map(|(name, value)| format!("{name:<20}{value}"))
@@ -517,6 +904,22 @@ impl Config {
             .unwrap_or_default()
     }
 
+    // this function takes the last question and answer so they can be sent again,
+    // the question is removed from the session along with its answer
+    pub fn take_last_message(&mut self) -> Result<(Input, String)> {
+        let (mut input, output) = self
+            .last_message
+            .take()
+            .ok_or_else(|| anyhow!("No last message"))?;
+        if let Some(session) = self.session.as_mut() {
+            if session.user_messages_len() > 0 {
+                session.undo()?;
+            }
+        }
+        input.clear_tool_messages();
+        Ok((input, output))
+    }
+
     // this function provides auto-completion options for the REPL
     pub fn repl_complete(&self, cmd: &str, args: &[&str]) -> Vec<String> {
         let (values, filter) = if args.len() == 1 {
@@ -524,12 +927,29 @@ impl Config {
                 ".role" => self.roles.iter().map(|v| v.name.clone()).collect(),
                 ".model" => list_models(self).into_iter().map(|v| v.id()).collect(),
                 ".session" => self.list_sessions(),
+                ".rag" => list_rags(),
+                ".branch" => self
+                    .session
+                    .as_ref()
+                    .map(|v| v.branch_names())
+                    .unwrap_or_default(),
                 ".set" => vec![
                     "temperature ",
+                    "top_p ",
+                    "max_output_tokens ",
+                    "stop ",
+                    "seed ",
+                    "presence_penalty ",
+                    "frequency_penalty ",
+                    "provider_options ",
                     "save ",
                     "highlight ",
                     "dry_run ",
                     "auto_copy ",
+                    "function_calling ",
+                    "context_strategy ",
+                    "session_history ",
+                    "rag_top_k ",
                 ]
                 .into_iter()
                 .map(|v| v.to_string())
@@ -544,6 +964,12 @@ impl Config {
                 "highlight" => to_vec(!self.highlight),
                 "dry_run" => to_vec(!self.dry_run),
                 "auto_copy" => to_vec(!self.auto_copy),
+                "function_calling" => to_vec(!self.function_calling),
+                "session_history" => to_vec(!self.session_history),
+                "context_strategy" => ["none", "truncate", "summarize"]
+                    .into_iter()
+                    .map(|v| v.to_string())
+                    .collect(),
                 _ => vec![],
             };
             (values, args[1])
@@ -578,6 +1004,10 @@ impl Config {
                 };
                 self.set_temperature(value)?;
             }
+            // updating a generation parameter
+            key if PARAM_KEYS.contains(&key) => {
+                self.set_param(key, value)?;
+            }
             // updating the save settings
             "save" => {
                 let value = value.parse().with_context(|| "Invalid value")?;
@@ -598,6 +1028,33 @@ impl Config {
                 let value = value.parse().with_context(|| "Invalid value")?;
                 self.auto_copy = value;
             }
+            // enabling or disabling function calling
+            "function_calling" => {
+                let value = value.parse().with_context(|| "Invalid value")?;
+                self.function_calling = value;
+            }
+            // updating the context strategy, of the session when there is one
+            "context_strategy" => {
+                let value = if unset {
+                    None
+                } else {
+                    Some(ContextStrategy::parse(value)?)
+                };
+                match self.session.as_mut() {
+                    Some(session) => session.set_context_strategy(value),
+                    None => self.context_strategy = value.unwrap_or_default(),
+                }
+            }
+            // switching between one REPL history per session and a shared one
+            "session_history" => {
+                let value = value.parse().with_context(|| "Invalid value")?;
+                self.session_history = value;
+            }
+            // updating the number of indexed chunks sent along with a question
+            "rag_top_k" => {
+                let value = value.parse().with_context(|| "Invalid value")?;
+                self.rag_top_k = value;
+            }
             // for all else keys, we return an error with the key as unknown
             _ => bail!("Unknown key `{key}`"),
         }
@@ -640,12 +1097,16 @@ impl Config {
                     let session = Session::load(name, &session_path)?;
                     let model = session.model().to_string();
                     self.temperature = session.temperature();
+                    self.params = self.default_params.merge(session.params());
                     self.session = Some(session);
                     self.set_model(&model)?;
                 }
             }
         }
+        // a new session keeps the fallbacks of the current model
+        self.sync_session_model()?;
         // session is empty and there is a last message
+        let answer_model = self.answer_model().id();
         if let Some(session) = self.session.as_mut() {
             if session.is_empty() {
                 if let Some((input, output)) = &self.last_message {
@@ -658,6 +1119,7 @@ impl Config {
                     .map_err(prompt_op_err)?;
                     if ans {
                         session.add_message(input, output)?;
+                        session.set_last_model(answer_model);
                     }
                 }
             }
@@ -674,6 +1136,7 @@ impl Config {
             self.last_message = None;
             // reseting the temperature setting
             self.temperature = self.default_temperature;
+            self.params = self.default_params.clone();
             // Checking if the session should be saved
             if session.should_save() {
                 // prompting user to confirm saving the session
@@ -710,6 +1173,59 @@ impl Config {
         Ok(())
     }
 
+    // this function removes the last turn of the session
+    pub fn undo_session(&mut self) -> Result<()> {
+        match self.session.as_mut() {
+            Some(session) => {
+                session.undo()?;
+                self.last_message = None;
+                Ok(())
+            }
+            None => bail!("No session"),
+        }
+    }
+
+    // this function saves a copy of the session under a new name and continues in the copy,
+    // the file of the original session is left as it was
+    pub fn fork_session(&mut self, name: &str) -> Result<()> {
+        let session_path = Self::session_file(name)?;
+        if session_path.exists() || name == TEMP_SESSION_NAME {
+            bail!("Session '{name}' already exists");
+        }
+        let session = self.session.as_mut().ok_or_else(|| anyhow!("No session"))?;
+        session.name = name.to_string();
+        session.dirty = true;
+        ensure_parent_exists(&session_path)?;
+        session.save(&session_path)
+    }
+
+    // this function lists, creates or switches the branches of the session
+    // `.branch` lists them, `.branch <name>` switches to a branch or creates it from the current point,
+    // `.branch <name> <turns>` creates it from the first turns of the current branch
+    pub fn branch_session(&mut self, args: Option<&str>) -> Result<Option<String>> {
+        let session = self.session.as_mut().ok_or_else(|| anyhow!("No session"))?;
+        let args: Vec<&str> = args.unwrap_or_default().split_whitespace().collect();
+        match args.as_slice() {
+            [] => return Ok(Some(session.list_branches())),
+            [name] => {
+                if session.branch_names().iter().any(|v| v == name) {
+                    session.switch_branch(name)?;
+                } else {
+                    session.create_branch(name, None)?;
+                }
+            }
+            [name, turns] => {
+                let turns = turns
+                    .parse()
+                    .with_context(|| format!("Invalid turns '{turns}'"))?;
+                session.create_branch(name, Some(turns))?;
+            }
+            _ => bail!("Usage: .branch [<name> [<turns>]]"),
+        }
+        self.last_message = None;
+        Ok(None)
+    }
+
     // This function is for listing all available sessions
     pub fn list_sessions(&self) -> Vec<String> {
         // Finding and reading the sessions directory
@@ -795,10 +1311,18 @@ impl Config {
         // we check if the total tokens of the messages exceed the model's limit
         self.model.max_tokens_limit(&messages)?;
         // return the built messages in SendData method
+        let tools = if self.function_calling {
+            self.tools.clone()
+        } else {
+            vec![]
+        };
         Ok(SendData {
             messages,
             temperature: self.get_temperature(),
+            params: self.params.clone(),
             stream,
+            tools,
+            schema: self.get_schema(),
         })
     }
 
@@ -809,8 +1333,13 @@ impl Config {
             if let Ok(messages) = self.build_messages(input) {
                 // get the max tokens
                 let tokens = self.model.total_tokens(&messages);
-                // Print the token count
-                println!(">>> This message consumes {tokens} tokens. <<<");
+                // Print the token count, and the tokenizer counting in place of the one of the model
+                match self.model.tokenizer_fallback() {
+                    Some(fallback) => {
+                        println!(">>> This message consumes {tokens} tokens, counted with {fallback}. <<<")
+                    }
+                    None => println!(">>> This message consumes {tokens} tokens. <<<"),
+                }
             }
         }
     }
@@ -849,12 +1378,32 @@ impl Config {
         if let Some(role) = &self.role {
             output.insert("role", role.name.clone());
         }
+        if let Some(rag) = &self.rag {
+            output.insert("rag", rag.name.clone());
+        }
+        if self.shell {
+            output.insert("shell", "true".to_string());
+        }
         if let Some(session) = &self.session {
             output.insert("session", session.name().to_string());
             let (tokens, percent) = session.tokens_and_percent();
             output.insert("consume_tokens", tokens.to_string());
             output.insert("consume_percent", percent.to_string());
             output.insert("user_messages_len", session.user_messages_len().to_string());
+            if let Some(branch) = session.branch() {
+                output.insert("branch", branch.to_string());
+            }
+            if session.compressed_messages() > 0 {
+                output.insert("compressed", session.compressed_messages().to_string());
+            }
+        }
+        // the cost of the session, or of the run outside of a session
+        let usage = match &self.session {
+            Some(session) => session.usage(),
+            None => self.usage,
+        };
+        if usage.cost > 0.0 {
+            output.insert("cost", format_cost(usage.cost));
         }
 
         // highlighting is enabled, we add ANSI color codes to the context
@@ -885,16 +1434,14 @@ impl Config {
     }
 
     // this function opens the message file for appending messages
-    fn open_message_file(&self) -> Result<File> {
-        // getting the message file path
-        let path = Self::messages_file()?;
+    fn open_message_file(&self, path: &Path) -> Result<File> {
         // ensuring the path exists
-        ensure_parent_exists(&path)?;
+        ensure_parent_exists(path)?;
         // we open the file in append mode, and/or create it if it doesn't exist
         OpenOptions::new()
             .create(true)
             .append(true)
-            .open(&path)
+            .open(path)
             .with_context(|| format!("Failed to create/append {}", path.display()))
     }
 
@@ -938,6 +1485,20 @@ impl Config {
         Ok(())
     }
 
+    // this function loads tools from a yaml file and sets the tools field of the struct
+    fn load_tools(&mut self) -> Result<()> {
+        let path = Self::tools_file()?;
+        if !path.exists() {
+            return Ok(());
+        }
+        let content = read_to_string(&path)
+            .with_context(|| format!("Failed to load tools at {}", path.display()))?;
+        let tools: Vec<Tool> =
+            serde_yaml::from_str(&content).with_context(|| "Invalid tools config")?;
+        self.tools = tools;
+        Ok(())
+    }
+
     // This function sets up the model using the provided model ID or selecting the first available model
     fn setup_model(&mut self) -> Result<()> {
         let model = match &self.model_id {
@@ -1137,6 +1698,40 @@ fn set_bool(target: &mut bool, value: &str) {
     }
 }
 
+// enum representing a `model:` value, either one model id or the list of models to try in order
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum ModelIds {
+    One(String),
+    List(Vec<String>),
+}
+
+impl ModelIds {
+    // this function joins the model ids with commas, the format accepted by `Config::set_model`
+    fn join(self) -> String {
+        match self {
+            ModelIds::One(v) => v,
+            ModelIds::List(v) => v.join(","),
+        }
+    }
+}
+
+// this function deserializes an optional `model:` value given as a string or a list
+fn deserialize_model_ids<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    Ok(Option::<ModelIds>::deserialize(deserializer)?.map(ModelIds::join))
+}
+
+// this function deserializes a required `model:` value given as a string or a list
+fn deserialize_model_chain<'de, D>(deserializer: D) -> Result<String, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    Ok(ModelIds::deserialize(deserializer)?.join())
+}
+
 // Below are the functions which configure logging based on whether the application is in debug mode or not
 #[cfg(debug_assertions)]
 // in this function logging set up to write debug logs to a file named "debug.log".
@@ -1160,3 +1755,36 @@ fn setup_logger() -> Result<()> {
 fn setup_logger() -> Result<()> {
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deserialize_model_ids() {
+        let config: Config = serde_yaml::from_str("model: openai:gpt-4").unwrap();
+        assert_eq!(config.model_id.as_deref(), Some("openai:gpt-4"));
+        let config: Config =
+            serde_yaml::from_str("model: [openai:gpt-4, claude:claude-3-opus-20240229]").unwrap();
+        assert_eq!(
+            config.model_id.as_deref(),
+            Some("openai:gpt-4,claude:claude-3-opus-20240229")
+        );
+        let config: Config = serde_yaml::from_str("save: false").unwrap();
+        assert_eq!(config.model_id, None);
+    }
+
+    #[test]
+    fn test_history_file() {
+        let mut config = Config::default();
+        let global = config.history_file().unwrap().unwrap();
+        assert!(global.ends_with("history.txt"));
+        config.session = Some(Session::new("rust", Model::default(), None));
+        let path = config.history_file().unwrap().unwrap();
+        assert!(path.ends_with("history/rust.txt"));
+        config.session_history = false;
+        assert_eq!(config.history_file().unwrap(), Some(global));
+        config.history_size = 0;
+        assert_eq!(config.history_file().unwrap(), None);
+    }
+}
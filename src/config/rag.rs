@@ -0,0 +1,469 @@
+// This file implements the local indexes built by `--index`, whose chunks closest to a question are
+// retrieved by `--rag` and `.rag` and sent along with it, so the model answers from the indexed files.
+// An index compares vectors of an embedding model, or the words of the chunks with BM25 when it has no model
+use super::input::{is_image_ext, read_text_file};
+use super::{Config, GlobalConfig, Input};
+
+use crate::client::{init_client, list_models, Model, ModelCapabilities};
+use crate::utils::{init_tokio_runtime, Bm25Index};
+
+use anyhow::{bail, Context, Result};
+use ignore::WalkBuilder;
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs::{read, read_dir, write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+// the number of characters of a chunk, which is cut on line boundaries
+const CHUNK_SIZE: usize = 1500;
+// the number of characters of the end of a chunk repeated at the start of the next one
+const CHUNK_OVERLAP: usize = 200;
+// the characters kept of a chunk made of a single long line, or of a question
+const MAX_TEXT_CHARS: usize = 4 * CHUNK_SIZE;
+// the number of texts sent in one embeddings request
+const EMBED_BATCH_SIZE: usize = 64;
+
+// struct representing an index, the chunks of the indexed files with their vectors or their words
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct Rag {
+    #[serde(skip)]
+    pub name: String,
+    pub model: Option<String>, // the id of the embedding model, which embeds the questions too
+    pub paths: Vec<String>,    // the indexed files and directories
+    pub chunks: Vec<Chunk>,
+    pub bm25: Option<Bm25Index>, // the words of the chunks, for an index without a model
+}
+
+// struct representing a part of an indexed file
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Chunk {
+    pub source: String,        // the path of the file
+    pub lines: (usize, usize), // the first and last lines, from 1
+    pub text: String,
+    pub vector: Vec<f32>, // empty in an index without a model
+}
+
+impl Chunk {
+    // this function returns the path and the lines of the chunk, like `src/main.rs:10-42`
+    pub fn location(&self) -> String {
+        format!("{}:{}-{}", self.source, self.lines.0, self.lines.1)
+    }
+}
+
+impl Rag {
+    // this function loads an index from the rags dir
+    pub fn load(name: &str) -> Result<Self> {
+        let path = Config::rag_file(name)?;
+        if !path.exists() {
+            bail!("No index '{name}', create it with `aichat --index {name} <paths>...`");
+        }
+        let data =
+            read(&path).with_context(|| format!("Failed to load index at {}", path.display()))?;
+        let mut rag: Self = bincode::deserialize(&data)
+            .with_context(|| format!("Invalid index at {}", path.display()))?;
+        rag.name = name.to_string();
+        Ok(rag)
+    }
+
+    // this function indexes the files of the paths, the directories being walked without their hidden
+    // and ignored entries, the chunks of an older index with the same text and model keep their vectors.
+    // The words of the chunks are indexed instead with `bm25`, or when no model can embed
+    pub fn build(
+        config: &GlobalConfig,
+        name: &str,
+        paths: &[String],
+        bm25: bool,
+    ) -> Result<String> {
+        if paths.is_empty() {
+            bail!("Usage: aichat --index <name> <paths>...");
+        }
+        let model = if bm25 {
+            None
+        } else {
+            match embedding_model(&config.read(), None) {
+                Ok(model) => Some(model),
+                Err(_) if config.read().embedding_model.is_none() => None,
+                Err(err) => return Err(err),
+            }
+        };
+        let mut files = vec![];
+        for path in paths {
+            let path = Path::new(path);
+            if !path.exists() {
+                bail!("Unable to use '{}'", path.display());
+            }
+            collect_files(path, &mut files);
+        }
+
+        let mut chunks = vec![];
+        let mut skipped = 0;
+        for file in &files {
+            // the images and the files that are not utf-8 text, like binaries, are skipped
+            let content = match is_image_ext(file) {
+                true => None,
+                false => read_text_file(file).ok(),
+            };
+            let Some(content) = content else {
+                skipped += 1;
+                continue;
+            };
+            let source = file.display().to_string();
+            chunks.extend(chunk_text(&content).into_iter().map(|(lines, text)| Chunk {
+                source: source.clone(),
+                lines,
+                text,
+                vector: vec![],
+            }));
+        }
+        if chunks.is_empty() {
+            bail!("No text to index in {}", paths.join(" "));
+        }
+
+        let (bm25, embedded) = match &model {
+            Some(model) => {
+                let mut known: HashMap<String, Vec<f32>> = HashMap::new();
+                if let Ok(old) = Self::load(name) {
+                    if old.model.as_deref() == Some(&model.id()) {
+                        known.extend(old.chunks.into_iter().map(|v| (v.text, v.vector)));
+                    }
+                }
+                let mut pending = vec![];
+                for (i, chunk) in chunks.iter_mut().enumerate() {
+                    match known.get(&chunk.text) {
+                        Some(vector) => chunk.vector = vector.clone(),
+                        None => pending.push(i),
+                    }
+                }
+                let texts: Vec<String> = pending.iter().map(|i| chunks[*i].text.clone()).collect();
+                let vectors = embed_texts(config, model, &texts)?;
+                for (i, vector) in pending.iter().zip(vectors) {
+                    chunks[*i].vector = vector;
+                }
+                (None, pending.len())
+            }
+            None => (
+                Some(Bm25Index::new(chunks.iter().map(|v| v.text.as_str()))),
+                0,
+            ),
+        };
+
+        let rag = Self {
+            name: name.to_string(),
+            model: model.map(|v| v.id()),
+            paths: paths.to_vec(),
+            chunks,
+            bm25,
+        };
+        let path = rag.save()?;
+        let method = match &rag.model {
+            Some(model) => format!("{model} ({embedded} embedded, {skipped} files skipped)"),
+            None => format!("bm25 ({skipped} files skipped)"),
+        };
+        Ok(format!(
+            "Indexed {} chunks of {} files with {method}\nSaved to {}",
+            rag.chunks.len(),
+            files.len() - skipped,
+            path.display()
+        ))
+    }
+
+    // this function saves the index in the rags dir and returns its path
+    fn save(&self) -> Result<PathBuf> {
+        let path = Config::rag_file(&self.name)?;
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let data = bincode::serialize(self)?;
+        write(&path, data)
+            .with_context(|| format!("Failed to save index at {}", path.display()))?;
+        Ok(path)
+    }
+
+    // this function returns the chunks closest to a question, by their vectors or their words
+    pub fn retrieve(&self, config: &GlobalConfig, question: &str) -> Result<Vec<&Chunk>> {
+        let top_k = config.read().rag_top_k;
+        let Some(id) = &self.model else {
+            let results = match &self.bm25 {
+                Some(bm25) => bm25.search(question, top_k),
+                None => vec![],
+            };
+            return Ok(results.into_iter().map(|(i, _)| &self.chunks[i]).collect());
+        };
+        let model = embedding_model(&config.read(), Some(id))?;
+        let question: String = question.chars().take(MAX_TEXT_CHARS).collect();
+        let vectors = embed_texts(config, &model, &[question])?;
+        match vectors.first() {
+            Some(vector) => Ok(self.search(vector, top_k)),
+            None => Ok(vec![]),
+        }
+    }
+
+    // this function returns the chunks whose vectors are the closest to the vector of a question
+    pub fn search(&self, vector: &[f32], top_k: usize) -> Vec<&Chunk> {
+        let mut scores: Vec<(f32, &Chunk)> = self
+            .chunks
+            .iter()
+            .map(|v| (cosine_similarity(vector, &v.vector), v))
+            .collect();
+        scores.sort_by(|a, b| b.0.total_cmp(&a.0));
+        scores.into_iter().take(top_k).map(|(_, v)| v).collect()
+    }
+
+    // this function returns the name, the model and the size of the index for `.info`
+    pub fn info(&self) -> String {
+        let files = self
+            .chunks
+            .iter()
+            .map(|v| v.source.as_str())
+            .collect::<HashSet<_>>()
+            .len();
+        format!(
+            "{} ({}, {} chunks of {} files)",
+            self.name,
+            self.model.as_deref().unwrap_or("bm25"),
+            self.chunks.len(),
+            files
+        )
+    }
+}
+
+// this function retrieves the chunks of the current index closest to the question and adds them
+// to the input as its context, an input that already has one, like a regenerated one, keeps it
+pub fn augment_input(config: &GlobalConfig, input: &mut Input) -> Result<()> {
+    let Some(rag) = config.read().rag.clone() else {
+        return Ok(());
+    };
+    if input.context().is_some() || input.text().trim().is_empty() {
+        return Ok(());
+    }
+    let chunks = rag.retrieve(config, input.text())?;
+    if !chunks.is_empty() {
+        let sources = chunks
+            .iter()
+            .enumerate()
+            .map(|(i, v)| format!("[{}] {}", i + 1, v.location()))
+            .collect();
+        input.set_context(format_context(&chunks), sources);
+    }
+    Ok(())
+}
+
+// this function returns the list of the sources of the passages sent with the input, printed under the answer
+pub fn render_sources(input: &Input) -> Option<String> {
+    if input.sources().is_empty() {
+        return None;
+    }
+    Some(format!("Sources:\n{}", input.sources().join("\n")))
+}
+
+// this function lists the names of the indexes in the rags dir
+pub fn list_rags() -> Vec<String> {
+    let Ok(rd) = Config::rags_dir().and_then(|v| Ok(read_dir(v)?)) else {
+        return vec![];
+    };
+    let mut names: Vec<String> = rd
+        .flatten()
+        .filter_map(|v| {
+            let name = v.file_name();
+            name.to_string_lossy()
+                .strip_suffix(".bin")
+                .map(|v| v.to_string())
+        })
+        .collect();
+    names.sort_unstable();
+    names
+}
+
+// this function formats the retrieved chunks, numbered so that the answer can cite them
+fn format_context(chunks: &[&Chunk]) -> String {
+    let sources: Vec<String> = chunks
+        .iter()
+        .enumerate()
+        .map(|(i, v)| format!("[{}] {}\n{}", i + 1, v.location(), v.text))
+        .collect();
+    format!(
+        "Answer the question that follows using these sources, and cite the ones you use by their number, like [1].\n\n{}",
+        sources.join("\n\n")
+    )
+}
+
+// this function returns the model embedding the texts: the given one, the `embedding_model` of the config,
+// or the first model that can embed
+fn embedding_model(config: &Config, id: Option<&str>) -> Result<Model> {
+    let models = list_models(config);
+    match id.or(config.embedding_model.as_deref()) {
+        Some(id) => {
+            Model::find(&models, id).with_context(|| format!("Unknown embedding model '{id}'"))
+        }
+        None => models
+            .into_iter()
+            .find(|v| v.capabilities.contains(ModelCapabilities::Embed))
+            .with_context(|| "No embedding model, set `embedding_model` in the config"),
+    }
+}
+
+// this function turns texts into vectors with a model, in batches
+fn embed_texts(config: &GlobalConfig, model: &Model, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+    if texts.is_empty() {
+        return Ok(vec![]);
+    }
+    let mut embed_config = config.read().clone();
+    embed_config.model = model.clone();
+    let client = init_client(&Arc::new(RwLock::new(embed_config)))?;
+    init_tokio_runtime()?.block_on(async {
+        let http_client = client.build_client()?;
+        let mut vectors = Vec::with_capacity(texts.len());
+        for batch in texts.chunks(EMBED_BATCH_SIZE) {
+            let list = client
+                .embeddings(&http_client, batch)
+                .await
+                .with_context(|| format!("Failed to embed with {}", model.id()))?;
+            if list.len() != batch.len() {
+                bail!(
+                    "Invalid embeddings, expected {} vectors but got {}",
+                    batch.len(),
+                    list.len()
+                );
+            }
+            vectors.extend(list);
+        }
+        Ok(vectors)
+    })
+}
+
+// this function adds the file of a path, or the files under a directory, skipping the hidden entries and the
+// ones matched by the `.gitignore` and `.ignore` files, in a git repository or not
+fn collect_files(path: &Path, files: &mut Vec<PathBuf>) {
+    let walker = WalkBuilder::new(path)
+        .require_git(false)
+        .sort_by_file_name(|a, b| a.cmp(b))
+        .build();
+    for entry in walker.flatten() {
+        if entry.file_type().is_some_and(|v| v.is_file()) {
+            files.push(entry.into_path());
+        }
+    }
+}
+
+// this function splits a text into chunks of about CHUNK_SIZE characters on line boundaries, each one
+// starting with the last lines of the previous one that fit in CHUNK_OVERLAP
+fn chunk_text(text: &str) -> Vec<((usize, usize), String)> {
+    let lines: Vec<&str> = text.lines().collect();
+    let mut chunks = vec![];
+    let mut start = 0;
+    while start < lines.len() {
+        let mut end = start;
+        let mut size = 0;
+        while end < lines.len() && (end == start || size + lines[end].len() < CHUNK_SIZE) {
+            size += lines[end].len() + 1;
+            end += 1;
+        }
+        let chunk = lines[start..end].join("\n");
+        if !chunk.trim().is_empty() {
+            let chunk = match chunk.char_indices().nth(MAX_TEXT_CHARS) {
+                Some((i, _)) => chunk[..i].to_string(),
+                None => chunk,
+            };
+            chunks.push(((start + 1, end), chunk));
+        }
+        if end == lines.len() {
+            break;
+        }
+        // the next chunk always starts after the start of this one
+        let mut next = end;
+        let mut overlap = 0;
+        while next > start + 1 && overlap + lines[next - 1].len() < CHUNK_OVERLAP {
+            next -= 1;
+            overlap += lines[next].len() + 1;
+        }
+        start = next;
+    }
+    chunks
+}
+
+// this function returns the cosine of the angle between two vectors, 0 when one is empty or null
+fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norms = norm(a) * norm(b);
+    if norms == 0.0 {
+        0.0
+    } else {
+        dot / norms
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chunk_text() {
+        let line = "x".repeat(99);
+        let text = vec![line.as_str(); 40].join("\n");
+        let chunks = chunk_text(&text);
+        // 15 lines of 100 characters fit in a chunk, the next one repeats the last 2
+        assert_eq!(chunks[0].0, (1, 15));
+        assert_eq!(chunks[1].0, (14, 28));
+        assert_eq!(chunks.last().unwrap().0 .1, 40);
+
+        let chunks = chunk_text("a\n\n\nb");
+        assert_eq!(chunks, [((1, 4), "a\n\n\nb".to_string())]);
+        assert!(chunk_text("").is_empty());
+        assert!(chunk_text("\n  \n").is_empty());
+    }
+
+    #[test]
+    fn test_search() {
+        let chunk = |source: &str, vector: Vec<f32>| Chunk {
+            source: source.into(),
+            lines: (1, 1),
+            text: String::new(),
+            vector,
+        };
+        let rag = Rag {
+            chunks: vec![
+                chunk("a", vec![1.0, 0.0]),
+                chunk("b", vec![0.0, 1.0]),
+                chunk("c", vec![0.7, 0.7]),
+            ],
+            ..Default::default()
+        };
+        let sources: Vec<&str> = rag
+            .search(&[1.0, 0.2], 2)
+            .iter()
+            .map(|v| v.source.as_str())
+            .collect();
+        assert_eq!(sources, ["a", "c"]);
+        assert_eq!(cosine_similarity(&[1.0, 0.0], &[0.0, 0.0]), 0.0);
+
+        let context = format_context(&rag.search(&[0.0, 1.0], 1));
+        assert!(context.ends_with("[1] b:1-1\n"));
+    }
+
+    #[test]
+    fn test_retrieve_bm25() {
+        let chunk = |source: &str, text: &str| Chunk {
+            source: source.into(),
+            lines: (1, 3),
+            text: text.into(),
+            vector: vec![],
+        };
+        let chunks = vec![
+            chunk("fruit.md", "Apples and pears grow on trees"),
+            chunk("lang.txt", "Rust is a systems programming language"),
+        ];
+        let rag = Rag {
+            bm25: Some(Bm25Index::new(chunks.iter().map(|v| v.text.as_str()))),
+            chunks,
+            ..Default::default()
+        };
+        let config = Arc::new(RwLock::new(Config::default()));
+        let found = rag.retrieve(&config, "What is Rust?").unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].location(), "lang.txt:1-3");
+        assert!(rag.retrieve(&config, "bananas").unwrap().is_empty());
+    }
+}
@@ -1,9 +1,10 @@
-use crate::client::{Message, MessageContent, MessageRole};
+use crate::client::{GenerationParams, Message, MessageContent, MessageRole};
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
-use super::Input;
+use super::{deserialize_model_chain, Input};
 
 // a constant string used as a placeholder for input within the role's prompt
 const INPUT_PLACEHOLDER: &str = "__INPUT__";
@@ -15,8 +16,21 @@ pub struct Role {
     pub name: String,
     /// Prompt text
     pub prompt: String,
+    /// Model to use, followed by its fallbacks
+    #[serde(
+        default,
+        skip_serializing_if = "String::is_empty",
+        deserialize_with = "deserialize_model_chain"
+    )]
+    pub model: String,
     /// What sampling temperature to use, between 0 and 2
     pub temperature: Option<f64>,
+    /// Other generation parameters, replacing the ones of the config
+    #[serde(flatten)]
+    pub params: GenerationParams,
+    /// Json schema the answers must match
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schema: Option<Value>,
 }
 
 impl Role {
@@ -90,6 +104,32 @@ impl Role {
             ]
         }
     }
+
+    // this function applies the role's prompt to messages that were built elsewhere, e.g. by an api caller
+    // an embedded prompt wraps the last user message, others replace the system message
+    pub fn patch_messages(&self, messages: &mut Vec<Message>) -> Result<()> {
+        if self.embedded() {
+            let message = match messages.iter_mut().rev().find(|v| v.role.is_user()) {
+                Some(v) => v,
+                None => bail!("No user message for role `{}`", self.name),
+            };
+            message
+                .content
+                .merge_prompt(|v: &str| self.prompt.replace(INPUT_PLACEHOLDER, v));
+        } else {
+            if messages.first().is_some_and(|v| v.role.is_system()) {
+                messages.remove(0);
+            }
+            messages.insert(
+                0,
+                Message {
+                    role: MessageRole::System,
+                    content: MessageContent::Text(self.prompt.clone()),
+                },
+            );
+        }
+        Ok(())
+    }
 }
 
 // this function replaces placeholder arguments in the prompt with actual values derived from the provided name
@@ -118,4 +158,59 @@ mod tests {
             "convert foo to bar"
         );
     }
+
+    #[test]
+    fn test_role_model() {
+        let role: Role = serde_yaml::from_str(
+            "name: coder\nprompt: write code\nmodel:\n  - openai:gpt-4\n  - ollama:llama3\n",
+        )
+        .unwrap();
+        assert_eq!(role.model, "openai:gpt-4,ollama:llama3");
+        let role: Role =
+            serde_yaml::from_str("name: pirate\nprompt: talk like a pirate\n").unwrap();
+        assert!(role.model.is_empty());
+        assert!(!role.info().unwrap().contains("model"));
+    }
+
+    #[test]
+    fn test_patch_messages() {
+        let user_message = |text: &str| Message {
+            role: MessageRole::User,
+            content: MessageContent::Text(text.into()),
+        };
+        let role = Role {
+            name: "translator".into(),
+            prompt: "translate `__INPUT__` to english".into(),
+            model: String::new(),
+            temperature: None,
+            params: Default::default(),
+            schema: None,
+        };
+        let mut messages = vec![user_message("bonjour")];
+        role.patch_messages(&mut messages).unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(
+            messages[0].content.to_text(),
+            "translate `bonjour` to english"
+        );
+
+        let role = Role {
+            name: "pirate".into(),
+            prompt: "talk like a pirate".into(),
+            model: String::new(),
+            temperature: None,
+            params: Default::default(),
+            schema: None,
+        };
+        let mut messages = vec![
+            Message {
+                role: MessageRole::System,
+                content: MessageContent::Text("be brief".into()),
+            },
+            user_message("hi"),
+        ];
+        role.patch_messages(&mut messages).unwrap();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].content.to_text(), "talk like a pirate");
+    }
 }
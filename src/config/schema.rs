@@ -0,0 +1,97 @@
+use super::{GlobalConfig, Input};
+
+use crate::utils::{extract_json, validate_json};
+
+use anyhow::{bail, Context, Result};
+use serde_json::Value;
+use std::fs::read_to_string;
+use std::path::Path;
+
+// the number of times an answer that does not match the schema is sent back to be repaired
+const SCHEMA_REPAIR_ROUNDS: usize = 1;
+
+// this function reads the json schema of `--schema`
+pub fn read_schema(path: &Path) -> Result<Value> {
+    let text = read_to_string(path)
+        .with_context(|| format!("Failed to read schema at '{}'", path.display()))?;
+    let schema: Value = serde_json::from_str(&text)
+        .with_context(|| format!("Invalid json schema at '{}'", path.display()))?;
+    if !schema.is_object() {
+        bail!(
+            "Invalid json schema at '{}', expected an object",
+            path.display()
+        );
+    }
+    Ok(schema)
+}
+
+// this function sends the input and, when the config has a schema, checks that the answer is a json matching it.
+// A wrong answer is sent back with the error for the model to repair, the answer returned is the json
+pub fn send_with_schema<F>(config: &GlobalConfig, input: &mut Input, mut send: F) -> Result<String>
+where
+    F: FnMut(&mut Input) -> Result<String>,
+{
+    let mut output = send(input)?;
+    // a dry run answers with the messages, which are not checked
+    let schema = match config.read().get_schema() {
+        Some(schema) if !config.read().dry_run => schema,
+        _ => return Ok(output),
+    };
+    let mut round = 0;
+    loop {
+        let checked =
+            extract_json(&output).and_then(|value| validate_json(&schema, &value).map(|_| value));
+        match checked {
+            Ok(value) => {
+                input.clear_repair_messages();
+                return Ok(serde_json::to_string_pretty(&value)?);
+            }
+            Err(err) if round < SCHEMA_REPAIR_ROUNDS => {
+                round += 1;
+                eprintln!("The answer does not match the schema, {err}, asking for a repair");
+                let request = format!(
+                    "Your answer does not match the JSON schema: {err}.\nAnswer again with only the corrected JSON, without any explanation or code fence."
+                );
+                input.add_repair(output, request);
+                output = send(input)?;
+            }
+            Err(err) => bail!("The answer does not match the schema, {err}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use parking_lot::RwLock;
+    use serde_json::json;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_send_with_schema() {
+        let config = Config {
+            schema: Some(json!({ "type": "object", "required": ["level"] })),
+            ..Default::default()
+        };
+        let config = Arc::new(RwLock::new(config));
+        let mut answers = vec!["{\"level\": \"warn\"}", "```json\n{}\n```"];
+        let mut input = Input::from_str("2024-05-01 WARN disk almost full");
+        let output = send_with_schema(&config, &mut input, |input| {
+            // the repair is asked for with the wrong answer
+            if answers.len() == 1 {
+                assert_eq!(input.repair_messages().len(), 2);
+            }
+            Ok(answers.pop().unwrap().to_string())
+        })
+        .unwrap();
+        assert_eq!(output, "{\n  \"level\": \"warn\"\n}");
+        assert!(input.repair_messages().is_empty());
+
+        let err = send_with_schema(&config, &mut input, |_| Ok("[]".into())).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "The answer does not match the schema, /: expected object, got array"
+        );
+    }
+}
@@ -0,0 +1,315 @@
+// This file implements the full-text search over the saved sessions and the messages file
+use super::message_log::load_message_records;
+use super::session::Session;
+use super::{Config, MESSAGES_FILE_NAME, MESSAGES_LOG_FILE_NAME};
+
+use crate::client::MessageRole;
+
+use anyhow::{bail, Context, Result};
+use fancy_regex::Regex;
+use nu_ansi_term::{Color, Style};
+use std::fs::{read_dir, read_to_string};
+
+// the maximum number of results of a search
+const SEARCH_LIMIT: usize = 20;
+
+// the number of characters kept around the first match in a snippet
+const SNIPPET_CONTEXT: usize = 60;
+
+// enum representing where a message was found
+#[derive(Debug, Clone, PartialEq)]
+pub enum SearchSource {
+    Session(String),    // a saved session, by name
+    File(&'static str), // the messages file, markdown or jsonl
+}
+
+// struct representing a message matching a search
+#[derive(Debug, Clone, PartialEq)]
+pub struct SearchHit {
+    pub source: SearchSource,
+    pub timestamp: String, // when the message was saved, the session file time for sessions
+    pub role: String,      // who wrote the message, user or assistant
+    pub snippet: String,   // the text around the first match, on one line
+    score: usize,
+}
+
+// struct representing the ranked results of a search
+#[derive(Debug)]
+pub struct SearchResults {
+    pub hits: Vec<SearchHit>,
+    terms: Vec<String>,
+    regex: Regex,
+}
+
+impl SearchResults {
+    // this function returns the session of the best ranked result found in a session
+    pub fn top_session(&self) -> Option<&str> {
+        self.hits.iter().find_map(|v| match &v.source {
+            SearchSource::Session(name) => Some(name.as_str()),
+            SearchSource::File(_) => None,
+        })
+    }
+
+    // this function renders the results as a numbered list, the matches being highlighted in color
+    pub fn render(&self, highlight: bool) -> String {
+        if self.hits.is_empty() {
+            return format!("No messages match '{}'", self.terms.join(" "));
+        }
+        let source_style = if highlight {
+            Style::new().fg(Color::Cyan)
+        } else {
+            Style::new()
+        };
+        self.hits
+            .iter()
+            .enumerate()
+            .map(|(i, hit)| {
+                let source = match &hit.source {
+                    SearchSource::Session(name) => name.as_str(),
+                    SearchSource::File(name) => name,
+                };
+                format!(
+                    "{}. {}  {}  {}\n   {}",
+                    i + 1,
+                    source_style.paint(source),
+                    hit.timestamp,
+                    hit.role,
+                    self.highlight_snippet(&hit.snippet, highlight)
+                )
+            })
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+
+    // this function paints the matches of the query in a snippet
+    fn highlight_snippet(&self, snippet: &str, highlight: bool) -> String {
+        if !highlight {
+            return snippet.to_string();
+        }
+        let style = Style::new().bold().fg(Color::Yellow);
+        let mut output = String::new();
+        let mut last = 0;
+        for m in self.regex.find_iter(snippet).flatten() {
+            output.push_str(&snippet[last..m.start()]);
+            output.push_str(&style.paint(m.as_str()).to_string());
+            last = m.end();
+        }
+        output.push_str(&snippet[last..]);
+        output
+    }
+}
+
+// this function searches every saved session and the messages file for the words of the query,
+// a message matches when it contains all of them and is ranked by how often they appear
+pub fn search_messages(query: &str) -> Result<SearchResults> {
+    let terms: Vec<String> = query.split_whitespace().map(|v| v.to_lowercase()).collect();
+    if terms.is_empty() {
+        bail!("Usage: .search [--open] <query>");
+    }
+    let pattern = terms
+        .iter()
+        .map(|v| fancy_regex::escape(v).to_string())
+        .collect::<Vec<String>>()
+        .join("|");
+    let regex = Regex::new(&format!("(?i){pattern}")).with_context(|| "Invalid search query")?;
+
+    let mut hits = vec![];
+    let mut add_hit = |source: SearchSource, timestamp: &str, role: &str, text: &str| {
+        if let Some(score) = score_text(&terms, text) {
+            hits.push(SearchHit {
+                source,
+                timestamp: timestamp.to_string(),
+                role: role.to_string(),
+                snippet: build_snippet(&regex, text),
+                score,
+            });
+        }
+    };
+
+    if let Ok(rd) = read_dir(Config::sessions_dir()?) {
+        for entry in rd.flatten() {
+            let path = entry.path();
+            let file_name = entry.file_name();
+            let Some(name) = file_name
+                .to_string_lossy()
+                .strip_suffix(".yaml")
+                .map(|v| v.to_string())
+            else {
+                continue;
+            };
+            // a broken session file must not prevent searching the others
+            let session = match Session::load(&name, &path) {
+                Ok(v) => v,
+                Err(err) => {
+                    debug!("Search skips session {name}: {err:#}");
+                    continue;
+                }
+            };
+            let timestamp = entry
+                .metadata()
+                .and_then(|v| v.modified())
+                .map(|v| {
+                    chrono::DateTime::<chrono::Local>::from(v)
+                        .to_rfc3339_opts(chrono::SecondsFormat::Secs, false)
+                })
+                .unwrap_or_default();
+            for message in session.messages() {
+                let role = match message.role {
+                    MessageRole::User => "user",
+                    MessageRole::Assistant => "assistant",
+                    _ => continue,
+                };
+                add_hit(
+                    SearchSource::Session(name.clone()),
+                    &timestamp,
+                    role,
+                    &message.content.to_text(),
+                );
+            }
+        }
+    }
+
+    let messages_file = Config::messages_file()?;
+    if messages_file.exists() {
+        let content = read_to_string(&messages_file)
+            .with_context(|| format!("Failed to load messages at {}", messages_file.display()))?;
+        for (timestamp, role, text) in parse_messages_file(&content) {
+            add_hit(
+                SearchSource::File(MESSAGES_FILE_NAME),
+                &timestamp,
+                role,
+                &text,
+            );
+        }
+    }
+
+    for (_, record) in load_message_records(&Config::messages_log_file()?)? {
+        let source = SearchSource::File(MESSAGES_LOG_FILE_NAME);
+        add_hit(source.clone(), &record.timestamp, "user", &record.input);
+        add_hit(source, &record.timestamp, "assistant", &record.output);
+    }
+
+    hits.sort_by(|a, b| {
+        b.score
+            .cmp(&a.score)
+            .then_with(|| b.timestamp.cmp(&a.timestamp))
+    });
+    hits.truncate(SEARCH_LIMIT);
+    Ok(SearchResults { hits, terms, regex })
+}
+
+// this function scores a text by the number of occurrences of the terms, with a bonus when they appear
+// together as in the query, or returns None when one of them is missing
+fn score_text(terms: &[String], text: &str) -> Option<usize> {
+    let text = text.to_lowercase();
+    let mut score = 0;
+    for term in terms {
+        let count = text.matches(term.as_str()).count();
+        if count == 0 {
+            return None;
+        }
+        score += count;
+    }
+    if terms.len() > 1 && text.contains(&terms.join(" ")) {
+        score += terms.len();
+    }
+    Some(score)
+}
+
+// this function extracts the text around the first match, with its whitespace collapsed
+fn build_snippet(regex: &Regex, text: &str) -> String {
+    let text = text.split_whitespace().collect::<Vec<&str>>().join(" ");
+    let (match_start, match_end) = match regex.find(&text) {
+        Ok(Some(m)) => (m.start(), m.end()),
+        _ => (0, 0),
+    };
+    let start = text[..match_start]
+        .char_indices()
+        .rev()
+        .nth(SNIPPET_CONTEXT - 1)
+        .map(|(i, _)| i)
+        .unwrap_or(0);
+    let end = text[match_end..]
+        .char_indices()
+        .nth(SNIPPET_CONTEXT * 2)
+        .map(|(i, _)| match_end + i)
+        .unwrap_or(text.len());
+    let prefix = if start > 0 { "..." } else { "" };
+    let suffix = if end < text.len() { "..." } else { "" };
+    format!("{prefix}{}{suffix}", &text[start..end])
+}
+
+// this function splits the messages file into its messages, as (timestamp, role, text),
+// each chat being a `# CHAT:[timestamp]` header followed by the input and the output between `--------` lines
+fn parse_messages_file(content: &str) -> Vec<(String, &'static str, String)> {
+    let mut messages = vec![];
+    let mut timestamp: Option<String> = None;
+    let mut parts: Vec<Vec<&str>> = vec![];
+    let mut flush = |timestamp: &Option<String>, parts: &mut Vec<Vec<&str>>| {
+        if let Some(timestamp) = timestamp {
+            for (role, lines) in ["user", "assistant"].into_iter().zip(parts.iter()) {
+                let text = lines.join("\n");
+                if !text.trim().is_empty() {
+                    messages.push((timestamp.clone(), role, text));
+                }
+            }
+        }
+        parts.clear();
+    };
+    for line in content.lines() {
+        if let Some(rest) = line.strip_prefix("# CHAT:[") {
+            flush(&timestamp, &mut parts);
+            timestamp = rest.split_once(']').map(|(v, _)| v.to_string());
+            parts.push(vec![]);
+        } else if line == "--------" {
+            parts.push(vec![]);
+        } else if let Some(lines) = parts.last_mut() {
+            lines.push(line);
+        }
+    }
+    flush(&timestamp, &mut parts);
+    messages
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_messages_file() {
+        let content = "# CHAT:[2024-03-01T10:00:00+08:00] openai:gpt-4\nkafka retention?\n--------\nSet retention.ms\n--------\n\n# CHAT:[2024-03-02T10:00:00+08:00] openai:gpt-4 (coder)\nhello\n--------\nhi\n--------\n\n";
+        let messages = parse_messages_file(content);
+        assert_eq!(
+            messages,
+            vec![
+                (
+                    "2024-03-01T10:00:00+08:00".into(),
+                    "user",
+                    "kafka retention?".into()
+                ),
+                (
+                    "2024-03-01T10:00:00+08:00".into(),
+                    "assistant",
+                    "Set retention.ms".into()
+                ),
+                ("2024-03-02T10:00:00+08:00".into(), "user", "hello".into()),
+                ("2024-03-02T10:00:00+08:00".into(), "assistant", "hi".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_score_and_snippet() {
+        let terms = vec!["kafka".to_string(), "config".to_string()];
+        assert_eq!(score_text(&terms, "The Kafka broker"), None);
+        assert_eq!(score_text(&terms, "Kafka: config of kafka"), Some(3));
+        assert_eq!(score_text(&terms, "my kafka config"), Some(4));
+
+        let regex = Regex::new("(?i)kafka|config").unwrap();
+        let text = format!("{} the\nKafka  config", "word ".repeat(30));
+        let snippet = build_snippet(&regex, &text);
+        assert!(snippet.starts_with("..."));
+        assert!(snippet.ends_with("the Kafka config"));
+        assert_eq!(build_snippet(&regex, "short config"), "short config");
+    }
+}
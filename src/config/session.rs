@@ -1,28 +1,108 @@
 use super::input::resolve_data_url;
 use super::role::Role;
-use super::{Input, Model};
+use super::usage::{format_cost, UsageTotals};
+use super::{deserialize_model_chain, GlobalConfig, Input, Model};
 
-use crate::client::{Message, MessageContent, MessageRole};
+use crate::client::{
+    Client, GenerationParams, Message, MessageContent, MessageRole, SendData, TokenUsage,
+};
 use crate::render::MarkdownRender;
+use crate::utils::init_tokio_runtime;
 
-use anyhow::{bail, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use serde::{Deserialize, Serialize};
-use serde_json::json;
+use serde_json::{json, Value};
 use std::collections::HashMap;
 use std::fs::{self, read_to_string};
+use std::ops::Range;
 use std::path::Path;
 
 // a constant representing the name used for temporary sessions.
 pub const TEMP_SESSION_NAME: &str = "temp";
 
+// the text introducing the summary of the earlier turns of a session
+const SUMMARY_PREFIX: &str = "Summary of the earlier conversation:\n";
+
+// the instructions used to summarize the earlier turns of a session
+const SUMMARIZE_PROMPT: &str = "Summarize the conversation below in a few short paragraphs. Keep the facts, decisions, names, numbers and open questions needed to continue it. Reply with the summary only.";
+
+// the name of the branch a session starts on
+const MAIN_BRANCH_NAME: &str = "main";
+
+// struct representing a branch of a session, an alternative continuation from one of its messages
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Branch {
+    name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    parent: Option<String>, // the branch it was created from
+    #[serde(default, skip_serializing_if = "is_zero")]
+    at: usize, // the number of messages it shared with its parent when created
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    messages: Vec<Message>, // its messages, empty for the current branch which lives in `Session::messages`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    summary: Option<String>, // the summary of its removed turns, kept in `Session::summary` for the current branch
+    #[serde(default, skip_serializing_if = "is_zero")]
+    compressed_messages: usize, // its number of removed messages, kept in `Session::compressed_messages` for the current branch
+}
+
+// enum representing what to do when a session no longer fits in the context window of the model
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ContextStrategy {
+    #[default]
+    None, // the request fails with "Exceed max tokens limit"
+    Truncate,  // the oldest turns are dropped
+    Summarize, // the oldest turns are replaced by a summary written by the model
+}
+
+impl ContextStrategy {
+    // this function parses a context strategy from its name
+    pub fn parse(value: &str) -> Result<Self> {
+        match value {
+            "none" => Ok(ContextStrategy::None),
+            "truncate" => Ok(ContextStrategy::Truncate),
+            "summarize" => Ok(ContextStrategy::Summarize),
+            _ => bail!("Invalid context strategy '{value}'"),
+        }
+    }
+
+    // this function returns the name of the context strategy
+    pub fn stringify(&self) -> &str {
+        match self {
+            ContextStrategy::None => "none",
+            ContextStrategy::Truncate => "truncate",
+            ContextStrategy::Summarize => "summarize",
+        }
+    }
+}
+
 // this struct represents a session within the system,
 // with its metadata, messages, and associated model and role
 #[derive(Debug, Clone, Default, Deserialize, Serialize)]
 pub struct Session {
-    #[serde(rename(serialize = "model", deserialize = "model"))]
-    model_id: String, // ID of the model associated with the session
+    #[serde(
+        rename(serialize = "model", deserialize = "model"),
+        deserialize_with = "deserialize_model_chain"
+    )]
+    model_id: String, // ID of the model associated with the session, followed by its fallbacks
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    last_model: String, // ID of the model that answered the last question, another one than the first after a fallback
     temperature: Option<f64>, // temperature to use for the session
-    messages: Vec<Message>,   // vector storing the messages exchanged within the session
+    #[serde(flatten)]
+    params: GenerationParams, // the other generation parameters of the session
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    context_strategy: Option<ContextStrategy>, // overrides the context strategy of the config
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    summary: Option<String>, // summary of the turns of the current branch removed by the `summarize` strategy
+    #[serde(default, skip_serializing_if = "is_zero")]
+    compressed_messages: usize, // number of messages of the current branch truncated or summarized so far
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    branch: String, // name of the current branch, empty until the session is branched
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    branches: Vec<Branch>, // every branch of the session, including the current one
+    #[serde(default, skip_serializing_if = "UsageTotals::is_empty")]
+    usage: UsageTotals, // tokens and cost of the requests of the session
+    messages: Vec<Message>, // vector storing the messages exchanged within the session
     #[serde(default)]
     data_urls: HashMap<String, String>, // hashmap storing data URLs
     #[serde(skip)]
@@ -42,9 +122,18 @@ impl Session {
     pub fn new(name: &str, model: Model, role: Option<Role>) -> Self {
         // Setting temperature based on the role, if provided
         let temperature = role.as_ref().and_then(|v| v.temperature);
+        let params = role.as_ref().map(|v| v.params.clone()).unwrap_or_default();
         Self {
             model_id: model.id(),
+            last_model: String::new(),
             temperature,
+            params,
+            context_strategy: None,
+            summary: None,
+            compressed_messages: 0,
+            branch: String::new(),
+            branches: vec![],
+            usage: Default::default(),
             messages: vec![],
             data_urls: Default::default(),
             name: name.to_string(),
@@ -86,11 +175,48 @@ impl Session {
         self.temperature
     }
 
+    // this function returns the generation parameters set for the session
+    pub fn params(&self) -> &GenerationParams {
+        &self.params
+    }
+
+    // this function returns the context strategy of the session, when it overrides the one of the config
+    pub fn context_strategy(&self) -> Option<ContextStrategy> {
+        self.context_strategy
+    }
+
+    // this function sets the context strategy of the session
+    pub fn set_context_strategy(&mut self, value: Option<ContextStrategy>) {
+        self.context_strategy = value;
+        self.dirty = true;
+    }
+
+    // this function returns the number of messages truncated or summarized so far
+    pub fn compressed_messages(&self) -> usize {
+        self.compressed_messages
+    }
+
     // this function calculates and returns the total number of tokens in the session
     pub fn tokens(&self) -> usize {
         self.model.total_tokens(&self.messages)
     }
 
+    // this function returns the tokens and the cost of the requests of the session
+    pub fn usage(&self) -> UsageTotals {
+        self.usage
+    }
+
+    // this function adds a request to the usage of the session
+    pub fn add_usage(&mut self, usage: TokenUsage, cost: Option<f64>) {
+        self.usage.add(usage, cost);
+        self.dirty = true;
+    }
+
+    // this function returns the messages of the current branch
+    pub fn messages(&self) -> &[Message] {
+        &self.messages
+    }
+
     // this function counts and returns the number of user messages in the session
     pub fn user_messages_len(&self) -> usize {
         self.messages.iter().filter(|v| v.role.is_user()).count()
@@ -111,6 +237,11 @@ impl Session {
         if let Some(temperature) = self.temperature() {
             data["temperature"] = temperature.into();
         }
+        if let Value::Object(params) = json!(self.params) {
+            for (key, value) in params {
+                data[key] = value;
+            }
+        }
         data["total_tokens"] = tokens.into();
         if let Some(max_tokens) = self.model.max_tokens {
             data["max_tokens"] = max_tokens.into();
@@ -136,16 +267,52 @@ impl Session {
             items.push(("path", path.to_string()));
         }
 
-        items.push(("model", self.model.id()));
+        items.push(("model", self.model_id.clone()));
+
+        if !self.last_model.is_empty() {
+            items.push(("last_model", self.last_model.clone()));
+        }
 
         if let Some(temperature) = self.temperature() {
             items.push(("temperature", temperature.to_string()));
         }
 
+        items.extend(self.params.items().into_iter().filter(|(_, v)| v != "-"));
+
         if let Some(max_tokens) = self.model.max_tokens {
             items.push(("max_tokens", max_tokens.to_string()));
         }
 
+        // the token counts are approximate when the tokenizer of the model failed to load
+        if let Some(fallback) = self.model.tokenizer_fallback() {
+            items.push(("tokenizer", fallback));
+        }
+
+        if let Some(context_strategy) = self.context_strategy {
+            items.push(("context_strategy", context_strategy.stringify().into()));
+        }
+
+        if self.compressed_messages > 0 {
+            items.push(("compressed", self.compressed_messages.to_string()));
+        }
+
+        if !self.branches.is_empty() {
+            items.push(("branch", self.branch_name().to_string()));
+        }
+
+        if !self.usage.is_empty() {
+            items.push((
+                "usage",
+                format!(
+                    "{} requests, {} + {} tokens, {}",
+                    self.usage.requests,
+                    self.usage.input_tokens,
+                    self.usage.output_tokens,
+                    format_cost(self.usage.cost)
+                ),
+            ));
+        }
+
         let mut lines: Vec<String> = items
             .iter()
             .map(|(name, value)| format!("{name:<20}{value}"))
@@ -157,12 +324,20 @@ impl Session {
 
             for message in &self.messages {
                 match message.role {
-                    MessageRole::System => {
+                    MessageRole::System | MessageRole::Tool => {
                         continue;
                     }
                     MessageRole::Assistant => {
-                        if let MessageContent::Text(text) = &message.content {
-                            lines.push(render.render(text));
+                        match &message.content {
+                            MessageContent::Text(text) => lines.push(render.render(text)),
+                            // assistant messages with tool calls are only shown by their text
+                            MessageContent::Array(_) => {
+                                let text = message.content.to_text();
+                                if text.is_empty() {
+                                    continue;
+                                }
+                                lines.push(render.render(&text));
+                            }
                         }
                         lines.push("".into());
                     }
@@ -200,6 +375,7 @@ impl Session {
         self.guard_empty()?;
         // updating the session's temperature based on the new role, if provided
         self.temperature = role.as_ref().and_then(|v| v.temperature);
+        self.params = role.as_ref().map(|v| v.params.clone()).unwrap_or_default();
         self.role = role;
         Ok(())
     }
@@ -209,9 +385,14 @@ impl Session {
         self.temperature = value;
     }
 
-    // this funciton sets the model of the session
-    pub fn set_model(&mut self, model: Model) -> Result<()> {
-        self.model_id = model.id();
+    // this function sets a generation parameter of the session from its text
+    pub fn set_param(&mut self, key: &str, value: &str) -> Result<()> {
+        self.params.set(key, value)
+    }
+
+    // this funciton sets the model of the session, the model id may list fallbacks after it
+    pub fn set_model(&mut self, model_id: &str, model: Model) -> Result<()> {
+        self.model_id = model_id.to_string();
         self.model = model;
         Ok(())
     }
@@ -267,9 +448,17 @@ impl Session {
         self.name == TEMP_SESSION_NAME
     }
 
-    // this function checks if the session is empty
+    // this function checks if the session is empty, a branched session never is
     pub fn is_empty(&self) -> bool {
-        self.messages.is_empty()
+        self.messages.is_empty() && self.branches.is_empty()
+    }
+
+    // this function records the model that answered the last question
+    pub fn set_last_model(&mut self, model_id: String) {
+        if self.last_model != model_id {
+            self.last_model = model_id;
+            self.dirty = true;
+        }
     }
 
     // this function adds a message exchange(user input and assistant output) to the session
@@ -288,6 +477,8 @@ impl Session {
                 content: input.to_message_content(),
             });
         }
+        // keeping the tool calls made while answering the input
+        self.messages.extend(input.tool_messages().iter().cloned());
         // updating data urls associated with the input
         self.data_urls.extend(input.data_urls());
         self.messages.push(Message {
@@ -324,7 +515,498 @@ impl Session {
                 content: input.to_message_content(),
             });
         }
+        // the summary of the earlier turns joins the system message
+        if let Some(summary) = &self.summary {
+            let note = format!("{SUMMARY_PREFIX}{summary}");
+            match messages.first_mut() {
+                Some(Message {
+                    role: MessageRole::System,
+                    content: MessageContent::Text(text),
+                }) => {
+                    text.push_str("\n\n");
+                    text.push_str(&note);
+                }
+                _ => messages.insert(
+                    0,
+                    Message {
+                        role: MessageRole::System,
+                        content: MessageContent::Text(note),
+                    },
+                ),
+            }
+        }
         // Returning a vector of messages
         messages
     }
+
+    // this function removes the last turn, the last user message and the replies to it
+    pub fn undo(&mut self) -> Result<()> {
+        match self.messages.iter().rposition(|v| v.role.is_user()) {
+            Some(index) => {
+                self.messages.truncate(index);
+                self.dirty = true;
+                Ok(())
+            }
+            None => bail!("Nothing to undo"),
+        }
+    }
+
+    // this function returns the name of the current branch
+    pub fn branch_name(&self) -> &str {
+        if self.branch.is_empty() {
+            MAIN_BRANCH_NAME
+        } else {
+            &self.branch
+        }
+    }
+
+    // this function returns the name of the current branch, once the session has been branched
+    pub fn branch(&self) -> Option<&str> {
+        if self.branches.is_empty() {
+            None
+        } else {
+            Some(self.branch_name())
+        }
+    }
+
+    // this function creates a branch from the current one and switches to it,
+    // the branch keeps the first `turns` turns, or all of them
+    pub fn create_branch(&mut self, name: &str, turns: Option<usize>) -> Result<()> {
+        if self.branch_name() == name || self.branches.iter().any(|v| v.name == name) {
+            bail!("Branch '{name}' already exists");
+        }
+        let mut messages = self.messages.clone();
+        if let Some(turns) = turns {
+            let len = self.user_messages_len();
+            if turns > len {
+                bail!("The branch '{}' has only {len} turns", self.branch_name());
+            }
+            // the messages before the first turn (the system message of the role) are always kept
+            let end = match self.oldest_turns_range(turns) {
+                Some(range) => range.end,
+                None => self
+                    .messages
+                    .iter()
+                    .position(|v| v.role.is_user())
+                    .unwrap_or(self.messages.len()),
+            };
+            messages.truncate(end);
+        }
+        // the branch follows the same summary, the turns it replaces come before the kept ones
+        let summary = self.summary.clone();
+        let compressed_messages = self.compressed_messages;
+        self.stash_branch();
+        self.branches.push(Branch {
+            name: name.to_string(),
+            parent: Some(self.branch_name().to_string()),
+            at: messages.len(),
+            messages: vec![],
+            summary: None,
+            compressed_messages: 0,
+        });
+        self.branch = name.to_string();
+        self.messages = messages;
+        self.summary = summary;
+        self.compressed_messages = compressed_messages;
+        self.dirty = true;
+        Ok(())
+    }
+
+    // this function switches to another branch, the messages and the summary of the current one are kept in its branch
+    pub fn switch_branch(&mut self, name: &str) -> Result<()> {
+        if self.branch_name() == name {
+            return Ok(());
+        }
+        let index = self
+            .branches
+            .iter()
+            .position(|v| v.name == name)
+            .ok_or_else(|| anyhow!("Not found branch '{name}'"))?;
+        self.stash_branch();
+        let branch = &mut self.branches[index];
+        self.messages = std::mem::take(&mut branch.messages);
+        self.summary = branch.summary.take();
+        self.compressed_messages = std::mem::take(&mut branch.compressed_messages);
+        self.branch = name.to_string();
+        self.dirty = true;
+        Ok(())
+    }
+
+    // this function lists the branches as a tree, the current one being marked with a star
+    pub fn list_branches(&self) -> String {
+        fn walk(session: &Session, parent: Option<&str>, depth: usize, lines: &mut Vec<String>) {
+            for branch in session.branches.iter() {
+                if branch.parent.as_deref() != parent {
+                    continue;
+                }
+                let current = branch.name == session.branch_name();
+                let messages = if current {
+                    session.messages.len()
+                } else {
+                    branch.messages.len()
+                };
+                let marker = if current { "*" } else { " " };
+                let indent = "  ".repeat(depth);
+                let from = match &branch.parent {
+                    Some(parent) => format!(", from {parent} at message {}", branch.at),
+                    None => String::new(),
+                };
+                lines.push(format!(
+                    "{marker} {indent}{} ({messages} messages{from})",
+                    branch.name
+                ));
+                walk(session, Some(&branch.name), depth + 1, lines);
+            }
+        }
+        if self.branches.is_empty() {
+            return format!(
+                "* {} ({} messages)",
+                self.branch_name(),
+                self.messages.len()
+            );
+        }
+        let mut lines = vec![];
+        walk(self, None, 0, &mut lines);
+        lines.join("\n")
+    }
+
+    // this function returns the names of the branches
+    pub fn branch_names(&self) -> Vec<String> {
+        if self.branches.is_empty() {
+            return vec![self.branch_name().to_string()];
+        }
+        self.branches.iter().map(|v| v.name.clone()).collect()
+    }
+
+    // this function moves the messages and the summary of the current branch to its entry in the branch list,
+    // the first branching records the session so far as the main branch
+    fn stash_branch(&mut self) {
+        if self.branches.is_empty() {
+            self.branches.push(Branch {
+                name: MAIN_BRANCH_NAME.to_string(),
+                parent: None,
+                at: 0,
+                messages: vec![],
+                summary: None,
+                compressed_messages: 0,
+            });
+            self.branch = MAIN_BRANCH_NAME.to_string();
+        }
+        let messages = std::mem::take(&mut self.messages);
+        let summary = self.summary.take();
+        let compressed_messages = std::mem::take(&mut self.compressed_messages);
+        if let Some(branch) = self.branches.iter_mut().find(|v| v.name == self.branch) {
+            branch.messages = messages;
+            branch.summary = summary;
+            branch.compressed_messages = compressed_messages;
+        }
+    }
+
+    // this function returns the range of the oldest turns, a turn being a user message and the replies to it,
+    // the messages before the first turn (the system message of the role) are never part of it
+    fn oldest_turns_range(&self, count: usize) -> Option<Range<usize>> {
+        let starts: Vec<usize> = self
+            .messages
+            .iter()
+            .enumerate()
+            .filter(|(_, v)| v.role.is_user())
+            .map(|(i, _)| i)
+            .collect();
+        if starts.is_empty() || count == 0 {
+            return None;
+        }
+        let end = starts.get(count).copied().unwrap_or(self.messages.len());
+        Some(starts[0]..end)
+    }
+
+    // this function removes the oldest turns and returns their messages
+    pub fn take_oldest_turns(&mut self, count: usize) -> Vec<Message> {
+        let Some(range) = self.oldest_turns_range(count) else {
+            return vec![];
+        };
+        let messages: Vec<Message> = self.messages.drain(range).collect();
+        self.compressed_messages += messages.len();
+        self.dirty = true;
+        messages
+    }
+}
+
+// this function makes the session fit in the context window of the model before the input is sent,
+// following the context strategy of the session or, by default, the one of the config
+pub fn compress_session(config: &GlobalConfig, client: &dyn Client, input: &Input) -> Result<()> {
+    let strategy = {
+        let config = config.read();
+        match &config.session {
+            Some(session) if !config.dry_run => session
+                .context_strategy()
+                .unwrap_or(config.context_strategy),
+            _ => return Ok(()),
+        }
+    };
+    loop {
+        let turns = {
+            let config = config.read();
+            let messages = config.build_messages(input)?;
+            if config.model.max_tokens_limit(&messages).is_ok() {
+                return Ok(());
+            }
+            config
+                .session
+                .as_ref()
+                .map(|v| v.user_messages_len())
+                .unwrap_or_default()
+        };
+        // nothing left to remove, sending the input fails with "Exceed max tokens limit"
+        if turns == 0 {
+            return Ok(());
+        }
+        match strategy {
+            ContextStrategy::None => return Ok(()),
+            ContextStrategy::Truncate => {
+                let mut config = config.write();
+                if let Some(session) = config.session.as_mut() {
+                    let messages = session.take_oldest_turns(1);
+                    eprintln!(
+                        "Dropped {} messages to fit in the context window",
+                        messages.len()
+                    );
+                }
+            }
+            ContextStrategy::Summarize => {
+                // the older half of the turns is summarized, along with the previous summary,
+                // they are only removed from the session once the summary is written
+                let count = turns.div_ceil(2);
+                let (summary, messages) = {
+                    let config = config.read();
+                    match config.session.as_ref() {
+                        Some(session) => (
+                            session.summary.clone(),
+                            session
+                                .oldest_turns_range(count)
+                                .map(|v| session.messages[v].to_vec())
+                                .unwrap_or_default(),
+                        ),
+                        None => return Ok(()),
+                    }
+                };
+                let summary = summarize(config, client, summary, &messages)?;
+                if let Some(session) = config.write().session.as_mut() {
+                    session.take_oldest_turns(count);
+                    session.summary = Some(summary);
+                }
+                eprintln!(
+                    "Summarized {} messages to fit in the context window",
+                    messages.len()
+                );
+            }
+        }
+    }
+}
+
+// this function asks the model to summarize the messages, following the previous summary.
+// The transcript is sent in chunks that fit in the context window, each one summarized with the summary
+// of the ones before, and a message too long for a chunk is cut
+fn summarize(
+    config: &GlobalConfig,
+    client: &dyn Client,
+    mut summary: Option<String>,
+    messages: &[Message],
+) -> Result<String> {
+    let model = client.model();
+    let mut chunk = String::new();
+    for message in messages {
+        let text = message.content.to_text();
+        if message.role.is_system() || text.is_empty() {
+            continue;
+        }
+        let role = if message.role.is_user() {
+            "User"
+        } else {
+            "Assistant"
+        };
+        let entry = format!("{role}: {text}\n\n");
+        if fits_summary_window(model, summary.as_deref(), &format!("{chunk}{entry}")) {
+            chunk.push_str(&entry);
+            continue;
+        }
+        if !chunk.is_empty() {
+            summary = Some(summarize_chunk(config, client, summary.as_deref(), &chunk)?);
+            chunk.clear();
+        }
+        let mut kept = entry.chars().count();
+        chunk = entry.clone();
+        while kept > 0 && !fits_summary_window(model, summary.as_deref(), &chunk) {
+            kept = kept * 3 / 4;
+            chunk = entry.chars().take(kept).collect::<String>() + "…\n\n";
+        }
+    }
+    if chunk.is_empty() {
+        return Ok(summary.unwrap_or_default());
+    }
+    summarize_chunk(config, client, summary.as_deref(), &chunk)
+}
+
+// this function returns the messages asking to summarize a part of the transcript, after the previous summary
+fn summarize_messages(summary: Option<&str>, chunk: &str) -> Vec<Message> {
+    let transcript = match summary {
+        Some(summary) => format!("{SUMMARY_PREFIX}{summary}\n\n{chunk}"),
+        None => chunk.to_string(),
+    };
+    vec![
+        Message {
+            role: MessageRole::System,
+            content: MessageContent::Text(SUMMARIZE_PROMPT.into()),
+        },
+        Message {
+            role: MessageRole::User,
+            content: MessageContent::Text(transcript),
+        },
+    ]
+}
+
+// this function checks whether the request summarizing the chunk leaves a quarter of the context window
+// for the summary
+fn fits_summary_window(model: &Model, summary: Option<&str>, chunk: &str) -> bool {
+    let Some(max_tokens) = model.max_tokens else {
+        return true;
+    };
+    let (_, bias) = model.tokens_count_factors;
+    let messages = summarize_messages(summary, chunk);
+    model.total_tokens(&messages) + bias < max_tokens * 3 / 4
+}
+
+// this function sends a chunk of the transcript to be summarized, the tokens used are counted in the usage
+fn summarize_chunk(
+    config: &GlobalConfig,
+    client: &dyn Client,
+    summary: Option<&str>,
+    chunk: &str,
+) -> Result<String> {
+    let messages = summarize_messages(summary, chunk);
+    let data = SendData {
+        messages: messages.clone(),
+        temperature: None,
+        params: Default::default(),
+        stream: false,
+        tools: vec![],
+        schema: None,
+    };
+    let output = init_tokio_runtime()?.block_on(async {
+        let http_client = client.build_client()?;
+        client.send_message_with_retry(&http_client, data).await
+    });
+    let output = output.with_context(|| "Failed to summarize the session")?;
+    config.write().record_usage(&messages, &output)?;
+    Ok(output.text.trim().to_string())
+}
+
+// this function tells serde to skip counters that are zero
+fn is_zero(value: &usize) -> bool {
+    *value == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_take_oldest_turns() {
+        let mut session = Session::new("test", Model::default(), None);
+        for i in 0..3 {
+            session
+                .add_message(&Input::from_str(&format!("q{i}")), &format!("a{i}"))
+                .unwrap();
+        }
+        let messages = session.take_oldest_turns(2);
+        assert_eq!(messages.len(), 4);
+        assert_eq!(session.compressed_messages(), 4);
+        assert_eq!(session.user_messages_len(), 1);
+
+        session.summary = Some("q0 and q1 were asked".into());
+        let messages = session.build_emssages(&Input::from_str("q3"));
+        assert!(messages[0].role.is_system());
+        assert_eq!(
+            messages[0].content.to_text(),
+            format!("{SUMMARY_PREFIX}q0 and q1 were asked")
+        );
+        assert_eq!(messages.len(), 4);
+    }
+
+    #[test]
+    fn test_branches() {
+        let mut session = Session::new("test", Model::default(), None);
+        for i in 0..3 {
+            session
+                .add_message(&Input::from_str(&format!("q{i}")), &format!("a{i}"))
+                .unwrap();
+        }
+        session.create_branch("alt", Some(1)).unwrap();
+        assert_eq!(session.messages.len(), 2);
+        session.add_message(&Input::from_str("q1'"), "a1'").unwrap();
+        assert_eq!(
+            session.list_branches(),
+            "  main (6 messages)\n*   alt (4 messages, from main at message 2)"
+        );
+
+        session.switch_branch("main").unwrap();
+        assert_eq!(session.messages.len(), 6);
+        session.undo().unwrap();
+        assert_eq!(session.messages.len(), 4);
+
+        let yaml = serde_yaml::to_string(&session).unwrap();
+        let mut session: Session = serde_yaml::from_str(&yaml).unwrap();
+        session.switch_branch("alt").unwrap();
+        assert_eq!(session.messages[2].content.to_text(), "q1'");
+        assert!(session.create_branch("main", None).is_err());
+        assert!(session.switch_branch("other").is_err());
+    }
+
+    #[test]
+    fn test_branch_summary() {
+        let mut session = Session::new("test", Model::default(), None);
+        for i in 0..4 {
+            session
+                .add_message(&Input::from_str(&format!("q{i}")), &format!("a{i}"))
+                .unwrap();
+        }
+        session.create_branch("alt", None).unwrap();
+        session.take_oldest_turns(2);
+        session.summary = Some("q0 and q1 were asked".into());
+
+        session.switch_branch("main").unwrap();
+        assert_eq!(session.summary, None);
+        assert_eq!(session.compressed_messages(), 0);
+        assert_eq!(session.messages.len(), 8);
+
+        let yaml = serde_yaml::to_string(&session).unwrap();
+        let mut session: Session = serde_yaml::from_str(&yaml).unwrap();
+        session.switch_branch("alt").unwrap();
+        assert_eq!(session.summary.as_deref(), Some("q0 and q1 were asked"));
+        assert_eq!(session.compressed_messages(), 4);
+        assert_eq!(session.messages.len(), 4);
+    }
+
+    #[test]
+    fn test_last_model() {
+        let mut session = Session::new("test", Model::default(), None);
+        session.add_message(&Input::from_str("q0"), "a0").unwrap();
+        session.set_last_model("ollama:llama3".into());
+        let yaml = serde_yaml::to_string(&session).unwrap();
+        assert!(yaml.contains("last_model: ollama:llama3"));
+        let session: Session = serde_yaml::from_str(&yaml).unwrap();
+        assert_eq!(session.last_model, "ollama:llama3");
+    }
+
+    #[test]
+    fn test_fits_summary_window() {
+        let mut model = Model::default();
+        assert!(fits_summary_window(&model, None, &"word ".repeat(10000)));
+        model.max_tokens = Some(200);
+        assert!(fits_summary_window(
+            &model,
+            Some("q0 was asked"),
+            "User: q1\n\n"
+        ));
+        assert!(!fits_summary_window(&model, None, &"word ".repeat(200)));
+    }
 }
@@ -0,0 +1,166 @@
+use super::{GlobalConfig, Input};
+
+use crate::client::{SendOutput, ToolCall, ToolResult};
+use crate::utils::prompt_op_err;
+
+use anyhow::{bail, Context, Result};
+use inquire::Confirm;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{
+    io::Write,
+    process::{Command, Stdio},
+};
+
+// the maximum number of tool call rounds for a single question, to avoid endless loops
+const MAX_TOOL_ROUNDS: usize = 16;
+
+// struct representing a locally defined tool that the model can call
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Tool {
+    /// Tool name
+    pub name: String,
+    /// What the tool does, shown to the model
+    #[serde(default)]
+    pub description: String,
+    /// JSON schema of the arguments
+    #[serde(default = "default_parameters")]
+    pub parameters: Value,
+    /// Local command to run, the arguments are written to its stdin as json
+    pub command: String,
+}
+
+impl Tool {
+    // this function returns the function declaration sent to the providers
+    pub fn declaration(&self) -> Value {
+        json!({
+            "name": self.name,
+            "description": self.description,
+            "parameters": self.parameters,
+        })
+    }
+
+    // this function runs the command with the given arguments and returns its output
+    pub fn run(&self, arguments: &Value) -> Result<String> {
+        let args = shell_words::split(&self.command)
+            .with_context(|| format!("Invalid command of tool '{}'", self.name))?;
+        let (program, args) = match args.split_first() {
+            Some(v) => v,
+            None => bail!("Empty command of tool '{}'", self.name),
+        };
+        let mut child = Command::new(program)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("Failed to run tool '{}'", self.name))?;
+        // writing the arguments to stdin, then closing it so the command sees EOF
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(arguments.to_string().as_bytes())?;
+        }
+        let output = child
+            .wait_with_output()
+            .with_context(|| format!("Failed to run tool '{}'", self.name))?;
+        if output.status.success() {
+            Ok(String::from_utf8_lossy(&output.stdout).to_string())
+        } else {
+            bail!(
+                "Tool '{}' exited with {}: {}",
+                self.name,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )
+        }
+    }
+}
+
+// this function keeps sending the input until the model answers without calling any tool
+// each round, the requested tools are run after user confirmation and the results are fed back
+pub fn send_with_tools<F>(config: &GlobalConfig, input: &mut Input, mut send: F) -> Result<String>
+where
+    F: FnMut(&Input) -> Result<SendOutput>,
+{
+    for _ in 0..MAX_TOOL_ROUNDS {
+        let SendOutput {
+            text, tool_calls, ..
+        } = send(input)?;
+        if tool_calls.is_empty() {
+            return Ok(text);
+        }
+        let tools = config.read().tools.clone();
+        let results = eval_tool_calls(&tools, &tool_calls)?;
+        input.add_tool_calls(text, tool_calls, results);
+    }
+    bail!("Exceed max tool call rounds ({MAX_TOOL_ROUNDS})")
+}
+
+// this function runs each tool call once the user confirms it
+fn eval_tool_calls(tools: &[Tool], tool_calls: &[ToolCall]) -> Result<Vec<ToolResult>> {
+    let mut results = vec![];
+    for tool_call in tool_calls {
+        let content = match tools.iter().find(|v| v.name == tool_call.name) {
+            None => format!("Error: unknown tool '{}'", tool_call.name),
+            Some(tool) => {
+                let ans = Confirm::new(&format!(
+                    "Call tool '{}' with {}?",
+                    tool.name, tool_call.arguments
+                ))
+                .with_default(true)
+                .prompt()
+                .map_err(prompt_op_err)?;
+                if ans {
+                    tool.run(&tool_call.arguments)
+                        .unwrap_or_else(|err| format!("Error: {err}"))
+                } else {
+                    "Error: the user refused to run this tool".to_string()
+                }
+            }
+        };
+        results.push(ToolResult {
+            id: tool_call.id.clone(),
+            name: tool_call.name.clone(),
+            content,
+        });
+    }
+    Ok(results)
+}
+
+// this function provides the schema used when a tool takes no arguments
+fn default_parameters() -> Value {
+    json!({ "type": "object", "properties": {} })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deserialize() {
+        let tools: Vec<Tool> = serde_yaml::from_str(
+            r#"
+- name: get_time
+  command: date
+- name: get_weather
+  description: Get the current weather
+  parameters:
+    type: object
+    properties:
+      city:
+        type: string
+    required: [city]
+  command: ./weather.sh
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            tools[0].declaration(),
+            json!({
+                "name": "get_time",
+                "description": "",
+                "parameters": { "type": "object", "properties": {} },
+            })
+        );
+        assert_eq!(tools[1].parameters["required"], json!(["city"]));
+    }
+}
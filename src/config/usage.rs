@@ -0,0 +1,229 @@
+// This file implements the token usage accounting, the totals kept per session and the `usage.jsonl` ledger,
+// one record per request, which `--usage` reports by model and by day
+use super::message_log::{in_date_range, parse_date};
+use super::Config;
+
+use crate::client::TokenUsage;
+
+use anyhow::{Context, Result};
+use chrono::DateTime;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{read_to_string, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+
+// struct representing the tokens and the cost of a number of requests
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+pub struct UsageTotals {
+    pub requests: usize,
+    pub input_tokens: usize,
+    pub output_tokens: usize,
+    pub cost: f64, // in USD, the requests to models without prices cost nothing
+}
+
+impl UsageTotals {
+    // this function adds a request to the totals
+    pub fn add(&mut self, usage: TokenUsage, cost: Option<f64>) {
+        self.requests += 1;
+        self.input_tokens += usage.input_tokens;
+        self.output_tokens += usage.output_tokens;
+        self.cost += cost.unwrap_or_default();
+    }
+
+    // this function checks whether no request was counted
+    pub fn is_empty(&self) -> bool {
+        self.requests == 0
+    }
+}
+
+// struct representing a line of the usage ledger
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct UsageRecord {
+    pub timestamp: String,
+    pub model: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session: Option<String>,
+    pub input_tokens: usize,
+    pub output_tokens: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cost: Option<f64>, // None when the model has no prices
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub estimated: bool, // whether the tokens were counted locally because the api did not report them
+}
+
+impl UsageRecord {
+    // this function returns the tokens of the record
+    pub fn usage(&self) -> TokenUsage {
+        TokenUsage {
+            input_tokens: self.input_tokens,
+            output_tokens: self.output_tokens,
+        }
+    }
+}
+
+// this function formats a cost in USD, small amounts keeping four decimals
+pub fn format_cost(cost: f64) -> String {
+    if cost >= 100.0 {
+        format!("${cost:.2}")
+    } else {
+        format!("${cost:.4}")
+    }
+}
+
+// this function appends a record to the usage ledger
+pub fn append_usage_record(path: &Path, record: &UsageRecord) -> Result<()> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Failed to open usage ledger at {}", path.display()))?;
+    let line = serde_json::to_string(record)?;
+    writeln!(file, "{line}").with_context(|| "Failed to record usage")
+}
+
+// this function loads the records of the usage ledger
+pub fn load_usage_records(path: &Path) -> Result<Vec<UsageRecord>> {
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+    let content = read_to_string(path)
+        .with_context(|| format!("Failed to load usage ledger at {}", path.display()))?;
+    let mut records = vec![];
+    for (index, line) in content.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record = serde_json::from_str(line)
+            .with_context(|| format!("Invalid usage record at line {}", index + 1))?;
+        records.push(record);
+    }
+    Ok(records)
+}
+
+// this function reports the requests, tokens and spend of each model and each day between two days given as YYYY-MM-DD
+pub fn usage_report(since: Option<&str>, until: Option<&str>) -> Result<String> {
+    let path = Config::usage_file()?;
+    let records = load_usage_records(&path)?;
+    let report = build_usage_report(
+        &records,
+        since.map(parse_date).transpose()?,
+        until.map(parse_date).transpose()?,
+    );
+    match report {
+        Some(report) => Ok(report),
+        None => Ok(format!("No matching usage in {}", path.display())),
+    }
+}
+
+// this function renders the totals of the records in the range as two tables, one model per row and a total row,
+// then one day per row, the cost being `-` when none of the requests had prices
+fn build_usage_report(
+    records: &[UsageRecord],
+    since: Option<chrono::NaiveDate>,
+    until: Option<chrono::NaiveDate>,
+) -> Option<String> {
+    let mut models: BTreeMap<String, (UsageTotals, bool)> = BTreeMap::new();
+    let mut days: BTreeMap<String, (UsageTotals, bool)> = BTreeMap::new();
+    let mut total = (UsageTotals::default(), false);
+    for record in records {
+        if !in_date_range(&record.timestamp, since, until) {
+            continue;
+        }
+        // the day is the one of the timestamp, in the time zone the request was made
+        let day = DateTime::parse_from_rfc3339(&record.timestamp)
+            .map(|v| v.date_naive().to_string())
+            .unwrap_or_else(|_| "-".into());
+        for (totals, priced) in [
+            models.entry(record.model.clone()).or_default(),
+            days.entry(day).or_default(),
+            &mut total,
+        ] {
+            totals.add(record.usage(), record.cost);
+            *priced |= record.cost.is_some();
+        }
+    }
+    if models.is_empty() {
+        return None;
+    }
+    let mut lines = usage_table("model", &models, Some(&total));
+    lines.push(String::new());
+    lines.extend(usage_table("day", &days, None));
+    Some(lines.join("\n"))
+}
+
+// this function renders the lines of a table of totals, with a header and an optional total row
+fn usage_table(
+    title: &str,
+    rows: &BTreeMap<String, (UsageTotals, bool)>,
+    total: Option<&(UsageTotals, bool)>,
+) -> Vec<String> {
+    let width = rows
+        .keys()
+        .map(|v| v.len())
+        .max()
+        .unwrap_or_default()
+        .max(5);
+    let row = |name: &str, (totals, priced): &(UsageTotals, bool)| {
+        let cost = if *priced {
+            format_cost(totals.cost)
+        } else {
+            "-".into()
+        };
+        format!(
+            "{name:<width$}  {:>8}  {:>12}  {:>12}  {cost:>10}",
+            totals.requests, totals.input_tokens, totals.output_tokens
+        )
+    };
+    let mut lines = vec![format!(
+        "{title:<width$}  {:>8}  {:>12}  {:>12}  {:>10}",
+        "requests", "input", "output", "cost"
+    )];
+    lines.extend(rows.iter().map(|(name, value)| row(name, value)));
+    if let Some(total) = total {
+        lines.push(row("total", total));
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_usage_report() {
+        let records: Vec<UsageRecord> = [
+            r#"{"timestamp":"2024-03-01T10:00:00+08:00","model":"openai:gpt-4","input_tokens":1000,"output_tokens":500,"cost":0.06}"#,
+            r#"{"timestamp":"2024-03-02T10:00:00+08:00","model":"openai:gpt-4","session":"work","input_tokens":2000,"output_tokens":100,"cost":0.066}"#,
+            r#"{"timestamp":"2024-03-02T11:00:00+08:00","model":"local:llama","input_tokens":30,"output_tokens":20,"estimated":true}"#,
+        ]
+        .iter()
+        .map(|v| serde_json::from_str(v).unwrap())
+        .collect();
+        let report = build_usage_report(&records, None, None).unwrap();
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(lines.len(), 8);
+        assert!(lines[1].starts_with("local:llama"));
+        assert!(lines[1].ends_with(" -"));
+        assert!(lines[2].starts_with("openai:gpt-4"));
+        assert!(lines[2].contains(" 3000 ") && lines[2].ends_with("$0.1260"));
+        assert!(lines[3].starts_with("total") && lines[3].ends_with("$0.1260"));
+        assert!(lines[5].starts_with("day"));
+        assert!(lines[6].starts_with("2024-03-01") && lines[6].ends_with("$0.0600"));
+        assert!(lines[7].starts_with("2024-03-02") && lines[7].contains(" 2030 "));
+        assert!(lines[7].ends_with("$0.0660"));
+
+        let since = parse_date("2024-03-02").ok();
+        let report = build_usage_report(&records, since, since).unwrap();
+        assert!(report.contains(" 2000 "));
+        assert!(!report.contains(" 3000 "));
+        let since = parse_date("2024-03-03").ok();
+        assert_eq!(build_usage_report(&records, since, None), None);
+    }
+
+    #[test]
+    fn test_format_cost() {
+        assert_eq!(format_cost(0.0123456), "$0.0123");
+        assert_eq!(format_cost(123.456), "$123.46");
+    }
+}
@@ -1,8 +1,12 @@
+mod batch;
 mod cli;
 mod client;
+mod code;
 mod config;
 mod render;
 mod repl;
+mod serve;
+mod shell;
 
 #[macro_use]
 extern crate log;
@@ -10,19 +14,25 @@ extern crate log;
 mod utils;
 
 use crate::cli::Cli;
-use crate::config::{Config, GlobalConfig};
+use crate::config::{augment_input, render_sources, Config, GlobalConfig, Rag};
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 // We are using clap for parsing command-line arguments
 use clap::Parser;
-use client::{ensure_model_capabilities, init_client, list_models};
-use config::Input;
+use client::{list_models, refresh_models, send_with_fallback, TokenUsage};
+use config::{
+    list_message_records, read_schema, search_messages, send_with_schema, send_with_tools,
+    show_message_record, usage_report, Input, MessageFilter,
+};
 use is_terminal::IsTerminal;
 use parking_lot::RwLock;
-use render::{render_error, render_stream, MarkdownRender};
+use render::{render_error, render_json_error, render_stream, MarkdownRender, OutputFormat};
 use repl::Repl;
+use serde_json::json;
 use std::io::{stderr, stdin, stdout, Read};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Instant;
 use utils::{cl100k_base_singleton, create_abort_signal};
 
 // This is the main entry point for our porgram
@@ -32,6 +42,11 @@ fn main() -> Result<()> {
     // initializing required variables and objects
     let cli = Cli::parse();
     let text = cli.text();
+    if cli.refresh_models {
+        let config = Arc::new(RwLock::new(Config::init_clients()?));
+        println!("{}", refresh_models(&config)?);
+        return Ok(());
+    }
     // making the config variable, for storing all the required configurations
     let config = Arc::new(RwLock::new(Config::init(text.is_none())?));
     if cli.list_roles {
@@ -53,6 +68,10 @@ fn main() -> Result<()> {
         println!("{sessions}");
         return Ok(());
     }
+    if let Some(name) = &cli.index {
+        println!("{}", Rag::build(&config, name, &cli.paths(), cli.bm25)?);
+        return Ok(());
+    }
     if let Some(wrap) = &cli.wrap {
         config.write().set_wrap(wrap)?;
     }
@@ -62,9 +81,28 @@ fn main() -> Result<()> {
     if cli.dry_run {
         config.write().dry_run = true;
     }
+    if cli.execute {
+        config.write().shell = true;
+    }
+    if let Some(output_format) = cli.output {
+        config.write().output_format = output_format;
+    }
+    if cli.code || cli.code_out.is_some() {
+        config.write().code = true;
+        config.write().code_out = cli.code_out.as_ref().map(PathBuf::from);
+    }
+    // the shell command and the code are printed as they are, never as json
+    if config.read().output_format != OutputFormat::Text
+        && (config.read().shell || config.read().code)
+    {
+        bail!("--output json and jsonl cannot be used with --execute, --code or --code-out");
+    }
     if let Some(name) = &cli.role {
         config.write().set_role(name)?;
     }
+    if let Some(path) = &cli.schema {
+        config.write().schema = Some(read_schema(Path::new(path))?);
+    }
     if let Some(session) = &cli.session {
         config
             .write()
@@ -73,6 +111,11 @@ fn main() -> Result<()> {
     if let Some(model) = &cli.model {
         config.write().set_model(model)?;
     }
+    // the options replace the parameters of the config, the role and the session for this run
+    config.write().override_params(&cli.params());
+    if let Some(name) = &cli.rag {
+        config.write().set_rag(name)?;
+    }
     if cli.no_highlight {
         config.write().highlight = false;
     }
@@ -81,11 +124,72 @@ fn main() -> Result<()> {
         println!("{}", info);
         return Ok(());
     }
+    if let Some(query) = &cli.search {
+        let results = search_messages(query)?;
+        let highlight = stdout().is_terminal() && config.read().highlight;
+        println!("{}", results.render(highlight));
+        if cli.open {
+            match results.top_session() {
+                Some(name) => {
+                    config.write().start_session(Some(name))?;
+                    config.write().onstart()?;
+                    return start_interactive(&config);
+                }
+                None => bail!("No session matches '{query}'"),
+            }
+        }
+        return Ok(());
+    }
+    if let Some(id) = cli.history {
+        let output = match id {
+            Some(id) => {
+                let mut render = if stdout().is_terminal() {
+                    let render_options = config.read().get_render_options()?;
+                    Some(MarkdownRender::init(render_options)?)
+                } else {
+                    None
+                };
+                show_message_record(id, render.as_mut())?
+            }
+            None => {
+                let filter = MessageFilter::new(
+                    cli.history_model,
+                    cli.history_role,
+                    cli.since.as_deref(),
+                    cli.until.as_deref(),
+                )?;
+                list_message_records(&filter)?
+            }
+        };
+        println!("{output}");
+        return Ok(());
+    }
+    if cli.usage {
+        println!(
+            "{}",
+            usage_report(cli.since.as_deref(), cli.until.as_deref())?
+        );
+        return Ok(());
+    }
+    if let Some(path) = &cli.batch {
+        return batch::run(&config, path, cli.batch_output, cli.concurrency);
+    }
+    if let Some(addr) = cli.serve {
+        return serve::run(&config, addr);
+    }
     config.write().onstart()?;
     // Here after initializing all the arguments, we call the start function to begin the processing the request
     if let Err(err) = start(&config, text, cli.file, cli.no_stream) {
+        if config.read().output_format != OutputFormat::Text {
+            render_json_error(err);
+            std::process::exit(1);
+        }
         let highlight = stderr().is_terminal() && config.read().highlight;
-        render_error(err, highlight)
+        render_error(err, highlight);
+        // the scripts reading the json of a schema are told that there is none
+        if config.read().get_schema().is_some() {
+            std::process::exit(1);
+        }
     }
     Ok(())
 }
@@ -132,35 +236,93 @@ fn start_directive(
         session.guard_save()?;
     }
     // make an input object
-    let input = Input::new(text, include.unwrap_or_default())?;
-    // make a new client with the given config
-    let mut client = init_client(config)?;
-    // ensuring that the client has the necessary capabilities to process the input
-    ensure_model_capabilities(client.as_mut(), input.required_capabilities())?;
-    config.read().maybe_print_send_tokens(&input);
-    // This assigns a value to output based on the value of no_stream variable, which is an argument for the function
-    let output = if no_stream {
-        // if true, send message to client and store the output in variable 'output'
-        let output = client.send_message(input.clone())?;
-        // check if the output is going to be in termina
-        if stdout().is_terminal() {
-            // initialize a markdown render object, for printing of the output
-            let render_options = config.read().get_render_options()?;
-            let mut markdown_render = MarkdownRender::init(render_options)?;
-            println!("{}", markdown_render.render(&output).trim());
-        } else {
-            // else we directly print
-            println!("{}", output);
+    let mut input = Input::new(text, include.unwrap_or_default())?;
+    // with --execute, the text is turned into a shell command instead of being answered
+    if config.read().shell {
+        return shell::run(config, input, create_abort_signal());
+    }
+    // with --code, only the code of the answer is printed or written to files
+    if config.read().code {
+        let out_dir = config.read().code_out.clone();
+        return code::run(config, input, out_dir.as_deref());
+    }
+    // the chunks of the index closest to the question are sent along with it
+    augment_input(config, &mut input)?;
+    let output_format = config.read().output_format;
+    if output_format == OutputFormat::Text {
+        config.read().maybe_print_send_tokens(&input);
+    }
+    // the json object, like the answer matching a schema, is printed once the answer is complete, without streaming
+    let has_schema = config.read().get_schema().is_some();
+    let no_stream = no_stream || output_format == OutputFormat::Json || has_schema;
+    // if no_stream is false, we create an abort signal
+    let abort = create_abort_signal();
+    let start = Instant::now();
+    let mut finish_reason = None;
+    let mut usages = vec![];
+    // the input is sent again as long as the model asks for tools to be called,
+    // each time with the first model of the fallback chain that answers,
+    // and once more when the answer does not match the schema
+    let output = send_with_schema(config, &mut input, |input| {
+        send_with_tools(config, input, |input| {
+            let output = send_with_fallback(config, input, |input, client| {
+                // This assigns a value to output based on the value of no_stream variable, which is an argument for the function
+                if no_stream {
+                    // if true, send message to client and store the output in variable 'output'
+                    let output = client.send_message(input.clone())?;
+                    // check if the output is going to be in termina
+                    if !output.text.is_empty() && output_format == OutputFormat::Text && !has_schema
+                    {
+                        if stdout().is_terminal() {
+                            // initialize a markdown render object, for printing of the output
+                            let render_options = config.read().get_render_options()?;
+                            let mut markdown_render = MarkdownRender::init(render_options)?;
+                            println!("{}", markdown_render.render(&output.text).trim());
+                        } else {
+                            // else we directly print
+                            println!("{}", output.text);
+                        }
+                    }
+                    Ok(output) // return the output
+                } else {
+                    // render the stream of output, using the render_stream function
+                    render_stream(input, client, config, abort.clone())
+                }
+            })?;
+            finish_reason = output.finish_reason.clone();
+            usages.push(output.usage);
+            Ok(output)
+        })
+    })?;
+    let latency = start.elapsed();
+    if output_format == OutputFormat::Text {
+        // the json checked against the schema
+        if has_schema {
+            println!("{output}");
+        }
+        // the sources of the retrieved passages are listed under the answer
+        if let Some(sources) = render_sources(&input) {
+            println!("\n{sources}");
         }
-        output // return the output
     } else {
-        // if no_stream is false, we create an abort signal
-        let abort = create_abort_signal();
-        // render the stream of output, using the render_stream function
-        render_stream(&input, client.as_ref(), config, abort)?
-    };
+        let config = config.read();
+        let value = json!({
+            "type": "result",
+            "model": config.answer_model().id(),
+            "role": config.role.as_ref().map(|v| v.name.clone()),
+            "session": config.session.as_ref().map(|v| v.name().to_string()),
+            "output": output,
+            "finish_reason": finish_reason,
+            "usage": config.usage,
+            "sources": input.sources(),
+            "latency_ms": latency.as_millis(),
+        });
+        println!("{value}");
+    }
     // call the save_message method on the config object, passing in the input and the output
-    config.write().save_message(input, &output)
+    config
+        .write()
+        .save_message(input, &output, TokenUsage::sum(&usages), latency)
 }
 
 fn start_interactive(config: &GlobalConfig) -> Result<()> {
@@ -1,7 +1,8 @@
 use anyhow::{anyhow, Context, Result};
-use crossterm::style::{Color, Stylize};
+use crossterm::style::{Attribute, Color, ContentStyle, StyledContent, Stylize};
 use crossterm::terminal;
 use lazy_static::lazy_static;
+use std::borrow::Cow;
 use std::collections::HashMap;
 use syntect::highlighting::{Color as SyntectColor, FontStyle, Style, Theme};
 use syntect::parsing::SyntaxSet;
@@ -12,6 +13,9 @@ use syntect::{easy::HighlightLines, parsing::SyntaxReference};
 // It's loaded from an external file using include_bytes!
 const SYNTAXES: &[u8] = include_bytes!("../../assets/syntaxes.bin");
 
+// the width below which the columns of a table do not shrink to fit the wrap width
+const MIN_COLUMN_WIDTH: usize = 3;
+
 // This macro is used to create a lazily initialized static variable
 lazy_static! {
     static ref LANG_MAPS: HashMap<String, String> = {
@@ -31,6 +35,8 @@ pub struct MarkdownRender {
     code_syntax: Option<SyntaxReference>,
     prev_line_type: LineType,
     wrap_width: Option<u16>,
+    styles: Option<MarkdownStyles>, // the styles of the headings, links, quotes..., when there is a theme
+    table_rows: Vec<String>,        // the rows of the table being read, laid out once it ends
 }
 
 impl MarkdownRender {
@@ -41,6 +47,7 @@ impl MarkdownRender {
 
         // setting the code color from options
         let code_color = options.theme.as_ref().map(get_code_color);
+        let styles = options.theme.as_ref().map(MarkdownStyles::new);
         // getting the Markdown syntax
         let md_syntax = syntax_set.find_syntax_by_extension("md").unwrap().clone();
         let line_type = LineType::Normal;
@@ -69,40 +76,218 @@ impl MarkdownRender {
             code_syntax: None,
             prev_line_type: line_type,
             wrap_width,
+            styles,
+            table_rows: vec![],
             options,
         })
     }
 
-    // this function splits the input text into lines and put them in the render_line_mut function
+    // this function splits the input text into lines and put them in the render_line_mut function,
+    // a table at the end of the text is laid out too
     pub fn render(&mut self, text: &str) -> String {
+        let mut lines = self.render_lines(text);
+        lines.extend(self.take_table());
+        lines.join("\n")
+    }
+
+    // this function renders the complete lines of a streamed text, the rows of a table are held until it ends
+    pub fn render_lines(&mut self, text: &str) -> Vec<String> {
         text.split('\n')
-            .map(|line| self.render_line_mut(line))
-            .collect::<Vec<String>>()
-            .join("\n")
+            .flat_map(|line| self.render_line_mut(line))
+            .collect()
     }
 
-    // this function is for analyzing the line and updating internal state variables based on its type and content
+    // this function renders the line being streamed without changing the state, after the rows of the table
+    // being read, which are laid out with the line when it is a row too
     pub fn render_line(&self, line: &str) -> String {
-        let (_, code_syntax, is_code) = self.check_line(line); // check_line function determines whether line contains code or not
-        if is_code {
-            // if its code, we highlight as per code
-            self.highlight_code_line(line, &code_syntax)
-        } else {
-            self.highlight_line(line, &self.md_syntax, false)
+        let (line_type, code_syntax, is_code) = self.check_line(line);
+        let is_row = line_type == LineType::Normal && is_table_row(line);
+        let mut rows = self.table_rows.clone();
+        if is_row {
+            rows.push(line.to_string());
+        }
+        let mut output = self.render_table(&rows);
+        if !is_row {
+            output.push(self.render_checked_line(line, line_type, &code_syntax, is_code));
         }
+        output.join("\n")
     }
 
-    // this function determines whether the line contains code or not
-    fn render_line_mut(&mut self, line: &str) -> String {
+    // this function determines whether the line contains code or not, and returns the lines to print,
+    // none while reading a table
+    fn render_line_mut(&mut self, line: &str) -> Vec<String> {
         let (line_type, code_syntax, is_code) = self.check_line(line);
-        let output = if is_code {
+        let mut output = vec![];
+        if line_type == LineType::Normal && is_table_row(line) {
+            self.table_rows.push(line.to_string());
+        } else {
+            output.extend(self.take_table());
+            output.push(self.render_checked_line(line, line_type, &code_syntax, is_code));
+        }
+        self.prev_line_type = line_type;
+        self.code_syntax = code_syntax;
+        output
+    }
+
+    fn render_checked_line(
+        &self,
+        line: &str,
+        line_type: LineType,
+        code_syntax: &Option<SyntaxReference>,
+        is_code: bool,
+    ) -> String {
+        if is_code {
             // if its code, we highlight as per code
-            self.highlight_code_line(line, &code_syntax)
+            self.highlight_code_line(line, code_syntax)
+        } else if line_type == LineType::Normal {
+            self.render_text_line(line)
         } else {
+            // the lines of the fences
             self.highlight_line(line, &self.md_syntax, false)
+        }
+    }
+
+    // this function lays out the rows of the table read so far
+    fn take_table(&mut self) -> Vec<String> {
+        let rows = std::mem::take(&mut self.table_rows);
+        self.render_table(&rows)
+    }
+
+    // this function renders a line of text, with the heading, quote and list markers and the emphasis,
+    // code spans and links turned into styles. Without a theme the line is kept, list items and quotes
+    // wrap after their marker
+    fn render_text_line(&self, line: &str) -> String {
+        let Some(styles) = &self.styles else {
+            return self.wrap_line(line.to_string(), false);
         };
-        self.prev_line_type = line_type;
-        self.code_syntax = code_syntax;
+        let width = self.wrap_width.map(|v| v as usize);
+        let indent = &line[..line.len() - line.trim_start_matches(' ').len()];
+        let content = &line[indent.len()..];
+        if let Some((level, text)) = parse_heading(content) {
+            let mut style = ContentStyle::new().bold();
+            if level == 1 {
+                style = style.underlined();
+            }
+            style.foreground_color = styles.heading;
+            let spans = parse_inline(text, style, styles);
+            return wrap_spans(&spans, width, indent, indent).join("\n");
+        }
+        if let Some((depth, text)) = parse_quote(content) {
+            let mut style = ContentStyle::new().italic();
+            style.foreground_color = styles.quote;
+            let bars = StyledContent::new(style, "│ ".repeat(depth));
+            let prefix = format!("{indent}{bars}");
+            let spans = parse_inline(text, style, styles);
+            return wrap_spans(&spans, width, &prefix, &prefix).join("\n");
+        }
+        if let Some((marker, text)) = parse_list_item(content) {
+            let marker = match marker {
+                "-" | "*" | "+" => "•",
+                _ => marker,
+            };
+            let mut style = ContentStyle::new();
+            style.foreground_color = styles.list;
+            let prefix = format!("{indent}{} ", StyledContent::new(style, marker));
+            // the next lines hang after the marker
+            let hang = " ".repeat(display_width(&prefix));
+            let spans = parse_inline(text, ContentStyle::new(), styles);
+            return wrap_spans(&spans, width, &prefix, &hang).join("\n");
+        }
+        let spans = parse_inline(content, ContentStyle::new(), styles);
+        wrap_spans(&spans, width, indent, indent).join("\n")
+    }
+
+    // this function lays out the rows of a table in a box, with the widest columns shrunk to fit the wrap width
+    // and their cells wrapped. Rows that are not a table, without the separator under the header, are text
+    fn render_table(&self, rows: &[String]) -> Vec<String> {
+        let header = rows.first().map(|v| split_table_row(v)).unwrap_or_default();
+        let separator = rows.get(1).filter(|v| is_table_separator(v));
+        let (Some(separator), false) = (separator, header.is_empty()) else {
+            return rows.iter().map(|v| self.render_text_line(v)).collect();
+        };
+        let aligns: Vec<Alignment> = split_table_row(separator)
+            .iter()
+            .map(|v| Alignment::new(v))
+            .collect();
+        let columns = header.len();
+        let cells: Vec<Vec<Vec<Span>>> = std::iter::once(&rows[0])
+            .chain(&rows[2..])
+            .enumerate()
+            .map(|(i, row)| {
+                let mut texts = split_table_row(row);
+                texts.resize(columns, String::new());
+                texts
+                    .iter()
+                    .map(|text| match &self.styles {
+                        Some(styles) => {
+                            let style = match i {
+                                0 => ContentStyle::new().bold(),
+                                _ => ContentStyle::new(),
+                            };
+                            parse_inline(text, style, styles)
+                        }
+                        None => vec![Span::new(text, ContentStyle::new())],
+                    })
+                    .collect()
+            })
+            .collect();
+        let mut widths = vec![1; columns];
+        for row in &cells {
+            for (width, cell) in widths.iter_mut().zip(row) {
+                let cell_width: usize = cell.iter().map(|v| display_width(&v.text)).sum();
+                *width = cell_width.max(*width);
+            }
+        }
+        if let Some(wrap_width) = self.wrap_width {
+            let available = (wrap_width as usize).saturating_sub(3 * columns + 1);
+            while widths.iter().sum::<usize>() > available {
+                let (index, width) = widths
+                    .iter()
+                    .enumerate()
+                    .max_by_key(|(_, v)| **v)
+                    .map(|(i, v)| (i, *v))
+                    .unwrap_or_default();
+                if width <= MIN_COLUMN_WIDTH {
+                    break;
+                }
+                widths[index] -= 1;
+            }
+        }
+        let border = |left: &str, middle: &str, right: &str| {
+            let lines: Vec<String> = widths.iter().map(|v| "─".repeat(v + 2)).collect();
+            format!("{left}{}{right}", lines.join(middle))
+        };
+        let mut output = vec![border("┌", "┬", "┐")];
+        for (i, row) in cells.iter().enumerate() {
+            let lines: Vec<Vec<String>> = row
+                .iter()
+                .zip(&widths)
+                .map(|(cell, width)| wrap_spans(cell, Some(*width), "", ""))
+                .collect();
+            let height = lines.iter().map(|v| v.len()).max().unwrap_or(1);
+            for j in 0..height {
+                let mut line = String::from("│");
+                for (k, cell_lines) in lines.iter().enumerate() {
+                    let text = cell_lines.get(j).map(|v| v.as_str()).unwrap_or_default();
+                    let pad = widths[k].saturating_sub(display_width(text));
+                    let (left, right) = match aligns.get(k) {
+                        Some(Alignment::Right) => (pad, 0),
+                        Some(Alignment::Center) => (pad / 2, pad - pad / 2),
+                        _ => (0, pad),
+                    };
+                    line.push_str(&format!(
+                        " {}{text}{} │",
+                        " ".repeat(left),
+                        " ".repeat(right)
+                    ));
+                }
+                output.push(line);
+            }
+            if i == 0 {
+                output.push(border("├", "┼", "┤"));
+            }
+        }
+        output.push(border("└", "┴", "┘"));
         output
     }
 
@@ -214,13 +399,20 @@ impl MarkdownRender {
                 return line;
             }
             // wrapping the line with the above width
-            wrap(&line, width as usize)
+            wrap(&line, width as usize, !is_code)
         } else {
             // returning the line unchanged
             line
         }
     }
 
+    // this function returns the file extension of a fence language, like `rs` for `rust`
+    pub fn code_extension(&self, lang: &str) -> Option<String> {
+        self.find_syntax(lang)
+            .and_then(|v| v.file_extensions.first())
+            .cloned()
+    }
+
     fn find_syntax(&self, lang: &str) -> Option<&SyntaxReference> {
         // Checking if a language mapping is available for the given language
         if let Some(new_lang) = LANG_MAPS.get(&lang.to_ascii_lowercase()) {
@@ -235,13 +427,27 @@ impl MarkdownRender {
     }
 }
 
-// this function wraps text to fit within a specified width
-fn wrap(text: &str, width: usize) -> String {
+// this function wraps text to fit within a specified width,
+// the next lines of a list item or a quote hang after its marker when `hang` is set
+fn wrap(text: &str, width: usize, hang: bool) -> String {
     // calculating the indentation of the text by counting leading whitespace characters
     let indent: usize = text.chars().take_while(|c| *c == ' ').count();
+    let content = &text[indent..];
+    let marker = if let (true, Some((_, rest))) = (hang, parse_list_item(content)) {
+        " ".repeat(content.len() - rest.len())
+    } else if let (true, Some((_, rest))) = (hang, parse_quote(content)) {
+        content[..content.len() - rest.len()].to_string()
+    } else {
+        String::new()
+    };
+    let subsequent_indent = match marker.is_empty() {
+        true => String::new(),
+        false => format!("{}{marker}", &text[0..indent]),
+    };
     let wrap_options = textwrap::Options::new(width)
         .wrap_algorithm(textwrap::WrapAlgorithm::FirstFit)
-        .initial_indent(&text[0..indent]);
+        .initial_indent(&text[0..indent])
+        .subsequent_indent(&subsequent_indent);
     // wrapping the text using the specified options and joining the resulting lines with new characters
     textwrap::wrap(&text[indent..], wrap_options).join("\n")
 }
@@ -265,6 +471,529 @@ impl RenderOptions {
     }
 }
 
+// struct representing the colors of the markdown elements, taken from the scopes of the theme
+#[derive(Debug, Clone)]
+struct MarkdownStyles {
+    code: Color,
+    heading: Option<Color>,
+    quote: Option<Color>,
+    link: Option<Color>,
+    list: Option<Color>,
+}
+
+impl MarkdownStyles {
+    fn new(theme: &Theme) -> Self {
+        Self {
+            code: find_scope_color(theme, "markup.raw.inline")
+                .unwrap_or_else(|| get_code_color(theme)),
+            heading: find_scope_color(theme, "markup.heading"),
+            quote: find_scope_color(theme, "markup.quote"),
+            link: find_scope_color(theme, "string.other.link.title.markdown"),
+            list: find_scope_color(theme, "punctuation.definition.list_item.markdown"),
+        }
+    }
+}
+
+// struct representing a piece of a line with its style, and its url when it is a link
+#[derive(Debug, Clone, PartialEq)]
+struct Span {
+    text: String,
+    style: ContentStyle,
+    link: Option<String>,
+}
+
+impl Span {
+    fn new(text: &str, style: ContentStyle) -> Self {
+        Self {
+            text: text.to_string(),
+            style,
+            link: None,
+        }
+    }
+
+    // this function styles a part of the text of the span, a link is an OSC 8 hyperlink of the terminal
+    fn render(&self, text: &str) -> String {
+        let output = StyledContent::new(self.style, text).to_string();
+        match &self.link {
+            Some(url) => format!("\x1b]8;;{url}\x1b\\{output}\x1b]8;;\x1b\\"),
+            None => output,
+        }
+    }
+}
+
+// enum representing the alignment of a column of a table, given by the colons of its separator
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Alignment {
+    Left,
+    Center,
+    Right,
+}
+
+impl Alignment {
+    fn new(separator: &str) -> Self {
+        match (separator.starts_with(':'), separator.ends_with(':')) {
+            (true, true) => Self::Center,
+            (false, true) => Self::Right,
+            _ => Self::Left,
+        }
+    }
+}
+
+// this function returns the width of a text in the terminal, without its escape sequences,
+// including the ones of the hyperlinks, which textwrap counts
+pub fn display_width(text: &str) -> usize {
+    textwrap::core::display_width(&strip_hyperlinks(text))
+}
+
+// this function removes the OSC 8 sequences around the text of the hyperlinks
+fn strip_hyperlinks(text: &str) -> Cow<'_, str> {
+    if !text.contains("\x1b]") {
+        return Cow::Borrowed(text);
+    }
+    let mut output = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find("\x1b]") {
+        output.push_str(&rest[..start]);
+        let sequence = &rest[start..];
+        // the sequence ends with ST or BEL
+        let end = match (sequence.find("\x1b\\"), sequence.find('\x07')) {
+            (Some(a), Some(b)) if b < a => b + 1,
+            (Some(a), _) => a + 2,
+            (None, Some(b)) => b + 1,
+            (None, None) => sequence.len(),
+        };
+        rest = &sequence[end..];
+    }
+    output.push_str(rest);
+    Cow::Owned(output)
+}
+
+// this function lays out the spans in lines of the width, the first line starts with the prefix and the next
+// ones with the indent, a word longer than a line is split
+fn wrap_spans(spans: &[Span], width: Option<usize>, prefix: &str, indent: &str) -> Vec<String> {
+    // each character with the span it comes from
+    let chars: Vec<(char, usize)> = spans
+        .iter()
+        .enumerate()
+        .flat_map(|(i, v)| v.text.chars().map(move |c| (c, i)))
+        .collect();
+    let limit = |first: bool| {
+        let indent = if first { prefix } else { indent };
+        width.map(|v| v.saturating_sub(display_width(indent)).max(1))
+    };
+    let chars_width = |chars: &[(char, usize)]| -> usize {
+        let text: String = chars.iter().map(|v| v.0).collect();
+        display_width(&text)
+    };
+    let mut lines: Vec<Vec<(char, usize)>> = vec![vec![]];
+    let mut line_width = 0;
+    let mut space: &[(char, usize)] = &[];
+    for word in chars.chunk_by(|a, b| a.0.is_whitespace() == b.0.is_whitespace()) {
+        if word[0].0.is_whitespace() {
+            space = word;
+            continue;
+        }
+        let max_width = limit(lines.len() == 1);
+        let line = lines.last_mut().unwrap();
+        let Some(max_width) = max_width else {
+            line.extend(space);
+            line.extend(word);
+            space = &[];
+            continue;
+        };
+        let space_width = chars_width(space);
+        if line_width > 0 && line_width + space_width + chars_width(word) > max_width {
+            lines.push(vec![]);
+            line_width = 0;
+        } else if line_width > 0 {
+            line.extend(space);
+            line_width += space_width;
+        }
+        space = &[];
+        // a word longer than a line is split
+        for c in word {
+            let char_width = chars_width(std::slice::from_ref(c));
+            if line_width > 0
+                && line_width + char_width > limit(lines.len() == 1).unwrap_or(usize::MAX)
+            {
+                lines.push(vec![]);
+                line_width = 0;
+            }
+            lines.last_mut().unwrap().push(*c);
+            line_width += char_width;
+        }
+    }
+    lines
+        .iter()
+        .enumerate()
+        .map(|(i, line)| {
+            let mut output = if i == 0 { prefix } else { indent }.to_string();
+            for group in line.chunk_by(|a, b| a.1 == b.1) {
+                let text: String = group.iter().map(|v| v.0).collect();
+                output.push_str(&spans[group[0].1].render(&text));
+            }
+            output
+        })
+        .collect()
+}
+
+// this function turns the emphasis, the code spans and the links of a line into styled spans
+fn parse_inline(text: &str, style: ContentStyle, styles: &MarkdownStyles) -> Vec<Span> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut spans = vec![];
+    parse_inline_chars(&chars, style, None, styles, &mut spans);
+    spans
+}
+
+fn parse_inline_chars(
+    chars: &[char],
+    style: ContentStyle,
+    link: Option<&str>,
+    styles: &MarkdownStyles,
+    spans: &mut Vec<Span>,
+) {
+    let mut literal = String::new();
+    let flush = |literal: &mut String, spans: &mut Vec<Span>| {
+        if !literal.is_empty() {
+            spans.push(Span {
+                text: std::mem::take(literal),
+                style,
+                link: link.map(|v| v.to_string()),
+            });
+        }
+    };
+    let link_style = {
+        let mut style = style.underlined();
+        style.foreground_color = styles.link.or(style.foreground_color);
+        style
+    };
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let prev = i.checked_sub(1).map(|v| chars[v]);
+        // the escaped punctuation is kept as it is
+        if c == '\\' {
+            if let Some(next) = chars.get(i + 1).filter(|v| v.is_ascii_punctuation()) {
+                literal.push(*next);
+                i += 2;
+                continue;
+            }
+        }
+        if c == '`' {
+            let count = run_length(chars, i);
+            if let Some(end) = find_closing_run(chars, i + count, count, |_| true) {
+                flush(&mut literal, spans);
+                let code: String = chars[i + count..end].iter().collect();
+                let mut code_style = style;
+                code_style.foreground_color = Some(styles.code);
+                spans.push(Span {
+                    text: code.trim().to_string(),
+                    style: code_style,
+                    link: link.map(|v| v.to_string()),
+                });
+                i = end + count;
+            } else {
+                literal.extend(&chars[i..i + count]);
+                i += count;
+            }
+            continue;
+        }
+        if matches!(c, '*' | '_' | '~') {
+            let count = run_length(chars, i);
+            let valid = match c {
+                '~' => count == 2,
+                _ => count <= 3,
+            };
+            // the underscores inside a word, like in snake_case, are kept
+            let opens = chars.get(i + count).is_some_and(|v| !v.is_whitespace())
+                && (c != '_' || !prev.is_some_and(|v| v.is_alphanumeric()));
+            let end = find_closing_run(chars, i + count, count, |end| {
+                end > i + count
+                    && !chars[end - 1].is_whitespace()
+                    && (c != '_' || !chars.get(end + count).is_some_and(|v| v.is_alphanumeric()))
+            });
+            if let (true, true, Some(end)) = (valid, opens, end) {
+                flush(&mut literal, spans);
+                let inner_style = match (c, count) {
+                    ('~', _) => style.attribute(Attribute::CrossedOut),
+                    (_, 1) => style.italic(),
+                    (_, 2) => style.bold(),
+                    _ => style.bold().italic(),
+                };
+                parse_inline_chars(&chars[i + count..end], inner_style, link, styles, spans);
+                i = end + count;
+            } else {
+                literal.extend(&chars[i..i + count]);
+                i += count;
+            }
+            continue;
+        }
+        // a link or an image, whose text is a link to it
+        let start = if c == '!' && chars.get(i + 1) == Some(&'[') {
+            i + 1
+        } else {
+            i
+        };
+        if chars[start] == '[' {
+            if let Some((label_end, url_end)) = find_link(chars, start) {
+                flush(&mut literal, spans);
+                let url: String = chars[label_end + 2..url_end].iter().collect();
+                // the title after the url is left out
+                let url = url.split_whitespace().next().unwrap_or_default();
+                let url = url.trim_start_matches('<').trim_end_matches('>');
+                let label = &chars[start + 1..label_end];
+                if label.is_empty() {
+                    let url_chars: Vec<char> = url.chars().collect();
+                    parse_inline_chars(&url_chars, link_style, Some(url), styles, spans);
+                } else {
+                    parse_inline_chars(label, link_style, Some(url), styles, spans);
+                }
+                i = url_end + 1;
+                continue;
+            }
+        }
+        // an autolink like <https://example.com>, or a url in the text
+        let url = if c == '<' {
+            let end = chars[i..].iter().position(|v| *v == '>').map(|v| i + v);
+            end.map(|end| (chars[i + 1..end].iter().collect::<String>(), end + 1))
+                .filter(|(url, _)| is_url(url) && !url.contains(char::is_whitespace))
+        } else if matches!(c, 'h' | 'H') && !prev.is_some_and(|v| v.is_alphanumeric()) {
+            let end = chars[i..]
+                .iter()
+                .position(|v| v.is_whitespace() || matches!(v, '<' | '>' | '"'))
+                .map_or(chars.len(), |v| i + v);
+            let mut url: String = chars[i..end].iter().collect();
+            // the punctuation after the url is not part of it
+            while url.ends_with(['.', ',', ';', ':', '!', '?', '\'', '*', '_'])
+                || (url.ends_with(')') && url.matches(')').count() > url.matches('(').count())
+            {
+                url.pop();
+            }
+            let end = i + url.chars().count();
+            Some((url, end)).filter(|(url, _)| is_url(url) && url.contains("://"))
+        } else {
+            None
+        };
+        if let Some((url, end)) = url {
+            flush(&mut literal, spans);
+            let mut span = Span::new(&url, link_style);
+            span.link = Some(url);
+            spans.push(span);
+            i = end;
+            continue;
+        }
+        literal.push(c);
+        i += 1;
+    }
+    flush(&mut literal, spans);
+}
+
+// this function returns the number of times the character at the index repeats from there
+fn run_length(chars: &[char], index: usize) -> usize {
+    chars[index..]
+        .iter()
+        .take_while(|v| **v == chars[index])
+        .count()
+}
+
+// this function returns where the run of the same character and length as the opening one closes,
+// for the first run the check accepts
+fn find_closing_run(
+    chars: &[char],
+    from: usize,
+    count: usize,
+    check: impl Fn(usize) -> bool,
+) -> Option<usize> {
+    let c = chars[from - 1];
+    let mut j = from;
+    while j + count <= chars.len() {
+        if chars[j] != c {
+            j += 1;
+            continue;
+        }
+        let length = run_length(chars, j);
+        if length == count && check(j) {
+            return Some(j);
+        }
+        j += length;
+    }
+    None
+}
+
+// this function returns where the label and the url of a link like [label](url) end
+fn find_link(chars: &[char], start: usize) -> Option<(usize, usize)> {
+    let find_end = |from: usize, open: char, close: char| {
+        let mut depth = 0;
+        let mut j = from;
+        while j < chars.len() {
+            match chars[j] {
+                '\\' => j += 1,
+                v if v == open => depth += 1,
+                v if v == close => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Some(j);
+                    }
+                }
+                _ => {}
+            }
+            j += 1;
+        }
+        None
+    };
+    let label_end = find_end(start, '[', ']')?;
+    if chars.get(label_end + 1) != Some(&'(') {
+        return None;
+    }
+    let url_end = find_end(label_end + 1, '(', ')')?;
+    Some((label_end, url_end))
+}
+
+fn is_url(text: &str) -> bool {
+    let text = text.to_ascii_lowercase();
+    ["http://", "https://", "mailto:"]
+        .iter()
+        .any(|v| text.starts_with(v) && text.len() > v.len())
+}
+
+// this function returns the level and the text of a heading, like `## Usage`
+fn parse_heading(line: &str) -> Option<(usize, &str)> {
+    let level = line.chars().take_while(|v| *v == '#').count();
+    let rest = &line[level..];
+    if !(1..=6).contains(&level) || !(rest.is_empty() || rest.starts_with(' ')) {
+        return None;
+    }
+    let text = rest.trim();
+    // the closing `#` are left out, not the ones of a word like C#
+    let trimmed = text.trim_end_matches('#');
+    if trimmed.is_empty() || trimmed.ends_with(' ') {
+        return Some((level, trimmed.trim_end()));
+    }
+    Some((level, text))
+}
+
+// this function returns the depth and the text of a quote, like `> > text`
+fn parse_quote(line: &str) -> Option<(usize, &str)> {
+    let mut depth = 0;
+    let mut rest = line;
+    while let Some(v) = rest.strip_prefix('>') {
+        depth += 1;
+        rest = v.strip_prefix(' ').unwrap_or(v);
+    }
+    (depth > 0).then_some((depth, rest))
+}
+
+// this function returns the marker and the text of a list item, like `-`, `*`, `+`, `1.` or `1)`
+fn parse_list_item(line: &str) -> Option<(&str, &str)> {
+    let marker_len = if line.starts_with(['-', '*', '+']) {
+        1
+    } else {
+        let digits = line.chars().take_while(|v| v.is_ascii_digit()).count();
+        if digits == 0 || digits > 9 || !line[digits..].starts_with(['.', ')']) {
+            return None;
+        }
+        digits + 1
+    };
+    let rest = &line[marker_len..];
+    let text = rest.trim_start_matches(' ');
+    if text.len() == rest.len() {
+        return None;
+    }
+    Some((&line[..marker_len], text))
+}
+
+fn is_table_row(line: &str) -> bool {
+    line.trim_start().starts_with('|')
+}
+
+// this function returns whether the row separates the header of a table from its body, like `|---|:-:|`
+fn is_table_separator(line: &str) -> bool {
+    let cells = split_table_row(line);
+    !cells.is_empty()
+        && cells.iter().all(|v| {
+            let v = v.strip_prefix(':').unwrap_or(v);
+            let v = v.strip_suffix(':').unwrap_or(v);
+            !v.is_empty() && v.chars().all(|c| c == '-')
+        })
+}
+
+// this function splits a row of a table into its trimmed cells, the `|` of a code span or after `\` is kept
+fn split_table_row(line: &str) -> Vec<String> {
+    let line = line.trim();
+    let line = line.strip_prefix('|').unwrap_or(line);
+    let mut cells = vec![];
+    let mut cell = String::new();
+    let mut in_code = false;
+    let mut escaped = false;
+    for c in line.chars() {
+        if escaped {
+            if c != '|' {
+                cell.push('\\');
+            }
+            cell.push(c);
+            escaped = false;
+            continue;
+        }
+        match c {
+            '\\' => escaped = true,
+            '`' => {
+                in_code = !in_code;
+                cell.push(c);
+            }
+            '|' if !in_code => cells.push(std::mem::take(&mut cell).trim().to_string()),
+            _ => cell.push(c),
+        }
+    }
+    if escaped {
+        cell.push('\\');
+    }
+    // the text after the closing `|` is a cell only when there is some
+    if !cell.trim().is_empty() {
+        cells.push(cell.trim().to_string());
+    }
+    cells
+}
+
+// struct representing a fenced code block of a markdown text
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CodeBlock {
+    pub language: String, // the language after the opening fence, may be empty
+    pub code: String,     // the lines between the fences, each ending with a newline
+}
+
+// this function returns the fenced code blocks of a text, the line types change like in `check_line`,
+// a block left open at the end of the text is kept
+pub fn extract_code_blocks(text: &str) -> Vec<CodeBlock> {
+    let mut blocks = vec![];
+    let mut line_type = LineType::Normal;
+    let mut current: Option<CodeBlock> = None;
+    for line in text.lines() {
+        line_type = match (detect_code_block(line), line_type) {
+            (Some(language), LineType::Normal | LineType::CodeEnd) => {
+                current = Some(CodeBlock {
+                    language,
+                    code: String::new(),
+                });
+                LineType::CodeBegin
+            }
+            (Some(_), LineType::CodeBegin | LineType::CodeInner) => {
+                blocks.extend(current.take());
+                LineType::CodeEnd
+            }
+            (None, LineType::Normal | LineType::CodeEnd) => LineType::Normal,
+            (None, LineType::CodeBegin | LineType::CodeInner) => {
+                if let Some(block) = current.as_mut() {
+                    block.code.push_str(line);
+                    block.code.push('\n');
+                }
+                LineType::CodeInner
+            }
+        };
+    }
+    blocks.extend(current);
+    blocks
+}
+
 // enum for different types of lines that can occur during rendering
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum LineType {
@@ -351,20 +1080,24 @@ fn detect_code_block(line: &str) -> Option<String> {
 
 // this function retrieves the color used for displaying code blocks in a given theme
 fn get_code_color(theme: &Theme) -> Color {
-    // searching for a style in theme's scope
-    // by the selector of "string"
-    let scope = theme.scopes.iter().find(|v| {
-        v.scope
-            .selectors
-            .iter()
-            .any(|v| v.path.scopes.iter().any(|v| v.to_string() == "string"))
-    });
-    // if we find a style
-    scope
-        // we retrieve foreground color
+    // searching for a style in theme's scope by the selector of "string",
+    // else, we just map it to yellow
+    find_scope_color(theme, "string").unwrap_or(Color::Yellow)
+}
+
+// this function returns the foreground color of the first style of the theme whose selectors have the scope
+fn find_scope_color(theme: &Theme, scope: &str) -> Option<Color> {
+    theme
+        .scopes
+        .iter()
+        .find(|v| {
+            v.scope
+                .selectors
+                .iter()
+                .any(|v| v.path.scopes.iter().any(|v| v.to_string() == scope))
+        })
         .and_then(|v| v.style.foreground)
-        // else, we just map it to yellow
-        .map_or_else(|| Color::Yellow, convert_color)
+        .map(convert_color)
 }
 
 #[cfg(test)]
@@ -414,6 +1147,37 @@ std::error::Error>> {
         let options = RenderOptions::default();
         let render = MarkdownRender::init(options).unwrap();
         assert!(render.find_syntax("csharp").is_some());
+        assert_eq!(render.code_extension("rust").as_deref(), Some("rs"));
+        assert_eq!(render.code_extension("python").as_deref(), Some("py"));
+        assert_eq!(render.code_extension("nolang"), None);
+    }
+
+    #[test]
+    fn test_extract_code_blocks() {
+        let blocks = extract_code_blocks(TEXT);
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].language, "rust");
+        assert!(blocks[0]
+            .code
+            .starts_with("use std::fs::File;\n\nfn unzip_file"));
+        assert!(blocks[0].code.ends_with("    todo!()\n}\n"));
+
+        let text = "a\n```\nplain\n```\nb\n```sh\nls\n";
+        let blocks = extract_code_blocks(text);
+        assert_eq!(
+            blocks,
+            [
+                CodeBlock {
+                    language: String::new(),
+                    code: "plain\n".into()
+                },
+                CodeBlock {
+                    language: "sh".into(),
+                    code: "ls\n".into()
+                },
+            ]
+        );
+        assert!(extract_code_blocks("no code").is_empty());
     }
 
     #[test]
@@ -444,4 +1208,105 @@ std::error::Error>> {
         let output = render.render(TEXT);
         assert_eq!(TEXT_WRAP_ALL, output);
     }
+
+    #[test]
+    fn test_render_table() {
+        let text = "| Name | Size | Notes |\n|:-----|-----:|:-----:|\n| a | 1 | short |\n| a `b|c` name | 12345 | a longer note to wrap |";
+        let mut render = MarkdownRender::init(RenderOptions::default()).unwrap();
+        render.wrap_width = Some(40);
+        let expect = r#"┌──────────────┬───────┬───────────────┐
+│ Name         │  Size │     Notes     │
+├──────────────┼───────┼───────────────┤
+│ a            │     1 │     short     │
+│ a `b|c` name │ 12345 │ a longer note │
+│              │       │    to wrap    │
+└──────────────┴───────┴───────────────┘"#;
+        assert_eq!(render.render(text), expect);
+
+        // the rows are held while the table is streamed, and previewed with the line being read
+        let mut render = MarkdownRender::init(RenderOptions::default()).unwrap();
+        render.wrap_width = Some(40);
+        let (head, tail) = text.rsplit_once('\n').unwrap();
+        assert!(render.render_lines(head).is_empty());
+        assert_eq!(render.render_line(tail), expect);
+        assert_eq!(
+            render.render_lines(&format!("{tail}\nafter")).join("\n"),
+            format!("{expect}\nafter")
+        );
+
+        // without the separator under the header, the rows are text
+        let mut render = MarkdownRender::init(RenderOptions::default()).unwrap();
+        assert_eq!(render.render("| a | b |\n| c |"), "| a | b |\n| c |");
+    }
+
+    #[test]
+    fn test_wrap_list_and_quote() {
+        let text = "- a list item long enough to wrap\n  1. a nested item to wrap too\n> a quote long enough to wrap";
+        let mut render = MarkdownRender::init(RenderOptions::default()).unwrap();
+        render.wrap_width = Some(20);
+        assert_eq!(
+            render.render(text),
+            "- a list item long\n  enough to wrap\n  1. a nested item\n     to wrap too\n> a quote long\n> enough to wrap"
+        );
+    }
+
+    #[test]
+    fn test_render_theme() {
+        let theme: Theme = bincode::deserialize_from(
+            &include_bytes!("../../assets/monokai-extended.theme.bin")[..],
+        )
+        .unwrap();
+        let styles = MarkdownStyles::new(&theme);
+        let options = RenderOptions {
+            theme: Some(theme),
+            ..Default::default()
+        };
+        let mut render = MarkdownRender::init(options).unwrap();
+        render.wrap_width = Some(30);
+        let output = render.render("## Usage ##\n* run `cargo test` in the snake_case dir\n> see [docs](https://example.com)");
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].contains("Usage") && !lines[0].contains('#'));
+        assert!(lines[1].contains('•') && lines[1].contains("cargo test"));
+        // the next line of the item hangs after the bullet
+        assert!(lines[2].starts_with("  ") && lines[2].contains("snake_case dir"));
+        assert!(lines[3].starts_with(
+            &StyledContent::new(
+                ContentStyle::new().italic().with(styles.quote.unwrap()),
+                "│ "
+            )
+            .to_string()
+        ));
+        assert!(lines[3].contains("\x1b]8;;https://example.com\x1b\\"));
+        assert_eq!(display_width(lines[3]), "│ see docs".chars().count());
+
+        let spans = parse_inline(
+            r"*a* **b** ~~c~~ `d*e` \*f\* g_h_i <https://x.io> [](mailto:me@x.io)",
+            ContentStyle::new(),
+            &styles,
+        );
+        let texts: Vec<(&str, bool)> = spans
+            .iter()
+            .map(|v| (v.text.as_str(), v.link.is_some()))
+            .collect();
+        assert_eq!(
+            texts,
+            [
+                ("a", false),
+                (" ", false),
+                ("b", false),
+                (" ", false),
+                ("c", false),
+                (" ", false),
+                ("d*e", false),
+                (" *f* g_h_i ", false),
+                ("https://x.io", true),
+                (" ", false),
+                ("mailto:me@x.io", true),
+            ]
+        );
+        assert_eq!(spans[0].style, ContentStyle::new().italic());
+        assert_eq!(spans[2].style, ContentStyle::new().bold());
+        assert_eq!(spans[6].style.foreground_color, Some(styles.code));
+    }
 }
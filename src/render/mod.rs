@@ -1,32 +1,46 @@
 mod markdown;
 mod stream;
 
-pub use self::markdown::{MarkdownRender, RenderOptions};
-use self::stream::{markdown_stream, raw_stream};
+pub use self::markdown::{
+    display_width, extract_code_blocks, CodeBlock, MarkdownRender, RenderOptions,
+};
+use self::stream::{json_stream, markdown_stream, raw_stream};
 
-use crate::client::Client;
+use crate::client::{error_code, Client, PartialOutputError, SendOutput, TokenUsage, ToolCall};
 use crate::config::{GlobalConfig, Input};
 use crate::utils::AbortSignal;
 
 use anyhow::{Context, Result};
+use clap::ValueEnum;
 use crossbeam::channel::{unbounded, Sender};
 use crossbeam::sync::WaitGroup;
 use is_terminal::IsTerminal;
 use nu_ansi_term::{Color, Style};
+use serde_json::json;
 use std::io::stdout;
 use std::thread::spawn;
 
+// enum representing the format in which the command mode prints the answer
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    #[default]
+    Text, // the rendered markdown, or the raw text when the output is not a terminal
+    Json,  // a json object once the answer is complete
+    Jsonl, // a json line for each event of the stream, then the json object
+}
+
 // this function renders a stream of messages based on the input
 pub fn render_stream(
     input: &Input,
     client: &dyn Client,
     config: &GlobalConfig,
     abort: AbortSignal,
-) -> Result<String> {
+) -> Result<SendOutput> {
     // creating a wait group wg to synchronize the rendering process
     let wg = WaitGroup::new();
     let wg_cloned = wg.clone();
     let render_options = config.read().get_render_options()?;
+    let jsonl = config.read().output_format == OutputFormat::Jsonl;
     let mut stream_handler = {
         let (tx, rx) = unbounded();
         let abort_clone = abort.clone();
@@ -36,7 +50,9 @@ pub fn render_stream(
             // Depending on whether the standard output is a terminal or not,
             // we initialize either a Markdown renderer or a raw stream renderer
             let run = move || {
-                if stdout().is_terminal() {
+                if jsonl {
+                    json_stream(&rx, &abort)
+                } else if stdout().is_terminal() {
                     let mut render = MarkdownRender::init(render_options)?;
                     markdown_stream(&rx, &mut render, &abort)
                 } else {
@@ -59,14 +75,25 @@ pub fn render_stream(
     match ret {
         Ok(_) => {
             // if no error, we return the renderer
-            println!();
-            Ok(output)
+            if !jsonl {
+                println!();
+            }
+            Ok(SendOutput {
+                text: output,
+                tool_calls: stream_handler.take_tool_calls(),
+                usage: stream_handler.get_usage(),
+                finish_reason: stream_handler.get_finish_reason(),
+            })
         }
         Err(err) => {
             // if we have an error, we return the error
-            if !output.is_empty() {
+            if !output.is_empty() && !jsonl {
                 println!();
             }
+            // a part of the answer has been printed, so it must not be answered again
+            if stream_handler.emitted() {
+                return Err(err.context(PartialOutputError));
+            }
             Err(err)
         }
     }
@@ -85,10 +112,27 @@ pub fn render_error(err: anyhow::Error, highlight: bool) {
     }
 }
 
+// this function prints an error as a json object with its code, for the json output formats
+pub fn render_json_error(err: anyhow::Error) {
+    let (code, status) = error_code(&err);
+    let value = json!({
+        "type": "error",
+        "error": {
+            "code": code,
+            "status": status,
+            "message": format!("{err:#}"),
+        },
+    });
+    println!("{value}");
+}
+
 // This struct handles the reply events received during rendering
 pub struct ReplyHandler {
     sender: Sender<ReplyEvent>,
     buffer: String,
+    tool_calls: Vec<ToolCall>,
+    usage: Option<TokenUsage>,
+    finish_reason: Option<String>,
     abort: AbortSignal,
 }
 
@@ -99,6 +143,9 @@ impl ReplyHandler {
             sender,
             abort,
             buffer: String::new(),
+            tool_calls: vec![],
+            usage: None,
+            finish_reason: None,
         }
     }
 
@@ -117,6 +164,38 @@ impl ReplyHandler {
         Ok(())
     }
 
+    // this function records a tool call requested by the model, it is not rendered
+    pub fn tool_call(&mut self, tool_call: ToolCall) -> Result<()> {
+        debug!("ReplyToolCall: {:?}", tool_call);
+        self.tool_calls.push(tool_call);
+        Ok(())
+    }
+
+    // this function records the tokens used by the request, as reported by the api, the last report wins
+    pub fn usage(&mut self, usage: TokenUsage) -> Result<()> {
+        debug!("ReplyUsage: {:?}", usage);
+        self.usage = Some(usage);
+        Ok(())
+    }
+
+    // this function records why the model stopped, as reported by the api
+    pub fn finish_reason(&mut self, reason: &str) -> Result<()> {
+        debug!("ReplyFinishReason: {reason}");
+        self.finish_reason = Some(reason.to_string());
+        Ok(())
+    }
+
+    // this function tells the renderer that the request is sent again after a transient error
+    pub fn retry(&mut self, attempt: usize, max_attempts: usize) -> Result<()> {
+        debug!("ReplyRetry: {attempt}/{max_attempts}");
+        let ret = self
+            .sender
+            .send(ReplyEvent::Retry(attempt, max_attempts))
+            .with_context(|| "Failed to send ReplyEvent::Retry");
+        self.safe_ret(ret)?;
+        Ok(())
+    }
+
     // this functon sends a done event to the sender
     pub fn done(&mut self) -> Result<()> {
         debug!("ReplyDone");
@@ -133,6 +212,26 @@ impl ReplyHandler {
         &self.buffer
     }
 
+    // this function checks whether any text or tool call has been received
+    pub fn emitted(&self) -> bool {
+        !self.buffer.is_empty() || !self.tool_calls.is_empty()
+    }
+
+    // this function returns the tokens used by the request, when the api reported them
+    pub fn get_usage(&self) -> Option<TokenUsage> {
+        self.usage
+    }
+
+    // this function returns why the model stopped, when the api reported it
+    pub fn get_finish_reason(&self) -> Option<String> {
+        self.finish_reason.clone()
+    }
+
+    // this function takes the tool calls collected so far
+    pub fn take_tool_calls(&mut self) -> Vec<ToolCall> {
+        std::mem::take(&mut self.tool_calls)
+    }
+
     // this function returns a clone of the abort signal
     pub fn get_abort(&self) -> AbortSignal {
         self.abort.clone()
@@ -148,8 +247,9 @@ impl ReplyHandler {
     }
 }
 
-// This enum represents different types of reply events, including text and done events
+// This enum represents different types of reply events, including text, retry and done events
 pub enum ReplyEvent {
     Text(String),
+    Retry(usize, usize), // the attempt number and the max attempts
     Done,
 }
@@ -1,4 +1,4 @@
-use super::{MarkdownRender, ReplyEvent};
+use super::{display_width, MarkdownRender, ReplyEvent};
 
 use crate::utils::AbortSignal;
 
@@ -10,12 +10,12 @@ use crossterm::{
     queue, style,
     terminal::{self, disable_raw_mode, enable_raw_mode},
 };
+use serde_json::json;
 use std::{
     io::{self, Stdout, Write},
     ops::Div,
     time::{Duration, Instant},
 };
-use textwrap::core::display_width;
 
 // this function makes the streaming Markdown-rendered text
 pub fn markdown_stream(
@@ -49,6 +49,8 @@ pub fn raw_stream(rx: &Receiver<ReplyEvent>, abort: &AbortSignal) -> Result<()>
                 ReplyEvent::Text(text) => {
                     print!("{}", text);
                 }
+                // there is no spinner to show the retries
+                ReplyEvent::Retry(..) => {}
                 // If its a Done event, we breaks the loop
                 ReplyEvent::Done => {
                     break;
@@ -59,6 +61,31 @@ pub fn raw_stream(rx: &Receiver<ReplyEvent>, abort: &AbortSignal) -> Result<()>
     Ok(())
 }
 
+// this function prints each event of the stream as a json line
+pub fn json_stream(rx: &Receiver<ReplyEvent>, abort: &AbortSignal) -> Result<()> {
+    loop {
+        if abort.aborted() {
+            return Ok(());
+        }
+        if let Ok(evt) = rx.try_recv() {
+            match evt {
+                ReplyEvent::Text(text) => {
+                    println!("{}", json!({ "type": "text", "text": text }));
+                }
+                ReplyEvent::Retry(attempt, max_attempts) => {
+                    let value = json!({ "type": "retry", "attempt": attempt, "max_attempts": max_attempts });
+                    println!("{value}");
+                }
+                ReplyEvent::Done => {
+                    println!("{}", json!({ "type": "done" }));
+                    break;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
 // this function holds the core logic for streaming Markdown-rendered text
 fn markdown_stream_inner(
     rx: &Receiver<ReplyEvent>,
@@ -88,6 +115,12 @@ fn markdown_stream_inner(
 
         // for all the events that are gathered, we do the following
         for reply_event in gather_events(rx) {
+            // a retry only happens before any text, so the spinner keeps running with a notice
+            if let ReplyEvent::Retry(attempt, max_attempts) = reply_event {
+                spinner.set_message(&format!(" Retrying ({attempt}/{max_attempts})"));
+                continue;
+            }
+
             // we stop the spinner
             spinner.stop(writer)?;
 
@@ -120,8 +153,11 @@ fn markdown_stream_inner(
                     if text.contains('\n') {
                         let text = format!("{buffer}{text}");
                         let (head, tail) = split_line_tail(&text);
-                        let output = render.render(head);
-                        print_block(writer, &output, columns)?;
+                        // the rows of a table are held until it ends, they are in the preview of the buffer
+                        let lines = render.render_lines(head);
+                        if !lines.is_empty() {
+                            print_block(writer, &lines.join("\n"), columns)?;
+                        }
                         buffer = tail.to_string();
                     } else {
                         buffer = format!("{buffer}{text}");
@@ -144,6 +180,7 @@ fn markdown_stream_inner(
 
                     writer.flush()?;
                 }
+                ReplyEvent::Retry(..) => {}
                 ReplyEvent::Done => {
                     break 'outer;
                 }
@@ -203,6 +240,11 @@ impl Spinner {
         }
     }
 
+    // this function changes the message shown next to the spinner
+    fn set_message(&mut self, message: &str) {
+        self.message = message.to_string();
+    }
+
     // This function progresses the spinner animation by one frame
     fn step(&mut self, writer: &mut Stdout) -> Result<()> {
         if self.stopped {
@@ -245,12 +287,15 @@ impl Spinner {
 // combines them into a single text event and also checks if a "Done" event is received
 fn gather_events(rx: &Receiver<ReplyEvent>) -> Vec<ReplyEvent> {
     let mut texts = vec![];
+    let mut retry = None;
     let mut done = false;
     // iterating over all the events received from the channel
     for reply_event in rx.try_iter() {
         match reply_event {
             // if the event is a text event, we append it to a vector of texts
             ReplyEvent::Text(v) => texts.push(v),
+            // only the latest retry matters
+            ReplyEvent::Retry(attempt, max_attempts) => retry = Some((attempt, max_attempts)),
             // If it's a "Done" event, we set a flag
             ReplyEvent::Done => {
                 done = true;
@@ -259,6 +304,9 @@ fn gather_events(rx: &Receiver<ReplyEvent>) -> Vec<ReplyEvent> {
     }
     // constructing a vector of events to return,
     let mut events = vec![];
+    if let Some((attempt, max_attempts)) = retry {
+        events.push(ReplyEvent::Retry(attempt, max_attempts))
+    }
     // combining all texts into a single text event if necessary
     if !texts.is_empty() {
         events.push(ReplyEvent::Text(texts.join("")))
@@ -306,5 +354,5 @@ fn split_line_tail(text: &str) -> (&str, &str) {
 // This function calculates the number of rows needed to display a given text block based on the terminal width
 fn need_rows(text: &str, columns: u16) -> u16 {
     let buffer_width = display_width(text).max(1) as u16;
-    (buffer_width + columns - 1) / columns
+    buffer_width.div_ceil(columns)
 }
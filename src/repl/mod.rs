@@ -6,27 +6,38 @@ use self::completer::ReplCompleter;
 use self::highlighter::ReplHighlighter;
 use self::prompt::ReplPrompt;
 
-use crate::client::{ensure_model_capabilities, init_client};
-use crate::config::{GlobalConfig, Input, State};
-use crate::render::{render_error, render_stream};
-use crate::utils::{create_abort_signal, set_text, AbortSignal};
+use crate::client::{send_with_fallback, TokenUsage};
+use crate::config::{
+    augment_input, render_sources, search_messages, send_with_schema, send_with_tools,
+    GlobalConfig, Input, State,
+};
+use crate::render::{extract_code_blocks, render_error, render_stream};
+use crate::shell;
+use crate::utils::{create_abort_signal, edit_text, set_text, AbortSignal};
 
-use anyhow::{bail, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use fancy_regex::Regex;
 use lazy_static::lazy_static;
 use reedline::Signal;
 use reedline::{
     default_emacs_keybindings, default_vi_insert_keybindings, default_vi_normal_keybindings,
-    ColumnarMenu, EditMode, Emacs, KeyCode, KeyModifiers, Keybindings, Reedline, ReedlineEvent,
-    ReedlineMenu, ValidationResult, Validator, Vi,
+    ColumnarMenu, EditCommand, EditMode, Emacs, FileBackedHistory, HistoryItem, KeyCode,
+    KeyModifiers, Keybindings, Reedline, ReedlineEvent, ReedlineMenu, ValidationResult, Validator,
+    Vi,
 };
+use serde::Deserialize;
+use std::fs::create_dir_all;
+use std::path::PathBuf;
+use std::time::Instant;
 
 // constant string for storing completion_menu
 const MENU_NAME: &str = "completion_menu";
+// the line returned by the editor when Ctrl+X is pressed, the current line is kept in the editor
+const EDIT_BUFFER_COMMAND: &str = "\0edit-buffer";
 
 // lazily initialized static array of ReplCommand, for representing a command that can be executed within the REPL
 lazy_static! {
-    static ref REPL_COMMANDS: [ReplCommand; 13] = [
+    static ref REPL_COMMANDS: [ReplCommand; 24] = [
         // Commands are .help; .info; .model; .role
         // the things the commands perform are written in front of them
         ReplCommand::new(".help", "Print this help message", vec![]),
@@ -62,14 +73,61 @@ lazy_static! {
             "End the current session",
             vec![State::Normal, State::Role]
         ),
+        ReplCommand::new(
+            ".undo", // another command
+            "Remove the last question and answer of the session",
+            vec![State::Normal, State::Role]
+        ),
+        ReplCommand::new(
+            ".fork", // another command
+            "Copy the session under a new name and continue in the copy",
+            vec![State::Normal, State::Role]
+        ),
+        ReplCommand::new(
+            ".branch", // another command
+            "List, create or switch the branches of the session",
+            vec![State::Normal, State::Role]
+        ),
+        ReplCommand::new(
+            ".search", // another command
+            "Search the saved sessions and messages",
+            vec![]
+        ),
+        ReplCommand::new(
+            ".rag", // another command
+            "Answer from the chunks of an index built with --index",
+            vec![]
+        ),
+        ReplCommand::new(
+            ".exit rag", // another command
+            "Stop answering from the index",
+            vec![]
+        ),
+        ReplCommand::new(
+            ".shell", // another command
+            "Turn the text, or each question until .exit shell, into a shell command",
+            vec![]
+        ),
+        ReplCommand::new(
+            ".exit shell", // another command
+            "Stop turning the questions into shell commands",
+            vec![]
+        ),
         ReplCommand::new(
             ".file", // another command
             "Attach files to the message and then submit it",
             vec![]
         ),
         // few more commands
+        ReplCommand::new(".editor", "Compose the message in the external editor", vec![]),
         ReplCommand::new(".set", "Modify the configuration parameters", vec![]),
-        ReplCommand::new(".copy", "Copy the last reply to the clipboard", vec![]),
+        ReplCommand::new(
+            ".copy",
+            "Copy the last reply, or its Nth code block with .copy code [N], to the clipboard",
+            vec![]
+        ),
+        ReplCommand::new(".regenerate", "Send the last question again for a new answer", vec![]),
+        ReplCommand::new(".edit-last", "Edit the last question and send it again", vec![]),
         ReplCommand::new(".exit", "Exit the REPL", vec![]),
     ];
     // a regex instance for matching commands (prefixed with a dot and followed by non-space characters)
@@ -84,13 +142,16 @@ pub struct Repl {
     editor: Reedline,
     prompt: ReplPrompt,
     abort: AbortSignal,
+    prefill: Option<String>, // the text put in the line editor before the next read
+    history_file: Option<PathBuf>, // the history file the editor was created with
 }
 
 impl Repl {
     // this function initializes a repl instance with the given configurations
     pub fn init(config: &GlobalConfig) -> Result<Self> {
         // setting up the editor
-        let editor = Self::create_editor(config)?;
+        let history_file = config.read().history_file()?;
+        let editor = Self::create_editor(config, history_file.clone())?;
 
         // Iinitialize prompt and abort signal
         let prompt = ReplPrompt::new(config);
@@ -102,6 +163,8 @@ impl Repl {
             editor,
             prompt,
             abort,
+            prefill: None,
+            history_file,
         })
     }
 
@@ -120,6 +183,11 @@ impl Repl {
             if self.abort.aborted_ctrlc() && !already_ctrlc {
                 already_ctrlc = true;
             }
+            // the question left by `.edit-last` is put back in the line editor
+            if let Some(text) = self.prefill.take() {
+                self.editor
+                    .run_edit_commands(&[EditCommand::InsertString(text)]);
+            }
             // reading the input from the user
             let sig = self.editor.read_line(&self.prompt);
             // match the imput with all the commands
@@ -129,8 +197,18 @@ impl Repl {
                     // set the ctrlc to false and reset the abort signal
                     already_ctrlc = false;
                     self.abort.reset();
-                    // match the "line" by calling the handle function
-                    match self.handle(&line) {
+                    // match the "line" by calling the handle function, Ctrl+X edits the current line first
+                    let ret = if line == EDIT_BUFFER_COMMAND {
+                        self.edit_buffer()
+                    } else {
+                        self.handle(&line)
+                    };
+                    let ret = ret.and_then(|quit| {
+                        // starting or leaving a session may switch to another history file
+                        self.sync_history_file()?;
+                        Ok(quit)
+                    });
+                    match ret {
                         // if the message is Ok(quit) and quit is true
                         Ok(quit) => {
                             if quit {
@@ -173,7 +251,7 @@ impl Repl {
     }
 
     // this function is responsible for interpreting and executing commands entered by the user
-    fn handle(&self, mut line: &str) -> Result<bool> {
+    fn handle(&mut self, mut line: &str) -> Result<bool> {
         // checking if the user input matches the multiline input pattern defined by MULTILINE_RE constant
         if let Ok(Some(captures)) = MULTILINE_RE.captures(line) {
             // extracting the actual content to be processed
@@ -223,7 +301,7 @@ impl Repl {
                 // this allows users to set or change the role
                 ".role" => match args {
                     // it has args that are associated with the role change
-                    Some(args) => match args.split_once(|c| c == '\n' || c == ' ') {
+                    Some(args) => match args.split_once(['\n', ' ']) {
                         Some((name, text)) => {
                             let name = name.trim();
                             let text = text.trim();
@@ -247,22 +325,105 @@ impl Repl {
                 ".session" => {
                     self.config.write().start_session(args)?;
                 }
+                // this removes the last turn of the session
+                ".undo" => {
+                    self.config.write().undo_session()?;
+                }
+                // this copies the session under a new name
+                ".fork" => match args {
+                    Some(name) => {
+                        self.config.write().fork_session(name)?;
+                    }
+                    None => println!("Usage: .fork <name>"),
+                },
+                // this lists, creates or switches the branches of the session
+                ".branch" => {
+                    if let Some(output) = self.config.write().branch_session(args)? {
+                        println!("{}", output);
+                    }
+                }
+                // this searches the saved sessions and messages, `--open` continues the session of the best result
+                ".search" => {
+                    let args = args.unwrap_or_default();
+                    let (open, query) = match args.strip_prefix("--open") {
+                        Some(query) => (true, query.trim()),
+                        None => (false, args),
+                    };
+                    let results = search_messages(query)?;
+                    println!("{}\n", results.render(self.config.read().highlight));
+                    if open {
+                        match results.top_session() {
+                            Some(name) => self.config.write().start_session(Some(name))?,
+                            None => bail!("No session matches '{query}'"),
+                        }
+                    }
+                }
                 // this updates config parameters with the provided arguments
                 ".set" => {
                     if let Some(args) = args {
                         self.config.write().update(args)?;
                     }
                 }
-                // this copies the last reply to the clipboard
+                // this copies the last reply, or one of its code blocks, to the clipboard
                 ".copy" => {
                     let config = self.config.read();
-                    self.copy(config.last_reply())
-                        .with_context(|| "Failed to copy the last output")?;
+                    match args.and_then(|v| v.strip_prefix("code")) {
+                        Some(index) => {
+                            let index = match index.trim() {
+                                "" => 1,
+                                v => v
+                                    .parse::<usize>()
+                                    .map_err(|_| anyhow!("Usage: .copy code [N]"))?,
+                            };
+                            let blocks = extract_code_blocks(config.last_reply());
+                            let block = index
+                                .checked_sub(1)
+                                .and_then(|i| blocks.get(i))
+                                .ok_or_else(|| {
+                                    anyhow!("No code block {index} in the last reply")
+                                })?;
+                            self.copy(&block.code)
+                                .with_context(|| "Failed to copy the code block")?;
+                        }
+                        None => {
+                            self.copy(config.last_reply())
+                                .with_context(|| "Failed to copy the last output")?;
+                        }
+                    }
+                }
+                // this composes the message in the external editor, starting from the given text
+                ".editor" => {
+                    let text = edit_text(args.unwrap_or_default())?;
+                    self.ask_text(text.trim())?;
+                }
+                // this sends the last question again, its new answer replaces the previous one
+                ".regenerate" => {
+                    self.regenerate(args)?;
+                }
+                // this takes back the last question to edit it before sending it again
+                ".edit-last" => {
+                    self.edit_last(args)?;
                 }
                 // this is a Deprecated command, suggesting the use of .file instead
                 ".read" => {
                     println!(r#"Deprecated. Use '.file' instead."#);
                 }
+                // this loads an index, whose closest chunks are then sent along with each question
+                ".rag" => match args {
+                    Some(name) => {
+                        self.config.write().set_rag(name)?;
+                    }
+                    None => println!("Usage: .rag <name>"),
+                },
+                // this turns the text into a shell command, without text the questions that follow are turned too
+                ".shell" => match args {
+                    Some(text) => {
+                        shell::run(&self.config, Input::from_str(text), self.abort.clone())?;
+                    }
+                    None => {
+                        self.config.write().shell = true;
+                    }
+                },
                 // this attaches files and optionally additional text to the message
                 ".file" => match args {
                     Some(args) => {
@@ -283,6 +444,12 @@ impl Repl {
                     Some("session") => {
                         self.config.write().end_session()?;
                     }
+                    Some("rag") => {
+                        self.config.write().clear_rag();
+                    }
+                    Some("shell") => {
+                        self.config.write().shell = false;
+                    }
                     Some(_) => unknown_command()?,
                     None => {
                         return Ok(true);
@@ -301,7 +468,11 @@ impl Repl {
                 _ => unknown_command()?,
             },
             None => {
-                self.ask(line, vec![])?;
+                if self.config.read().shell && !line.trim().is_empty() {
+                    shell::run(&self.config, Input::from_str(line), self.abort.clone())?;
+                } else {
+                    self.ask_text(line)?;
+                }
             }
         }
 
@@ -310,6 +481,20 @@ impl Repl {
         Ok(false)
     }
 
+    // this function sends a text typed or composed by the user, its front matter sets the files,
+    // the role, the model or the temperature of this message only
+    fn ask_text(&self, text: &str) -> Result<()> {
+        match split_front_matter(text)? {
+            (Some(front_matter), text) => self.with_overrides(
+                front_matter.model.as_deref(),
+                front_matter.temperature,
+                front_matter.role.as_deref(),
+                || self.ask(text, front_matter.files.clone()),
+            ),
+            (None, text) => self.ask(text, vec![]),
+        }
+    }
+
     // this function handles the sending of user input to an AI model
     fn ask(&self, text: &str, files: Vec<String>) -> Result<()> {
         // if both text and files are empty, we immediately return, that there's nothing to process
@@ -324,13 +509,38 @@ impl Repl {
             // otherwise, we create a new Input instance from both text and files
             Input::new(text, files)?
         };
+        self.ask_input(input)
+    }
+
+    // this function sends an input to the AI model and prints the answer
+    fn ask_input(&self, mut input: Input) -> Result<()> {
+        // adding the chunks of the index closest to the question, unless the input already has them
+        augment_input(&self.config, &mut input)?;
         // printing the tokens of the input if configured to do so
         self.config.read().maybe_print_send_tokens(&input);
-        // making new client
-        let mut client = init_client(&self.config)?;
-        ensure_model_capabilities(client.as_mut(), input.required_capabilities())?;
-        let output = render_stream(&input, client.as_ref(), &self.config, self.abort.clone())?;
-        self.config.write().save_message(input, &output)?;
+        let start = Instant::now();
+        let mut usages = vec![];
+        // the input is sent again as long as the model asks for tools to be called,
+        // each time with the first model of the fallback chain that answers,
+        // and once more when the answer does not match the schema
+        let output = send_with_schema(&self.config, &mut input, |input| {
+            send_with_tools(&self.config, input, |input| {
+                let output = send_with_fallback(&self.config, input, |input, client| {
+                    render_stream(input, client, &self.config, self.abort.clone())
+                })?;
+                usages.push(output.usage);
+                Ok(output)
+            })
+        })?;
+        if let Some(sources) = render_sources(&input) {
+            println!("{sources}\n");
+        }
+        self.config.write().save_message(
+            input,
+            &output,
+            TokenUsage::sum(&usages),
+            start.elapsed(),
+        )?;
         if self.config.read().auto_copy {
             let _ = self.copy(&output);
         }
@@ -348,14 +558,14 @@ Type ".help" for more information.
     }
 
     // this function initializes and configures the Reedline editor for user input
-    fn create_editor(config: &GlobalConfig) -> Result<Reedline> {
+    fn create_editor(config: &GlobalConfig, history_file: Option<PathBuf>) -> Result<Reedline> {
         // initializing a completer, highlighter, configuring a menu and the edit mode for the editor
         let completer = ReplCompleter::new(config);
         let highlighter = ReplHighlighter::new(config);
         let menu = Self::create_menu();
         let edit_mode = Self::create_edit_mode(config);
         // we finally create a new Reedline editor using the above configurations
-        let editor = Reedline::create()
+        let mut editor = Reedline::create()
             .with_completer(Box::new(completer))
             .with_highlighter(Box::new(highlighter))
             .with_menu(menu)
@@ -365,11 +575,33 @@ Type ".help" for more information.
             .use_bracketed_paste(true)
             .with_validator(Box::new(ReplValidator))
             .with_ansi_colors(true);
+        // the history is kept in a file, where Ctrl+R searches it
+        if let Some(path) = history_file {
+            if let Some(parent) = path.parent() {
+                create_dir_all(parent).with_context(|| {
+                    format!("Failed to create history dir at {}", parent.display())
+                })?;
+            }
+            let history_size = config.read().history_size;
+            let history = FileBackedHistory::with_file(history_size, path.clone())
+                .with_context(|| format!("Failed to load history at {}", path.display()))?;
+            editor = editor.with_history(Box::new(history));
+        }
 
         // returning the editor wrapped in result
         Ok(editor)
     }
 
+    // this function recreates the editor when the history file has changed, the old history is saved when it is dropped
+    fn sync_history_file(&mut self) -> Result<()> {
+        let history_file = self.config.read().history_file()?;
+        if history_file != self.history_file {
+            self.editor = Self::create_editor(&self.config, history_file.clone())?;
+            self.history_file = history_file;
+        }
+        Ok(())
+    }
+
     // this function adds additional keybindings to the editor
     fn extra_keybindings(keybindings: &mut Keybindings) {
         keybindings.add_binding(
@@ -385,6 +617,12 @@ Type ".help" for more information.
             KeyCode::BackTab,
             ReedlineEvent::MenuPrevious,
         );
+        // Ctrl+X opens the current line in the external editor, see `edit_buffer`
+        keybindings.add_binding(
+            KeyModifiers::CONTROL,
+            KeyCode::Char('x'),
+            ReedlineEvent::ExecuteHostCommand(EDIT_BUFFER_COMMAND.into()),
+        );
     }
 
     // this function determines and configures the edit mode for the editor based on the user configuration
@@ -414,6 +652,124 @@ Type ".help" for more information.
         ReedlineMenu::EngineCompleter(Box::new(completion_menu))
     }
 
+    // this function sends the last question again, optionally with another model or temperature,
+    // the session is left untouched if it fails
+    fn regenerate(&self, args: Option<&str>) -> Result<()> {
+        let (model, temperature) = parse_regenerate_args(args.unwrap_or_default())?;
+        let (session, last_message) = {
+            let config = self.config.read();
+            (config.session.clone(), config.last_message.clone())
+        };
+        let ret = self.with_overrides(model.as_deref(), temperature, None, || {
+            let (input, _) = self.config.write().take_last_message()?;
+            self.ask_input(input)
+        });
+        if ret.is_err() {
+            let mut config = self.config.write();
+            config.session = session;
+            config.last_message = last_message;
+        }
+        ret
+    }
+
+    // this function runs `f` with a model, a temperature or a role that only apply to it
+    fn with_overrides<F>(
+        &self,
+        model: Option<&str>,
+        temperature: Option<f64>,
+        role: Option<&str>,
+        f: F,
+    ) -> Result<()>
+    where
+        F: FnOnce() -> Result<()>,
+    {
+        let (old_model, old_temperature, old_role) = {
+            let config = self.config.read();
+            (
+                config.model_chain(),
+                config.temperature,
+                config.role.as_ref().map(|v| v.name.clone()),
+            )
+        };
+        let ret = (|| {
+            if let Some(role) = role {
+                self.config.write().set_role(role)?;
+            }
+            if let Some(model) = model {
+                self.config.write().set_model(model)?;
+            }
+            if temperature.is_some() {
+                self.config.write().set_temperature(temperature)?;
+            }
+            f()
+        })();
+        // the config is restored whatever happened, the error of `f` comes before the one of restoring
+        let restored = (|| {
+            let mut config = self.config.write();
+            if role.is_some() {
+                match old_role {
+                    Some(name) => config.set_role(&name)?,
+                    None => config.clear_role()?,
+                }
+            }
+            // the role may have changed the model too
+            if model.is_some() || role.is_some() {
+                config.set_model(&old_model)?;
+            }
+            if role.is_some() || temperature.is_some() {
+                config.set_temperature(old_temperature)?;
+            }
+            anyhow::Ok(())
+        })();
+        ret.and(restored)
+    }
+
+    // this function removes the last question and answer, and puts the question back in the line editor,
+    // or in the external editor with `--editor`, from where it is sent again
+    fn edit_last(&mut self, args: Option<&str>) -> Result<()> {
+        // the input is given back as typed, with the attached files as `.file` rather than their content
+        let text = match &self.config.read().last_message {
+            Some((input, _)) => input.command(),
+            None => bail!("No last message"),
+        };
+        match args {
+            None => {
+                self.config.write().take_last_message()?;
+                self.prefill = Some(if text.contains('\n') {
+                    format!(":::\n{text}\n:::")
+                } else {
+                    text
+                });
+            }
+            Some("--editor") => {
+                let text = edit_text(&text)?;
+                self.config.write().take_last_message()?;
+                let text = text.trim();
+                if !text.is_empty() {
+                    self.handle(text)?;
+                }
+            }
+            Some(_) => bail!("Usage: .edit-last [--editor]"),
+        }
+        Ok(())
+    }
+
+    // this function opens the current line in the external editor, what is saved is submitted only if it is not empty and was changed
+    fn edit_buffer(&mut self) -> Result<bool> {
+        let buffer = self.editor.current_buffer_contents().to_string();
+        let text = edit_text(&buffer)?;
+        let text = text.trim_end();
+        if text.trim().is_empty() || text == buffer.trim_end() {
+            return Ok(false);
+        }
+        self.editor.run_edit_commands(&[EditCommand::Clear]);
+        self.editor
+            .history_mut()
+            .save(HistoryItem::from_command_line(text))
+            .with_context(|| "Failed to save the history")?;
+        self.handle(text)
+    }
+
     // this function just makes the copy of a given text
     fn copy(&self, text: &str) -> Result<()> {
         // if the input is empty, we throw an error
@@ -486,6 +842,62 @@ Press Ctrl+C to abort aichat, Ctrl+D to exit the REPL"###,
     );
 }
 
+// struct representing the front matter of a message, a yaml block between two `---` lines at its start
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+struct FrontMatter {
+    role: Option<String>,
+    model: Option<String>,
+    temperature: Option<f64>,
+    #[serde(default)]
+    files: Vec<String>,
+}
+
+// this function splits the front matter from the text of a message
+fn split_front_matter(text: &str) -> Result<(Option<FrontMatter>, &str)> {
+    let Some(rest) = text
+        .strip_prefix("---\n")
+        .or_else(|| text.strip_prefix("---\r\n"))
+    else {
+        return Ok((None, text));
+    };
+    let mut offset = 0;
+    for line in rest.split_inclusive('\n') {
+        if line.trim_end() == "---" {
+            let yaml = &rest[..offset];
+            let front_matter = if yaml.trim().is_empty() {
+                FrontMatter::default()
+            } else {
+                serde_yaml::from_str(yaml).with_context(|| "Invalid front matter")?
+            };
+            return Ok((Some(front_matter), rest[offset + line.len()..].trim()));
+        }
+        offset += line.len();
+    }
+    Ok((None, text))
+}
+
+// this function parses the arguments of `.regenerate`, which override the model or the temperature
+fn parse_regenerate_args(args: &str) -> Result<(Option<String>, Option<f64>)> {
+    let usage = "Usage: .regenerate [--model <id>] [--temperature <value>]";
+    let (mut model, mut temperature) = (None, None);
+    let mut args = args.split_whitespace();
+    while let Some(arg) = args.next() {
+        let value = args.next();
+        match (arg, value) {
+            ("--model", Some(value)) => model = Some(value.to_string()),
+            ("--temperature", Some(value)) => {
+                let value = value
+                    .parse()
+                    .with_context(|| format!("Invalid temperature '{value}'"))?;
+                temperature = Some(value);
+            }
+            _ => bail!("{usage}"),
+        }
+    }
+    Ok((model, temperature))
+}
+
 // this function is to analyze a line of input text and determine if it starts with a command
 fn parse_command(line: &str) -> Option<(&str, Option<&str>)> {
     // using the COMMAND_RE to match the input line
@@ -523,4 +935,37 @@ mod tests {
             Some((".prompt", Some("abc")))
         );
     }
+
+    #[test]
+    fn test_parse_regenerate_args() {
+        assert_eq!(parse_regenerate_args("").unwrap(), (None, None));
+        assert_eq!(
+            parse_regenerate_args("--model openai:gpt-4 --temperature 0.5").unwrap(),
+            (Some("openai:gpt-4".into()), Some(0.5))
+        );
+        assert!(parse_regenerate_args("--model").is_err());
+        assert!(parse_regenerate_args("--temperature hot").is_err());
+    }
+
+    #[test]
+    fn test_split_front_matter() {
+        assert_eq!(split_front_matter("hello").unwrap(), (None, "hello"));
+        assert_eq!(
+            split_front_matter("---\nrole: coder\nfiles: [a.rs]\n---\nexplain\n").unwrap(),
+            (
+                Some(FrontMatter {
+                    role: Some("coder".into()),
+                    files: vec!["a.rs".into()],
+                    ..Default::default()
+                }),
+                "explain"
+            )
+        );
+        assert_eq!(
+            split_front_matter("---\n---\nhi").unwrap(),
+            (Some(FrontMatter::default()), "hi")
+        );
+        assert_eq!(split_front_matter("---\nhi").unwrap(), (None, "---\nhi"));
+        assert!(split_front_matter("---\nmodle: x\n---\nhi").is_err());
+    }
 }
@@ -20,23 +20,23 @@ impl ReplPrompt {
 
 impl Prompt for ReplPrompt {
     // this function renders the left part of the prompt
-    fn render_prompt_left(&self) -> Cow<str> {
+    fn render_prompt_left(&self) -> Cow<'_, str> {
         // retrieving the prompt content from the global configuration
         Cow::Owned(self.config.read().render_prompt_left())
     }
 
     // this function renders the right part of the prompt
-    fn render_prompt_right(&self) -> Cow<str> {
+    fn render_prompt_right(&self) -> Cow<'_, str> {
         Cow::Owned(self.config.read().render_prompt_right())
     }
 
     // this function renders the prompt indicator
-    fn render_prompt_indicator(&self, _prompt_mode: reedline::PromptEditMode) -> Cow<str> {
+    fn render_prompt_indicator(&self, _prompt_mode: reedline::PromptEditMode) -> Cow<'_, str> {
         Cow::Borrowed("")
     }
 
     // this function renders the indicator for multiline input
-    fn render_prompt_multiline_indicator(&self) -> Cow<str> {
+    fn render_prompt_multiline_indicator(&self) -> Cow<'_, str> {
         Cow::Borrowed("")
     }
 
@@ -44,7 +44,7 @@ impl Prompt for ReplPrompt {
     fn render_prompt_history_search_indicator(
         &self,
         history_search: PromptHistorySearch, // object containing information about the history search status and term
-    ) -> Cow<str> {
+    ) -> Cow<'_, str> {
         let prefix = match history_search.status {
             PromptHistorySearchStatus::Passing => "",
             PromptHistorySearchStatus::Failing => "failing ",
@@ -0,0 +1,413 @@
+// This file implements the `--serve` mode, a local HTTP server exposing an OpenAI-compatible API
+// every request is routed to the configured client of the requested model
+use crate::client::{
+    error_code, init_client, list_models, record_usage_async, GenerationParams, Message, Model,
+    SendData, SendOutput, TokenUsage,
+};
+use crate::config::{Config, GlobalConfig};
+use crate::render::{ReplyEvent, ReplyHandler};
+use crate::utils::{create_abort_signal, init_tokio_runtime};
+
+use anyhow::{anyhow, Context, Result};
+use futures_util::stream;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{body, Body, Method, Request, Response, Server, StatusCode};
+use parking_lot::RwLock;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+
+// the address to listen on when `--serve` is given without one
+const DEFAULT_ADDRESS: &str = "127.0.0.1:8000";
+
+// the prefix of pseudo models that answer with a role
+const ROLE_MODEL_PREFIX: &str = "role:";
+
+// struct representing the body of a `/v1/chat/completions` request
+#[derive(Debug, Deserialize)]
+struct ChatCompletionsRequest {
+    model: String,
+    messages: Vec<Message>,
+    temperature: Option<f64>,
+    top_p: Option<f64>,
+    max_tokens: Option<usize>,
+    #[serde(default)]
+    stop: StopSequences,
+    seed: Option<u64>,
+    presence_penalty: Option<f64>,
+    frequency_penalty: Option<f64>,
+    #[serde(default)]
+    stream: bool,
+}
+
+// enum representing the `stop` of a request, a single sequence or a list of them
+#[derive(Debug, Default, Deserialize)]
+#[serde(untagged)]
+enum StopSequences {
+    #[default]
+    None,
+    One(String),
+    Many(Vec<String>),
+}
+
+// this function starts the server and blocks until it is stopped with ctrl-c
+pub fn run(config: &GlobalConfig, addr: Option<String>) -> Result<()> {
+    let addr = addr.unwrap_or_else(|| DEFAULT_ADDRESS.to_string());
+    let addr: SocketAddr = addr
+        .parse()
+        .with_context(|| format!("Invalid address '{addr}'"))?;
+    let config = config.clone();
+    init_tokio_runtime()?.block_on(async move {
+        let make_svc = make_service_fn(move |_| {
+            let config = config.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| {
+                    let config = config.clone();
+                    async move { Ok::<_, Infallible>(handle(config, req).await) }
+                }))
+            }
+        });
+        let server = Server::try_bind(&addr)
+            .with_context(|| format!("Failed to listen on {addr}"))?
+            .serve(make_svc);
+        println!("Chat Completions API: http://{addr}/v1/chat/completions");
+        println!("Models API:           http://{addr}/v1/models");
+        server
+            .with_graceful_shutdown(async {
+                let _ = tokio::signal::ctrl_c().await;
+            })
+            .await
+            .with_context(|| "Server failed")
+    })
+}
+
+// this function routes a request to its handler and turns errors into json responses
+async fn handle(config: GlobalConfig, req: Request<Body>) -> Response<Body> {
+    let ret = match (req.method(), req.uri().path()) {
+        (&Method::GET, "/v1/models") => Ok(list_models_response(&config.read())),
+        (&Method::POST, "/v1/chat/completions") => chat_completions(config, req).await,
+        _ => {
+            return error_response(StatusCode::NOT_FOUND, "invalid_request_error", "Not Found");
+        }
+    };
+    match ret {
+        Ok(res) => res,
+        Err(err) => {
+            debug!("Serve Error: {err:?}");
+            let (status, typ) = error_status(&err);
+            error_response(status, typ, &format!("{err:#}"))
+        }
+    }
+}
+
+// this function lists the models of all clients, followed by the roles as pseudo models
+fn list_models_response(config: &Config) -> Response<Body> {
+    let mut data: Vec<Value> = list_models(config)
+        .into_iter()
+        .map(|v| {
+            json!({
+                "id": v.id(),
+                "object": "model",
+                "created": 0,
+                "owned_by": v.client_name,
+            })
+        })
+        .collect();
+    data.extend(config.roles.iter().map(|v| {
+        json!({
+            "id": format!("{ROLE_MODEL_PREFIX}{}", v.name),
+            "object": "model",
+            "created": 0,
+            "owned_by": "aichat",
+        })
+    }));
+    json_response(json!({ "object": "list", "data": data }))
+}
+
+// this function answers a chat completions request, as a single json or as server-sent events
+async fn chat_completions(config: GlobalConfig, req: Request<Body>) -> Result<Response<Body>> {
+    let bytes = body::to_bytes(req.into_body()).await?;
+    let ChatCompletionsRequest {
+        model,
+        mut messages,
+        mut temperature,
+        top_p,
+        max_tokens,
+        stop,
+        seed,
+        presence_penalty,
+        frequency_penalty,
+        stream,
+    } = serde_json::from_slice(&bytes).with_context(|| "Invalid request body")?;
+
+    // each request works on its own copy of the config, so it never changes the shared state
+    let mut request_config = config.read().clone();
+    request_config.session = None;
+    request_config.role = None;
+    let mut schema = None;
+    if let Some(name) = model.strip_prefix(ROLE_MODEL_PREFIX) {
+        let role = request_config.retrieve_role(name)?;
+        if !role.model.is_empty() {
+            request_config.set_model(&role.model)?;
+        }
+        if temperature.is_none() {
+            temperature = role.temperature;
+        }
+        request_config.params = request_config.params.merge(&role.params);
+        schema = role.schema.clone();
+        role.patch_messages(&mut messages)?;
+    } else {
+        request_config.model = find_model(&request_config, &model)?;
+    }
+    request_config.model.max_tokens_limit(&messages)?;
+    let model_id = request_config.model.id();
+    let temperature = temperature.or(request_config.temperature);
+    // the parameters of the request replace the ones of the config and the role
+    let params = request_config.params.merge(&GenerationParams {
+        top_p,
+        max_output_tokens: max_tokens,
+        stop: match stop {
+            StopSequences::None => vec![],
+            StopSequences::One(v) => vec![v],
+            StopSequences::Many(v) => v,
+        },
+        seed,
+        presence_penalty,
+        frequency_penalty,
+        provider_options: Default::default(),
+    });
+
+    let request_config = Arc::new(RwLock::new(request_config));
+    let client = init_client(&request_config)?;
+    let http_client = client.build_client()?;
+    let data = SendData {
+        messages: messages.clone(),
+        temperature,
+        params,
+        stream,
+        tools: vec![],
+        schema,
+    };
+
+    let id = format!("chatcmpl-{}", chrono::Utc::now().timestamp_millis());
+    let created = chrono::Utc::now().timestamp();
+
+    if !stream {
+        let output = client
+            .send_message_with_retry(&http_client, data)
+            .await
+            .with_context(|| "Failed to get answer")?;
+        // the tokens are estimated when the api does not report them
+        let usage = output.usage.unwrap_or_else(|| {
+            let config = request_config.read();
+            TokenUsage {
+                input_tokens: config.model.total_tokens(&messages),
+                output_tokens: config.model.count_tokens(&output.text),
+            }
+        });
+        let value = json!({
+            "id": id,
+            "object": "chat.completion",
+            "created": created,
+            "model": model_id,
+            "choices": [{
+                "index": 0,
+                "message": { "role": "assistant", "content": output.text },
+                "finish_reason": output.finish_reason.as_deref().unwrap_or("stop"),
+            }],
+            "usage": {
+                "prompt_tokens": usage.input_tokens,
+                "completion_tokens": usage.output_tokens,
+                "total_tokens": usage.input_tokens + usage.output_tokens,
+            },
+        });
+        record_usage_async(request_config, messages, output).await?;
+        return Ok(json_response(value));
+    }
+
+    // the handler sends the text to a crossbeam channel, which is bridged to the response body
+    let (tx, mut rx) = unbounded_channel::<String>();
+    let chunk = move |delta: Value, finish_reason: Value| {
+        let value = json!({
+            "id": id,
+            "object": "chat.completion.chunk",
+            "created": created,
+            "model": model_id,
+            "choices": [{ "index": 0, "delta": delta, "finish_reason": finish_reason }],
+        });
+        format!("data: {value}\n\n")
+    };
+    tokio::spawn(async move {
+        let (reply_tx, reply_rx) = crossbeam::channel::unbounded();
+        let mut handler = ReplyHandler::new(reply_tx, create_abort_signal());
+        let bridge = {
+            let tx = tx.clone();
+            let chunk = chunk.clone();
+            tokio::task::spawn_blocking(move || {
+                let _ = tx.send(chunk(
+                    json!({ "role": "assistant", "content": "" }),
+                    Value::Null,
+                ));
+                for event in reply_rx {
+                    match event {
+                        ReplyEvent::Text(text) => {
+                            let _ = tx.send(chunk(json!({ "content": text }), Value::Null));
+                        }
+                        ReplyEvent::Retry(..) => {}
+                        ReplyEvent::Done => break,
+                    }
+                }
+            })
+        };
+        let ret = client
+            .send_message_streaming_with_retry(&http_client, &mut handler, data)
+            .await;
+        let finish_reason = handler.get_finish_reason().unwrap_or_else(|| "stop".into());
+        if ret.is_ok() {
+            let output = SendOutput {
+                text: handler.get_buffer().to_string(),
+                tool_calls: vec![],
+                usage: handler.get_usage(),
+                finish_reason: handler.get_finish_reason(),
+            };
+            let _ = record_usage_async(request_config, messages, output).await;
+        }
+        drop(handler);
+        let _ = bridge.await;
+        finish_stream(&tx, ret, chunk(json!({}), finish_reason.into()));
+    });
+
+    let body = Body::wrap_stream(stream::poll_fn(move |cx| {
+        rx.poll_recv(cx).map(|v| v.map(Ok::<_, Infallible>))
+    }));
+    Ok(Response::builder()
+        .header("content-type", "text/event-stream")
+        .header("cache-control", "no-cache")
+        .body(body)?)
+}
+
+// this function sends the last events of a stream, an error is reported in the same way as openai
+fn finish_stream(tx: &UnboundedSender<String>, ret: Result<()>, last_chunk: String) {
+    if let Err(err) = ret {
+        let value = json!({ "error": { "message": format!("{err:#}"), "type": "api_error" } });
+        let _ = tx.send(format!("data: {value}\n\n"));
+    } else {
+        let _ = tx.send(last_chunk);
+    }
+    let _ = tx.send("data: [DONE]\n\n".to_string());
+}
+
+// this function returns the status and the type of the error response, a failure of the provider is a
+// bad gateway, except the rate limits and timeouts which are passed on
+fn error_status(err: &anyhow::Error) -> (StatusCode, &'static str) {
+    match error_code(err).0 {
+        "rate_limit" => (StatusCode::TOO_MANY_REQUESTS, "rate_limit_error"),
+        "timeout" => (StatusCode::GATEWAY_TIMEOUT, "api_error"),
+        "auth_error" | "server_error" | "http_error" | "connection_error" => {
+            (StatusCode::BAD_GATEWAY, "api_error")
+        }
+        // the request was refused by the provider or by the server itself
+        _ => (StatusCode::BAD_REQUEST, "invalid_request_error"),
+    }
+}
+
+// this function finds a configured model by its id, like `--model` does
+fn find_model(config: &Config, value: &str) -> Result<Model> {
+    let models = list_models(config);
+    Model::find(&models, value).ok_or_else(|| anyhow!("Invalid model '{value}'"))
+}
+
+// this function builds a json response with status 200
+fn json_response(value: Value) -> Response<Body> {
+    Response::builder()
+        .header("content-type", "application/json")
+        .body(Body::from(value.to_string()))
+        .unwrap_or_default()
+}
+
+// this function builds an error response in the format of the openai api
+fn error_response(status: StatusCode, typ: &str, message: &str) -> Response<Body> {
+    let value = json!({ "error": { "message": message, "type": typ } });
+    let mut res = json_response(value);
+    *res.status_mut() = status;
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::StatusError;
+
+    fn status_err(status: u16) -> anyhow::Error {
+        StatusError {
+            status,
+            retry_after: None,
+        }
+        .wrap(anyhow!("failed"))
+    }
+
+    #[test]
+    fn test_error_status() {
+        assert_eq!(
+            error_status(&status_err(429)),
+            (StatusCode::TOO_MANY_REQUESTS, "rate_limit_error")
+        );
+        assert_eq!(
+            error_status(&status_err(504)),
+            (StatusCode::GATEWAY_TIMEOUT, "api_error")
+        );
+        assert_eq!(
+            error_status(&status_err(401)),
+            (StatusCode::BAD_GATEWAY, "api_error")
+        );
+        assert_eq!(
+            error_status(&status_err(500)),
+            (StatusCode::BAD_GATEWAY, "api_error")
+        );
+        assert_eq!(
+            error_status(&status_err(400)),
+            (StatusCode::BAD_REQUEST, "invalid_request_error")
+        );
+        assert_eq!(
+            error_status(&anyhow!("Invalid model 'x'")),
+            (StatusCode::BAD_REQUEST, "invalid_request_error")
+        );
+    }
+
+    #[test]
+    fn test_stop_sequences() {
+        let parse = |body: &str| serde_json::from_str::<ChatCompletionsRequest>(body).unwrap();
+        let body = r#"{"model":"m","messages":[]}"#;
+        assert!(matches!(parse(body).stop, StopSequences::None));
+        let body = r#"{"model":"m","messages":[],"stop":"END"}"#;
+        assert!(matches!(parse(body).stop, StopSequences::One(v) if v == "END"));
+        let body = r#"{"model":"m","messages":[],"stop":["a","b"]}"#;
+        assert!(matches!(parse(body).stop, StopSequences::Many(v) if v == ["a", "b"]));
+    }
+
+    #[test]
+    fn test_list_models_response() {
+        let config = Config {
+            roles: vec![serde_yaml::from_str("name: coder\nprompt: write code").unwrap()],
+            ..Default::default()
+        };
+        let res = list_models_response(&config);
+        assert_eq!(res.status(), StatusCode::OK);
+        let bytes = init_tokio_runtime()
+            .unwrap()
+            .block_on(body::to_bytes(res.into_body()))
+            .unwrap();
+        let data: Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(data["object"], "list");
+        let list = data["data"].as_array().unwrap();
+        assert_eq!(list[0]["id"], "openai:gpt-3.5-turbo");
+        assert_eq!(list[0]["owned_by"], "openai");
+        assert_eq!(
+            list.last().unwrap(),
+            &json!({ "id": "role:coder", "object": "model", "created": 0, "owned_by": "aichat" })
+        );
+    }
+}
@@ -0,0 +1,107 @@
+// This file implements a BM25 inverted index, which ranks texts by the words they share with a query,
+// the rare words weighing more, and works without any model
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+// the saturation of the count of a word in a text
+const K1: f32 = 1.2;
+// how much the length of a text lowers its score
+const B: f32 = 0.75;
+
+// struct representing the index of a list of texts, called documents
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct Bm25Index {
+    postings: HashMap<String, Vec<(u32, u32)>>, // the (document, count) of each word
+    lengths: Vec<u32>,                          // the number of words of each document
+}
+
+impl Bm25Index {
+    // this function indexes the words of the documents
+    pub fn new<'a>(documents: impl IntoIterator<Item = &'a str>) -> Self {
+        let mut index = Self::default();
+        for (i, document) in documents.into_iter().enumerate() {
+            let words = bm25_words(document);
+            index.lengths.push(words.len() as u32);
+            let mut counts: HashMap<String, u32> = HashMap::new();
+            for word in words {
+                *counts.entry(word).or_default() += 1;
+            }
+            for (word, count) in counts {
+                index
+                    .postings
+                    .entry(word)
+                    .or_default()
+                    .push((i as u32, count));
+            }
+        }
+        index
+    }
+
+    // this function returns the (document, score) of the best documents for the query, best first,
+    // the documents without any word of the query are left out
+    pub fn search(&self, query: &str, top_k: usize) -> Vec<(usize, f32)> {
+        let total = self.lengths.len() as f32;
+        if total == 0.0 {
+            return vec![];
+        }
+        let average = self.lengths.iter().sum::<u32>() as f32 / total;
+        let words: HashSet<String> = bm25_words(query).into_iter().collect();
+        let mut scores: HashMap<usize, f32> = HashMap::new();
+        for word in &words {
+            let Some(postings) = self.postings.get(word) else {
+                continue;
+            };
+            let found = postings.len() as f32;
+            let idf = (1.0 + (total - found + 0.5) / (found + 0.5)).ln();
+            for (document, count) in postings {
+                let count = *count as f32;
+                let length = self.lengths[*document as usize] as f32;
+                let norm = K1 * (1.0 - B + B * length / average.max(1.0));
+                *scores.entry(*document as usize).or_default() +=
+                    idf * count * (K1 + 1.0) / (count + norm);
+            }
+        }
+        let mut scores: Vec<(usize, f32)> = scores.into_iter().collect();
+        scores.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+        scores.truncate(top_k);
+        scores
+    }
+}
+
+// this function splits a text into lowercase words of letters, digits and underscores,
+// the single characters are left out
+pub fn bm25_words(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric() && c != '_')
+        .filter(|v| v.chars().nth(1).is_some())
+        .map(|v| v.to_lowercase())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bm25_search() {
+        let index = Bm25Index::new([
+            "The proxy is set with the `proxy` field of the client",
+            "Sessions are saved in the sessions dir",
+            "The client retries the requests, set max_attempts to 1 to disable it",
+            "",
+        ]);
+        assert_eq!(
+            bm25_words("Set max_attempts=3, a b"),
+            ["set", "max_attempts"]
+        );
+
+        let results = index.search("how do I set a PROXY?", 4);
+        let documents: Vec<usize> = results.iter().map(|v| v.0).collect();
+        // the rare word `proxy` weighs more than `set`, the other documents share no word
+        assert_eq!(documents, [0, 2]);
+        assert!(results[0].1 > results[1].1);
+
+        assert_eq!(index.search("session", 4), []);
+        assert_eq!(index.search("sessions client", 1).len(), 1);
+        assert!(Bm25Index::default().search("proxy", 4).is_empty());
+    }
+}
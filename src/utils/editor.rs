@@ -0,0 +1,107 @@
+// This file opens a text in the external editor of the user ($VISUAL or $EDITOR)
+use anyhow::{anyhow, bail, Context, Result};
+use std::{
+    env,
+    fs::{self, OpenOptions},
+    io::{ErrorKind, Write},
+    path::PathBuf,
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+// this function builds the editor command of the user, with its arguments but without the file
+pub fn editor_command() -> Result<Command> {
+    let editor = env::var("VISUAL")
+        .or_else(|_| env::var("EDITOR"))
+        .unwrap_or_else(|_| {
+            if cfg!(windows) {
+                "notepad".into()
+            } else {
+                "vi".into()
+            }
+        });
+    let args = shell_words::split(&editor).with_context(|| format!("Invalid editor '{editor}'"))?;
+    match args.split_first() {
+        Some((program, args)) => {
+            let mut command = Command::new(program);
+            command.args(args);
+            Ok(command)
+        }
+        None => bail!("Invalid editor '{editor}'"),
+    }
+}
+
+// this function creates a new temporary file for the text, only readable by the user
+// the file must not exist yet, so that a file or a symlink put at its path by someone else is never written to
+fn create_temp_file(text: &str) -> Result<PathBuf> {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|v| v.subsec_nanos())
+        .unwrap_or_default();
+    let mut attempt = 0;
+    loop {
+        let path = env::temp_dir().join(format!(
+            "aichat-{}-{nanos:x}{attempt}.md",
+            std::process::id()
+        ));
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        match options.open(&path) {
+            Ok(mut file) => {
+                file.write_all(text.as_bytes())
+                    .with_context(|| format!("Failed to write {}", path.display()))?;
+                return Ok(path);
+            }
+            Err(err) if err.kind() == ErrorKind::AlreadyExists && attempt < 100 => attempt += 1,
+            Err(err) => {
+                return Err(err).with_context(|| format!("Failed to create {}", path.display()))
+            }
+        }
+    }
+}
+
+// this function writes the text to a temporary file, opens it in the editor and reads it back
+pub fn edit_text(text: &str) -> Result<String> {
+    let path = create_temp_file(text)?;
+    let ret = editor_command().and_then(|mut command| {
+        let status = command.arg(&path).status().with_context(|| {
+            format!(
+                "Failed to run editor '{}'",
+                command.get_program().to_string_lossy()
+            )
+        })?;
+        if status.success() {
+            fs::read_to_string(&path).with_context(|| format!("Failed to read {}", path.display()))
+        } else {
+            Err(anyhow!("Editor exited with {status}"))
+        }
+    });
+    let _ = fs::remove_file(&path);
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_create_temp_file() {
+        let path = create_temp_file("hello").unwrap();
+        let other = create_temp_file("").unwrap();
+        assert_ne!(path, other);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "hello");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        fs::remove_file(path).unwrap();
+        fs::remove_file(other).unwrap();
+    }
+}
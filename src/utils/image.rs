@@ -0,0 +1,92 @@
+// This file reads the dimensions of the images attached to messages, from the headers of their data urls
+use base64::{engine::general_purpose, Engine as _};
+
+// the number of base64 characters decoded to find the dimensions, jpeg keeps them after its metadata
+const HEADER_BASE64_LEN: usize = 128 * 1024;
+
+// this function returns the (width, height) of the image of a base64 data url, or None for other urls
+pub fn data_url_image_size(url: &str) -> Option<(u32, u32)> {
+    let (_, data) = url.strip_prefix("data:")?.split_once(";base64,")?;
+    let len = data.len().min(HEADER_BASE64_LEN) / 4 * 4;
+    let bytes = general_purpose::STANDARD.decode(&data[..len]).ok()?;
+    image_size(&bytes)
+}
+
+// this function reads the (width, height) of a png, gif, webp or jpeg image from its first bytes
+pub fn image_size(data: &[u8]) -> Option<(u32, u32)> {
+    let u16_be = |i: usize| Some(u16::from_be_bytes(data.get(i..i + 2)?.try_into().ok()?) as u32);
+    let u16_le = |i: usize| Some(u16::from_le_bytes(data.get(i..i + 2)?.try_into().ok()?) as u32);
+    let u32_be = |i: usize| Some(u32::from_be_bytes(data.get(i..i + 4)?.try_into().ok()?));
+    let u24_le = |i: usize| {
+        let v = data.get(i..i + 3)?;
+        Some(v[0] as u32 | (v[1] as u32) << 8 | (v[2] as u32) << 16)
+    };
+
+    if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        return Some((u32_be(16)?, u32_be(20)?));
+    }
+    if data.starts_with(b"GIF8") {
+        return Some((u16_le(6)?, u16_le(8)?));
+    }
+    if data.starts_with(b"RIFF") && data.get(8..12) == Some(b"WEBP") {
+        return match data.get(12..16)? {
+            b"VP8 " => Some((u16_le(26)? & 0x3fff, u16_le(28)? & 0x3fff)),
+            b"VP8L" => {
+                let bits = u32::from_le_bytes(data.get(21..25)?.try_into().ok()?);
+                Some(((bits & 0x3fff) + 1, ((bits >> 14) & 0x3fff) + 1))
+            }
+            b"VP8X" => Some((u24_le(24)? + 1, u24_le(27)? + 1)),
+            _ => None,
+        };
+    }
+    if data.starts_with(b"\xff\xd8") {
+        // walking the segments until a start of frame, which holds the dimensions
+        let mut i = 2;
+        while i + 4 <= data.len() {
+            if data[i] != 0xff {
+                return None;
+            }
+            let marker = data[i + 1];
+            if marker == 0xff {
+                i += 1;
+                continue;
+            }
+            let len = u16_be(i + 2)? as usize;
+            if matches!(marker, 0xc0..=0xcf) && !matches!(marker, 0xc4 | 0xc8 | 0xcc) {
+                return Some((u16_be(i + 7)?, u16_be(i + 5)?));
+            }
+            i += 2 + len;
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_image_size() {
+        let mut png = b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR".to_vec();
+        png.extend(800u32.to_be_bytes());
+        png.extend(600u32.to_be_bytes());
+        assert_eq!(image_size(&png), Some((800, 600)));
+
+        let gif = b"GIF89a\x40\x01\xf0\x00";
+        assert_eq!(image_size(gif), Some((320, 240)));
+
+        // an APP0 segment followed by the start of frame
+        let mut jpeg = b"\xff\xd8\xff\xe0\x00\x04\x00\x00".to_vec();
+        jpeg.extend(b"\xff\xc0\x00\x11\x08\x02\x00\x04\x00");
+        assert_eq!(image_size(&jpeg), Some((1024, 512)));
+
+        assert_eq!(image_size(b"not an image"), None);
+
+        let url = format!(
+            "data:image/png;base64,{}",
+            general_purpose::STANDARD.encode(&png)
+        );
+        assert_eq!(data_url_image_size(&url), Some((800, 600)));
+        assert_eq!(data_url_image_size("https://example.com/a.png"), None);
+    }
+}
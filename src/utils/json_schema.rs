@@ -0,0 +1,340 @@
+// This file checks a json value against a json schema, for the answers of a role or of `--schema`.
+// It knows the keywords the structured outputs of the providers use: type, enum, const, properties, required,
+// additionalProperties, items, the bounds of numbers, strings and arrays, pattern, anyOf, oneOf, allOf, not
+// and the local `$ref`, the other keywords are ignored
+use anyhow::{anyhow, bail, Context, Result};
+use fancy_regex::Regex;
+use serde_json::{Map, Value};
+
+// this function returns the json of an answer, which may be in a code block or follow a sentence
+pub fn extract_json(text: &str) -> Result<Value> {
+    let text = text.trim();
+    if let Ok(value) = serde_json::from_str(text) {
+        return Ok(value);
+    }
+    let text = match text.find("```") {
+        Some(start) => {
+            let rest = &text[start + 3..];
+            // the first line of the fence is its language
+            let body = rest.split_once('\n').map(|v| v.1).unwrap_or_default();
+            body.split("```").next().unwrap_or_default().trim()
+        }
+        None => text,
+    };
+    // the json is the longest value starting at a `{` or a `[`, so that a reference like `[1]` in the sentence
+    // is not taken for it, the values starting inside the longest one being part of it
+    let mut best: Option<(usize, Value)> = None;
+    let mut best_end = 0;
+    let mut error = None;
+    for (start, _) in text.match_indices(['{', '[']) {
+        if start < best_end {
+            continue;
+        }
+        let mut values = serde_json::Deserializer::from_str(&text[start..]).into_iter::<Value>();
+        match values.next() {
+            Some(Ok(value)) => {
+                let length = values.byte_offset();
+                if best.as_ref().map(|(v, _)| length > *v).unwrap_or(true) {
+                    best = Some((length, value));
+                    best_end = start + length;
+                }
+            }
+            Some(Err(err)) => {
+                error.get_or_insert(err);
+            }
+            None => {}
+        }
+    }
+    match (best, error) {
+        (Some((_, value)), _) => Ok(value),
+        (None, Some(err)) => bail!("invalid json, {err}"),
+        (None, None) => bail!("no json found"),
+    }
+}
+
+// this function checks the value against the schema, the error tells where the value is wrong, like
+// `/items/0/name: expected string, got number`
+pub fn validate_json(schema: &Value, value: &Value) -> Result<()> {
+    Validator { root: schema }.validate(schema, value, "")
+}
+
+struct Validator<'a> {
+    root: &'a Value, // the schema the `$ref` point into
+}
+
+impl<'a> Validator<'a> {
+    fn validate(&self, schema: &'a Value, value: &Value, path: &str) -> Result<()> {
+        let schema = match schema {
+            Value::Bool(true) => return Ok(()),
+            Value::Bool(false) => bail!("{}: no value is allowed", show_path(path)),
+            Value::Object(schema) => schema,
+            _ => bail!("{}: invalid schema", show_path(path)),
+        };
+        let fail = |message: String| Err(anyhow!("{}: {message}", show_path(path)));
+        if let Some(reference) = schema.get("$ref").and_then(|v| v.as_str()) {
+            let target = self.resolve(reference)?;
+            self.validate(target, value, path)?;
+        }
+        if let Some(types) = schema.get("type") {
+            let types: Vec<&str> = match types {
+                Value::Array(list) => list.iter().filter_map(|v| v.as_str()).collect(),
+                _ => types.as_str().into_iter().collect(),
+            };
+            if !types.iter().any(|v| is_type(value, v)) {
+                return fail(format!(
+                    "expected {}, got {}",
+                    types.join(" or "),
+                    type_name(value)
+                ));
+            }
+        }
+        if let Some(list) = schema.get("enum").and_then(|v| v.as_array()) {
+            if !list.contains(value) {
+                return fail(format!("expected one of {}", Value::Array(list.clone())));
+            }
+        }
+        if let Some(expected) = schema.get("const") {
+            if expected != value {
+                return fail(format!("expected {expected}"));
+            }
+        }
+        match value {
+            Value::Object(object) => self.validate_object(schema, object, path)?,
+            Value::Array(list) => {
+                if let Some(min) = schema.get("minItems").and_then(|v| v.as_u64()) {
+                    if (list.len() as u64) < min {
+                        return fail(format!("expected at least {min} items"));
+                    }
+                }
+                if let Some(max) = schema.get("maxItems").and_then(|v| v.as_u64()) {
+                    if list.len() as u64 > max {
+                        return fail(format!("expected at most {max} items"));
+                    }
+                }
+                if let Some(items) = schema.get("items") {
+                    for (i, item) in list.iter().enumerate() {
+                        self.validate(items, item, &format!("{path}/{i}"))?;
+                    }
+                }
+            }
+            Value::String(text) => {
+                let length = text.chars().count() as u64;
+                if let Some(min) = schema.get("minLength").and_then(|v| v.as_u64()) {
+                    if length < min {
+                        return fail(format!("expected at least {min} characters"));
+                    }
+                }
+                if let Some(max) = schema.get("maxLength").and_then(|v| v.as_u64()) {
+                    if length > max {
+                        return fail(format!("expected at most {max} characters"));
+                    }
+                }
+                if let Some(pattern) = schema.get("pattern").and_then(|v| v.as_str()) {
+                    let re = Regex::new(pattern)
+                        .with_context(|| format!("Invalid pattern '{pattern}' in the schema"))?;
+                    if !re.is_match(text).unwrap_or_default() {
+                        return fail(format!("expected to match '{pattern}'"));
+                    }
+                }
+            }
+            Value::Number(number) => {
+                let number = number.as_f64().unwrap_or_default();
+                let bound = |key: &str| schema.get(key).and_then(|v| v.as_f64());
+                if let Some(min) = bound("minimum").filter(|v| number < *v) {
+                    return fail(format!("expected at least {min}"));
+                }
+                if let Some(max) = bound("maximum").filter(|v| number > *v) {
+                    return fail(format!("expected at most {max}"));
+                }
+                if let Some(min) = bound("exclusiveMinimum").filter(|v| number <= *v) {
+                    return fail(format!("expected more than {min}"));
+                }
+                if let Some(max) = bound("exclusiveMaximum").filter(|v| number >= *v) {
+                    return fail(format!("expected less than {max}"));
+                }
+            }
+            _ => {}
+        }
+        if let Some(list) = schema.get("allOf").and_then(|v| v.as_array()) {
+            for schema in list {
+                self.validate(schema, value, path)?;
+            }
+        }
+        if let Some(list) = schema.get("anyOf").and_then(|v| v.as_array()) {
+            let errors: Vec<anyhow::Error> = list
+                .iter()
+                .filter_map(|schema| self.validate(schema, value, path).err())
+                .collect();
+            if errors.len() == list.len() {
+                let errors: Vec<String> = errors.iter().map(|v| v.to_string()).collect();
+                return fail(format!(
+                    "expected any of the schemas, {}",
+                    errors.join("; ")
+                ));
+            }
+        }
+        if let Some(list) = schema.get("oneOf").and_then(|v| v.as_array()) {
+            let matches = list
+                .iter()
+                .filter(|schema| self.validate(schema, value, path).is_ok())
+                .count();
+            if matches != 1 {
+                return fail(format!(
+                    "expected exactly one of the schemas, {matches} match"
+                ));
+            }
+        }
+        if let Some(schema) = schema.get("not") {
+            if self.validate(schema, value, path).is_ok() {
+                return fail("expected not to match the schema of `not`".into());
+            }
+        }
+        Ok(())
+    }
+
+    fn validate_object(
+        &self,
+        schema: &'a Map<String, Value>,
+        object: &Map<String, Value>,
+        path: &str,
+    ) -> Result<()> {
+        if let Some(required) = schema.get("required").and_then(|v| v.as_array()) {
+            for key in required.iter().filter_map(|v| v.as_str()) {
+                if !object.contains_key(key) {
+                    bail!("{}: missing property '{key}'", show_path(path));
+                }
+            }
+        }
+        let properties = schema.get("properties").and_then(|v| v.as_object());
+        let additional = schema.get("additionalProperties");
+        for (key, item) in object {
+            let item_path = format!("{path}/{key}");
+            match properties.and_then(|v| v.get(key)) {
+                Some(schema) => self.validate(schema, item, &item_path)?,
+                None => match additional {
+                    Some(Value::Bool(false)) => {
+                        bail!("{}: unexpected property '{key}'", show_path(path))
+                    }
+                    Some(schema) => self.validate(schema, item, &item_path)?,
+                    None => {}
+                },
+            }
+        }
+        Ok(())
+    }
+
+    // this function returns the schema of a local `$ref`, like `#/$defs/item`
+    fn resolve(&self, reference: &str) -> Result<&'a Value> {
+        let pointer = reference
+            .strip_prefix('#')
+            .with_context(|| format!("Unsupported $ref '{reference}', only local ones are"))?;
+        self.root
+            .pointer(pointer)
+            .with_context(|| format!("Unknown $ref '{reference}'"))
+    }
+}
+
+fn is_type(value: &Value, name: &str) -> bool {
+    match name {
+        "null" => value.is_null(),
+        "boolean" => value.is_boolean(),
+        "object" => value.is_object(),
+        "array" => value.is_array(),
+        "string" => value.is_string(),
+        "number" => value.is_number(),
+        "integer" => value.as_f64().map(|v| v.fract() == 0.0).unwrap_or_default(),
+        _ => false,
+    }
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Object(_) => "object",
+        Value::Array(_) => "array",
+        Value::String(_) => "string",
+        Value::Number(_) => "number",
+    }
+}
+
+fn show_path(path: &str) -> &str {
+    if path.is_empty() {
+        "/"
+    } else {
+        path
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_validate_json() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "level": { "enum": ["info", "warn", "error"] },
+                "count": { "type": "integer", "minimum": 0 },
+                "items": { "type": "array", "items": { "$ref": "#/$defs/item" } }
+            },
+            "required": ["level", "items"],
+            "additionalProperties": false,
+            "$defs": {
+                "item": {
+                    "type": "object",
+                    "properties": { "name": { "type": "string", "minLength": 1 } },
+                    "required": ["name"]
+                }
+            }
+        });
+        let check = |value: Value| validate_json(&schema, &value).map_err(|v| v.to_string());
+        assert!(check(json!({ "level": "warn", "count": 3, "items": [{ "name": "a" }] })).is_ok());
+        assert_eq!(
+            check(json!({ "level": "warn", "items": [{ "name": 1 }] })).unwrap_err(),
+            "/items/0/name: expected string, got number"
+        );
+        assert_eq!(
+            check(json!({ "level": "warn" })).unwrap_err(),
+            "/: missing property 'items'"
+        );
+        assert_eq!(
+            check(json!({ "level": "debug", "items": [] })).unwrap_err(),
+            r#"/level: expected one of ["info","warn","error"]"#
+        );
+        assert_eq!(
+            check(json!({ "level": "info", "count": 1.5, "items": [] })).unwrap_err(),
+            "/count: expected integer, got number"
+        );
+        assert_eq!(
+            check(json!({ "level": "info", "items": [], "extra": 1 })).unwrap_err(),
+            "/: unexpected property 'extra'"
+        );
+
+        let schema = json!({ "anyOf": [{ "type": "string" }, { "type": "null" }] });
+        assert!(validate_json(&schema, &json!(null)).is_ok());
+        assert!(validate_json(&schema, &json!(1)).is_err());
+    }
+
+    #[test]
+    fn test_extract_json() {
+        assert_eq!(extract_json(r#" {"a": 1} "#).unwrap(), json!({ "a": 1 }));
+        assert_eq!(extract_json("```json\n[1, 2]\n```").unwrap(), json!([1, 2]));
+        assert_eq!(
+            extract_json("Here is the json: {\"a\": {\"b\": true}}.").unwrap(),
+            json!({ "a": { "b": true } })
+        );
+        assert_eq!(
+            extract_json("As said in [1]: {\"a\": [2]} and [3].").unwrap(),
+            json!({ "a": [2] })
+        );
+        assert_eq!(
+            extract_json("{ not json } but {\"a\": 1}").unwrap(),
+            json!({ "a": 1 })
+        );
+        assert!(extract_json("no json").is_err());
+        assert!(extract_json("{\"a\": }").is_err());
+    }
+}
@@ -1,14 +1,24 @@
 mod abort_signal;
+mod bm25;
 mod clipboard;
+mod editor;
+mod image;
+mod json_schema;
 mod prompt_input;
 mod render_prompt;
 mod tiktoken;
+mod tokenizer;
 
 pub use self::abort_signal::{create_abort_signal, AbortSignal};
+pub use self::bm25::Bm25Index;
 pub use self::clipboard::set_text;
+pub use self::editor::edit_text;
+pub use self::image::data_url_image_size;
+pub use self::json_schema::{extract_json, validate_json};
 pub use self::prompt_input::*;
 pub use self::render_prompt::render_prompt;
 pub use self::tiktoken::cl100k_base_singleton;
+pub use self::tokenizer::{default_tokenizer, get_tokenizer, CL100K_BASE};
 
 use sha2::{Digest, Sha256};
 
@@ -55,14 +65,6 @@ pub fn tokenize(text: &str) -> Vec<String> {
     output
 }
 
-// this function counts how many tokens a piece of text needs to consume
-pub fn count_tokens(text: &str) -> usize {
-    cl100k_base_singleton()
-        .lock()
-        .encode_with_special_tokens(text)
-        .len()
-}
-
 // this function determines whether a light theme should be used based on the
 // background color provided in the colorfgbg string
 pub fn light_theme_from_colorfgbg(colorfgbg: &str) -> Option<bool> {
@@ -116,6 +118,6 @@ mod tests {
 
     #[test]
     fn test_count_tokens() {
-        assert_eq!(count_tokens("😊 hello world"), 4);
+        assert_eq!(get_tokenizer(CL100K_BASE).count("😊 hello world"), 4);
     }
 }
@@ -8,7 +8,7 @@ use std::collections::HashMap;
 /// - `{var}` - When `var` has a value, replace `var` with the value and eval `template`
 /// - `{?var <template>}` - Eval `template` when `var` is evaluated as true
 /// - `{!var <template>}` - Eval `template` when `var` is evaluated as false
-
+//
 // this function takes a template string and a hashmap of variables and renders them
 pub fn render_prompt(template: &str, variables: &HashMap<&str, String>) -> String {
     // we first parse the template
@@ -19,16 +19,7 @@ pub fn cl100k_base() -> Result<CoreBPE> {
     // reading the file
     let cl100k_base = include_str!("../../assets/cl100k_base.tiktoken");
 
-    let mut encoder = HashMap::default();
-    // parsing each line to extract the token and its rank
-    for line in cl100k_base.lines() {
-        let mut parts = line.split(' ');
-        let raw = parts.next().unwrap();
-        let token = &general_purpose::STANDARD.decode(raw)?;
-        let rank: usize = parts.next().unwrap().parse().unwrap();
-        // inserting the token and rank in the encoder
-        encoder.insert(token.clone(), rank);
-    }
+    let encoder = parse_tiktoken_ranks(cl100k_base)?;
 
     let mut special_tokens = HashMap::default();
     special_tokens.insert(String::from("<|endoftext|>"), 100257);
@@ -45,6 +36,49 @@ pub fn cl100k_base() -> Result<CoreBPE> {
     )
 }
 
+// this function initializes a CoreBPE object from the content of the o200k_base.tiktoken file,
+// which is too large to be embedded and is downloaded on first use
+pub fn o200k_base(content: &str) -> Result<CoreBPE> {
+    let encoder = parse_tiktoken_ranks(content)?;
+
+    let mut special_tokens = HashMap::default();
+    special_tokens.insert(String::from("<|endoftext|>"), 199999);
+    special_tokens.insert(String::from("<|endofprompt|>"), 200018);
+
+    CoreBPE::new(
+        encoder,
+        special_tokens,
+        &[
+            "[^\\r\\n\\p{L}\\p{N}]?[\\p{Lu}\\p{Lt}\\p{Lm}\\p{Lo}\\p{M}]*[\\p{Ll}\\p{Lm}\\p{Lo}\\p{M}]+(?i:'s|'t|'re|'ve|'m|'ll|'d)?",
+            "[^\\r\\n\\p{L}\\p{N}]?[\\p{Lu}\\p{Lt}\\p{Lm}\\p{Lo}\\p{M}]+[\\p{Ll}\\p{Lm}\\p{Lo}\\p{M}]*(?i:'s|'t|'re|'ve|'m|'ll|'d)?",
+            "\\p{N}{1,3}",
+            " ?[^\\s\\p{L}\\p{N}]+[\\r\\n/]*",
+            "\\s*[\\r\\n]+",
+            "\\s+(?!\\S)",
+            "\\s+",
+        ]
+        .join("|"),
+    )
+}
+
+// this function parses the lines of a .tiktoken file, each one a base64 token followed by its rank
+pub fn parse_tiktoken_ranks(content: &str) -> Result<HashMap<Vec<u8>, usize>> {
+    let mut encoder = HashMap::default();
+    for line in content.lines() {
+        if line.is_empty() {
+            continue;
+        }
+        let (raw, rank) = line
+            .split_once(' ')
+            .ok_or_else(|| anyhow!("Invalid tiktoken line '{line}'"))?;
+        let token = general_purpose::STANDARD.decode(raw)?;
+        let rank: usize = rank.parse()?;
+        // inserting the token and rank in the encoder
+        encoder.insert(token, rank);
+    }
+    Ok(encoder)
+}
+
 // this function returns a thread-safe singleton instance of the CoreBPE object created by cl100k_base
 pub fn cl100k_base_singleton() -> Arc<Mutex<CoreBPE>> {
     // ensuring that only one instance of the CoreBPE object is created and is shared across different threads
@@ -405,7 +439,7 @@ impl CoreBPE {
         if unstable_bytes.len() > 1 {
             let last_decoded = bstr::decode_last_utf8(unstable_bytes.as_slice());
             if unstable_bytes.len() - last_decoded.1 > 0
-                && last_decoded.0.map_or(false, |c| c.is_whitespace())
+                && last_decoded.0.is_some_and(|c| c.is_whitespace())
             {
                 let mut reencoded = byte_pair_encode(
                     &unstable_bytes[..unstable_bytes.len() - last_decoded.1],
@@ -423,7 +457,7 @@ impl CoreBPE {
     }
 }
 
-// 
+//
 impl CoreBPE {
     // this is the constructor method for CoreBPE
     fn new(
@@ -432,8 +466,8 @@ impl CoreBPE {
         pattern: &str,
     ) -> Result<Self> {
         let regex = Regex::new(pattern)?;
-        
-        // regular expression patterns for the main tokens and special tokens 
+
+        // regular expression patterns for the main tokens and special tokens
         let special_regex = {
             let _parts = special_tokens_encoder
                 .keys()
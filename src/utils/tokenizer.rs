@@ -0,0 +1,412 @@
+// This file implements the registry of the tokenizers used to count the tokens of a model:
+// the cl100k_base and o200k_base encodings of openai, and the BPE `tokenizer.json` files of hugging face
+use super::tiktoken::{cl100k_base_singleton, o200k_base, CoreBPE};
+use super::{init_tokio_runtime, sha256sum};
+
+use crate::config::Config;
+
+use anyhow::{anyhow, bail, Context, Result};
+use fancy_regex::Regex;
+use lazy_static::lazy_static;
+use parking_lot::Mutex;
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::fs::{create_dir_all, read_to_string, rename, write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+pub const CL100K_BASE: &str = "cl100k_base";
+pub const O200K_BASE: &str = "o200k_base";
+
+// the directory of the config dir where the tokenizer files are looked for
+const TOKENIZERS_DIR_NAME: &str = "tokenizers";
+
+// where o200k_base.tiktoken is downloaded from on first use, and the sha256 it must have
+const O200K_BASE_URL: &str =
+    "https://openaipublic.blob.core.windows.net/encodings/o200k_base.tiktoken";
+const O200K_BASE_SHA256: &str = "446a9538cb6c348e3516120d7c08b09f57c36495e2acfffe59a5bf8b0cfb1a2d";
+
+// the pre-tokenizer of GPT-2, used by byte-level tokenizers that do not give their own
+const BYTE_LEVEL_PATTERN: &str =
+    r"'s|'t|'re|'ve|'m|'ll|'d| ?\p{L}+| ?\p{N}+| ?[^\s\p{L}\p{N}]+|\s+(?!\S)|\s+";
+
+// the character sentencepiece uses in place of spaces
+const METASPACE: char = '▁';
+
+// enum representing a loaded tokenizer
+pub enum Tokenizer {
+    Tiktoken(Arc<Mutex<CoreBPE>>), // an openai encoding
+    Bpe(Box<BpeTokenizer>),        // a hugging face tokenizer.json
+    Fallback(String), // a tokenizer which failed to load, with the error, cl100k_base counting in its place
+}
+
+impl Tokenizer {
+    // this function counts the tokens of a text
+    pub fn count(&self, text: &str) -> usize {
+        match self {
+            Tokenizer::Tiktoken(bpe) => bpe.lock().encode_with_special_tokens(text).len(),
+            Tokenizer::Bpe(bpe) => bpe.count(text),
+            Tokenizer::Fallback(_) => cl100k_base_singleton()
+                .lock()
+                .encode_with_special_tokens(text)
+                .len(),
+        }
+    }
+
+    // this function returns the error of a tokenizer which failed to load and is replaced by cl100k_base
+    pub fn fallback(&self) -> Option<&str> {
+        match self {
+            Tokenizer::Fallback(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+// this function returns the tokenizer of a model without a configured one,
+// the models from gpt-4o on use o200k_base and the others are counted with cl100k_base
+pub fn default_tokenizer(model_name: &str) -> &'static str {
+    let name = model_name.rsplit('/').next().unwrap_or(model_name);
+    if ["gpt-4o", "chatgpt-4o", "o1", "o3"]
+        .iter()
+        .any(|v| name.starts_with(v))
+    {
+        O200K_BASE
+    } else {
+        CL100K_BASE
+    }
+}
+
+// this function returns the tokenizer with the given name, `cl100k_base`, `o200k_base` or the path of a
+// tokenizer.json, which is loaded once, a tokenizer failing to load is replaced by cl100k_base
+pub fn get_tokenizer(name: &str) -> Arc<Tokenizer> {
+    lazy_static! {
+        static ref TOKENIZERS: Mutex<HashMap<String, Arc<Tokenizer>>> = Default::default();
+    }
+    let mut tokenizers = TOKENIZERS.lock();
+    if let Some(tokenizer) = tokenizers.get(name) {
+        return tokenizer.clone();
+    }
+    let tokenizer = match load_tokenizer(name) {
+        Ok(v) => v,
+        Err(err) => {
+            warn!("Failed to load tokenizer '{name}', counting with {CL100K_BASE}: {err:#}");
+            Tokenizer::Fallback(format!("{err:#}"))
+        }
+    };
+    let tokenizer = Arc::new(tokenizer);
+    tokenizers.insert(name.to_string(), tokenizer.clone());
+    tokenizer
+}
+
+// this function loads a tokenizer, the files being relative to the tokenizers dir of the config dir
+fn load_tokenizer(name: &str) -> Result<Tokenizer> {
+    match name {
+        CL100K_BASE => Ok(Tokenizer::Tiktoken(cl100k_base_singleton())),
+        O200K_BASE => {
+            let path = tokenizer_path(&format!("{O200K_BASE}.tiktoken"))?;
+            let content = if path.exists() {
+                read_to_string(&path)
+                    .with_context(|| format!("Failed to load {}", path.display()))?
+            } else {
+                download_tiktoken(O200K_BASE_URL, O200K_BASE_SHA256, &path)?
+            };
+            let bpe = o200k_base(&content)?;
+            Ok(Tokenizer::Tiktoken(Arc::new(Mutex::new(bpe))))
+        }
+        _ => {
+            let path = tokenizer_path(name)?;
+            let content = read_to_string(&path)
+                .with_context(|| format!("Failed to load {}", path.display()))?;
+            let data: Value = serde_json::from_str(&content)
+                .with_context(|| format!("Invalid tokenizer at {}", path.display()))?;
+            Ok(Tokenizer::Bpe(Box::new(BpeTokenizer::from_json(&data)?)))
+        }
+    }
+}
+
+// this function downloads a .tiktoken file and saves it to the given path once its sha256 is checked,
+// on its own thread as the tokens may be counted from within the runtime of a request
+fn download_tiktoken(url: &str, sha256: &str, path: &Path) -> Result<String> {
+    let url = url.to_string();
+    let content = std::thread::spawn(move || -> Result<String> {
+        init_tokio_runtime()?
+            .block_on(async {
+                let client = reqwest::Client::builder()
+                    .connect_timeout(Duration::from_secs(10))
+                    .build()?;
+                let res = client.get(&url).send().await?.error_for_status()?;
+                let content = res.text().await?;
+                anyhow::Ok(content)
+            })
+            .with_context(|| format!("Failed to download {url}"))
+    })
+    .join()
+    .map_err(|_| anyhow!("Failed to download the tokenizer"))??;
+    if sha256sum(&content) != sha256 {
+        bail!("Invalid checksum of the downloaded {}", path.display());
+    }
+    if let Some(dir) = path.parent() {
+        create_dir_all(dir)
+            .with_context(|| format!("Failed to create the tokenizers dir {}", dir.display()))?;
+    }
+    // the file is renamed once complete, an interrupted write is not loaded the next time
+    let temp_path = path.with_extension("tmp");
+    write(&temp_path, &content)
+        .and_then(|_| rename(&temp_path, path))
+        .with_context(|| format!("Failed to save {}", path.display()))?;
+    Ok(content)
+}
+
+// this function resolves the path of a tokenizer file
+fn tokenizer_path(name: &str) -> Result<PathBuf> {
+    let path = PathBuf::from(name);
+    if path.is_absolute() {
+        return Ok(path);
+    }
+    let mut dir = Config::local_path(TOKENIZERS_DIR_NAME)?;
+    dir.push(path);
+    Ok(dir)
+}
+
+// struct representing a BPE tokenizer loaded from a hugging face tokenizer.json, which only counts tokens
+pub struct BpeTokenizer {
+    vocab: HashSet<String>,
+    ranks: HashMap<(String, String), usize>, // the rank of each merge, the lowest applied first
+    byte_level: bool, // whether the bytes are mapped to printable characters like GPT-2, or sentencepiece is used
+    byte_fallback: bool, // whether an unknown piece is split into one token per byte
+    pattern: Option<Regex>, // the pre-tokenizer splitting the text of byte-level tokenizers
+}
+
+impl BpeTokenizer {
+    // this function builds the tokenizer from the json of a tokenizer.json file
+    pub fn from_json(data: &Value) -> Result<Self> {
+        let model = &data["model"];
+        match model["type"].as_str() {
+            Some("BPE") => {}
+            None if model["merges"].is_array() => {}
+            Some(v) => bail!("Unsupported tokenizer model '{v}', only BPE is supported"),
+            None => bail!("Invalid tokenizer, missing model"),
+        }
+        let vocab: HashSet<String> = model["vocab"]
+            .as_object()
+            .map(|v| v.keys().cloned().collect())
+            .unwrap_or_default();
+        let mut ranks = HashMap::new();
+        for (rank, merge) in model["merges"].as_array().into_iter().flatten().enumerate() {
+            // the merges are "a b" strings, or ["a", "b"] pairs in newer files
+            let pair = match merge {
+                Value::String(v) => v
+                    .split_once(' ')
+                    .map(|(a, b)| (a.to_string(), b.to_string())),
+                Value::Array(v) => match (v.first(), v.get(1)) {
+                    (Some(Value::String(a)), Some(Value::String(b))) => {
+                        Some((a.to_string(), b.to_string()))
+                    }
+                    _ => None,
+                },
+                _ => None,
+            };
+            let Some(pair) = pair else {
+                bail!("Invalid tokenizer merge {merge}");
+            };
+            ranks.entry(pair).or_insert(rank);
+        }
+        let pre_tokenizer = &data["pre_tokenizer"];
+        let byte_level = pre_tokenizer.to_string().contains(r#""ByteLevel""#);
+        let pattern = if byte_level {
+            let pattern = find_split_pattern(pre_tokenizer).unwrap_or(BYTE_LEVEL_PATTERN);
+            let regex = Regex::new(pattern)
+                .with_context(|| format!("Invalid tokenizer pattern '{pattern}'"))?;
+            Some(regex)
+        } else {
+            None
+        };
+        Ok(Self {
+            vocab,
+            ranks,
+            byte_level,
+            byte_fallback: model["byte_fallback"].as_bool().unwrap_or_default(),
+            pattern,
+        })
+    }
+
+    // this function counts the tokens of a text
+    pub fn count(&self, text: &str) -> usize {
+        if text.is_empty() {
+            return 0;
+        }
+        if self.byte_level {
+            let Some(pattern) = &self.pattern else {
+                return 0;
+            };
+            pattern
+                .find_iter(text)
+                .flatten()
+                .map(|m| {
+                    let piece: String = m.as_str().bytes().map(byte_to_char).collect();
+                    self.count_symbols(self.merge(&piece))
+                })
+                .sum()
+        } else {
+            // sentencepiece replaces the spaces and starts the text with one, the merges never cross a word
+            let text = format!("{METASPACE}{}", text.replace(' ', &METASPACE.to_string()));
+            let mut words = vec![];
+            let mut start = 0;
+            for (i, ch) in text.char_indices() {
+                if ch == METASPACE && i > start && !text[start..i].ends_with(METASPACE) {
+                    words.push(&text[start..i]);
+                    start = i;
+                }
+            }
+            words.push(&text[start..]);
+            words
+                .into_iter()
+                .map(|word| self.count_symbols(self.merge(word)))
+                .sum()
+        }
+    }
+
+    // this function applies the merges to a word, always the one of lowest rank first
+    fn merge(&self, word: &str) -> Vec<String> {
+        let mut symbols: Vec<String> = word.chars().map(|v| v.to_string()).collect();
+        loop {
+            let best = symbols
+                .windows(2)
+                .enumerate()
+                .filter_map(|(i, pair)| {
+                    self.ranks
+                        .get(&(pair[0].clone(), pair[1].clone()))
+                        .map(|rank| (*rank, i))
+                })
+                .min();
+            let Some((_, i)) = best else {
+                break;
+            };
+            let next = symbols.remove(i + 1);
+            symbols[i].push_str(&next);
+        }
+        symbols
+    }
+
+    // this function counts the tokens of the merged symbols, an unknown one taking a token per byte
+    // with byte fallback
+    fn count_symbols(&self, symbols: Vec<String>) -> usize {
+        symbols
+            .iter()
+            .map(|v| {
+                if self.byte_fallback && !self.vocab.contains(v) {
+                    v.len()
+                } else {
+                    1
+                }
+            })
+            .sum()
+    }
+}
+
+// this function finds the regex of a `Split` pre-tokenizer, alone or in a sequence
+fn find_split_pattern(pre_tokenizer: &Value) -> Option<&str> {
+    if pre_tokenizer["type"] == "Split" {
+        return pre_tokenizer["pattern"]["Regex"].as_str();
+    }
+    pre_tokenizer["pretokenizers"]
+        .as_array()?
+        .iter()
+        .find_map(find_split_pattern)
+}
+
+// this function maps a byte to the printable character byte-level tokenizers use for it, as GPT-2 does:
+// the printable latin-1 characters stand for themselves and the others are shifted after 255
+fn byte_to_char(byte: u8) -> char {
+    let printable = |b: u8| matches!(b, b'!'..=b'~' | 0xA1..=0xAC | 0xAE..=0xFF);
+    if printable(byte) {
+        return byte as char;
+    }
+    let shift = (0..byte).filter(|v| !printable(*v)).count() as u32;
+    char::from_u32(256 + shift).unwrap_or(byte as char)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_default_tokenizer() {
+        assert_eq!(default_tokenizer("gpt-4"), CL100K_BASE);
+        assert_eq!(default_tokenizer("gpt-4o-mini"), O200K_BASE);
+        assert_eq!(default_tokenizer("openai/o1-preview"), O200K_BASE);
+        assert_eq!(default_tokenizer("gemini-pro"), CL100K_BASE);
+    }
+
+    #[test]
+    fn test_download_tiktoken() {
+        let content = "IQ== 0\nIg== 1\n";
+        let url = crate::client::mock_server(&[
+            ("200 OK", "text/plain", content),
+            ("200 OK", "text/plain", content),
+        ]);
+        let dir = std::env::temp_dir().join(format!("aichat-tokenizers-{}", std::process::id()));
+        let path = dir.join("tiny.tiktoken");
+
+        let err = download_tiktoken(&url, "0000", &path).unwrap_err();
+        assert!(err.to_string().contains("Invalid checksum"));
+        assert!(!path.exists());
+
+        let downloaded = download_tiktoken(&url, &sha256sum(content), &path).unwrap();
+        assert_eq!(downloaded, content);
+        assert_eq!(read_to_string(&path).unwrap(), content);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_fallback_tokenizer() {
+        let tokenizer = get_tokenizer("/nonexistent/tokenizer.json");
+        assert!(tokenizer
+            .fallback()
+            .unwrap()
+            .contains("/nonexistent/tokenizer.json"));
+        assert_eq!(
+            tokenizer.count("hello world"),
+            get_tokenizer(CL100K_BASE).count("hello world")
+        );
+        assert_eq!(get_tokenizer(CL100K_BASE).fallback(), None);
+    }
+
+    #[test]
+    fn test_byte_level_bpe() {
+        let data = json!({
+            "pre_tokenizer": { "type": "ByteLevel", "add_prefix_space": false },
+            "model": {
+                "type": "BPE",
+                "vocab": { "h": 0, "e": 1, "l": 2, "o": 3, "Ġ": 4, "he": 5, "ll": 6, "hell": 7, "hello": 8, "Ġhello": 9 },
+                "merges": ["h e", "l l", "he ll", "hell o", ["Ġ", "hello"]],
+            },
+        });
+        let bpe = BpeTokenizer::from_json(&data).unwrap();
+        assert_eq!(byte_to_char(b' '), 'Ġ');
+        assert_eq!(bpe.merge("Ġhello"), ["Ġhello"]);
+        assert_eq!(bpe.count("hello hello"), 2);
+        assert_eq!(bpe.count("hell"), 1);
+        assert_eq!(bpe.count("oh"), 2);
+    }
+
+    #[test]
+    fn test_sentencepiece_bpe() {
+        let data = json!({
+            "pre_tokenizer": null,
+            "model": {
+                "type": "BPE",
+                "byte_fallback": true,
+                "vocab": { "▁": 0, "h": 1, "i": 2, "▁h": 3, "▁hi": 4 },
+                "merges": ["▁ h", "▁h i"],
+            },
+        });
+        let bpe = BpeTokenizer::from_json(&data).unwrap();
+        assert_eq!(bpe.count("hi hi"), 2);
+        // the unknown characters take a token per byte
+        assert_eq!(bpe.count("hi é"), 1 + 1 + 2);
+        assert!(BpeTokenizer::from_json(&json!({ "model": { "type": "Unigram" } })).is_err());
+    }
+}